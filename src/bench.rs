@@ -0,0 +1,261 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use arrow::array::{Float64Array, Int64Array, StringArray};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use clap::ValueEnum;
+
+use crate::cli::BenchArgs;
+
+// Distinct strings of the name column of GA tables
+const NAME_VALUES: usize = 16;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Workload {
+    // SD of byte values
+    Set,
+    // GD of byte values written beforehand
+    Get,
+    // GA queries over Arrow tables written beforehand
+    Ga,
+}
+
+// Drives one workload against a running CupidDB from several connections at once, then
+// prints the throughput and latency percentiles. Every connection sends its next request
+// once the previous one is answered.
+pub fn run_bench(args: &BenchArgs) {
+    if args.clients == 0 || args.keys == 0 || args.requests == 0 {
+        eprintln!("--clients, --keys and --requests must be at least 1");
+        exit(1);
+    }
+
+    let value = match args.workload {
+        Workload::Set | Workload::Get => {
+            let mut value = vec!['B' as u8];
+            value.extend(vec!['x' as u8; args.value_size]);
+            value
+        },
+        Workload::Ga => {
+            let mut value = vec!['A' as u8];
+            value.extend(arrow_table(args.rows));
+            value
+        },
+    };
+    let value = Arc::new(value);
+
+    // Keys read by the benchmark are written first
+    if let Workload::Get | Workload::Ga = args.workload {
+        println!("Writing {} keys of {} bytes", args.keys, value.len());
+        let started_at = Instant::now();
+        let mut stream = connect(&args.cupid_address);
+        for key_index in 0..args.keys {
+            let response = request(&mut stream, "SD", &set_payload(key_index, &value));
+            if let Err(e) = check_response(response) {
+                eprintln!("Can not write key {}: {}", bench_key(key_index), e);
+                exit(1);
+            }
+        }
+        println!("Wrote them in {:.2?}", started_at.elapsed());
+    }
+
+    let query_filters = filters_json(args.filters, args.rows);
+    let stride = key_stride(args.keys);
+    let started_at = Instant::now();
+    let mut workers = Vec::new();
+    for client_index in 0..args.clients {
+        // Requests are shared out evenly, the first clients take the remainder
+        let remainder = (client_index < args.requests % args.clients) as usize;
+        let client_requests = args.requests / args.clients + remainder;
+        let mut stream = connect(&args.cupid_address);
+        let value = Arc::clone(&value);
+        let workload = args.workload;
+        let keys = args.keys;
+        let query_filters = query_filters.clone();
+        workers.push(thread::spawn(move || {
+            let mut latencies: Vec<Duration> = Vec::with_capacity(client_requests);
+            let mut errors: usize = 0;
+            for request_index in 0..client_requests {
+                // Spreads the clients over the keys instead of all of them going through in step
+                let key_index = (request_index * stride + client_index) % keys;
+                let (message_type, payload) = match workload {
+                    Workload::Set => ("SD", set_payload(key_index, &value)),
+                    Workload::Get => ("GD", bench_key(key_index).into_bytes()),
+                    Workload::Ga => ("GA", query_payload(key_index, &query_filters)),
+                };
+                let sent_at = Instant::now();
+                let response = request(&mut stream, message_type, &payload);
+                latencies.push(sent_at.elapsed());
+                match check_response(response) {
+                    Ok(_) => {},
+                    Err(e) if e.kind() == io::ErrorKind::Other => errors += 1,
+                    Err(e) => {
+                        eprintln!("Client {} lost its connection: {}", client_index, e);
+                        break;
+                    },
+                }
+            }
+            return (latencies, errors);
+        }));
+    }
+
+    let mut latencies: Vec<Duration> = Vec::with_capacity(args.requests);
+    let mut errors: usize = 0;
+    for worker in workers {
+        let (client_latencies, client_errors) = worker.join().unwrap();
+        latencies.extend(client_latencies);
+        errors += client_errors;
+    }
+    let elapsed = started_at.elapsed();
+    if latencies.len() == 0 {
+        eprintln!("No request was answered");
+        exit(1);
+    }
+    latencies.sort_unstable();
+
+    println!(
+        "{} requests from {} clients in {:.2?}, {} errors",
+        latencies.len(), args.clients, elapsed, errors
+    );
+    println!("Throughput: {:.0} requests/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!(
+        "Latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, p99.9 {:.2?}, max {:.2?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        percentile(&latencies, 99.9),
+        latencies[latencies.len() - 1],
+    );
+}
+
+// Step between the keys a client asks for, coprime with the key count so every key is used
+fn key_stride(keys: usize) -> usize {
+    let mut stride: usize = 7919;
+    while gcd(stride, keys) != 1 {
+        stride += 1;
+    }
+    return stride;
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    return match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    };
+}
+
+// Latency under which `percent` of the sorted latencies fall
+fn percentile(latencies: &Vec<Duration>, percent: f64) -> Duration {
+    let position = ((latencies.len() as f64 * percent / 100.0).ceil() as usize).max(1) - 1;
+    return latencies[position.min(latencies.len() - 1)];
+}
+
+fn bench_key(key_index: usize) -> String {
+    return format!("bench:{}", key_index);
+}
+
+fn set_payload(key_index: usize, value: &Vec<u8>) -> Vec<u8> {
+    let key = bench_key(key_index);
+    let mut payload = 0u64.to_be_bytes().to_vec();
+    payload.extend((key.len() as u16).to_be_bytes());
+    payload.extend(key.into_bytes());
+    payload.extend(value);
+    return payload;
+}
+
+fn query_payload(key_index: usize, query_filters: &String) -> Vec<u8> {
+    let query = format!(
+        r#"{{"key":"{}","columns":[],"filterlogic":"AND","filter":{},"cachetime":0,"compression_type":""}}"#,
+        bench_key(key_index), query_filters
+    );
+    return query.into_bytes();
+}
+
+// `count` filters going round the columns of the GA table
+fn filters_json(count: usize, rows: usize) -> String {
+    let filters: Vec<serde_json::Value> = (0..count)
+        .map(|filter_index| match filter_index % 3 {
+            0 => serde_json::json!({
+                "col": "id", "filter_type": "gte", "data_type": "IN", "value_int": (rows / 2) as i64
+            }),
+            1 => serde_json::json!({
+                "col": "price", "filter_type": "lt", "data_type": "FL", "value_flt": rows as f64 * 0.75
+            }),
+            _ => serde_json::json!({
+                "col": "name", "filter_type": "eq", "data_type": "ST",
+                "value_str": format!("name{}", filter_index % NAME_VALUES)
+            }),
+        })
+        .collect();
+    return serde_json::Value::Array(filters).to_string();
+}
+
+// Arrow IPC stream of a table with `rows` rows of an Int64, a Float64 and a Utf8 column
+fn arrow_table(rows: usize) -> Vec<u8> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("price", DataType::Float64, false),
+        Field::new("name", DataType::Utf8, false),
+    ]));
+    let ids: Vec<i64> = (0..rows as i64).collect();
+    let prices: Vec<f64> = ids.iter().map(|id| *id as f64 * 1.5).collect();
+    let names: Vec<String> = ids.iter().map(|id| format!("name{}", *id as usize % NAME_VALUES)).collect();
+    let record_batch = RecordBatch::try_new(schema.clone(), vec![
+        Arc::new(Int64Array::from(ids)),
+        Arc::new(Float64Array::from(prices)),
+        Arc::new(StringArray::from(names)),
+    ]).unwrap();
+
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema).unwrap();
+    writer.write(&record_batch).unwrap();
+    writer.finish().unwrap();
+    return writer.into_inner().unwrap();
+}
+
+fn connect(address: &str) -> TcpStream {
+    match TcpStream::connect(address) {
+        Ok(stream) => {
+            let _ = stream.set_nodelay(true);
+            return stream;
+        },
+        Err(e) => {
+            eprintln!("Can not connect to CupidDB at {}: {}", address, e);
+            exit(1);
+        }
+    }
+}
+
+// Sends one frame and reads its response, returns the message type and payload
+fn request(stream: &mut TcpStream, message_type: &str, payload: &[u8]) -> io::Result<(String, Vec<u8>)> {
+    let mut frame = format!("A{}", message_type).into_bytes();
+    frame.extend((payload.len() as u64).to_be_bytes());
+    frame.extend(payload);
+    stream.write_all(&frame)?;
+
+    let mut header_buffer = [0; 11];
+    stream.read_exact(&mut header_buffer)?;
+    let payload_length = u64::from_be_bytes(header_buffer[3..11].try_into().unwrap());
+    let mut response_payload = vec![0; payload_length as usize];
+    stream.read_exact(&mut response_payload)?;
+    return Ok((String::from_utf8_lossy(&header_buffer[1..3]).to_string(), response_payload));
+}
+
+// An "ER" response is an error of kind Other, anything else the connection failing
+fn check_response(response: io::Result<(String, Vec<u8>)>) -> io::Result<()> {
+    let (message_type, payload) = response?;
+    if message_type == "ER" {
+        let error_code = match payload.len() {
+            2 => u16::from_be_bytes([payload[0], payload[1]]).to_string(),
+            _ => "unknown".to_string(),
+        };
+        return Err(io::Error::other(format!("error code {}", error_code)));
+    }
+    if message_type == "CC" || message_type == "SC" {
+        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the server closed the connection"));
+    }
+    return Ok(());
+}
@@ -0,0 +1,78 @@
+use clap::{Args, Parser, Subcommand};
+use tracing::Level;
+
+use crate::bench::Workload;
+
+// Command line of the server. Its flags take precedence over environment variables and the
+// config file.
+#[derive(Parser)]
+#[command(name = "cupiddb", version, about = "In-memory database for caching Arrow DataFrames")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(long, value_name = "PATH", help = "TOML file to read settings from")]
+    pub config: Option<String>,
+    #[arg(long, value_name = "ADDRESS", help = "Address to listen on [default: 0.0.0.0]")]
+    pub bind: Option<String>,
+    #[arg(long, help = "Port to listen on [default: 5995]")]
+    pub port: Option<u16>,
+    #[arg(long, value_name = "COUNT", help = "Number of worker threads [default: number of CPU cores]")]
+    pub workers: Option<usize>,
+    #[arg(long, value_name = "LEVEL", help = "ERROR, WARN, INFO, DEBUG or TRACE [default: INFO]")]
+    pub log_level: Option<Level>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Copy string keys, with their TTLs, from Redis into a running CupidDB")]
+    ImportRedis {
+        #[arg(value_name = "REDIS_ADDRESS", help = "host:port of the Redis instance")]
+        redis_address: String,
+        #[arg(value_name = "CUPIDDB_ADDRESS", default_value = "127.0.0.1:5995", help = "host:port of CupidDB")]
+        cupid_address: String,
+        #[arg(long, help = "Store strings holding a decimal integer as integers, so II works on them")]
+        integers: bool,
+    },
+    #[command(about = "Measure the throughput and latency of a running CupidDB under a SET, GET or GA workload")]
+    Bench(BenchArgs),
+}
+
+#[derive(Args)]
+pub struct BenchArgs {
+    #[arg(value_name = "CUPIDDB_ADDRESS", default_value = "127.0.0.1:5995", help = "host:port of CupidDB")]
+    pub cupid_address: String,
+    #[arg(long, value_enum, default_value = "get", help = "Requests to send")]
+    pub workload: Workload,
+    #[arg(long, default_value_t = 100_000, help = "Number of requests to send")]
+    pub requests: usize,
+    #[arg(long, default_value_t = 50, help = "Number of connections sending requests at once")]
+    pub clients: usize,
+    #[arg(long, default_value_t = 1000, help = "Number of distinct keys the requests go to")]
+    pub keys: usize,
+    #[arg(long, value_name = "BYTES", default_value_t = 100, help = "Size of the values of set and get")]
+    pub value_size: usize,
+    #[arg(long, default_value_t = 10_000, help = "Rows of the Arrow table behind each key of ga")]
+    pub rows: usize,
+    #[arg(long, value_name = "COUNT", default_value_t = 1, help = "Number of filters of each ga query")]
+    pub filters: usize,
+}
+
+impl Cli {
+    // (config key, flag, value) of every setting given on the command line
+    pub fn setting_flags(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut flags = Vec::new();
+        if let Some(bind) = &self.bind {
+            flags.push(("bind_address", "--bind", bind.clone()));
+        }
+        if let Some(port) = self.port {
+            flags.push(("port", "--port", port.to_string()));
+        }
+        if let Some(workers) = self.workers {
+            flags.push(("worker_threads", "--workers", workers.to_string()));
+        }
+        if let Some(log_level) = self.log_level {
+            flags.push(("log_level", "--log-level", log_level.to_string()));
+        }
+        return flags;
+    }
+}
@@ -1,31 +1,79 @@
 use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::thread::available_parallelism;
+use std::time::Duration;
 use tracing::{subscriber, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, Registry};
+
+use crate::cli::Cli;
+use crate::handler::compression::compression_id;
+use crate::handler::loader::{parse_loader, Loader};
+use crate::handler::webhook::{parse_events, Webhook};
+use crate::handler::write_behind::{parse_sink, WriteBehind};
+use crate::listener::SocketOptions;
 
 pub struct AppConfig {
     pub worker_threads: usize,
-    pub bind_address: String,
+    // TCP addresses and Unix socket paths to listen on
+    pub bind_addresses: Vec<String>,
+    // Address of the HTTP health checks, None disables them
+    pub health_address: Option<String>,
+    // Where keys missing from the cache are loaded from, None disables read-through
+    pub loader: Option<Arc<dyn Loader>>,
+    // Where written keys are persisted, None disables write-behind
+    pub write_behind: Option<Arc<WriteBehind>>,
+    pub write_behind_interval_ms: u64,
+    // Where key events are posted, None disables the webhook
+    pub webhook: Option<Arc<Webhook>>,
+    pub webhook_interval_ms: u64,
+    pub socket_options: SocketOptions,
     pub cache_initial_capacity: usize,
     pub cache_shards: usize,
     pub graceful_timeout: usize,
+    pub cleanup_interval_ms: u64,
+    pub cleanup_batch_size: u64,
+    pub adaptive_cleanup: bool,
+    pub max_payload_size: u64,
+    pub max_connections: u64,
+    pub batch_cache_size: u64,
+    pub value_compression: u8,
+    pub compression_threshold: u64,
+    pub dictionary_max_distinct: u64,
+    pub defrag_interval_ms: u64,
+    pub default_ttl_ms: u64,
+    pub memory_soft_limit: u64,
+    pub memory_hard_limit: u64,
+    pub max_scan_rows: u64,
+    pub max_result_rows: u64,
+    pub max_result_bytes: u64,
+    pub max_string_length: u64,
+    pub change_log_size: u64,
+    pub negative_cache_ttl_ms: u64,
+    pub max_namespaces: u64,
+    pub log_level: Level,
+    pub log_reload: reload::Handle<LevelFilter, Registry>,
+    pub config_reload: ConfigReload,
 }
 
 impl AppConfig {
-    pub fn new() -> AppConfig {
-        // Logging
-        let log_level: Level = match env::var("CUPID_LOG_LEVEL") {
-            Ok(val) => {
-                match val.as_str() {
-                    "ERROR" => Level::ERROR,
-                    "WARN" => Level::WARN,
-                    "INFO" => Level::INFO,
-                    "DEBUG" => Level::DEBUG,
-                    "TRACE" => Level::TRACE,
-                    _ => Level::INFO,
-                }
-            }
-            Err(_) => Level::INFO,
+    // Reads every setting from its command line flag, else its CUPID_ environment variable,
+    // else the TOML file given with --config, else its default. The error names the setting at
+    // fault.
+    pub fn new(cli: &Cli) -> Result<AppConfig, String> {
+        let config_reload = ConfigReload {
+            flags: cli.setting_flags(),
+            config_path: cli.config.clone(),
         };
+        let source = ConfigSource::load(&config_reload)?;
+
+        // Logging
+        let log_level: Level = source.read("log_level", Level::INFO)?;
         let debug_mode: bool;
         if log_level == Level::DEBUG || log_level == Level::TRACE {
             debug_mode = true;
@@ -33,60 +81,396 @@ impl AppConfig {
             debug_mode = false;
         }
 
-        let subscriber = tracing_subscriber::fmt()
-            .compact()
-            .with_file(debug_mode)
-            .with_line_number(debug_mode)
-            .with_thread_ids(debug_mode)
-            .with_target(false)
-            .with_max_level(log_level)
-            .finish();
+        // The level sits behind a reload layer so it can be changed at runtime with CS
+        let (level_filter, log_reload) = reload::Layer::new(LevelFilter::from_level(log_level));
+        let subscriber = tracing_subscriber::registry()
+            .with(level_filter)
+            .with(fmt::layer()
+                .compact()
+                .with_file(debug_mode)
+                .with_line_number(debug_mode)
+                .with_thread_ids(debug_mode)
+                .with_target(false)
+            );
         let _ = subscriber::set_global_default(subscriber);
         tracing::info!("Starting CupidDB");
         tracing::info!("Log level set to {log_level}");
+        if let Some(path) = &cli.config {
+            tracing::info!("Read configuration from {path}");
+        }
 
         // Tokio worker threads
-        let worker_threads: usize = match env::var("CUPID_WORKER_THREADS") {
-            Ok(val) => val.parse().unwrap(),
-            Err(_) => available_parallelism().unwrap().get(),
-        };
+        let worker_threads: usize = source.read("worker_threads", available_parallelism().unwrap().get())?;
+        if worker_threads == 0 {
+            return Err(source.invalid("worker_threads", "must be at least 1"));
+        }
         tracing::info!("Starting CupidDB with {worker_threads} threads");
 
         // Cache
-        let cache_initial_capacity: usize = match env::var("CUPID_INITIAL_CAPACITY") {
-            Ok(val) => val.parse().unwrap(),
-            Err(_) => 64,
-        };
-        let cache_shards: usize = match env::var("CUPID_CACHE_SHARDS") {
-            Ok(val) => val.parse().unwrap(),
-            Err(_) => 64,
-        };
+        let cache_initial_capacity: usize = source.read("initial_capacity", 64)?;
+        let cache_shards: usize = source.read("cache_shards", 64)?;
+        if cache_shards < 2 || !cache_shards.is_power_of_two() {
+            return Err(source.invalid("cache_shards", "must be a power of two greater than 1"));
+        }
         tracing::info!("Running with {cache_shards} shards");
 
         // Graceful timeout
-        let graceful_timeout: usize = match env::var("CUPID_GRACEFUL_TIMEOUT") {
-            Ok(val) => val.parse().unwrap(),
-            Err(_) => 30,
+        let graceful_timeout: usize = source.read("graceful_timeout", 30)?;
+
+        // Expiry sweeps, a batch size of 0 removes every expired key in one sweep
+        let cleanup_interval_ms: u64 = source.read("cleanup_interval", 250)?;
+        if cleanup_interval_ms == 0 {
+            return Err(source.invalid("cleanup_interval", "must be at least 1"));
+        }
+        let cleanup_batch_size: u64 = source.read("cleanup_batch_size", 0)?;
+        let adaptive_cleanup: bool = source.read("adaptive_cleanup", true)?;
+
+        // Largest accepted frame payload, 0 accepts any size
+        let max_payload_size: u64 = source.read("max_payload_size", 0)?;
+
+        // Connections over the limit are turned away, 0 accepts any number
+        let max_connections: u64 = source.read("max_connections", 0)?;
+
+        // Decoded Arrow chunks kept for repeated queries, 0 disables the cache
+        let batch_cache_size: u64 = source.read("batch_cache_size", 256 * 1024 * 1024)?;
+
+        // Stored values at least the threshold in bytes are compressed with lz4 or zstd
+        let compression_name: String = source.read("value_compression", "none".to_string())?;
+        let value_compression: u8 = match compression_id(compression_name.as_str()) {
+            Some(algorithm) => algorithm,
+            None => return Err(source.invalid("value_compression", "must be none, lz4 or zstd")),
+        };
+        let compression_threshold: u64 = source.read("compression_threshold", 64 * 1024)?;
+
+        // Utf8 columns with few distinct values are stored dictionary encoded, 0 disables it
+        let dictionary_max_distinct: u64 = source.read("dictionary_encoding", 0)?;
+        if dictionary_max_distinct > 100 {
+            return Err(source.invalid("dictionary_encoding", "must be a percentage from 0 to 100"));
+        }
+
+        // Background defragmentation after large deletions, 0 disables it
+        let defrag_interval_ms: u64 = source.read("defrag_interval", 60 * 1000)?;
+
+        // Keys written without a cache time expire after the default TTL, 0 keeps them forever
+        let default_ttl_ms: u64 = source.read("default_ttl_ms", 0)?;
+
+        // Memory watermarks in bytes, 0 disables them
+        let memory_soft_limit: u64 = source.read("memory_soft_limit", 0)?;
+        let memory_hard_limit: u64 = source.read("memory_hard_limit", 0)?;
+
+        // Limits of a single GA query, 0 disables them
+        let max_scan_rows: u64 = source.read("max_scan_rows", 0)?;
+        let max_result_rows: u64 = source.read("max_result_rows", 0)?;
+        let max_result_bytes: u64 = source.read("max_result_bytes", 0)?;
+
+        // Longest string value in bytes, 0 disables the limit
+        let max_string_length: u64 = source.read("max_string_length", 0)?;
+
+        // Changes to keys kept for CD, 0 turns the change log off
+        let change_log_size: u64 = source.read("change_log_size", 0)?;
+
+        // How long a key found missing is remembered as missing, 0 turns negative caching off
+        let negative_cache_ttl_ms: u64 = source.read("negative_cache_ttl_ms", 0)?;
+
+        // Most namespaces at once, 0 disables the limit
+        let max_namespaces: u64 = source.read("max_namespaces", 1024)?;
+
+        // Network, a comma separated list of addresses and Unix socket paths. Addresses without a
+        // port listen on the configured one.
+        let address_list: String = source.read("bind_address", "0.0.0.0".to_string())?;
+        let port: u16 = source.read("port", 5995)?;
+        let bind_addresses = parse_bind_addresses(&address_list, port);
+        if bind_addresses.len() == 0 {
+            return Err(source.invalid("bind_address", "must list at least one address"));
+        }
+
+        // TCP keepalive and socket buffers of clients, 0 leaves them at the system default
+        let socket_options = SocketOptions {
+            keepalive_idle: source.read("keepalive_idle", 0)?,
+            keepalive_interval: source.read("keepalive_interval", 0)?,
+            keepalive_count: source.read("keepalive_count", 0)?,
+            receive_buffer_size: source.read("socket_receive_buffer", 0)?,
+            send_buffer_size: source.read("socket_send_buffer", 0)?,
+            ip_tos: source.read("ip_tos", 0)?,
+        };
+        if socket_options.ip_tos > 255 {
+            return Err(source.invalid("ip_tos", "must be from 0 to 255"));
+        }
+
+        // HTTP liveness and readiness probes, an empty address disables them
+        let health_address: String = source.read("health_address", String::new())?;
+        let health_address = match health_address.trim() {
+            "" => None,
+            address => Some(address.to_string()),
+        };
+
+        // Read-through loading of missed keys, an empty setting disables it
+        let read_through: String = source.read("read_through", String::new())?;
+        let read_through_timeout_ms: u64 = source.read("read_through_timeout", 10000)?;
+        if read_through_timeout_ms == 0 {
+            return Err(source.invalid("read_through_timeout", "must be at least 1"));
+        }
+        let loader: Option<Arc<dyn Loader>> = match read_through.trim() {
+            "" => None,
+            spec => match parse_loader(spec, Duration::from_millis(read_through_timeout_ms)) {
+                Ok(loader) => {
+                    tracing::info!("Loading missed keys from {spec}");
+                    Some(Arc::from(loader))
+                },
+                Err(reason) => return Err(source.invalid("read_through", &reason)),
+            },
+        };
+
+        // Write-behind persistence of SD and AP writes, an empty setting disables it
+        let write_behind_spec: String = source.read("write_behind", String::new())?;
+        let write_behind_interval_ms: u64 = source.read("write_behind_interval", 1000)?;
+        if write_behind_interval_ms == 0 {
+            return Err(source.invalid("write_behind_interval", "must be at least 1"));
+        }
+        let write_behind_batch_size: usize = source.read("write_behind_batch", 1000)?;
+        if write_behind_batch_size == 0 {
+            return Err(source.invalid("write_behind_batch", "must be at least 1"));
+        }
+        let write_behind: Option<Arc<WriteBehind>> = match write_behind_spec.trim() {
+            "" => None,
+            spec => match parse_sink(spec) {
+                Ok(sink) => {
+                    tracing::info!("Persisting writes to {spec}");
+                    Some(Arc::new(WriteBehind::new(sink, write_behind_batch_size)))
+                },
+                Err(reason) => return Err(source.invalid("write_behind", &reason)),
+            },
         };
 
-        // Network
-        let address: String = match env::var("CUPID_BIND_ADDRESS") {
-            Ok(val) => val,
-            Err(_) => "0.0.0.0".to_string(),
+        // Webhook posting key events, an empty URL disables it
+        let webhook_url: String = source.read("webhook_url", String::new())?;
+        let webhook_patterns: String = source.read("webhook_patterns", String::new())?;
+        let webhook_events: String = source.read("webhook_events", String::new())?;
+        let webhook_events = match parse_events(&webhook_events) {
+            Ok(webhook_events) => webhook_events,
+            Err(reason) => return Err(source.invalid("webhook_events", &reason)),
         };
-        let port: u16 = match env::var("CUPID_PORT") {
-            Ok(val) => val.parse().unwrap(),
-            Err(_) => 5995,
+        let webhook_interval_ms: u64 = source.read("webhook_interval", 1000)?;
+        if webhook_interval_ms == 0 {
+            return Err(source.invalid("webhook_interval", "must be at least 1"));
+        }
+        let webhook_batch_size: usize = source.read("webhook_batch", 100)?;
+        if webhook_batch_size == 0 {
+            return Err(source.invalid("webhook_batch", "must be at least 1"));
+        }
+        let webhook: Option<Arc<Webhook>> = match webhook_url.trim() {
+            "" => None,
+            url => match Webhook::new(url, &webhook_patterns, webhook_events, webhook_batch_size) {
+                Ok(webhook) => {
+                    tracing::info!("Posting key events to {url}");
+                    Some(Arc::new(webhook))
+                },
+                Err(reason) => return Err(source.invalid("webhook_url", &reason)),
+            },
         };
-        let bind_address = address + ":" + port.to_string().as_str();
-        tracing::info!("Listening on {bind_address}");
 
-        return AppConfig {
+        return Ok(AppConfig {
             worker_threads: worker_threads,
-            bind_address: bind_address,
+            bind_addresses: bind_addresses,
+            health_address: health_address,
+            loader: loader,
+            write_behind: write_behind,
+            write_behind_interval_ms: write_behind_interval_ms,
+            webhook: webhook,
+            webhook_interval_ms: webhook_interval_ms,
+            socket_options: socket_options,
             cache_initial_capacity: cache_initial_capacity,
             cache_shards: cache_shards,
             graceful_timeout: graceful_timeout,
+            cleanup_interval_ms: cleanup_interval_ms,
+            cleanup_batch_size: cleanup_batch_size,
+            adaptive_cleanup: adaptive_cleanup,
+            max_payload_size: max_payload_size,
+            max_connections: max_connections,
+            batch_cache_size: batch_cache_size,
+            value_compression: value_compression,
+            compression_threshold: compression_threshold,
+            dictionary_max_distinct: dictionary_max_distinct,
+            defrag_interval_ms: defrag_interval_ms,
+            default_ttl_ms: default_ttl_ms,
+            memory_soft_limit: memory_soft_limit,
+            memory_hard_limit: memory_hard_limit,
+            max_scan_rows: max_scan_rows,
+            max_result_rows: max_result_rows,
+            max_result_bytes: max_result_bytes,
+            max_string_length: max_string_length,
+            change_log_size: change_log_size,
+            negative_cache_ttl_ms: negative_cache_ttl_ms,
+            max_namespaces: max_namespaces,
+            log_level: log_level,
+            log_reload: log_reload,
+            config_reload: config_reload,
+        });
+    }
+}
+
+// Keys a config file may set. Each one is also read from the environment variable of its
+// name in upper case with a CUPID_ prefix, which takes precedence over the file. Some can
+// also be given as command line flags, which take precedence over both.
+const CONFIG_KEYS: [&str; 44] = [
+    "log_level", "worker_threads", "initial_capacity", "cache_shards", "graceful_timeout", "cleanup_interval",
+    "cleanup_batch_size", "adaptive_cleanup", "max_payload_size", "max_connections", "batch_cache_size",
+    "value_compression", "compression_threshold", "dictionary_encoding", "defrag_interval", "default_ttl_ms",
+    "memory_soft_limit", "memory_hard_limit", "bind_address", "port", "health_address", "keepalive_idle",
+    "keepalive_interval", "keepalive_count", "socket_receive_buffer", "socket_send_buffer", "ip_tos",
+    "max_scan_rows", "max_result_rows", "max_result_bytes", "max_string_length", "read_through",
+    "read_through_timeout", "write_behind", "write_behind_interval", "write_behind_batch", "change_log_size",
+    "negative_cache_ttl_ms", "webhook_url", "webhook_patterns", "webhook_events", "webhook_interval", "webhook_batch",
+    "max_namespaces"
+];
+
+// Config keys of the settings that can change while the server runs, with their names in CG/CS
+const RUNTIME_KEYS: [(&str, &str); 21] = [
+    ("log_level", "log_level"), ("cleanup_interval", "cleanup_interval_ms"), ("cleanup_batch_size", "cleanup_batch_size"),
+    ("adaptive_cleanup", "adaptive_cleanup"), ("max_payload_size", "max_payload_size"),
+    ("max_connections", "max_connections"), ("batch_cache_size", "batch_cache_size"),
+    ("value_compression", "value_compression"), ("compression_threshold", "compression_threshold"),
+    ("dictionary_encoding", "dictionary_max_distinct"), ("defrag_interval", "defrag_interval_ms"),
+    ("default_ttl_ms", "default_ttl_ms"), ("memory_soft_limit", "memory_soft_limit"),
+    ("memory_hard_limit", "memory_hard_limit"), ("max_scan_rows", "max_scan_rows"),
+    ("max_result_rows", "max_result_rows"), ("max_result_bytes", "max_result_bytes"),
+    ("max_string_length", "max_string_length"), ("change_log_size", "change_log_size"),
+    ("negative_cache_ttl_ms", "negative_cache_ttl_ms"), ("max_namespaces", "max_namespaces")
+];
+
+// Where the configuration was read from, kept to read it again on SIGHUP or RC
+#[derive(Clone)]
+pub struct ConfigReload {
+    // (config key, flag, value)
+    flags: Vec<(&'static str, &'static str, String)>,
+    config_path: Option<String>,
+}
+
+impl ConfigReload {
+    // (config key, setting name, value) of the settings that can change at runtime which the
+    // command line, the environment or the config file set now. The others are left out.
+    pub fn runtime_settings(&self) -> Result<Vec<(&'static str, &'static str, String)>, String> {
+        let source = ConfigSource::load(self)?;
+        let mut settings = Vec::new();
+        for (key, name) in RUNTIME_KEYS {
+            if let Some(value) = source.value(key)? {
+                settings.push((key, name, value));
+            }
         }
+        return Ok(settings);
     }
 }
+
+// The command line, the environment and the config file, if any
+struct ConfigSource {
+    // (config key, flag, value)
+    flags: Vec<(&'static str, &'static str, String)>,
+    // Empty without a file
+    file_path: String,
+    file: toml::Table,
+}
+
+impl ConfigSource {
+    // Fails on a file that can not be read or parsed, or that sets a key CupidDB does not know
+    fn load(config_reload: &ConfigReload) -> Result<ConfigSource, String> {
+        let flags = config_reload.flags.clone();
+        let path = match &config_reload.config_path {
+            Some(path) => path,
+            None => return Ok(ConfigSource { flags: flags, file_path: String::new(), file: toml::Table::new() }),
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(format!("Can not read config file {}: {}", path, e)),
+        };
+        let file: toml::Table = match contents.parse() {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Can not parse config file {}: {}", path, e.message())),
+        };
+        if let Some(key) = file.keys().find(|key| !CONFIG_KEYS.contains(&key.as_str())) {
+            return Err(format!(
+                "Unknown setting `{}` in config file {}, expected one of: {}", key, path, CONFIG_KEYS.join(", ")
+            ));
+        }
+        return Ok(ConfigSource { flags: flags, file_path: path.to_string(), file: file });
+    }
+
+    // The setting from its flag, else its environment variable, else the file, else `default`
+    fn read<T>(&self, key: &str, default: T) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Some((_, flag, value)) = self.flags.iter().find(|(flag_key, _, _)| *flag_key == key) {
+            return value.parse().map_err(|e| format!("Invalid {} {:?}: {}", flag, value, e));
+        }
+        let variable = env_variable(key);
+        if let Ok(value) = env::var(&variable) {
+            return value.parse().map_err(|e| format!("Invalid {}={:?}: {}", variable, value, e));
+        }
+        let value = match self.file_value(key)? {
+            Some(value) => value,
+            None => return Ok(default),
+        };
+        return value.parse().map_err(|e| format!("Invalid `{} = {:?}` in {}: {}", key, value, self.file_path, e));
+    }
+
+    // The setting as text from its flag, else its environment variable, else the file, None if
+    // none of them set it
+    fn value(&self, key: &str) -> Result<Option<String>, String> {
+        if let Some((_, _, value)) = self.flags.iter().find(|(flag_key, _, _)| *flag_key == key) {
+            return Ok(Some(value.clone()));
+        }
+        if let Ok(value) = env::var(env_variable(key)) {
+            return Ok(Some(value));
+        }
+        return self.file_value(key);
+    }
+
+    fn file_value(&self, key: &str) -> Result<Option<String>, String> {
+        match self.file.get(key) {
+            Some(toml::Value::String(value)) => return Ok(Some(value.clone())),
+            Some(toml::Value::Integer(value)) => return Ok(Some(value.to_string())),
+            Some(toml::Value::Boolean(value)) => return Ok(Some(value.to_string())),
+            Some(value) => {
+                let reason = format!("expected a string, integer or boolean, found {}", value.type_str());
+                return Err(format!("Invalid `{}` in {}: {}", key, self.file_path, reason));
+            },
+            None => return Ok(None),
+        }
+    }
+
+    // Error for a setting that was read but is out of range, naming where it came from
+    fn invalid(&self, key: &str, reason: &str) -> String {
+        if let Some((_, flag, _)) = self.flags.iter().find(|(flag_key, _, _)| *flag_key == key) {
+            return format!("Invalid {}: {}", flag, reason);
+        }
+        let variable = env_variable(key);
+        if env::var(&variable).is_ok() {
+            return format!("Invalid {}: {}", variable, reason);
+        }
+        return format!("Invalid `{}` in {}: {}", key, self.file_path, reason);
+    }
+}
+
+fn env_variable(key: &str) -> String {
+    return format!("CUPID_{}", key.to_uppercase());
+}
+
+// Bind addresses from a comma separated list. Entries without a port get `port`, entries
+// starting with / are Unix socket paths.
+fn parse_bind_addresses(list: &str, port: u16) -> Vec<String> {
+    return list
+        .split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| entry.len() > 0)
+        .map(|entry| {
+            if entry.starts_with('/') || entry.parse::<SocketAddr>().is_ok() {
+                return entry.to_string();
+            }
+            match entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                Ok(ip) => return SocketAddr::new(ip, port).to_string(),
+                Err(_) => return format!("{}:{}", entry, port),
+            }
+        })
+        .collect();
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use arrow::array::Array;
+use arrow::record_batch::RecordBatch;
+use dashmap::DashMap;
+
+// Decoded chunks of one Arrow value
+struct CachedChunks {
+    version: u64,
+    chunks: Vec<RecordBatch>,
+    size: u64,
+    last_used: AtomicU64,
+}
+
+// Decoded chunks of recently read Arrow keys, so repeated queries skip decoding the stored
+// IPC stream. Entries belong to one version of a value and never match after a write, the
+// least recently used ones are evicted once the cache grows over its capacity.
+pub struct BatchCache {
+    entries: DashMap<String, CachedChunks>,
+    used_bytes: AtomicU64,
+    clock: AtomicU64,
+}
+
+impl BatchCache {
+    pub fn new() -> BatchCache {
+        BatchCache {
+            entries: DashMap::new(),
+            used_bytes: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str, version: u64) -> Option<Vec<RecordBatch>> {
+        let entry = self.entries.get(key)?;
+        if entry.version != version {
+            return None;
+        }
+        entry.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        return Some(entry.chunks.clone());
+    }
+
+    // Values larger than `capacity` bytes are not cached
+    pub fn insert(&self, key: &str, version: u64, chunks: &Vec<RecordBatch>, capacity: u64) {
+        let size: u64 = chunks.iter().map(chunk_size).sum();
+        if size > capacity {
+            return;
+        }
+        let cached = CachedChunks {
+            version: version,
+            chunks: chunks.clone(),
+            size: size,
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        };
+        // Counted before it becomes visible, so a concurrent remove never takes the total below 0
+        self.used_bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some(replaced) = self.entries.insert(key.to_string(), cached) {
+            self.used_bytes.fetch_sub(replaced.size, Ordering::Relaxed);
+        }
+        self.shrink_to(capacity);
+    }
+
+    pub fn remove(&self, key: &str) {
+        if let Some((_, removed)) = self.entries.remove(key) {
+            self.used_bytes.fetch_sub(removed.size, Ordering::Relaxed);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        return self.used_bytes.load(Ordering::Relaxed);
+    }
+
+    // Evicts the least recently used entries until at most `capacity` bytes are cached
+    pub fn shrink_to(&self, capacity: u64) {
+        while self.used_bytes.load(Ordering::Relaxed) > capacity {
+            let oldest_key = self.entries
+                .iter()
+                .min_by_key(|entry| entry.value().last_used.load(Ordering::Relaxed))
+                .map(|entry| entry.key().clone());
+            match oldest_key {
+                Some(key) => self.remove(&key),
+                None => return,
+            }
+        }
+    }
+}
+
+// Decoded columns are slices of one buffer per IPC message, so only the bytes each column
+// covers are counted rather than the whole shared allocation
+fn chunk_size(chunk: &RecordBatch) -> u64 {
+    return chunk.columns()
+        .iter()
+        .map(|column| column.to_data().get_slice_memory_size().unwrap_or(0) as u64)
+        .sum();
+}
@@ -0,0 +1,42 @@
+use bytes::BytesMut;
+
+use crate::handler::list::range_bounds;
+
+// Bit operations on byte values, so a value can track presence over a large range of ids
+// with one bit each. Bit 0 is the most significant bit of the first byte, as in Redis.
+
+// Highest bit offset a command may set, which keeps a bitmap under 512 MiB
+pub const MAX_BIT_OFFSET: u64 = (1 << 32) - 1;
+
+// Sets the bit at `offset` and returns its previous value. The bytes grow with zeros to
+// reach the offset.
+pub fn set_bit(bits: &mut BytesMut, offset: u64, bit: bool) -> bool {
+    let byte_index = (offset / 8) as usize;
+    let mask = 0x80u8 >> (offset % 8);
+    if byte_index >= bits.len() {
+        bits.resize(byte_index + 1, 0);
+    }
+    let previous = bits[byte_index] & mask != 0;
+    match bit {
+        true => bits[byte_index] |= mask,
+        false => bits[byte_index] &= !mask,
+    }
+    return previous;
+}
+
+// Bits past the end of the bytes are 0
+pub fn get_bit(bits: &[u8], offset: u64) -> bool {
+    match bits.get((offset / 8) as usize) {
+        Some(byte) => return byte & (0x80u8 >> (offset % 8)) != 0,
+        None => return false,
+    }
+}
+
+// Bits set in the bytes from `start` to `end` included, negative positions counting from
+// the last byte
+pub fn count_bits(bits: &[u8], start: i64, end: i64) -> u64 {
+    match range_bounds(bits.len(), start, end) {
+        Some((start, end)) => return bits[start..=end].iter().map(|byte| byte.count_ones() as u64).sum(),
+        None => return 0,
+    }
+}
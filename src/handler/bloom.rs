@@ -0,0 +1,120 @@
+use std::f64::consts::LN_2;
+
+use crate::handler::hyperloglog::element_hash;
+
+// Stored layout of a Bloom filter value: 'M' followed by the number of bits set per element
+// (u8) and the bits. It tells whether an element may have been added, never missing one that
+// was, and wrongly claims one that was not at about the rate it was sized for until more
+// elements than its capacity are added.
+pub const BLOOM_TAG: u8 = b'M';
+
+// Size of the Bloom filters MA creates
+pub const DEFAULT_CAPACITY: u64 = 10_000;
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+// Largest Bloom filter in bytes, and most bits set per element
+const MAX_FILTER_BYTES: f64 = 512.0 * 1024.0 * 1024.0;
+const MAX_HASHES: f64 = 32.0;
+
+// An empty Bloom filter, after its tag, sized to hold `capacity` elements at the false positive
+// rate. Fails with error code 3 when the capacity is 0 or the rate is not between 0 and 1, and
+// 9 when the filter would be larger than 512 MiB.
+pub fn new_filter(capacity: u64, false_positive_rate: f64) -> Result<Vec<u8>, u16> {
+    if capacity == 0 || !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+        return Err(3);
+    }
+    let bits = -(capacity as f64) * false_positive_rate.ln() / (LN_2 * LN_2);
+    let filter_bytes = (bits / 8.0).ceil().max(1.0);
+    if filter_bytes > MAX_FILTER_BYTES {
+        return Err(9);
+    }
+    let hashes = (filter_bytes * 8.0 / capacity as f64 * LN_2).round().clamp(1.0, MAX_HASHES);
+    let mut filter = vec![0u8; filter_bytes as usize + 1];
+    filter[0] = hashes as u8;
+    return Ok(filter);
+}
+
+// Fails with error code 12 when a stored Bloom filter, after its tag, is damaged
+pub fn check_filter(filter: &[u8]) -> Result<(), u16> {
+    match filter {
+        [hashes, _, ..] if *hashes > 0 => return Ok(()),
+        _ => return Err(12),
+    }
+}
+
+// Sets the bits of the element in the filter, returns whether one of them was not set yet, in
+// which case the element had certainly not been added
+pub fn add_to_filter(filter: &mut [u8], element: &[u8]) -> bool {
+    let (hashes, bits) = filter.split_first_mut().unwrap();
+    let mut added = false;
+    for position in bit_positions(element, *hashes, bits.len()) {
+        let mask = 1u8 << (position % 8);
+        added |= bits[position / 8] & mask == 0;
+        bits[position / 8] |= mask;
+    }
+    return added;
+}
+
+// Whether the element may have been added to the filter
+pub fn might_contain(filter: &[u8], element: &[u8]) -> bool {
+    let (hashes, bits) = filter.split_first().unwrap();
+    return bit_positions(element, *hashes, bits.len()).all(|position| bits[position / 8] & (1 << (position % 8)) != 0);
+}
+
+// Bits of an element, derived from two hashes of it by double hashing
+fn bit_positions(element: &[u8], hashes: u8, filter_bytes: usize) -> impl Iterator<Item = usize> {
+    let bit_count = filter_bytes as u64 * 8;
+    let hash = element_hash(element);
+    let step = element_hash(&hash.to_be_bytes()) | 1;
+    return (0..hashes as u64).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % bit_count) as usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_elements_are_always_found() {
+        let mut filter = new_filter(1000, 0.01).unwrap();
+        for element in 0..1000u32 {
+            add_to_filter(&mut filter, &element.to_be_bytes());
+        }
+        assert!((0..1000u32).all(|element| might_contain(&filter, &element.to_be_bytes())));
+    }
+
+    #[test]
+    fn false_positive_rate_is_close_to_the_requested_one() {
+        for false_positive_rate in [0.01, 0.05] {
+            let mut filter = new_filter(DEFAULT_CAPACITY, false_positive_rate).unwrap();
+            for element in 0..DEFAULT_CAPACITY {
+                add_to_filter(&mut filter, format!("added-{}", element).as_bytes());
+            }
+            let probes = 100_000;
+            let false_positives = (0..probes)
+                .filter(|element| might_contain(&filter, format!("other-{}", element).as_bytes()))
+                .count();
+            let rate = false_positives as f64 / probes as f64;
+            assert!(
+                rate > false_positive_rate * 0.5 && rate < false_positive_rate * 1.5,
+                "asked for {} got {}", false_positive_rate, rate
+            );
+        }
+    }
+
+    #[test]
+    fn damaged_filters_are_errors() {
+        assert_eq!(check_filter(&new_filter(10, 0.01).unwrap()), Ok(()));
+        assert_eq!(check_filter(&[]), Err(12));
+        assert_eq!(check_filter(&[3]), Err(12));
+        assert_eq!(check_filter(&[0, 0xFF, 0xFF]), Err(12));
+    }
+
+    #[test]
+    fn sizes_out_of_range_are_refused() {
+        assert_eq!(new_filter(0, 0.01), Err(3));
+        assert_eq!(new_filter(10, 0.0), Err(3));
+        assert_eq!(new_filter(10, 1.0), Err(3));
+        assert_eq!(new_filter(10, f64::NAN), Err(3));
+        assert_eq!(new_filter(u64::MAX, 0.01), Err(9));
+    }
+}
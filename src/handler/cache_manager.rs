@@ -1,31 +1,144 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+use tokio::task::{spawn_blocking, yield_now};
 use tokio::time::{sleep, Duration};
 use tokio_util::sync::CancellationToken;
-use dashmap::DashMap;
 
-type TimeoutDB = Arc<DashMap<String, SystemTime>>;
-type SharedDB = Arc<DashMap<String, Vec<u8>>>;
+use crate::handler::database::{Db, Namespaces};
+use crate::handler::defrag::defragment;
+use crate::handler::dependency::invalidate_dependents;
+use crate::handler::handler::{refresh_cached_results, refresh_views, trim_retained_keys};
+use crate::handler::memory::evict_least_recent;
 
+// Bytes that deletes and expiry must have freed before a background defragmentation pass
+const DEFRAG_MIN_FREED_BYTES: u64 = 64 * 1024 * 1024;
 
-pub async fn cache_manager(shutdown_token: CancellationToken, timeout_db: TimeoutDB, shared_db: SharedDB) {
+// Most times an adaptive sweep doubles the configured batch size
+const MAX_BATCH_DOUBLINGS: u32 = 6;
+
+pub async fn cache_manager(shutdown_token: CancellationToken, namespaces: Arc<Namespaces>) {
+    let mut last_defrag = Instant::now();
+    let mut freed_at_last_defrag: u64 = 0;
+    let mut batch_doublings: u32 = 0;
     loop {
         if shutdown_token.is_cancelled() {
             break;
         }
-        let now = SystemTime::now();
-        let mut remove_keys: Vec<String> = Vec::new();
-        for entry in timeout_db.iter() {
-            if now > *entry.value() {
-                remove_keys.push(entry.key().clone());
+        let settings = &namespaces.default_db().settings;
+        let batch_size = settings.cleanup_batch_size.load(Ordering::Relaxed) << batch_doublings;
+        let dropped_namespaces = namespaces.drop_empty();
+        if dropped_namespaces > 0 {
+            tracing::debug!("Dropped {} empty namespaces", dropped_namespaces);
+        }
+        let all_namespaces = namespaces.all();
+        let mut backlog = false;
+        for db in all_namespaces.iter() {
+            backlog |= remove_expired(db, batch_size).await;
+        }
+        for db in all_namespaces.iter() {
+            trim_retained(db).await;
+            refresh_views(db).await;
+            refresh_cached_results(db).await;
+        }
+
+        // With a memory watermark set every sweep counts the memory in use, and past the soft one
+        // evicts keys for as many bytes as it is over
+        let memory = &namespaces.default_db().memory;
+        let watermarks_set = settings.memory_soft_limit.load(Ordering::Relaxed) > 0
+            || settings.memory_hard_limit.load(Ordering::Relaxed) > 0;
+        if watermarks_set {
+            memory.measure(&all_namespaces);
+            let over_soft_limit = memory.over_soft_limit(settings);
+            if over_soft_limit > 0 {
+                let evicted_bytes = evict_least_recent(&all_namespaces, over_soft_limit).await;
+                memory.measure(&all_namespaces);
+                tracing::debug!("Evicted {} bytes of keys over the soft memory limit", evicted_bytes);
             }
         }
-        for key in remove_keys {
-            shared_db.remove(&key);
-            timeout_db.remove(&key);
+
+        // Memory freed by large deletions is compacted at most once per defrag interval. The
+        // allocator is shared, so every namespace is compacted together.
+        let defrag_interval_ms = settings.defrag_interval_ms.load(Ordering::Relaxed);
+        let freed_bytes: u64 = all_namespaces
+            .iter()
+            .map(|db| db.stats.freed_value_bytes.load(Ordering::Relaxed))
+            .sum();
+        if defrag_interval_ms > 0
+            && last_defrag.elapsed() >= Duration::from_millis(defrag_interval_ms)
+            && freed_bytes - freed_at_last_defrag >= DEFRAG_MIN_FREED_BYTES
+        {
+            for db in all_namespaces.iter() {
+                let defrag_db = Arc::clone(db);
+                if let Ok(report) = spawn_blocking(move || defragment(&defrag_db)).await {
+                    tracing::debug!(
+                        "Defragmented {} values ({} bytes) of namespace {}, released {} map slots",
+                        report.compacted_values, report.compacted_bytes, db.namespace, report.released_slots
+                    );
+                }
+            }
+            last_defrag = Instant::now();
+            freed_at_last_defrag = freed_bytes;
         }
 
-        sleep(Duration::from_millis(250)).await;
+        // In adaptive mode a sweep that filled its batch is followed right away by a larger one,
+        // until the expired backlog is gone
+        if backlog && settings.adaptive_cleanup.load(Ordering::Relaxed) {
+            batch_doublings = (batch_doublings + 1).min(MAX_BATCH_DOUBLINGS);
+            yield_now().await;
+            continue;
+        }
+        batch_doublings = 0;
+        let cleanup_interval_ms = settings.cleanup_interval_ms.load(Ordering::Relaxed);
+        sleep(Duration::from_millis(cleanup_interval_ms)).await;
     }
     tracing::debug!("Stopped cache manager");
 }
+
+// Drops the rows of the namespace's Arrow keys past their retention
+async fn trim_retained(db: &Db) {
+    if db.retention_db.is_empty() {
+        return;
+    }
+    let _write_permit = db.write_gate.read().await;
+    let trim_db = Arc::clone(db);
+    if let Ok(trimmed_keys) = spawn_blocking(move || trim_retained_keys(&trim_db)).await {
+        if trimmed_keys > 0 {
+            tracing::debug!("Trimmed {} keys of namespace {} to their retention", trimmed_keys, db.namespace);
+        }
+    }
+}
+
+// Drops the expired keys, cached results and miss markers of one namespace, at most `batch_size` keys unless
+// it is 0. Returns whether the batch was full, so more keys may have expired.
+async fn remove_expired(db: &Db, batch_size: u64) -> bool {
+    let now = SystemTime::now();
+    let mut remove_keys: Vec<String> = Vec::new();
+    for entry in db.timeout_db.iter() {
+        if now > *entry.value() {
+            remove_keys.push(entry.key().clone());
+            if remove_keys.len() as u64 == batch_size {
+                break;
+            }
+        }
+    }
+    let expired_count = remove_keys.len() as u64;
+    if remove_keys.len() > 0 {
+        let _write_permit = db.write_gate.read().await;
+        for key in remove_keys {
+            if db.remove_key(&key) {
+                db.notifier.publish(&db.namespace, &key, "expired");
+                db.record_change("expired", Some(&key), None);
+            }
+            invalidate_dependents(&key, db);
+        }
+    }
+    let expired_result_count = db.result_cache.remove_expired(now);
+    db.miss_db.retain(|_key, until| *until > now);
+    let sweep_micros = now.elapsed().unwrap_or_default().as_micros() as u64;
+    db.stats.expiry_sweeps.fetch_add(1, Ordering::Relaxed);
+    db.stats.expired_keys.fetch_add(expired_count, Ordering::Relaxed);
+    db.stats.expired_results.fetch_add(expired_result_count, Ordering::Relaxed);
+    db.stats.last_sweep_micros.store(sweep_micros, Ordering::Relaxed);
+    return batch_size > 0 && expired_count == batch_size;
+}
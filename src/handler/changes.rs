@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Changes a lagging CD connection can fall behind by before it reads them from the log instead
+const CHANGES_CAPACITY: usize = 1024;
+
+// One change made by a command, or by the server to a key it expired, evicted, invalidated or loaded
+pub struct Change {
+    pub sequence: u64,
+    pub at_ms: u64,
+    pub namespace: String,
+    // Message type of the command, or "expired", "evicted", "invalidated" for keys derived from
+    // a changed key or "loaded" for keys read through
+    pub command: String,
+    // None for commands that change a whole namespace, FL and FA
+    pub key: Option<String>,
+    // Hash of the value the key has after the change, as SG expects it. None once it is gone.
+    pub value_hash: Option<u64>,
+}
+
+impl Change {
+    pub fn to_json(&self) -> String {
+        let line = serde_json::json!({
+            "sequence": self.sequence,
+            "at_ms": self.at_ms,
+            "namespace": self.namespace,
+            "command": self.command,
+            "key": self.key,
+            "value_hash": self.value_hash,
+        });
+        return line.to_string();
+    }
+}
+
+// Ordered log of the changes made to keys in every namespace. The latest change_log_size
+// changes are kept, so a consumer can catch up from the sequence number it stopped at, and
+// new ones are sent to the connections tailing the log with CD.
+pub struct ChangeLog {
+    // Sequence numbers are given out under the lock, so the log and the feed are in order
+    retained: Mutex<RetainedChanges>,
+    sender: broadcast::Sender<Arc<Change>>,
+}
+
+struct RetainedChanges {
+    changes: VecDeque<Arc<Change>>,
+    next_sequence: u64,
+}
+
+impl ChangeLog {
+    pub fn new() -> ChangeLog {
+        let (sender, _) = broadcast::channel(CHANGES_CAPACITY);
+        ChangeLog {
+            retained: Mutex::new(RetainedChanges { changes: VecDeque::new(), next_sequence: 1 }),
+            sender: sender,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Change>> {
+        return self.sender.subscribe();
+    }
+
+    // Adds a change and drops the oldest ones past `capacity`
+    pub fn record(&self, capacity: usize, namespace: &str, command: &str, key: Option<&str>, value_hash: Option<u64>) {
+        let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut retained = self.retained.lock().unwrap();
+        let change = Arc::new(Change {
+            sequence: retained.next_sequence,
+            at_ms: at_ms,
+            namespace: namespace.to_string(),
+            command: command.to_string(),
+            key: key.map(|key| key.to_string()),
+            value_hash: value_hash,
+        });
+        retained.next_sequence += 1;
+        retained.changes.push_back(Arc::clone(&change));
+        while retained.changes.len() > capacity {
+            retained.changes.pop_front();
+        }
+        // Fails only when no connection tails the log
+        let _ = self.sender.send(change);
+    }
+
+    // Retained changes from `sequence` on, along with the sequence number of the first change
+    // still in the log. Changes before it are lost to a consumer asking for them.
+    pub fn since(&self, sequence: u64) -> (Vec<Arc<Change>>, u64) {
+        let retained = self.retained.lock().unwrap();
+        let first_retained = retained.changes.front().map(|change| change.sequence).unwrap_or(retained.next_sequence);
+        let changes = retained.changes
+            .iter()
+            .filter(|change| change.sequence >= sequence)
+            .map(|change| Arc::clone(change))
+            .collect();
+        return (changes, first_retained);
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use tokio_util::sync::CancellationToken;
+
+pub struct ClientInfo {
+    pub address: String,
+    pub connected_at: SystemTime,
+    pub last_command: String,
+    pub last_command_at: SystemTime,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // Cancelled by CK to close the connection
+    pub kill_token: CancellationToken,
+}
+
+// Connected clients by id, for CL and CK
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: DashMap<u64, ClientInfo>,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients {
+            next_id: AtomicU64::new(1),
+            clients: DashMap::new(),
+        }
+    }
+
+    pub fn register(&self, address: String, kill_token: CancellationToken) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now();
+        self.clients.insert(id, ClientInfo {
+            address: address,
+            connected_at: now,
+            last_command: String::new(),
+            last_command_at: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            kill_token: kill_token,
+        });
+        return id;
+    }
+
+    pub fn unregister(&self, id: u64) {
+        let _ = self.clients.remove(&id);
+    }
+
+    // Byte counts are the connection totals so far. Pipelined requests can finish out of
+    // order, so an older total never replaces a newer one.
+    pub fn record_command(&self, id: u64, message_type: &str, bytes_in: u64, bytes_out: u64) {
+        if let Some(mut client) = self.clients.get_mut(&id) {
+            client.last_command = message_type.to_string();
+            client.last_command_at = SystemTime::now();
+            client.bytes_in = client.bytes_in.max(bytes_in);
+            client.bytes_out = client.bytes_out.max(bytes_out);
+        }
+    }
+
+    // Returns whether a client with that id was connected
+    pub fn kill(&self, id: u64) -> bool {
+        match self.clients.get(&id) {
+            Some(client) => {
+                client.kill_token.cancel();
+                return true;
+            },
+            None => return false,
+        }
+    }
+
+    pub fn list(&self) -> serde_json::Value {
+        let mut clients: Vec<serde_json::Value> = self.clients
+            .iter()
+            .map(|entry| {
+                let client = entry.value();
+                serde_json::json!({
+                    "id": *entry.key(),
+                    "address": client.address,
+                    "connected_at_ms": unix_millis(client.connected_at),
+                    "last_command": client.last_command,
+                    "last_command_at_ms": unix_millis(client.last_command_at),
+                    "bytes_in": client.bytes_in,
+                    "bytes_out": client.bytes_out,
+                })
+            })
+            .collect();
+        clients.sort_by_key(|client| client["id"].as_u64());
+        return serde_json::Value::Array(clients);
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    return time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+}
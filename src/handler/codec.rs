@@ -0,0 +1,17 @@
+// Registry of content types that 'C' (coded bytes) values can be tagged with.
+// Stored layout is 'C' + codec id (u8) + encoded bytes.
+
+pub struct Codec {
+    pub id: u8,
+    pub content_type: &'static str,
+}
+
+pub const CODECS: [Codec; 3] = [
+    Codec { id: 1, content_type: "application/msgpack" },
+    Codec { id: 2, content_type: "application/protobuf" },
+    Codec { id: 3, content_type: "application/python-pickle" },
+];
+
+pub fn lookup_codec(id: u8) -> Option<&'static Codec> {
+    return CODECS.iter().find(|codec| codec.id == id);
+}
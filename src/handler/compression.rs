@@ -0,0 +1,89 @@
+// Compression of stored values and of responses. A compressed value is stored as
+// 'Z' + algorithm id (u8) + the compressed bytes of the original value, type tag included,
+// and is handed back in its original form to everything that reads it.
+use bytes::Bytes;
+
+pub const COMPRESSION_NONE: u8 = 0;
+pub const COMPRESSION_LZ4: u8 = 1;
+pub const COMPRESSION_ZSTD: u8 = 2;
+
+const COMPRESSED_TAG: u8 = b'Z';
+const ZSTD_LEVEL: i32 = 3;
+
+// `level` only applies to zstd. None for an unknown algorithm or when compression fails.
+pub fn compress_bytes(data: &[u8], algorithm: u8, level: i32) -> Option<Vec<u8>> {
+    match algorithm {
+        COMPRESSION_LZ4 => return Some(lz4_flex::compress_prepend_size(data)),
+        COMPRESSION_ZSTD => return zstd::bulk::compress(data, level).ok(),
+        _ => return None,
+    }
+}
+
+pub fn decompress_bytes(data: &[u8], algorithm: u8) -> Option<Vec<u8>> {
+    match algorithm {
+        COMPRESSION_LZ4 => return lz4_flex::decompress_size_prepended(data).ok(),
+        COMPRESSION_ZSTD => return zstd::stream::decode_all(data).ok(),
+        _ => return None,
+    }
+}
+
+pub fn compression_id(name: &str) -> Option<u8> {
+    match name {
+        "none" => Some(COMPRESSION_NONE),
+        "lz4" => Some(COMPRESSION_LZ4),
+        "zstd" => Some(COMPRESSION_ZSTD),
+        _ => None,
+    }
+}
+
+pub fn compression_name(id: u8) -> &'static str {
+    match id {
+        COMPRESSION_LZ4 => "lz4",
+        COMPRESSION_ZSTD => "zstd",
+        _ => "none",
+    }
+}
+
+// Stored form of a value. Arrow, bytes, string and coded values of at least `threshold` bytes are
+// compressed, and kept as they are when compression does not make them smaller.
+pub fn compress_value(value: Bytes, algorithm: u8, threshold: u64) -> Bytes {
+    let compressible = matches!(value.first(), Some(b'A') | Some(b'B') | Some(b'C') | Some(b'S'));
+    if algorithm == COMPRESSION_NONE || !compressible || (value.len() as u64) < threshold {
+        return value;
+    }
+    let compressed = match compress_bytes(&value, algorithm, ZSTD_LEVEL) {
+        Some(compressed) => compressed,
+        None => return value,
+    };
+    if compressed.len() + 2 >= value.len() {
+        return value;
+    }
+    let mut stored_value = Vec::with_capacity(compressed.len() + 2);
+    stored_value.push(COMPRESSED_TAG);
+    stored_value.push(algorithm);
+    stored_value.extend(compressed);
+    return Bytes::from(stored_value);
+}
+
+// The original value of a stored one, sharing it when it was not compressed
+pub fn decompress_value(stored_value: &Bytes) -> Result<Bytes, u16> {
+    match decompress_stored(stored_value)? {
+        Some(value) => return Ok(Bytes::from(value)),
+        None => return Ok(stored_value.clone()),
+    }
+}
+
+// The original value of a compressed stored value, None when it was stored uncompressed.
+// Fails with error code 12 if the compressed bytes are damaged.
+pub fn decompress_stored(stored_value: &[u8]) -> Result<Option<Vec<u8>>, u16> {
+    if stored_value.len() < 2 || stored_value[0] != COMPRESSED_TAG {
+        return Ok(None);
+    }
+    match decompress_bytes(&stored_value[2..], stored_value[1]) {
+        Some(value) => return Ok(Some(value)),
+        None => {
+            tracing::error!("Failed to decompress a stored value");
+            return Err(12);
+        },
+    }
+}
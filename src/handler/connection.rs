@@ -1,33 +1,193 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::io::IoSlice;
+use std::sync::Arc;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use bytes::Bytes;
+use crc32c::{crc32c, crc32c_append};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::handler::compression::{compress_bytes, COMPRESSION_NONE};
+use crate::handler::deadline::Deadline;
 
 const PROTOCOL_VERSION: char = 'A';
+// Same frame followed by a u64 request id, which the response carries back in its header
+const TAGGED_PROTOCOL_VERSION: char = 'B';
+// Tagged frame followed by a u32 number of milliseconds after which the client no longer
+// wants the answer, 0 for no deadline. Its response is an ordinary tagged frame.
+const DEADLINE_PROTOCOL_VERSION: char = 'C';
+
+// Lets one read pick up several small pipelined frames
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+// Lets responses written back to back go out in one write, larger frames skip the buffer
+const WRITE_BUFFER_SIZE: usize = 16 * 1024;
+// Spare payload buffers kept per connection
+const POOLED_BUFFERS: usize = 8;
+// Buffers this many times larger than recent frames are freed instead of kept
+const POOLED_SIZE_FACTOR: usize = 4;
+const MIN_POOLED_CAPACITY: usize = 4 * 1024;
+// Smaller response payloads are sent uncompressed even when the client asked for compression
+const MIN_COMPRESSED_PAYLOAD_SIZE: usize = 512;
+
+// Halves of a TCP or Unix socket
+type ReadHalf = Box<dyn AsyncRead + Send + Sync + Unpin>;
+type WriteHalf = Box<dyn AsyncWrite + Send + Sync + Unpin>;
 
 pub struct Connection {
-    stream: TcpStream,
+    stream: BufReader<ReadHalf>,
+    peer_address: String,
+    writer: FrameWriter,
+    // Shared with the writer, set once the client asks for checksums with HE
+    checksums: Arc<AtomicBool>,
+    pool: BufferPool,
+    pub bytes_read: u64,
+}
+
+// Payload buffers of one connection. Response buffers go back to the pool once written and
+// are reused for the payloads of later requests.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    recent_frame_size: Arc<AtomicUsize>,
+}
+
+impl BufferPool {
+    fn new() -> BufferPool {
+        BufferPool {
+            buffers: Arc::new(std::sync::Mutex::new(Vec::with_capacity(POOLED_BUFFERS))),
+            recent_frame_size: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // A zeroed buffer of `length` bytes
+    pub fn take(&self, length: usize) -> Vec<u8> {
+        // Moving average over roughly the last 8 frames
+        let recent_frame_size = self.recent_frame_size.load(Ordering::Relaxed);
+        self.recent_frame_size.store(recent_frame_size - recent_frame_size / 8 + length / 8, Ordering::Relaxed);
+
+        let pooled_buffer = self.buffers.lock().unwrap().pop();
+        match pooled_buffer {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer.resize(length, 0);
+                return buffer;
+            },
+            None => return vec![0; length],
+        }
+    }
+
+    fn give_back(&self, buffer: Vec<u8>) {
+        let recent_frame_size = self.recent_frame_size.load(Ordering::Relaxed);
+        let size_limit = (recent_frame_size * POOLED_SIZE_FACTOR).max(MIN_POOLED_CAPACITY);
+        if buffer.capacity() == 0 || buffer.capacity() > size_limit {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < POOLED_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+}
+
+// Write side of a connection, shared with the tasks answering pipelined requests.
+// Each frame is written whole under the lock so concurrent responses never interleave.
+#[derive(Clone)]
+pub struct FrameWriter {
+    stream: Arc<Mutex<BufWriter<WriteHalf>>>,
+    // Frames waiting for the lock or being written. The last one out flushes the buffer, so
+    // responses of pipelined requests that finish together are sent in as few writes as possible.
+    pending_frames: Arc<AtomicUsize>,
+    pool: BufferPool,
+    bytes_written: Arc<AtomicU64>,
+    // Response compression the client asked for with HE
+    compression: Arc<AtomicU8>,
+    compression_level: Arc<AtomicI32>,
+    // Whether frames carry a CRC32C of their payload after the request id
+    checksums: Arc<AtomicBool>,
+    // Cancelled once a write fails. Nothing more is written after that, since the frame it
+    // failed on may have been sent in part.
+    failed: CancellationToken,
 }
 
 impl Connection {
     pub fn new(socket: TcpStream) -> Connection {
+        let peer_address = match socket.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => "unknown".to_string(),
+        };
+        let (read_half, write_half) = socket.into_split();
+        return Connection::from_halves(Box::new(read_half), Box::new(write_half), peer_address);
+    }
+
+    // Clients on a Unix socket have no address of their own, they are named after the socket
+    pub fn from_unix(socket: UnixStream) -> Connection {
+        let socket_path = socket
+            .local_addr()
+            .ok()
+            .and_then(|address| address.as_pathname().map(|path| path.display().to_string()));
+        let peer_address = match socket_path {
+            Some(path) => format!("unix:{}", path),
+            None => "unix".to_string(),
+        };
+        let (read_half, write_half) = socket.into_split();
+        return Connection::from_halves(Box::new(read_half), Box::new(write_half), peer_address);
+    }
+
+    fn from_halves(read_half: ReadHalf, write_half: WriteHalf, peer_address: String) -> Connection {
+        let pool = BufferPool::new();
+        let checksums = Arc::new(AtomicBool::new(false));
         Connection {
-            stream: socket,
+            stream: BufReader::with_capacity(READ_BUFFER_SIZE, read_half),
+            peer_address: peer_address,
+            checksums: Arc::clone(&checksums),
+            writer: FrameWriter {
+                stream: Arc::new(Mutex::new(BufWriter::with_capacity(WRITE_BUFFER_SIZE, write_half))),
+                pending_frames: Arc::new(AtomicUsize::new(0)),
+                pool: pool.clone(),
+                bytes_written: Arc::new(AtomicU64::new(0)),
+                compression: Arc::new(AtomicU8::new(COMPRESSION_NONE)),
+                compression_level: Arc::new(AtomicI32::new(0)),
+                checksums: checksums,
+                failed: CancellationToken::new(),
+            },
+            pool: pool,
+            bytes_read: 0,
         }
     }
 
-    pub async fn read_frame(&mut self) -> (String, Vec<u8>) {
+    pub fn writer(&self) -> FrameWriter {
+        return self.writer.clone();
+    }
+
+    pub fn peer_address(&self) -> String {
+        return self.peer_address.clone();
+    }
+
+    // Returns the message type, the request id of a tagged frame, its deadline and the payload.
+    // A payload over `max_payload_size` (0 for no limit) is left unread and reported as "PL",
+    // the connection can not be used after that. With checksums on, a payload that does not
+    // match its checksum is reported as "CE".
+    pub async fn read_frame(&mut self, max_payload_size: u64) -> (String, Option<u64>, Deadline, Vec<u8>) {
         let mut header_buffer = [0; 11];
         let mut payload_length_buffer = [0; 8];
         let packet_length: u64;
         let message_type: String;
+        let mut request_id: Option<u64> = None;
+        let mut deadline = Deadline::none();
 
         match self.stream.read_exact(&mut header_buffer).await {
             Ok(_) => {
+                self.bytes_read += header_buffer.len() as u64;
                 payload_length_buffer.clone_from_slice(&header_buffer[3..11]);
                 packet_length = u64::from_be_bytes(payload_length_buffer);
-                if header_buffer[0] as char == PROTOCOL_VERSION {
+                let protocol_version = header_buffer[0] as char;
+                if [PROTOCOL_VERSION, TAGGED_PROTOCOL_VERSION, DEADLINE_PROTOCOL_VERSION].contains(&protocol_version) {
+                    // Reported as "BT" once the payload is read, so the next frame still lines up
                     message_type = match String::from_utf8((&header_buffer[1..3]).to_vec()) {
                         Ok(mt) => { mt },
-                        Err(_) => { panic!("Invalid string") },
+                        Err(_) => { "BT".to_string() },
                     }
                 } else {
                     message_type = "WP".to_string();
@@ -40,34 +200,222 @@ impl Connection {
             },
         }
 
-        let mut payload = vec![0; (packet_length) as usize];
+        let tagged = [TAGGED_PROTOCOL_VERSION, DEADLINE_PROTOCOL_VERSION].contains(&(header_buffer[0] as char));
+        if message_type != "CC" && tagged {
+            let mut request_id_buffer = [0; 8];
+            match self.stream.read_exact(&mut request_id_buffer).await {
+                Ok(_) => {
+                    self.bytes_read += request_id_buffer.len() as u64;
+                    request_id = Some(u64::from_be_bytes(request_id_buffer));
+                },
+                Err(e) => {
+                    tracing::debug!("Failed to read request id: {}", e);
+                    return ("CC".to_string(), None, deadline, vec![0; 0]);
+                },
+            }
+        }
+
+        if message_type != "CC" && header_buffer[0] as char == DEADLINE_PROTOCOL_VERSION {
+            let mut timeout_buffer = [0; 4];
+            match self.stream.read_exact(&mut timeout_buffer).await {
+                Ok(_) => {
+                    self.bytes_read += timeout_buffer.len() as u64;
+                    let timeout_ms = u32::from_be_bytes(timeout_buffer) as u64;
+                    if timeout_ms > 0 {
+                        deadline = Deadline::after(Duration::from_millis(timeout_ms));
+                    }
+                },
+                Err(e) => {
+                    tracing::debug!("Failed to read deadline: {}", e);
+                    return ("CC".to_string(), None, deadline, vec![0; 0]);
+                },
+            }
+        }
+
+        let mut checksum: Option<u32> = None;
+        if message_type != "CC" && message_type != "WP" && self.checksums.load(Ordering::Relaxed) {
+            let mut checksum_buffer = [0; 4];
+            match self.stream.read_exact(&mut checksum_buffer).await {
+                Ok(_) => {
+                    self.bytes_read += checksum_buffer.len() as u64;
+                    checksum = Some(u32::from_be_bytes(checksum_buffer));
+                },
+                Err(e) => {
+                    tracing::debug!("Failed to read checksum: {}", e);
+                    return ("CC".to_string(), None, deadline, vec![0; 0]);
+                },
+            }
+        }
+
+        if max_payload_size > 0 && packet_length > max_payload_size {
+            tracing::warn!("Rejected a {} byte payload over the {} byte limit", packet_length, max_payload_size);
+            return ("PL".to_string(), request_id, deadline, vec![0; 0]);
+        }
+
+        let mut payload = self.pool.take(packet_length as usize);
         if packet_length > 0 {
             match self.stream.read_exact(&mut payload).await {
-                Ok(_) => {},
+                Ok(_) => { self.bytes_read += packet_length },
                 Err(e) => {
+                    // The peer went away mid frame, there is nothing left to answer
                     tracing::error!("Failed to read payload: {}", e);
+                    return ("CC".to_string(), None, deadline, vec![0; 0]);
                 },
             }
         }
-        return (message_type, payload);
+        if let Some(checksum) = checksum {
+            if crc32c(&payload) != checksum {
+                tracing::warn!("Discarded a {} frame with a corrupted payload", message_type);
+                return ("CE".to_string(), request_id, deadline, vec![0; 0]);
+            }
+        }
+        return (message_type, request_id, deadline, payload);
+    }
+
+    // Resolves once the next frame starts arriving or the peer closes the connection. Nothing is
+    // consumed, so it can be raced against shutdown without losing part of a frame.
+    pub async fn wait_for_frame(&mut self) {
+        let _ = self.stream.fill_buf().await;
     }
 
-    pub async fn write_frame(&mut self, message_type: String, payload: Vec<u8>) {
-        let header = "A".to_string() + message_type.as_str();
+    // Resolves once the peer closes the connection, anything it sends meanwhile is discarded
+    pub async fn wait_closed(&mut self) {
+        let mut discard_buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut discard_buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {},
+            }
+        }
+    }
+
+    pub async fn write_frame(
+        &self, message_type: String, request_id: Option<u64>, payload: Bytes
+    ) -> std::io::Result<()> {
+        return self.writer.write_frame(message_type, request_id, payload).await;
+    }
+}
+
+impl FrameWriter {
+    pub fn bytes_written(&self) -> u64 {
+        return self.bytes_written.load(Ordering::Relaxed);
+    }
+
+    pub fn set_compression(&self, algorithm: u8, level: i32) {
+        self.compression_level.store(level, Ordering::Relaxed);
+        self.compression.store(algorithm, Ordering::Relaxed);
+    }
+
+    pub fn set_checksums(&self, enabled: bool) {
+        self.checksums.store(enabled, Ordering::Relaxed);
+    }
+
+    // Resolves once a write failed, the connection should be closed then
+    pub async fn wait_failed(&self) {
+        self.failed.cancelled().await;
+    }
+
+    // Answers with a tagged frame when the request had an id. Once the client asked for
+    // compression, every payload starts with the id of the algorithm it was compressed with,
+    // 0 when it was left as is. Fails once any write to the connection failed.
+    pub async fn write_frame(
+        &self, message_type: String, request_id: Option<u64>, payload: Bytes
+    ) -> std::io::Result<()> {
+        if self.failed.is_cancelled() {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        let protocol_version = match request_id {
+            Some(_) => TAGGED_PROTOCOL_VERSION,
+            None => PROTOCOL_VERSION,
+        };
+        let header = protocol_version.to_string() + message_type.as_str();
+        let (compression, payload) = self.compress_payload(payload);
         let payload_length = payload.len() as u64;
 
         let mut header_buffer = header.into_bytes();
-        header_buffer.extend(payload_length.to_be_bytes());
-        match self.stream.write_all(&header_buffer).await {
-            Ok(_) => {},
-            Err(_) => {},
+        let frame_payload_length = match compression {
+            Some(_) => payload_length + 1,
+            None => payload_length,
+        };
+        header_buffer.extend(frame_payload_length.to_be_bytes());
+        if let Some(request_id) = request_id {
+            header_buffer.extend(request_id.to_be_bytes());
         }
-
-        if payload_length > 0 {
-            match self.stream.write_all(&payload).await {
-                Ok(_) => {},
-                Err(_) => {},
+        // The checksum covers the algorithm byte, which is sent as the start of the payload
+        if self.checksums.load(Ordering::Relaxed) {
+            let checksum = match compression {
+                Some(algorithm) => crc32c_append(crc32c(&[algorithm]), &payload),
+                None => crc32c(&payload),
+            };
+            header_buffer.extend(checksum.to_be_bytes());
+        }
+        if let Some(algorithm) = compression {
+            header_buffer.push(algorithm);
+        }
+        self.pending_frames.fetch_add(1, Ordering::AcqRel);
+        let mut stream = self.stream.lock().await;
+        let mut result = match self.failed.is_cancelled() {
+            true => Err(std::io::ErrorKind::BrokenPipe.into()),
+            false => write_all_vectored(&mut stream, &header_buffer, &payload).await,
+        };
+        if result.is_ok() {
+            self.bytes_written.fetch_add(header_buffer.len() as u64 + payload_length, Ordering::Relaxed);
+        }
+        if self.pending_frames.fetch_sub(1, Ordering::AcqRel) == 1 && result.is_ok() {
+            result = stream.flush().await;
+        }
+        if let Err(e) = &result {
+            if !self.failed.is_cancelled() {
+                tracing::debug!("Failed to write to socket: {}", e);
+                self.failed.cancel();
             }
         }
+        drop(stream);
+        // Slices of stored values are still in use, only buffers made for this response are reused
+        if payload.is_unique() {
+            self.pool.give_back(Vec::from(payload));
+        }
+        return result;
+    }
+
+    // The algorithm the payload is sent with, None when the client did not ask for compression
+    fn compress_payload(&self, payload: Bytes) -> (Option<u8>, Bytes) {
+        let algorithm = self.compression.load(Ordering::Relaxed);
+        if algorithm == COMPRESSION_NONE {
+            return (None, payload);
+        }
+        if payload.len() < MIN_COMPRESSED_PAYLOAD_SIZE {
+            return (Some(COMPRESSION_NONE), payload);
+        }
+        let level = self.compression_level.load(Ordering::Relaxed);
+        match compress_bytes(&payload, algorithm, level) {
+            Some(compressed) if compressed.len() < payload.len() => {
+                if payload.is_unique() {
+                    self.pool.give_back(Vec::from(payload));
+                }
+                return (Some(algorithm), Bytes::from(compressed));
+            },
+            _ => return (Some(COMPRESSION_NONE), payload),
+        }
+    }
+}
+
+// Writes the header and payload of a frame together, so a response is one write however it
+// is split between the two buffers
+async fn write_all_vectored(
+    stream: &mut BufWriter<WriteHalf>, header: &[u8], payload: &[u8]
+) -> std::io::Result<()> {
+    let mut written: usize = 0;
+    let frame_length = header.len() + payload.len();
+    while written < frame_length {
+        let slices = match written < header.len() {
+            true => [IoSlice::new(&header[written..]), IoSlice::new(payload)],
+            false => [IoSlice::new(&payload[written - header.len()..]), IoSlice::new(&[])],
+        };
+        match stream.write_vectored(&slices).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            count => written += count,
+        }
     }
+    return Ok(());
 }
@@ -0,0 +1,91 @@
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::Arc;
+use arrow::csv::reader::Format;
+use arrow::csv::{ReaderBuilder, WriterBuilder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use serde::Deserialize;
+
+// Rows read to guess the column types of CSV sent without a schema
+const INFER_RECORDS: usize = 1000;
+
+// Rows per chunk of the Arrow value the CSV is stored as
+const CSV_CHUNK_ROWS: usize = 8192;
+
+// How IC reads its CSV, sent as JSON before it. Every field may be left out.
+#[derive(Deserialize, Default)]
+pub struct CsvOptions {
+    // Columns in file order, their types are guessed from the first rows when left out
+    schema: Option<Vec<CsvColumn>>,
+    // Whether the first line names the columns instead of holding values, true by default
+    header: Option<bool>,
+    delimiter: Option<char>,
+}
+
+#[derive(Deserialize)]
+struct CsvColumn {
+    name: String,
+    // Arrow type as it is displayed, such as Int64, Float64, Utf8 or Timestamp(Millisecond, None)
+    #[serde(rename = "type")]
+    data_type: String,
+    nullable: Option<bool>,
+}
+
+// Options of an IC request, the defaults when there are none. Fails with error code 3 when they
+// are not valid JSON.
+pub fn parse_csv_options(options: &[u8]) -> Result<CsvOptions, u16> {
+    if options.len() == 0 {
+        return Ok(CsvOptions::default());
+    }
+    return serde_json::from_slice(options).map_err(|_| 3u16);
+}
+
+// Chunks of the table in the CSV, at least one even when it has no rows. Fails with error code
+// 3 when the options name an unknown type or a delimiter that is not one ASCII character, and 4
+// when the CSV can not be read with them.
+pub fn read_csv(options: &CsvOptions, csv: &[u8]) -> Result<Vec<RecordBatch>, u16> {
+    let delimiter = match options.delimiter {
+        None => b',',
+        Some(delimiter) if delimiter.is_ascii() => delimiter as u8,
+        Some(_) => return Err(3),
+    };
+    let format = Format::default().with_header(options.header.unwrap_or(true)).with_delimiter(delimiter);
+    let schema: SchemaRef = match &options.schema {
+        Some(columns) => {
+            let fields = columns
+                .iter()
+                .map(|column| {
+                    let data_type = DataType::from_str(&column.data_type).map_err(|_| 3u16)?;
+                    return Ok(Field::new(&column.name, data_type, column.nullable.unwrap_or(true)));
+                })
+                .collect::<Result<Vec<Field>, u16>>()?;
+            Arc::new(Schema::new(fields))
+        },
+        None => {
+            let (schema, _) = format.clone().infer_schema(Cursor::new(csv), Some(INFER_RECORDS)).map_err(|_| 4u16)?;
+            Arc::new(schema)
+        },
+    };
+    if schema.fields().len() == 0 {
+        return Err(4);
+    }
+    let reader = ReaderBuilder::new(Arc::clone(&schema))
+        .with_format(format)
+        .with_batch_size(CSV_CHUNK_ROWS)
+        .build(Cursor::new(csv))
+        .map_err(|_| 4u16)?;
+    let mut chunks = reader.collect::<Result<Vec<RecordBatch>, _>>().map_err(|_| 4u16)?;
+    if chunks.len() == 0 {
+        chunks.push(RecordBatch::new_empty(schema));
+    }
+    return Ok(chunks);
+}
+
+// The rows of a GA result as CSV, after a line naming the columns when `header` is set. Fails
+// with error code 5 when a column has a type CSV can not hold, such as a list.
+pub fn write_csv(record_batch: &RecordBatch, header: bool) -> Result<Vec<u8>, u16> {
+    let mut writer = WriterBuilder::new().with_header(header).build(Vec::new());
+    writer.write(record_batch).map_err(|_| 5u16)?;
+    return Ok(writer.into_inner());
+}
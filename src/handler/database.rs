@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use bytes::Bytes;
+use dashmap::DashMap;
+use tokio::sync::RwLock;
+
+use crate::config::AppConfig;
+use crate::handler::batch_cache::BatchCache;
+use crate::handler::changes::ChangeLog;
+use crate::handler::clients::Clients;
+use crate::handler::hyperloglog::element_hash;
+use crate::handler::indexer::KeyIndex;
+use crate::handler::key_locks::KeyLocks;
+use crate::handler::loader::Loader;
+use crate::handler::monitor::Monitor;
+use crate::handler::memory::MemoryUsage;
+use crate::handler::notifier::Notifier;
+use crate::handler::result_cache::ResultCache;
+use crate::handler::retention::Retention;
+use crate::handler::settings::Settings;
+use crate::handler::singleflight::Singleflight;
+use crate::handler::stats::Stats;
+use crate::handler::triggers::Trigger;
+use crate::handler::views::View;
+use crate::handler::webhook::Webhook;
+use crate::handler::write_behind::WriteBehind;
+use crate::handler::zonemap::ZoneMap;
+
+pub type Db = Arc<Database>;
+
+// Namespace connections start in
+pub const DEFAULT_NAMESPACE: &str = "0";
+
+// All state of one namespace shared between connections and the cache manager. Settings,
+// clients, the monitor, the notifier, the change log and the memory usage belong to the whole
+// server and are shared by every namespace.
+pub struct Database {
+    pub namespace: String,
+    // Values are shared with the responses reading them instead of copied
+    pub shared_db: DashMap<String, Bytes>,
+    pub timeout_db: DashMap<String, SystemTime>,
+    pub dependency_db: DashMap<String, HashSet<String>>,
+    pub index_db: DashMap<String, KeyIndex>,
+    // One zone map per stored chunk, in chunk order
+    pub zone_db: DashMap<String, Arc<Vec<ZoneMap>>>,
+    pub version_db: DashMap<String, u64>,
+    // Arrow keys whose schema declares a retention, which the cache manager trims
+    pub retention_db: DashMap<String, Retention>,
+    // Views registered with VW, by the key holding them
+    pub view_db: DashMap<String, View>,
+    // Triggers registered with TR, by name
+    pub trigger_db: DashMap<String, Trigger>,
+    // Keys lookups found missing with negative caching on, until when they count as missing
+    pub miss_db: DashMap<String, SystemTime>,
+    pub batch_cache: BatchCache,
+    pub result_cache: ResultCache,
+    // GA queries running right now, so identical ones wait for them instead of repeating them
+    pub query_flights: Singleflight,
+    // Held by commands that change a value they read, such as AP, while they change it
+    pub key_locks: KeyLocks,
+    // Loads of keys that missed, so identical misses at the same time share one load
+    pub load_flights: Singleflight,
+    // Versions come from one counter so a recreated key never reuses an old version
+    version_counter: AtomicU64,
+    // Writes hold it shared, EX holds it exclusively so that checking the watched keys and
+    // applying its commands happen as one step
+    pub write_gate: RwLock<()>,
+    // Kept by the empty namespace that replaces this one on an asynchronous flush
+    pub stats: Arc<Stats>,
+    pub settings: Arc<Settings>,
+    pub clients: Arc<Clients>,
+    pub monitor: Arc<Monitor>,
+    pub notifier: Arc<Notifier>,
+    pub changes: Arc<ChangeLog>,
+    pub memory: Arc<MemoryUsage>,
+    // Where keys that miss on GD and GA are loaded from, None when read-through is off
+    pub loader: Option<Arc<dyn Loader>>,
+    // Where keys written with SD and AP are queued to be persisted, None when write-behind is off
+    pub write_behind: Option<Arc<WriteBehind>>,
+    // Where key events are posted, None when no webhook is configured
+    pub webhook: Option<Arc<Webhook>>,
+    // TCP addresses and Unix socket paths the server listens on
+    pub bind_addresses: Arc<Vec<String>>,
+    initial_capacity: usize,
+    shard_amount: usize,
+}
+
+// Server-wide state every namespace shares
+struct SharedState {
+    settings: Arc<Settings>,
+    clients: Arc<Clients>,
+    monitor: Arc<Monitor>,
+    notifier: Arc<Notifier>,
+    changes: Arc<ChangeLog>,
+    memory: Arc<MemoryUsage>,
+    loader: Option<Arc<dyn Loader>>,
+    write_behind: Option<Arc<WriteBehind>>,
+    webhook: Option<Arc<Webhook>>,
+    bind_addresses: Arc<Vec<String>>,
+}
+
+impl Database {
+    // The default namespace of a server started with the configuration
+    pub fn new(config: &AppConfig) -> Database {
+        let shared = SharedState {
+            settings: Arc::new(Settings::new(config)),
+            clients: Arc::new(Clients::new()),
+            monitor: Arc::new(Monitor::new()),
+            notifier: Arc::new(Notifier::new()),
+            changes: Arc::new(ChangeLog::new()),
+            memory: Arc::new(MemoryUsage::new()),
+            loader: config.loader.clone(),
+            write_behind: config.write_behind.clone(),
+            webhook: config.webhook.clone(),
+            bind_addresses: Arc::new(config.bind_addresses.clone()),
+        };
+        return Database::with_shared_state(
+            DEFAULT_NAMESPACE, config.cache_initial_capacity, config.cache_shards, shared
+        );
+    }
+
+    // An empty namespace sized like this one and sharing its server-wide state
+    pub fn new_namespace(&self, namespace: &str) -> Database {
+        let shared = SharedState {
+            settings: Arc::clone(&self.settings),
+            clients: Arc::clone(&self.clients),
+            monitor: Arc::clone(&self.monitor),
+            notifier: Arc::clone(&self.notifier),
+            changes: Arc::clone(&self.changes),
+            memory: Arc::clone(&self.memory),
+            loader: self.loader.clone(),
+            write_behind: self.write_behind.clone(),
+            webhook: self.webhook.clone(),
+            bind_addresses: Arc::clone(&self.bind_addresses),
+        };
+        return Database::with_shared_state(namespace, self.initial_capacity, self.shard_amount, shared);
+    }
+
+    // An empty copy of this namespace that keeps its stats and continues its versions
+    pub fn emptied(&self) -> Database {
+        let mut emptied = self.new_namespace(&self.namespace);
+        emptied.stats = Arc::clone(&self.stats);
+        emptied.version_counter = AtomicU64::new(self.version_counter.load(Ordering::Relaxed));
+        return emptied;
+    }
+
+    fn with_shared_state(namespace: &str, initial_capacity: usize, shards: usize, shared: SharedState) -> Database {
+        Database {
+            namespace: namespace.to_string(),
+            shared_db: DashMap::with_capacity_and_shard_amount(initial_capacity, shards),
+            timeout_db: DashMap::with_capacity_and_shard_amount(initial_capacity, shards),
+            dependency_db: DashMap::with_capacity_and_shard_amount(initial_capacity, shards),
+            index_db: DashMap::with_capacity_and_shard_amount(initial_capacity, shards),
+            zone_db: DashMap::with_capacity_and_shard_amount(initial_capacity, shards),
+            version_db: DashMap::with_capacity_and_shard_amount(initial_capacity, shards),
+            retention_db: DashMap::new(),
+            view_db: DashMap::new(),
+            trigger_db: DashMap::new(),
+            miss_db: DashMap::new(),
+            batch_cache: BatchCache::new(),
+            result_cache: ResultCache::new(),
+            query_flights: Singleflight::new(),
+            key_locks: KeyLocks::new(),
+            load_flights: Singleflight::new(),
+            version_counter: AtomicU64::new(0),
+            write_gate: RwLock::new(()),
+            stats: Arc::new(Stats::new()),
+            settings: shared.settings,
+            clients: shared.clients,
+            monitor: shared.monitor,
+            notifier: shared.notifier,
+            changes: shared.changes,
+            memory: shared.memory,
+            loader: shared.loader,
+            write_behind: shared.write_behind,
+            webhook: shared.webhook,
+            bind_addresses: shared.bind_addresses,
+            initial_capacity: initial_capacity,
+            shard_amount: shards,
+        }
+    }
+
+    // Keys in the order of a hash of them, the `count` keys whose hash comes first from `cursor`
+    // on. Returns the cursor of the next page, or 0 once every key was visited. The hash of a key
+    // never changes, so a key present for the whole scan is returned exactly once. Keys sharing
+    // the hash of the last one are returned with it, which may make a page a little longer.
+    pub fn scan_keys(&self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let count = count.max(1);
+        let mut page: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        let mut page_len = 0;
+        for entry in self.shared_db.iter() {
+            let hash = scan_hash(entry.key());
+            if hash < cursor {
+                continue;
+            }
+            if page_len >= count && page.last_key_value().map_or(false, |(last, _)| hash > *last) {
+                continue;
+            }
+            page.entry(hash).or_default().push(entry.key().clone());
+            page_len += 1;
+            // Lets go of the keys of the last hash while the page is full without them
+            while let Some((_, last_keys)) = page.last_key_value() {
+                if page_len - last_keys.len() < count {
+                    break;
+                }
+                page_len -= last_keys.len();
+                page.pop_last();
+            }
+        }
+        let next_cursor = match page.last_key_value() {
+            Some((last, _)) if page_len >= count => last + 1,
+            _ => 0,
+        };
+        return (next_cursor, page.into_values().flatten().collect());
+    }
+
+    // Gives the key a new version, and ends a miss remembered for it since it now exists. Must be
+    // called while holding the key's entry in shared_db, so that readers holding the value always
+    // see the version that belongs to it.
+    pub fn bump_version(&self, key: &str) {
+        let version = self.version_counter.fetch_add(1, Ordering::Relaxed) + 1;
+        self.version_db.insert(key.to_string(), version);
+        let _ = self.miss_db.remove(key);
+    }
+
+    // Replaces the value of a key, dropping its zone maps and retention and giving it a new
+    // version
+    pub fn insert_value(&self, key: &str, value: Bytes) {
+        let entry = self.shared_db.entry(key.to_string());
+        let previous_len = match &entry {
+            dashmap::Entry::Occupied(occupied) => Some(occupied.get().len()),
+            dashmap::Entry::Vacant(_) => None,
+        };
+        let _ = self.zone_db.remove(key);
+        let _ = self.retention_db.remove(key);
+        self.bump_version(key);
+        self.memory.value_changed(key, previous_len, Some(value.len()));
+        entry.insert(value);
+    }
+
+    // Drops a key together with its expiry, indexes, zone map and version, returns whether the
+    // key existed. Its state is forgotten while holding its entry, so readers of the key can not
+    // count it again in between.
+    pub fn remove_key(&self, key: &str) -> bool {
+        match self.shared_db.entry(key.to_string()) {
+            dashmap::Entry::Occupied(entry) => {
+                self.forget_key_state(key);
+                let (_, value) = entry.remove_entry();
+                self.value_removed(key, &value);
+                return true;
+            },
+            dashmap::Entry::Vacant(_) => {
+                self.forget_key_state(key);
+                return false;
+            },
+        }
+    }
+
+    // Counts the value of a key a command removed as freed, for the memory watermarks and
+    // defragmentation
+    pub fn value_removed(&self, key: &str, value: &Bytes) {
+        self.memory.value_changed(key, Some(value.len()), None);
+        self.stats.freed_value_bytes.fetch_add(value.len() as u64, Ordering::Relaxed);
+    }
+
+    // Adds a change of a key, or of the whole namespace when `key` is None, to the change log
+    // unless change_log_size turned it off, and queues the changes of keys for the webhook
+    pub fn record_change(&self, command: &str, key: Option<&str>, value_hash: Option<u64>) {
+        let capacity = self.settings.change_log_size.load(Ordering::Relaxed) as usize;
+        if capacity > 0 {
+            self.changes.record(capacity, &self.namespace, command, key, value_hash);
+        }
+        if let (Some(webhook), Some(key)) = (&self.webhook, key) {
+            webhook.queue(&self.namespace, key, command, value_hash);
+        }
+    }
+
+    // Counts a read of the key for HK and eviction if it still exists, for readers that no
+    // longer hold its entry
+    pub fn record_read(&self, key: &str) {
+        if let Some(_stored_value) = self.shared_db.get(key) {
+            self.stats.record_key_access(key);
+        }
+    }
+
+    // Drops the expiry, indexes, zone map, retention, view, version and cached batches of a key.
+    // Also for commands that removed the value themselves, while holding its entry.
+    pub fn forget_key_state(&self, key: &str) {
+        let _ = self.timeout_db.remove(key);
+        let _ = self.index_db.remove(key);
+        let _ = self.zone_db.remove(key);
+        let _ = self.retention_db.remove(key);
+        let _ = self.view_db.remove(key);
+        let _ = self.version_db.remove(key);
+        self.batch_cache.remove(key);
+        self.stats.forget_key(key);
+    }
+}
+
+// Logical keyspaces by name. Each has its own keys, caches and stats, so pipelines that must
+// not see each other's keys can share one server.
+pub struct Namespaces {
+    namespaces: DashMap<String, Db>,
+}
+
+impl Namespaces {
+    pub fn new(default_db: Db) -> Namespaces {
+        let namespaces = DashMap::new();
+        namespaces.insert(default_db.namespace.clone(), default_db);
+        return Namespaces { namespaces: namespaces };
+    }
+
+    pub fn default_db(&self) -> Db {
+        return self.select(DEFAULT_NAMESPACE);
+    }
+
+    // The namespace called `name`, created empty the first time it is selected
+    pub fn select(&self, name: &str) -> Db {
+        if let Some(db) = self.namespaces.get(name) {
+            return Arc::clone(&db);
+        }
+        let default_db = Arc::clone(&self.namespaces.get(DEFAULT_NAMESPACE).unwrap());
+        let db = self.namespaces
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(default_db.new_namespace(name)));
+        return Arc::clone(&db);
+    }
+
+    // Replaces the namespace with an empty one, returns the old one unless there was none.
+    // Commands already running on the old one finish there.
+    pub fn empty(&self, name: &str) -> Option<Db> {
+        let mut db = self.namespaces.get_mut(name)?;
+        let emptied = Arc::new(db.emptied());
+        return Some(std::mem::replace(&mut *db, emptied));
+    }
+
+    pub fn all(&self) -> Vec<Db> {
+        return self.namespaces.iter().map(|entry| Arc::clone(entry.value())).collect();
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        return self.namespaces.contains_key(name);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.namespaces.len();
+    }
+
+    // Forgets the namespaces other than the default one that have no keys or triggers and that no
+    // command is using, so names selected once do not pile up. Returns how many were dropped.
+    pub fn drop_empty(&self) -> usize {
+        let names: Vec<String> = self.namespaces
+            .iter()
+            .filter(|entry| entry.key() != DEFAULT_NAMESPACE)
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut dropped: usize = 0;
+        for name in names {
+            let removed = self.namespaces.remove_if(&name, |_, db| {
+                return Arc::strong_count(db) == 1 && db.shared_db.is_empty() && db.trigger_db.is_empty();
+            });
+            if removed.is_some() {
+                dropped += 1;
+            }
+        }
+        return dropped;
+    }
+}
+
+// Position of a key in a scan. Below u64::MAX, so the cursor after the last one is never 0.
+fn scan_hash(key: &str) -> u64 {
+    return element_hash(key.as_bytes()) >> 1;
+}
@@ -0,0 +1,44 @@
+use std::time::{Duration, Instant};
+
+// Error code of a request whose deadline passed before it was answered
+pub const DEADLINE_EXCEEDED: u16 = 16;
+
+// When the client stops waiting for the answer to a request. Long running work checks it
+// between steps and gives up once it has passed, rather than finishing a result nobody reads.
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    pub fn none() -> Deadline {
+        Deadline {
+            at: None,
+        }
+    }
+
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            at: Some(Instant::now() + timeout),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        return self.at.is_some();
+    }
+
+    pub fn expired(&self) -> bool {
+        return match self.at {
+            Some(at) => Instant::now() >= at,
+            None => false,
+        };
+    }
+
+    // Fails with DEADLINE_EXCEEDED once the deadline has passed
+    pub fn check(&self) -> Result<(), u16> {
+        if self.expired() {
+            return Err(DEADLINE_EXCEEDED);
+        }
+        return Ok(());
+    }
+}
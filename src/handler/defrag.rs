@@ -0,0 +1,61 @@
+use std::hash::Hash;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::handler::database::Database;
+
+// What a defragmentation pass gave back
+pub struct DefragReport {
+    pub compacted_values: u64,
+    pub compacted_bytes: u64,
+    pub released_slots: u64,
+}
+
+// Copies every stored value into an allocation of its exact size, shrinks the maps to their
+// length and has the allocator return the freed pages to the OS. Values left in oversized
+// buffers by appends, and maps sized for a keyspace that has since been deleted, otherwise
+// keep holding their memory.
+pub fn defragment(db: &Database) -> DefragReport {
+    let started_at = Instant::now();
+    let mut report = DefragReport { compacted_values: 0, compacted_bytes: 0, released_slots: 0 };
+
+    // One key at a time, so no shard stays locked for a whole pass
+    let keys: Vec<String> = db.shared_db.iter().map(|entry| entry.key().clone()).collect();
+    for key in keys {
+        if let Some(mut value) = db.shared_db.get_mut(&key) {
+            // Values that responses still hold would not be freed by the copy
+            if value.len() > 0 && value.is_unique() {
+                *value = Bytes::copy_from_slice(&value);
+                report.compacted_values += 1;
+                report.compacted_bytes += value.len() as u64;
+            }
+        }
+    }
+
+    report.released_slots += shrink_map(&db.shared_db);
+    report.released_slots += shrink_map(&db.timeout_db);
+    report.released_slots += shrink_map(&db.dependency_db);
+    report.released_slots += shrink_map(&db.index_db);
+    report.released_slots += shrink_map(&db.zone_db);
+    report.released_slots += shrink_map(&db.version_db);
+    report.released_slots += shrink_map(&db.retention_db);
+    report.released_slots += shrink_map(&db.view_db);
+    report.released_slots += shrink_map(&db.miss_db);
+    // SAFETY: mi_collect has no preconditions, it only returns pages that are no longer in use
+    unsafe {
+        libmimalloc_sys::mi_collect(true);
+    }
+
+    db.stats.defrag_passes.fetch_add(1, Ordering::Relaxed);
+    db.stats.last_defrag_micros.store(started_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+    return report;
+}
+
+// Shrinks the map to fit its entries, returns by how many slots its capacity went down
+fn shrink_map<K: Eq + Hash + Clone, V>(map: &DashMap<K, V>) -> u64 {
+    let capacity = map.capacity();
+    map.shrink_to_fit();
+    return capacity.saturating_sub(map.capacity()) as u64;
+}
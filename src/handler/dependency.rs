@@ -0,0 +1,24 @@
+use crate::handler::database::Database;
+
+// Removes every key derived from `key`, following the dependency graph transitively.
+// Edges are consumed as they are followed, so cycles terminate and a derived key has
+// to be declared again once it is rebuilt. Cached query results read from any of these
+// keys are dropped as well.
+pub fn invalidate_dependents(key: &str, db: &Database) {
+    db.result_cache.invalidate(key);
+    let mut pending: Vec<String> = match db.dependency_db.remove(key) {
+        Some((_, dependents)) => dependents.into_iter().collect(),
+        None => return,
+    };
+
+    while let Some(dependent) = pending.pop() {
+        tracing::debug!("Invalidating {} derived from {}", dependent, key);
+        if db.remove_key(&dependent) {
+            db.record_change("invalidated", Some(&dependent), None);
+        }
+        db.result_cache.invalidate(&dependent);
+        if let Some((_, dependents)) = db.dependency_db.remove(&dependent) {
+            pending.extend(dependents);
+        }
+    }
+}
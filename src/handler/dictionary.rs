@@ -0,0 +1,110 @@
+// Dictionary encoding of string columns. Utf8 columns that repeat few distinct values are
+// stored as Dictionary(Int32, Utf8), which keeps every distinct string once and lets
+// filters compare the distinct values instead of every row.
+use std::collections::HashSet;
+use std::sync::Arc;
+use arrow::array::{ArrayRef, AsArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+
+// Chunks with their Utf8 columns dictionary encoded when their distinct values are at most
+// `max_distinct_percent` of the rows. None when no column qualifies or encoding is off.
+pub fn dictionary_encode_chunks(
+    chunks: &Vec<RecordBatch>,
+    max_distinct_percent: u64,
+) -> Result<Option<Vec<RecordBatch>>, ArrowError> {
+    if max_distinct_percent == 0 || chunks.len() == 0 {
+        return Ok(None);
+    }
+    let schema = chunks[0].schema();
+    let row_count: usize = chunks.iter().map(|chunk| chunk.num_rows()).sum();
+    let encoded_columns: Vec<usize> = (0..schema.fields().len())
+        .filter(|index| *schema.field(*index).data_type() == DataType::Utf8)
+        .filter(|index| {
+            let mut distinct_values: HashSet<&str> = HashSet::new();
+            for chunk in chunks.iter() {
+                distinct_values.extend(chunk.column(*index).as_string::<i32>().iter().flatten());
+            }
+            (distinct_values.len() as u64) * 100 <= (row_count as u64) * max_distinct_percent
+        })
+        .collect();
+    if encoded_columns.len() == 0 {
+        return Ok(None);
+    }
+
+    // Every dictionary column needs its own id for the IPC stream to tell them apart
+    let fields: Vec<Field> = schema.fields()
+        .iter()
+        .enumerate()
+        .map(|(index, field)| {
+            if !encoded_columns.contains(&index) {
+                return field.as_ref().clone();
+            }
+            let data_type = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+            return Field::new_dict(field.name(), data_type, field.is_nullable(), index as i64, false)
+                .with_metadata(field.metadata().clone());
+        })
+        .collect();
+    let encoded_schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()));
+    let encoded_chunks = chunks
+        .iter()
+        .map(|chunk| cast_chunk(chunk, encoded_schema.clone()))
+        .collect::<Result<Vec<RecordBatch>, ArrowError>>()?;
+    return Ok(Some(encoded_chunks));
+}
+
+// The chunk with its columns cast to the types of `schema`
+pub fn cast_chunk(chunk: &RecordBatch, schema: Arc<Schema>) -> Result<RecordBatch, ArrowError> {
+    let columns = chunk.columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| cast(column.as_ref(), field.data_type()))
+        .collect::<Result<Vec<ArrayRef>, ArrowError>>()?;
+    return RecordBatch::try_new(schema, columns);
+}
+
+// Whether `schema` is `encoded_schema` before some of its Utf8 columns were dictionary encoded
+pub fn matches_encoded_schema(schema: &Schema, encoded_schema: &Schema) -> bool {
+    if schema.fields().len() != encoded_schema.fields().len() {
+        return false;
+    }
+    return schema.fields().iter().zip(encoded_schema.fields()).all(|(field, encoded_field)| {
+        let same_type = field.data_type() == encoded_field.data_type() || (
+            *field.data_type() == DataType::Utf8 && is_string_dictionary(encoded_field.data_type())
+        );
+        field.name() == encoded_field.name() && field.is_nullable() == encoded_field.is_nullable() && same_type
+    });
+}
+
+pub fn has_string_dictionaries(schema: &Schema) -> bool {
+    return schema.fields().iter().any(|field| is_string_dictionary(field.data_type()));
+}
+
+// The record batch with its dictionary encoded string columns back as plain Utf8
+pub fn dictionary_decode(record_batch: RecordBatch) -> Result<RecordBatch, ArrowError> {
+    let schema = record_batch.schema();
+    if !has_string_dictionaries(&schema) {
+        return Ok(record_batch);
+    }
+    let fields: Vec<Field> = schema.fields()
+        .iter()
+        .map(|field| {
+            if !is_string_dictionary(field.data_type()) {
+                return field.as_ref().clone();
+            }
+            return Field::new(field.name(), DataType::Utf8, field.is_nullable())
+                .with_metadata(field.metadata().clone());
+        })
+        .collect();
+    let decoded_schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()));
+    return cast_chunk(&record_batch, decoded_schema);
+}
+
+fn is_string_dictionary(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Dictionary(_, value_type) => **value_type == DataType::Utf8,
+        _ => false,
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::net::SocketAddr;
+use crc32c::crc32c;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Layout version of dumped keys, RS refuses envelopes of a version it does not know
+const DUMP_VERSION: u8 = 1;
+
+// Version and time to live before the value, checksum after it
+const ENVELOPE_OVERHEAD: usize = 1 + 8 + 4;
+
+// Envelope DU answers with and RS reads back: the layout version, the u64 milliseconds the key
+// has left to live, 0 when it never expires, the value with its type tag as in SD and a CRC32C
+// of everything before it. Values are always uncompressed, so any instance can restore them
+// whatever its compression settings.
+pub fn dump_envelope(value: &[u8], ttl_ms: u64) -> Vec<u8> {
+    let mut envelope: Vec<u8> = Vec::with_capacity(value.len() + ENVELOPE_OVERHEAD);
+    envelope.push(DUMP_VERSION);
+    envelope.extend(ttl_ms.to_be_bytes());
+    envelope.extend(value);
+    envelope.extend(crc32c(&envelope).to_be_bytes());
+    return envelope;
+}
+
+// The value and time to live of an envelope. Fails with error code 3 when it is too short, has
+// no value or a version this server does not know, and 15 when its checksum does not match.
+pub fn read_envelope(envelope: &[u8]) -> Result<(&[u8], u64), u16> {
+    if envelope.len() <= ENVELOPE_OVERHEAD || envelope[0] != DUMP_VERSION {
+        return Err(3);
+    }
+    let (content, checksum) = envelope.split_at(envelope.len() - 4);
+    if crc32c(content).to_be_bytes() != checksum {
+        return Err(15);
+    }
+    let ttl_ms = u64::from_be_bytes(content[1..9].try_into().unwrap());
+    return Ok((&content[9..], ttl_ms));
+}
+
+// Connects to another instance for MG. None when the address leads back to this server, which
+// listens on `bind_addresses`: the connection then goes from this host to one of its ports.
+pub async fn connect_instance(address: &str, bind_addresses: &[String]) -> io::Result<Option<TcpStream>> {
+    let stream = TcpStream::connect(address).await?;
+    let (peer_address, local_address) = (stream.peer_addr()?, stream.local_addr()?);
+    let same_host = peer_address.ip() == local_address.ip() || peer_address.ip().is_loopback();
+    let own_port = bind_addresses
+        .iter()
+        .filter_map(|bind_address| bind_address.parse::<SocketAddr>().ok())
+        .any(|bind_address| {
+            return bind_address.port() == peer_address.port()
+                && (bind_address.ip().is_unspecified() || bind_address.ip() == peer_address.ip());
+        });
+    if same_host && own_port {
+        return Ok(None);
+    }
+    return Ok(Some(stream));
+}
+
+// Sends the requests one after the other over a connection to another instance, for MG, and
+// returns its responses as (message type, payload)
+pub async fn request_instance(
+    stream: &mut TcpStream, requests: Vec<(&str, Vec<u8>)>
+) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut responses: Vec<(String, Vec<u8>)> = Vec::with_capacity(requests.len());
+    for (message_type, payload) in requests {
+        let mut frame: Vec<u8> = Vec::with_capacity(11 + payload.len());
+        frame.push(b'A');
+        frame.extend(message_type.as_bytes());
+        frame.extend((payload.len() as u64).to_be_bytes());
+        frame.extend(payload);
+        stream.write_all(&frame).await?;
+
+        let mut header_buffer = [0; 11];
+        stream.read_exact(&mut header_buffer).await?;
+        let payload_length = u64::from_be_bytes(header_buffer[3..11].try_into().unwrap());
+        let mut response_payload = vec![0; payload_length as usize];
+        stream.read_exact(&mut response_payload).await?;
+        responses.push((String::from_utf8_lossy(&header_buffer[1..3]).to_string(), response_payload));
+    }
+    return Ok(responses);
+}
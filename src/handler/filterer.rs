@@ -1,26 +1,34 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use arrow::array::{self, Array, BooleanArray};
-use arrow::compute::filter;
+use arrow::array::{self, Array, ArrayRef, AsArray, BooleanArray, UInt32Array};
+use arrow::compute::{cast, filter, sort_to_indices, take, SortOptions};
 use arrow::compute::kernels::cmp::{gt, eq, lt, gt_eq, lt_eq, neq};
 use arrow::datatypes::{Field, Schema, DataType, TimeUnit};
 use arrow::record_batch::RecordBatch;
 use rayon::iter::{
     IntoParallelRefIterator,
-    IndexedParallelIterator,
-    IntoParallelIterator,
     ParallelIterator,
 };
 
-use crate::handler::handler::{ColumnFilter};
+use crate::handler::deadline::Deadline;
+use crate::handler::handler::{ColumnFilter, Query, Sample};
+use crate::handler::indexer::ColumnIndex;
+use crate::handler::zonemap::{zone_map_outcome, ZoneMap};
 
 pub fn process_filter(
     record_batch: &RecordBatch,
-    cols: &Vec<String>,
-    filterlogic: &str,
-    columns_filters: &Vec<ColumnFilter>
-) -> RecordBatch {
+    query: &Query,
+    indexes: Option<&HashMap<String, ColumnIndex>>,
+    zone_map: Option<&ZoneMap>,
+    deadline: &Deadline,
+) -> Result<RecordBatch, u16> {
+    let cols = &query.columns;
+    let filterlogic = query.filterlogic.as_str();
+    let columns_filters = &query.filter;
+    let top_k = &query.top_k;
+    let sample = &query.sample;
     let filtering_mask: BooleanArray;
     let schema = record_batch.schema();
 
@@ -33,85 +41,45 @@ pub fn process_filter(
                     schema.field_with_name(&item.col).is_ok()
             })
             .map(|item| {
+                // Each filter is a full pass over its column, the request may have run out of time
+                deadline.check()?;
                 let data_array = record_batch.column_by_name(&item.col)
                     .expect("Can not access to a col of the record bacth.");
                 let data_len = data_array.len();
 
-                let bool_arr = match data_array.data_type() {
-                    DataType::Int64 => {
-                        let value = item.value_int.unwrap() as i64;
-                        let filter_arr = array::Int64Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Int32 => {
-                        let value = item.value_int.unwrap() as i32;
-                        let filter_arr = array::Int32Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Int16 => {
-                        let value = item.value_int.unwrap() as i16;
-                        let filter_arr = array::Int16Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Int8 => {
-                        let value = item.value_int.unwrap() as i8;
-                        let filter_arr = array::Int8Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::UInt64 => {
-                        let value = item.value_int.unwrap() as u64;
-                        let filter_arr = array::UInt64Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::UInt32 => {
-                        let value = item.value_int.unwrap() as u32;
-                        let filter_arr = array::UInt32Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::UInt16 => {
-                        let value = item.value_int.unwrap() as u16;
-                        let filter_arr = array::UInt16Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::UInt8 => {
-                        let value = item.value_int.unwrap() as u8;
-                        let filter_arr = array::UInt8Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Float64 => {
-                        let value = item.value_flt.unwrap();
-                        let filter_arr = array::Float64Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Float32 => {
-                        let value = item.value_flt.unwrap() as f32;
-                        let filter_arr = array::Float32Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Boolean => {
-                        let value = item.value_bol.unwrap();
-                        let filter_arr = array::BooleanArray::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    }
-                    DataType::Utf8 => {
-                        let value = item.value_str.clone().unwrap();
-                        let filter_arr = array::StringArray::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
-                    },
-                    DataType::Date32 => {
-                        let value = item.value_int.unwrap() as i32;
-                        let filter_arr = array::Date32Array::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
+                // The column bounds alone may already settle the filter for every row
+                let zone_outcome = zone_map.and_then(|zone_map| zone_map.get(&item.col)).and_then(|stats| {
+                    let filter_arr = filter_value_array(item, data_array.data_type(), 1);
+                    zone_map_outcome(stats, filter_arr.as_ref(), &item.filter_type)
+                });
+                if let Some(matched) = zone_outcome {
+                    return Ok(BooleanArray::from(vec![matched; data_len]));
+                }
+
+                let bool_arr = match indexes.and_then(|indexes| indexes.get(&item.col)) {
+                    Some(index) => {
+                        let filter_arr = filter_value_array(item, data_array.data_type(), 1);
+                        index.mask(filter_arr.as_ref(), &item.filter_type)
                     },
-                    DataType::Timestamp(TimeUnit::Nanosecond, ..) => {
-                        let value = item.value_int.unwrap() as i64;
-                        let filter_arr = array::TimestampNanosecondArray::from(vec![value; data_len]);
-                        filter_array(&data_array, &filter_arr, &item.filter_type)
+                    None => match data_array.as_any_dictionary_opt() {
+                        // Only the distinct values are compared, rows take the outcome of their key
+                        Some(dictionary) => {
+                            let values = dictionary.values();
+                            let filter_arr = filter_value_array(item, values.data_type(), values.len());
+                            let values_mask = filter_array(values, filter_arr.as_ref(), &item.filter_type);
+                            let rows_mask = take(&values_mask, dictionary.keys(), None).unwrap();
+                            rows_mask.as_boolean().clone()
+                        },
+                        None => {
+                            let filter_arr = filter_value_array(item, data_array.data_type(), data_len);
+                            filter_array(&data_array, filter_arr.as_ref(), &item.filter_type)
+                        },
                     },
-                    _ => { panic!("Not implemented for data type") }
                 };
-                return bool_arr;
+                return Ok(bool_arr);
             })
+            .collect::<Result<Vec<BooleanArray>, u16>>()?
+            .into_iter()
             .reduce(|m1, m2| {
                 if filterlogic == "AND" {
                     return (m1.values() & m2.values()).into();
@@ -123,34 +91,68 @@ pub fn process_filter(
         filtering_mask = BooleanArray::from(vec![true; record_batch.num_rows()]);
     }
 
+    // Sampled positions among the filtered rows, kept in their original order
+    let sample_indices: Option<UInt32Array> = match sample {
+        Some(sample) => Some(sample_rows(filtering_mask.true_count(), sample)),
+        None => None,
+    };
+
+    // Positions of the k best (sampled) rows among the filtered rows, found with a partial sort
+    let row_indices: Option<UInt32Array> = match top_k {
+        Some(top_k) => match record_batch.column_by_name(&top_k.column) {
+            Some(column) => {
+                let mut sort_column = filter(column.as_ref(), &filtering_mask).unwrap();
+                if let Some(indices) = &sample_indices {
+                    sort_column = take(sort_column.as_ref(), indices, None).unwrap();
+                }
+                let sort_options = SortOptions {
+                    descending: top_k.direction.as_deref() != Some("asc"),
+                    nulls_first: false,
+                };
+                let ranked = sort_to_indices(&sort_column, Some(sort_options), Some(top_k.k)).unwrap();
+                match &sample_indices {
+                    Some(indices) => Some(ranked.values().iter().map(|i| indices.value(*i as usize)).collect()),
+                    None => Some(ranked),
+                }
+            },
+            None => sample_indices,
+        },
+        None => sample_indices,
+    };
+    let select_rows = |column: &ArrayRef| -> ArrayRef {
+        let filtered_column = filter(column.as_ref(), &filtering_mask).unwrap();
+        match &row_indices {
+            Some(indices) => take(filtered_column.as_ref(), indices, None).unwrap(),
+            None => filtered_column,
+        }
+    };
+
+    deadline.check()?;
     let new_record_batch: RecordBatch;
     let new_schema: Schema;
     if cols.len() > 0 {
-        let cols_set: HashSet<String> = cols.clone().into_iter().collect();
-        let filtered_columns = record_batch
-            .columns()
-            .par_iter()
-            .zip(schema.fields().par_iter())
-            .into_par_iter()
-            .filter(|(_, field)| {
-                cols_set.contains(field.name())
-            })
-            .map(|(column, _)| {
-                filter(column.as_ref(), &filtering_mask).unwrap()
+        // Columns are returned in the requested order, renamed to their alias if one was given
+        let selected_columns: Vec<(usize, Field)> = cols
+            .iter()
+            .filter_map(|col| {
+                let index = schema.index_of(col.name()).ok()?;
+                let field = schema.field(index).clone().with_name(col.output_name());
+                Some((index, field))
             })
-            .collect::<Vec<_>>();
+            .collect();
 
-        let fields: Vec<Field> = schema
-            .fields()
+        let filtered_columns = selected_columns
             .par_iter()
-            .filter(|field| {
-                cols_set.contains(field.name())
-            })
-            .map(|field| {
-                schema.field_with_name(field.name()).expect("Can not access to a col of the schema").clone()
+            .map(|(index, _)| {
+                select_rows(record_batch.column(*index))
             })
             .collect::<Vec<_>>();
 
+        let fields: Vec<Field> = selected_columns
+            .into_iter()
+            .map(|(_, field)| field)
+            .collect();
+
         new_schema = Schema::new_with_metadata(fields, schema.metadata().clone());
         new_record_batch = RecordBatch::try_new(Arc::new(new_schema), filtered_columns).unwrap();
     } else {
@@ -158,13 +160,80 @@ pub fn process_filter(
             .columns()
             .par_iter()
             .map(|column| {
-                filter(column.as_ref(), &filtering_mask).unwrap()
+                select_rows(column)
             })
             .collect::<Vec<_>>();
 
         new_record_batch = RecordBatch::try_new(schema, filtered_columns).unwrap();
     }
-    return new_record_batch;
+    return Ok(new_record_batch);
+}
+
+// Repeats the filter value `len` times as an array of the column's type
+fn filter_value_array(item: &ColumnFilter, data_type: &DataType, len: usize) -> ArrayRef {
+    match data_type {
+        DataType::Int64 => {
+            let value = item.value_int.unwrap() as i64;
+            Arc::new(array::Int64Array::from(vec![value; len]))
+        },
+        DataType::Int32 => {
+            let value = item.value_int.unwrap() as i32;
+            Arc::new(array::Int32Array::from(vec![value; len]))
+        },
+        DataType::Int16 => {
+            let value = item.value_int.unwrap() as i16;
+            Arc::new(array::Int16Array::from(vec![value; len]))
+        },
+        DataType::Int8 => {
+            let value = item.value_int.unwrap() as i8;
+            Arc::new(array::Int8Array::from(vec![value; len]))
+        },
+        DataType::UInt64 => {
+            let value = item.value_int.unwrap() as u64;
+            Arc::new(array::UInt64Array::from(vec![value; len]))
+        },
+        DataType::UInt32 => {
+            let value = item.value_int.unwrap() as u32;
+            Arc::new(array::UInt32Array::from(vec![value; len]))
+        },
+        DataType::UInt16 => {
+            let value = item.value_int.unwrap() as u16;
+            Arc::new(array::UInt16Array::from(vec![value; len]))
+        },
+        DataType::UInt8 => {
+            let value = item.value_int.unwrap() as u8;
+            Arc::new(array::UInt8Array::from(vec![value; len]))
+        },
+        DataType::Float64 => {
+            let value = item.value_flt.unwrap();
+            Arc::new(array::Float64Array::from(vec![value; len]))
+        },
+        DataType::Float32 => {
+            let value = item.value_flt.unwrap() as f32;
+            Arc::new(array::Float32Array::from(vec![value; len]))
+        },
+        DataType::Boolean => {
+            let value = item.value_bol.unwrap();
+            Arc::new(array::BooleanArray::from(vec![value; len]))
+        }
+        DataType::Utf8 => {
+            let value = item.value_str.clone().unwrap();
+            Arc::new(array::StringArray::from(vec![value; len]))
+        },
+        DataType::Date32 => {
+            let value = item.value_int.unwrap() as i32;
+            Arc::new(array::Date32Array::from(vec![value; len]))
+        },
+        DataType::Timestamp(TimeUnit::Nanosecond, ..) => {
+            let value = item.value_int.unwrap() as i64;
+            Arc::new(array::TimestampNanosecondArray::from(vec![value; len]))
+        },
+        DataType::Dictionary(_, value_type) => {
+            let values = filter_value_array(item, value_type, len);
+            cast(values.as_ref(), data_type).unwrap()
+        },
+        _ => { panic!("Not implemented for data type") }
+    }
 }
 
 fn filter_array(data_array: &Arc<dyn Array>, filter_value: &dyn Array, filter_type: &String) -> BooleanArray {
@@ -184,3 +253,46 @@ fn filter_array(data_array: &Arc<dyn Array>, filter_value: &dyn Array, filter_ty
     }
     return mask;
 }
+
+
+fn sample_rows(row_count: usize, sample: &Sample) -> UInt32Array {
+    let sample_size = match (sample.n, sample.fraction) {
+        (Some(n), _) => n.min(row_count),
+        (None, Some(fraction)) => ((row_count as f64) * fraction.clamp(0.0, 1.0)).round() as usize,
+        (None, None) => row_count,
+    };
+    let seed = match sample.seed {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+    };
+    let mut rng = SplitMix64::new(seed);
+
+    // Partial Fisher-Yates shuffle, the first sample_size positions are a uniform sample
+    let mut positions: Vec<u32> = (0..row_count as u32).collect();
+    for i in 0..sample_size {
+        let j = i + (rng.next() % (row_count - i) as u64) as usize;
+        positions.swap(i, j);
+    }
+    positions.truncate(sample_size);
+    positions.sort_unstable();
+    return UInt32Array::from(positions);
+}
+
+// Small seeded random number generator, for sampling
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        return SplitMix64 { state: seed };
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+}
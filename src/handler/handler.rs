@@ -1,34 +1,161 @@
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use std::time::{SystemTime, Duration};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, Duration, Instant, UNIX_EPOCH};
 
-use tokio::net::TcpStream;
 use tokio::select;
+use tokio::sync::{broadcast, Semaphore};
 use tokio_util::sync::CancellationToken;
+use bytes::{Bytes, BytesMut};
+use arrow::compute::concat_batches;
 use arrow::record_batch::RecordBatch;
 use arrow::ipc::reader::StreamReader;
 use arrow::ipc::writer::{StreamWriter, IpcWriteOptions};
-use arrow::ipc::CompressionType;
+use arrow::ipc::{root_as_message, CompressionType};
 use arrow::ipc::gen::Schema::MetadataVersion;
-use dashmap::DashMap;
-use serde::Deserialize;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
 
-use crate::handler::connection::Connection;
+use crate::handler::connection::{Connection, FrameWriter};
+use crate::handler::database::{Database, Db, Namespaces, DEFAULT_NAMESPACE};
 use crate::handler::filterer::process_filter;
+use crate::handler::codec::lookup_codec;
+use crate::handler::csv::{parse_csv_options, read_csv, write_csv};
+use crate::handler::bitmap::{count_bits, get_bit, set_bit, MAX_BIT_OFFSET};
+use crate::handler::bloom::{
+    add_to_filter, check_filter, might_contain, new_filter, BLOOM_TAG, DEFAULT_CAPACITY, DEFAULT_FALSE_POSITIVE_RATE
+};
+use crate::handler::compression::{
+    compress_value, decompress_stored, decompress_value, COMPRESSION_LZ4, COMPRESSION_NONE, COMPRESSION_ZSTD
+};
+use crate::handler::deadline::Deadline;
+use crate::handler::defrag::defragment;
+use crate::handler::dependency::invalidate_dependents;
+use crate::handler::dump::{connect_instance, dump_envelope, read_envelope, request_instance};
+use crate::handler::dictionary::{
+    cast_chunk, dictionary_decode, dictionary_encode_chunks, has_string_dictionaries, matches_encoded_schema
+};
+use crate::handler::hyperloglog::{add_element, estimate_count, hyperloglog_registers, HYPERLOGLOG_TAG, REGISTERS};
+use crate::handler::indexer::KeyIndex;
+use crate::handler::joiner::hash_join;
+use crate::handler::json::{delete_path, get_path, parse_path, set_path, write_json_lines, JSON_TAG};
+use crate::handler::hash::{delete_fields, get_field, hash_fields, set_fields, HASH_TAG};
+use crate::handler::loader::Loader;
+use crate::handler::list::{list_range, pop_elements, push_elements, range_bounds, LIST_TAG};
+use crate::handler::monitor::CommandEvent;
+use crate::handler::pattern::{glob_match, is_glob};
+use crate::handler::payload::{
+    read_f64, read_framed, read_i32, read_i64, read_prefixed_key, read_rest, read_str, read_u32, read_u64
+};
+use crate::handler::resample::{resample, ResampleQuery};
+use crate::handler::retention::{exceeds_retention, retention, trim_chunks, Retention};
+use crate::handler::set::{
+    add_members, contains_member, intersect_members, remove_members, union_members, SET_TAG
+};
+use crate::handler::settings::SETTING_NAMES;
+use crate::handler::sorted_set::{
+    add_entries, member_position, range_by_rank, range_by_score, remove_entries, scored_members, SORTED_SET_TAG
+};
+use crate::handler::stats::LOOKUP_COMMANDS;
+use crate::handler::string::{validate_string, STRING_TAG};
+use crate::handler::topk::{increment, new_sketch, top_elements, DEFAULT_DEPTH, DEFAULT_TOP, DEFAULT_WIDTH, TOP_K_TAG};
+use crate::handler::triggers::Trigger;
+use crate::handler::views::View;
+use crate::handler::timeseries::{chunk_times, latest_time, sort_by_time, time_column, window_rows, TimeWindow};
+use crate::handler::zonemap::{compute_zone_map, ZoneMap};
 
-type TimeoutDB = Arc<DashMap<String, SystemTime>>;
-type SharedDB = Arc<DashMap<String, Vec<u8>>>;
+// Chunks of an Arrow value with their zone maps, and with the value's version
+type ZonedChunks = (Vec<RecordBatch>, Option<Arc<Vec<ZoneMap>>>);
+type VersionedChunks = (Vec<RecordBatch>, Option<Arc<Vec<ZoneMap>>>, Option<u64>);
 
-#[derive(Deserialize)]
-struct Query {
-    key: String,
-    columns: Vec<String>,
-    filterlogic: String,
-    filter: Vec<ColumnFilter>,
+#[derive(Deserialize, Serialize)]
+pub struct Query {
+    key: QueryKey,
+    pub columns: Vec<ColumnSelect>,
+    pub filterlogic: String,
+    pub filter: Vec<ColumnFilter>,
     cachetime: u64,
     compression_type: String,
+    pub top_k: Option<TopK>,
+    pub sample: Option<Sample>,
+    stream_chunk_size: Option<usize>,
+    if_version_not: Option<u64>,
+    // Dictionary encoded string columns are returned as plain Utf8
+    plain_strings: Option<bool>,
+    // "arrow", the default, "csv" or "jsonl", for consumers without an Arrow library
+    output_format: Option<String>,
+    // Time range or as-of row of a time-series key, read before the filters apply
+    time_window: Option<TimeWindow>,
+    // A result cached with cachetime and read since is computed again this long before it
+    // expires, in the background, as long as its keys exist
+    refresh_ahead_ms: Option<u64>,
+}
+
+// Encoding of GA results
+#[derive(Clone, Copy)]
+enum ResultFormat {
+    Arrow,
+    Csv,
+    JsonLines,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum QueryKey {
+    Single(String),
+    Many(Vec<String>),
 }
 
 #[derive(Deserialize)]
+struct JoinQuery {
+    left: String,
+    right: String,
+    left_on: Vec<String>,
+    right_on: Vec<String>,
+    how: String,
+    store_key: Option<String>,
+    cachetime: u64,
+    compression_type: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ColumnSelect {
+    Name(String),
+    Alias { name: String, alias: Option<String> },
+}
+
+impl ColumnSelect {
+    pub fn name(&self) -> &str {
+        match self {
+            ColumnSelect::Name(name) => name,
+            ColumnSelect::Alias { name, .. } => name,
+        }
+    }
+
+    pub fn output_name(&self) -> &str {
+        match self {
+            ColumnSelect::Name(name) => name,
+            ColumnSelect::Alias { name, alias } => alias.as_deref().unwrap_or(name),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TopK {
+    pub column: String,
+    pub k: usize,
+    pub direction: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Sample {
+    pub fraction: Option<f64>,
+    pub n: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct ColumnFilter {
     pub col: String,
     pub filter_type: String,
@@ -39,320 +166,4085 @@ pub struct ColumnFilter {
     pub value_str: Option<String>,
 }
 
-pub async fn handle_stream(socket: TcpStream, token: CancellationToken, timeout_db: TimeoutDB, shared_db: SharedDB) {
+// What a command answers: a response frame, or the code of the error frame sent instead
+type Response = Result<(String, Bytes), u16>;
+
+// The error frame answering a request that failed with error_code
+fn error_response(error_code: u16) -> (String, Bytes) {
+    return ("ER".to_string(), Bytes::copy_from_slice(&error_code.to_be_bytes()));
+}
+
+// Commands that change values, they are held back while an EX runs
+const WRITE_COMMANDS: [&str; 42] = [
+    "SD", "SG", "SX", "AP", "II", "IF", "DL", "DM", "RN", "RX", "TA", "JN", "LK", "UL", "FL",
+    "LP", "RP", "LO", "RO", "HS", "HD", "SA", "SR", "ZA", "ZR", "BS", "PA", "IB", "FB", "BA", "BW",
+    "JS", "JD", "RS", "IC", "TS", "VW", "MR", "MA", "KR", "KA", "MG"
+];
+
+// Write commands that change two keys at once, they take the write gate for themselves like an EX
+const EXCLUSIVE_COMMANDS: [&str; 2] = ["RN", "RX"];
+
+// Commands that store new values, they are slowed down or refused when memory runs short
+const VALUE_WRITE_COMMANDS: [&str; 23] = [
+    "SD", "SG", "SX", "AP", "JN", "LP", "RP", "HS", "SA", "ZA", "BS", "PA", "BA", "BW", "JS", "RS", "IC", "TS", "VW",
+    "MR", "MA", "KR", "KA"
+];
+
+// Commands that only change the expiry of keys, they are in the change log whenever they succeed
+const TTL_COMMANDS: [&str; 4] = ["TA", "TH", "PS", "HM"];
+
+// Commands that use the state of their connection, they always run on its read loop
+const SERIAL_COMMANDS: [&str; 14] = [
+    "WA", "UW", "EX", "MN", "SB", "CD", "HE", "SE", "FA", "WP", "PL", "BT", "CE", "CC"
+];
+
+// Longest byte value BW may write up to
+const MAX_BYTE_RANGE_END: u64 = 512 * 1024 * 1024;
+
+// Longest namespace name SE accepts
+const MAX_NAMESPACE_NAME_LEN: usize = 64;
+
+// Longest MG waits for the instance it moves a key to, unless it asks for another time, and
+// the longest it may ask for
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_MIGRATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Tagged requests of one connection that may run at once before reading more waits
+const MAX_IN_FLIGHT_REQUESTS: usize = 32;
+
+// Times GS reads its keys without holding writers back before it takes the write gate
+const SNAPSHOT_READ_ATTEMPTS: usize = 3;
+
+// Commands an EX may carry
+const EXEC_COMMANDS: [&str; 39] = [
+    "SD", "SG", "SX", "AP", "II", "IF", "DL", "DM", "RN", "RX", "TA", "TH", "PS", "HM", "DP",
+    "LP", "RP", "LO", "RO", "HS", "HD", "SA", "SR", "ZA", "ZR", "BS", "PA", "IB", "FB", "BA", "BW",
+    "JS", "JD", "RS", "TS", "MR", "MA", "KR", "KA"
+];
+
+pub async fn handle_stream(mut connection: Connection, token: CancellationToken, namespaces: Arc<Namespaces>) {
     tracing::debug!("Client accepted");
-    let mut connection = Connection::new(socket);
+    let writer = connection.writer();
+    // Cancelled on shutdown or by CK for this client only
+    let kill_token = token.child_token();
+    // Connections are counted in the default namespace and start there until SE moves them.
+    // The namespace is looked up again for every command, since FA may have replaced it.
+    let default_db = namespaces.default_db();
+    let mut namespace = DEFAULT_NAMESPACE.to_string();
+    let client_id = default_db.clients.register(connection.peer_address(), kill_token.clone());
+    default_db.stats.total_connections.fetch_add(1, Ordering::Relaxed);
+    // Versions of the keys this connection watches, None for keys that did not exist
+    let mut watched_versions: HashMap<String, Option<u64>> = HashMap::new();
+    // Response compression and checksums asked for with HE, applied once its answer is written
+    let mut requested_options: Option<(u8, i32, bool)> = None;
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_REQUESTS));
+    let mut killed = false;
 
     loop {
-        let (message_type, payload) = select! {
-            res = connection.read_frame() => res,
-            _ = token.cancelled() => {
-                ("CC".to_string(), vec![0; 0])
-            }
+        // Frames are only raced against cancellation before they start arriving, a frame being
+        // read or a request being answered is always seen through
+        select! {
+            biased;
+            _ = kill_token.cancelled() => {
+                killed = true;
+                break;
+            },
+            _ = writer.wait_failed() => break,
+            _ = connection.wait_for_frame() => {},
+        }
+        let (message_type, request_id, deadline, payload) = connection
+            .read_frame(default_db.settings.max_payload_size.load(Ordering::Relaxed))
+            .await;
+        let db = namespaces.select(&namespace);
+        db.stats.record_command(&message_type);
+        let command = CommandContext {
+            client_id: client_id,
+            request_id: request_id,
+            request_bytes: payload.len(),
+            bytes_read: connection.bytes_read,
+            monitored_key: match db.monitor.is_active() {
+                true => command_key(&message_type, &payload),
+                false => None,
+            },
+            started_at: Instant::now(),
         };
-        let cloned_timeout_db = Arc::clone(&timeout_db);
-        let cloned_db = Arc::clone(&shared_db);
-
-        let (response_type, response_payload) = match message_type.as_str() {
-            "SD" => handle_set_data(cloned_timeout_db, payload, cloned_db).await,
-            "II" => handle_increment_integer(payload, cloned_db).await,
-            "IF" => handle_increment_float(payload, cloned_db).await,
-            "GA" => handle_get_arrow_data(cloned_timeout_db, payload, cloned_db).await,
-            "GD" => handle_get_data(payload, cloned_db).await,
-            "DL" => handle_delete(cloned_timeout_db, payload, cloned_db).await,
-            "TH" => handle_touch(cloned_timeout_db, payload, cloned_db).await,
-            "TL" => handle_ttl(cloned_timeout_db, payload, cloned_db).await,
-            "LS" => handle_list_keys(cloned_db).await,
-            "DM" => handle_delete_many(cloned_timeout_db, payload, cloned_db).await,
-            "WP" => handle_wrong_protocol().await,
-            "CC" => handle_connection_close().await,
-            _ => handle_unknown_type().await,
+
+        // Tagged requests are answered by their own task so a slow one does not hold up the rest
+        if request_id.is_some() && !SERIAL_COMMANDS.contains(&message_type.as_str()) {
+            let permit = Arc::clone(&in_flight).acquire_owned().await.unwrap();
+            let cloned_db = Arc::clone(&db);
+            let cloned_writer = writer.clone();
+            tokio::spawn(async move {
+                let response = dispatch_command(
+                    &cloned_db, &message_type, payload, &cloned_writer, request_id, deadline
+                ).await.unwrap_or_else(error_response);
+                // A failed write closes the connection through the writer
+                let _ = finish_command(&cloned_db, &cloned_writer, &message_type, command, response).await;
+                drop(permit);
+            });
+            continue;
+        }
+
+        let cloned_db = Arc::clone(&db);
+        let response = match message_type.as_str() {
+            "WA" => handle_watch(cloned_db, payload, &mut watched_versions).await,
+            "UW" => handle_unwatch(&mut watched_versions).await,
+            "EX" => handle_exec(cloned_db, payload, &mut watched_versions).await,
+            "MN" => handle_monitor(cloned_db, &mut connection, request_id, &kill_token).await,
+            "SB" => handle_subscribe(cloned_db, payload, &mut connection, request_id, &kill_token).await,
+            "CD" => handle_change_data(cloned_db, payload, &mut connection, request_id, &kill_token).await,
+            "HE" => handle_hello(payload, &mut requested_options).await,
+            "SE" => handle_select(&namespaces, payload, &mut namespace, &watched_versions).await,
+            "FA" => handle_flush_async(&namespaces, &namespace, payload).await,
+            _ => dispatch_command(&db, &message_type, payload, &writer, request_id, deadline).await,
         };
-        if response_type == "CC" || message_type == "WP" {
-            connection.write_frame(response_type, response_payload).await;
+        let response = response.unwrap_or_else(error_response);
+        // MN, SB and CD end on cancellation, their clients get the same notice as everyone else
+        if response.0 == "CC" && kill_token.is_cancelled() {
+            killed = true;
+            break;
+        }
+        let closing = response.0 == "CC" || message_type == "WP" || message_type == "PL";
+        let written = finish_command(&db, &writer, &message_type, command, response).await;
+        if let Some((algorithm, level, checksums)) = requested_options.take() {
+            writer.set_compression(algorithm, level);
+            writer.set_checksums(checksums);
+        }
+        if closing || written.is_err() {
             break;
         }
-
-        connection.write_frame(response_type, response_payload).await;
     }
+    // Let pipelined requests still running write their responses
+    let _ = in_flight.acquire_many(MAX_IN_FLIGHT_REQUESTS as u32).await;
+    if killed {
+        // Last frame the client gets: "SC" when the server is shutting down, "CC" after a CK
+        let notice = match token.is_cancelled() {
+            true => "SC",
+            false => "CC",
+        };
+        let _ = writer.write_frame(notice.to_string(), None, Bytes::new()).await;
+    }
+    default_db.clients.unregister(client_id);
     tracing::debug!("End connection");
 }
 
-async fn handle_set_data(timeout_db: TimeoutDB, payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let cache_time_bytes: [u8; 8] = payload[0..8].try_into().expect("Incorrect length");
-    let cache_time_ms = u64::from_be_bytes(cache_time_bytes);
-    let key_index_until = (u16::from_be_bytes([payload[8], payload[9]]) + 10) as usize;
-    let key = match std::str::from_utf8(&payload[10..key_index_until]) {
-        Ok(valid_str) => { valid_str.to_string() },
-        Err(_) => { panic!("Invalid") },
+// What is known about a request before it runs, for the monitor feed and client stats
+struct CommandContext {
+    client_id: u64,
+    request_id: Option<u64>,
+    request_bytes: usize,
+    bytes_read: u64,
+    monitored_key: Option<String>,
+    started_at: Instant,
+}
+
+// Runs every command that needs no state of the connection it came from. A request whose
+// deadline passed while it waited is not run at all.
+async fn dispatch_command(
+    db: &Db, message_type: &str, payload: Vec<u8>, writer: &FrameWriter, request_id: Option<u64>, deadline: Deadline
+) -> Response {
+    deadline.check()?;
+    let cloned_db = Arc::clone(db);
+    if VALUE_WRITE_COMMANDS.contains(&message_type) {
+        wait_for_memory(db, payload.len()).await?;
+    }
+    let exclusive_permit = match EXCLUSIVE_COMMANDS.contains(&message_type) {
+        true => Some(db.write_gate.write().await),
+        false => None,
+    };
+    let _write_permit = match WRITE_COMMANDS.contains(&message_type) && exclusive_permit.is_none() {
+        true => Some(db.write_gate.read().await),
+        false => None,
     };
+    let versions_before = key_versions_before(db, message_type, &payload);
 
-    shared_db.insert(key.clone(), payload[key_index_until..].to_vec());
-    if cache_time_ms > 0 {
-        let now = SystemTime::now();
-        let duration = Duration::from_millis(cache_time_ms);
-        timeout_db.insert(key, now + duration);
-    } else {
-        let _ = timeout_db.remove(&key);
+    let response = match message_type {
+        "SD" => handle_set_data(cloned_db, payload).await,
+        "SG" => handle_set_data_guarded(cloned_db, payload).await,
+        "SX" => handle_set_data_existing(cloned_db, payload).await,
+        "AP" => handle_append_data(cloned_db, payload).await,
+        "IC" => handle_ingest_csv(cloned_db, payload).await,
+        "TS" => handle_time_series_append(cloned_db, payload).await,
+        "II" => handle_increment_integer(cloned_db, payload).await,
+        "IF" => handle_increment_float(cloned_db, payload).await,
+        "IB" => handle_increment_integer_bounded(cloned_db, payload).await,
+        "FB" => handle_increment_float_bounded(cloned_db, payload).await,
+        "LP" => handle_list_push(cloned_db, payload, true).await,
+        "RP" => handle_list_push(cloned_db, payload, false).await,
+        "LO" => handle_list_pop(cloned_db, payload, true).await,
+        "RO" => handle_list_pop(cloned_db, payload, false).await,
+        "LR" => handle_list_range(cloned_db, payload).await,
+        "HS" => handle_hash_set(cloned_db, payload).await,
+        "HG" => handle_hash_get(cloned_db, payload).await,
+        "HD" => handle_hash_delete(cloned_db, payload).await,
+        "HA" => handle_hash_get_all(cloned_db, payload).await,
+        "SA" => handle_set_add(cloned_db, payload).await,
+        "SR" => handle_set_remove(cloned_db, payload).await,
+        "SH" => handle_set_has(cloned_db, payload).await,
+        "SM" => handle_set_members(cloned_db, payload).await,
+        "SU" => handle_set_combine(cloned_db, payload, false).await,
+        "SI" => handle_set_combine(cloned_db, payload, true).await,
+        "ZA" => handle_sorted_set_add(cloned_db, payload).await,
+        "ZR" => handle_sorted_set_remove(cloned_db, payload).await,
+        "ZC" => handle_sorted_set_score(cloned_db, payload).await,
+        "ZK" => handle_sorted_set_rank(cloned_db, payload).await,
+        "ZB" => handle_sorted_set_range_by_score(cloned_db, payload).await,
+        "ZI" => handle_sorted_set_range_by_rank(cloned_db, payload).await,
+        "BS" => handle_set_bit(cloned_db, payload).await,
+        "BG" => handle_get_bit(cloned_db, payload).await,
+        "BN" => handle_count_bits(cloned_db, payload).await,
+        "BA" => handle_append_bytes(cloned_db, payload).await,
+        "BR" => handle_get_byte_range(cloned_db, payload).await,
+        "BW" => handle_set_byte_range(cloned_db, payload).await,
+        "PA" => handle_hyperloglog_add(cloned_db, payload).await,
+        "PC" => handle_hyperloglog_count(cloned_db, payload).await,
+        "MR" => handle_bloom_reserve(cloned_db, payload).await,
+        "MA" => handle_bloom_add(cloned_db, payload).await,
+        "MC" => handle_bloom_check(cloned_db, payload).await,
+        "KR" => handle_top_k_reserve(cloned_db, payload).await,
+        "KA" => handle_top_k_add(cloned_db, payload).await,
+        "KT" => handle_top_k_list(cloned_db, payload).await,
+        "JG" => handle_json_get(cloned_db, payload).await,
+        "JS" => handle_json_set(cloned_db, payload).await,
+        "JD" => handle_json_delete(cloned_db, payload).await,
+        "GA" => handle_get_arrow_data(cloned_db, payload, writer, request_id, deadline).await,
+        "GD" => handle_get_data(cloned_db, payload).await,
+        "GV" => handle_get_data_versioned(cloned_db, payload).await,
+        "GS" => handle_get_snapshot(cloned_db, payload).await,
+        "DL" => handle_delete(cloned_db, payload).await,
+        "TH" => handle_touch(cloned_db, payload).await,
+        "TA" => handle_touch_at(cloned_db, payload).await,
+        "TL" => handle_ttl(cloned_db, payload).await,
+        "PS" => handle_persist(cloned_db, payload).await,
+        "TM" => handle_ttl_many(cloned_db, payload).await,
+        "HM" => handle_touch_many(cloned_db, payload).await,
+        "LS" => handle_list_keys(cloned_db).await,
+        "SN" => handle_scan_keys(cloned_db, payload).await,
+        "DM" => handle_delete_many(cloned_db, payload).await,
+        "FL" => handle_flush(cloned_db).await,
+        "RN" => handle_rename(cloned_db, payload, true).await,
+        "RX" => handle_rename(cloned_db, payload, false).await,
+        "DU" => handle_dump(cloned_db, payload).await,
+        "RS" => handle_restore(cloned_db, payload).await,
+        "MG" => handle_migrate(cloned_db, payload).await,
+        "DP" => handle_declare_dependency(cloned_db, payload).await,
+        "LK" => handle_lock(cloned_db, payload).await,
+        "UL" => handle_unlock(cloned_db, payload).await,
+        "JN" => handle_join(cloned_db, payload).await,
+        "RE" => handle_resample(cloned_db, payload).await,
+        "VW" => handle_create_view(cloned_db, payload).await,
+        "TR" => handle_create_trigger(cloned_db, payload).await,
+        "TU" => handle_drop_trigger(cloned_db, payload).await,
+        "IX" => handle_create_index(cloned_db, payload).await,
+        "DX" => handle_drop_index(cloned_db, payload).await,
+        "NF" => handle_info(cloned_db).await,
+        "DF" => handle_defragment(cloned_db).await,
+        "CG" => handle_config_get(cloned_db, payload).await,
+        "CS" => handle_config_set(cloned_db, payload).await,
+        "RC" => handle_config_reload(cloned_db).await,
+        "CL" => handle_client_list(cloned_db).await,
+        "CK" => handle_client_kill(cloned_db, payload).await,
+        "HK" => handle_hot_keys(cloned_db, payload).await,
+        "PI" => handle_ping(payload).await,
+        "WP" => handle_wrong_protocol().await,
+        "PL" => handle_payload_too_large().await,
+        "BT" => handle_bad_message_type().await,
+        "CE" => handle_checksum_mismatch().await,
+        "CC" => handle_connection_close().await,
+        _ => handle_unknown_type().await,
+    };
+    record_changes(db, message_type, versions_before, &response);
+    return response;
+}
+
+// Holds a value write back past the soft memory watermark. Fails with error code 14 past the
+// hard one.
+async fn wait_for_memory(db: &Db, payload_size: usize) -> Result<(), u16> {
+    let delay = db.memory.admit_write(&db.settings, payload_size)?;
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    return Ok(());
+}
+
+// Whether a lookup found the key missing within negative_cache_ttl_ms, so lookups of it answer
+// with error code 18 without trying to load it until then
+fn known_missing(db: &Db, key: &str) -> bool {
+    return db.miss_db.get(key).map_or(false, |until| *until > SystemTime::now());
+}
+
+// Remembers that a lookup found the key missing, when negative caching is on
+fn remember_miss(db: &Db, key: &str) {
+    let ttl_ms = db.settings.negative_cache_ttl_ms.load(Ordering::Relaxed);
+    if ttl_ms > 0 {
+        db.miss_db.insert(key.to_string(), SystemTime::now() + Duration::from_millis(ttl_ms));
     }
-    return ("OK".to_string(), vec![0; 0]);
 }
 
-async fn handle_increment_integer(payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let key = match std::str::from_utf8(&payload[8..]) {
-        Ok(valid_str) => { valid_str.to_string() },
-        Err(_) => { panic!("Invalid") },
+// Stores a key that missed from the read-through loader, when one is configured and has the
+// key. Misses of the same key at the same time share one load, and a value written by a client
+// in the meantime is kept.
+async fn load_missing_key(db: &Db, key: &str) {
+    let loader = match &db.loader {
+        Some(loader) => Arc::clone(loader),
+        None => return,
     };
+    if key.len() > u16::MAX as usize {
+        return;
+    }
+    let load_db = Arc::clone(db);
+    let load_key = key.to_string();
+    let _ = db.load_flights.run(key, || load_key_value(load_db, load_key, loader)).await;
+}
 
-    match shared_db.entry(key.clone()) {
-        dashmap::Entry::Occupied(mut entry) => {
-            let int_bytes = entry.get_mut();
-            if int_bytes[0] as char != 'I' || int_bytes.len() != 9 {
-                let error_code: u16 = 5;
-                return ("ER".to_string(), error_code.to_be_bytes().to_vec());
-            }
-            let mut int_data = i64::from_be_bytes(int_bytes[1..].try_into().unwrap());
-            let increment_amount = i64::from_be_bytes(payload[0..8].try_into().unwrap());
-            int_data += increment_amount;
+async fn load_key_value(db: Db, key: String, loader: Arc<dyn Loader>) -> Response {
+    let load_key = key.clone();
+    let value = match run_blocking(move || loader.load(&load_key)).await? {
+        Ok(Some(value)) => value,
+        Ok(None) => return Err(2),
+        Err(reason) => {
+            tracing::warn!("Loading {} failed: {}", key, reason);
+            db.stats.failed_loads.fetch_add(1, Ordering::Relaxed);
+            return Err(12);
+        },
+    };
+    wait_for_memory(&db, value.len()).await?;
 
-            int_bytes[1..].clone_from_slice(&int_data.to_be_bytes());
-            return ("IN".to_string(), int_data.to_be_bytes().to_vec());
-        }
-        dashmap::Entry::Vacant(entry) => {
-            let mut int_bytes_vec = payload[0..8].to_vec();
-            int_bytes_vec.insert(0, 'I' as u8);
+    // Stored like an SD without a cache time that only creates the key
+    let mut payload: Vec<u8> = Vec::with_capacity(10 + key.len() + value.len());
+    payload.extend(0u64.to_be_bytes());
+    payload.extend((key.len() as u16).to_be_bytes());
+    payload.extend(key.as_bytes());
+    payload.extend(value);
+    let _write_permit = db.write_gate.read().await;
+    let response = run_set_data(Arc::clone(&db), payload, 0, SetGuard::ValueHash(0)).await;
+    if matches!(&response, Ok((response_type, _)) if response_type == "OK") {
+        db.stats.loaded_keys.fetch_add(1, Ordering::Relaxed);
+        let value_hash = db.shared_db.get(&key).map(|stored_value| stored_value_hash(&stored_value));
+        db.record_change("loaded", Some(&key), value_hash);
+    }
+    return response;
+}
 
-            entry.insert(int_bytes_vec.clone());
-            return ("IN".to_string(), int_bytes_vec[1..].to_vec());
-        }
+// Runs CPU-heavy work such as Arrow decoding and filtering on the blocking pool, so the
+// threads driving connections stay responsive. A panic in `work` is answered with ER 12.
+async fn run_blocking<T, F>(work: F) -> Result<T, u16>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => return Ok(result),
+        Err(e) => {
+            tracing::error!("Blocking task failed: {}", e);
+            return Err(12);
+        },
     }
 }
 
-async fn handle_increment_float(payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let key = match std::str::from_utf8(&payload[8..]) {
-        Ok(valid_str) => { valid_str.to_string() },
-        Err(_) => { panic!("Invalid") },
+// Reports the command to monitors and writes its response. Fails when the response could not
+// be written.
+async fn finish_command(
+    db: &Db, writer: &FrameWriter, message_type: &str, command: CommandContext, response: (String, Bytes)
+) -> std::io::Result<()> {
+    let (response_type, response_payload) = response;
+    if message_type != "MN" && message_type != "SB" && message_type != "CD" && message_type != "CC" {
+        db.monitor.publish(CommandEvent {
+            client_id: command.client_id,
+            message_type: message_type,
+            key: command.monitored_key,
+            request_bytes: command.request_bytes,
+            response_type: &response_type,
+            response_bytes: response_payload.len(),
+            latency: command.started_at.elapsed(),
+        });
+    }
+    db.stats.record_response(message_type, &response_type, &response_payload);
+    let written = writer.write_frame(response_type, command.request_id, response_payload).await;
+    db.clients.record_command(command.client_id, message_type, command.bytes_read, writer.bytes_written());
+    return written;
+}
+
+// The key a command works on, for the monitor feed. None for commands without a single key.
+fn command_key(message_type: &str, payload: &[u8]) -> Option<String> {
+    let key = match message_type {
+        "GD" | "GV" | "DL" | "TL" | "PS" | "HA" | "SM" | "DU" => read_str(payload).ok()?,
+        "TH" | "TA" | "II" | "IF" | "BG" => read_rest(payload, 8).and_then(read_str).ok()?,
+        "BS" => read_rest(payload, 9).and_then(read_str).ok()?,
+        "SD" | "SX" | "LK" | "BW" | "IC" => read_prefixed_key(payload, 8).ok()?.0,
+        "SG" => read_prefixed_key(payload, 16).ok()?.0,
+        "RS" => read_prefixed_key(payload, 1).ok()?.0,
+        "MG" => read_prefixed_key(payload, 9).ok()?.0,
+        "AP" | "TS" | "UL" | "LP" | "RP" | "HS" | "HG" | "HD" | "SA" | "SR" | "SH" | "ZA" | "ZR" | "ZC" | "ZK" | "PA" | "BA"
+        | "JG" | "JS" | "JD" | "MR" | "MA" | "MC" | "KR" | "KA" | "KT" => {
+            read_prefixed_key(payload, 0).ok()?.0
+        },
+        "LO" | "RO" => read_rest(payload, 4).and_then(read_str).ok()?,
+        "IB" | "FB" => read_rest(payload, 33).and_then(read_str).ok()?,
+        "LR" | "ZB" | "ZI" | "BN" | "BR" => read_rest(payload, 16).and_then(read_str).ok()?,
+        "GA" | "RE" => {
+            let query: serde_json::Value = serde_json::from_slice(payload).ok()?;
+            return query.get("key")?.as_str().map(|key| key.to_string());
+        },
+        "VW" => {
+            let query: serde_json::Value = serde_json::from_slice(payload).ok()?;
+            return query.get("view")?.as_str().map(|key| key.to_string());
+        },
+        _ => return None,
     };
+    return Some(key.to_string());
+}
 
-    match shared_db.entry(key.clone()) {
-        dashmap::Entry::Occupied(mut entry) => {
-            let float_bytes = entry.get_mut();
-            if float_bytes[0] as char != 'F' || float_bytes.len() != 9 {
-                let error_code: u16 = 5;
-                return ("ER".to_string(), error_code.to_be_bytes().to_vec());
-            }
-            let mut float_data = f64::from_be_bytes(float_bytes[1..].try_into().unwrap());
-            let increment_amount = f64::from_be_bytes(payload[0..8].try_into().unwrap());
-            float_data += increment_amount;
+// Keys a command may change, with their versions before it runs, for the change log, the webhook,
+// the triggers and the views. None when none of them is on, or the command changes no keys.
+fn key_versions_before(db: &Database, message_type: &str, payload: &[u8]) -> Option<Vec<(String, Option<u64>)>> {
+    if !records_changes(db) && db.trigger_db.is_empty() && db.view_db.is_empty() {
+        return None;
+    }
+    if !WRITE_COMMANDS.contains(&message_type) && !TTL_COMMANDS.contains(&message_type) {
+        return None;
+    }
+    let keys: Vec<String> = match message_type {
+        "DM" | "RN" | "RX" => read_str(payload).unwrap_or("").split(0 as char).map(|key| key.to_string()).collect(),
+        "HM" => read_rest(payload, 8)
+            .and_then(read_str)
+            .unwrap_or("")
+            .split(0 as char)
+            .map(|key| key.to_string())
+            .collect(),
+        "JN" => serde_json::from_slice::<serde_json::Value>(payload)
+            .ok()
+            .and_then(|query| query.get("store_key")?.as_str().map(|key| key.to_string()))
+            .into_iter()
+            .collect(),
+        _ => command_key(message_type, payload).into_iter().collect(),
+    };
+    let versions = keys
+        .into_iter()
+        .map(|key| {
+            let version = db.version_db.get(&key).map(|version| *version);
+            return (key, version);
+        })
+        .collect();
+    return Some(versions);
+}
 
-            float_bytes[1..].clone_from_slice(&float_data.to_be_bytes());
-            return ("FL".to_string(), float_data.to_be_bytes().to_vec());
+// Adds the keys whose version the command changed to the change log and the webhook, fires the
+// triggers watching them and computes the views of them again. Commands that only change
+// expiries record the keys they were given that exist and fire no triggers, unless they removed
+// the key with an expiry already past, which counts as deleting it. FL records one change of the
+// whole namespace.
+fn record_changes(
+    db: &Db, message_type: &str, versions_before: Option<Vec<(String, Option<u64>)>>, response: &Response
+) {
+    let versions_before = match versions_before {
+        Some(versions_before) => versions_before,
+        None => return,
+    };
+    if response.is_err() {
+        return;
+    }
+    if message_type == "FL" {
+        db.record_change(message_type, None, None);
+        return;
+    }
+    for (key, version_before) in versions_before {
+        let version = db.version_db.get(&key).map(|version| *version);
+        let removed = version_before.is_some() && version.is_none();
+        let changes_value = !TTL_COMMANDS.contains(&message_type) || removed;
+        let changed = match changes_value {
+            true => version != version_before,
+            false => version.is_some(),
+        };
+        if !changed {
+            continue;
         }
-        dashmap::Entry::Vacant(entry) => {
-            let mut float_bytes_vec = payload[0..8].to_vec();
-            float_bytes_vec.insert(0, 'F' as u8);
-
-            entry.insert(float_bytes_vec.clone());
-            return ("FL".to_string(), float_bytes_vec[1..].to_vec());
+        let stored_value = db.shared_db.get(&key).map(|stored_value| stored_value.clone());
+        if records_changes(db) {
+            let value_hash = stored_value.as_ref().map(|stored_value| stored_value_hash(stored_value));
+            db.record_change(message_type, Some(&key), value_hash);
+        }
+        if changes_value && !db.trigger_db.is_empty() {
+            fire_triggers(db, &key, if stored_value.is_some() { "set" } else { "deleted" });
+        }
+        if changes_value && !db.view_db.is_empty() {
+            refresh_source_views(db, &key);
         }
     }
 }
 
-async fn handle_get_arrow_data(timeout_db: TimeoutDB, payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let payload_str = std::str::from_utf8(&payload).expect("Payload error");
-    let payload_query_string = payload_str.to_string();
-
-    if let Some(byte_data) = shared_db.get(&payload_query_string) {
-        return ("AR".to_string(), byte_data.to_vec());
+// Computes the views of a source key again, once the command that changed it lets go of the
+// write gate, so they do not wait for the next cleanup sweep
+fn refresh_source_views(db: &Db, source_key: &str) {
+    let view_keys: Vec<String> = db
+        .view_db
+        .iter()
+        .filter(|view| view.source == source_key)
+        .map(|view| view.key().clone())
+        .collect();
+    for view_key in view_keys {
+        let refresh_db = Arc::clone(db);
+        tokio::spawn(async move {
+            refresh_view(&refresh_db, &view_key).await;
+        });
     }
+}
 
-    let query: Query = match serde_json::from_str(payload_str) {
-        Ok(q) => q,
-        Err(_e) => {
-            let error_code: u16 = 3;
-            return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+// Whether changes to keys go anywhere, the change log or the webhook
+fn records_changes(db: &Database) -> bool {
+    return db.settings.change_log_size.load(Ordering::Relaxed) > 0 || db.webhook.is_some();
+}
+
+// Runs the actions of the triggers watching the key for the event. Keys the actions delete fire
+// no triggers themselves, so triggers can not set one another off in a loop.
+fn fire_triggers(db: &Db, key: &str, event: &'static str) {
+    let triggers: Vec<Trigger> = db
+        .trigger_db
+        .iter()
+        .filter(|trigger| trigger.fires_on(key, event))
+        .map(|trigger| trigger.value().clone())
+        .collect();
+    for trigger in triggers {
+        tracing::debug!("Trigger {} fired on {} of {}", trigger.name, event, key);
+        for derived_key in trigger.invalidate.iter().flatten() {
+            if db.remove_key(derived_key) {
+                db.record_change("invalidated", Some(derived_key), None);
+            }
+            invalidate_dependents(derived_key, db);
         }
-    };
+        if let Some(view_key) = trigger.refresh_view {
+            // Runs once the command that fired the trigger lets go of the write gate
+            let refresh_db = Arc::clone(db);
+            tokio::spawn(async move {
+                refresh_view(&refresh_db, &view_key).await;
+            });
+        }
+        if trigger.notify == Some(true) {
+            db.notifier.publish(&db.namespace, key, event);
+        }
+    }
+}
 
-    let record_batch: Option<RecordBatch>;
-    if let Some(record_batch_bytes) = shared_db.get(&query.key) {
-        if record_batch_bytes[0] as char != 'A' {
-            let error_code: u16 = 4;
-            return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+// Turns the connection into a feed of every command the server processes, one JSON object
+// per "MN" frame, until the client disconnects or is closed
+async fn handle_monitor(
+    db: Db, connection: &mut Connection, request_id: Option<u64>, kill_token: &CancellationToken
+) -> Response {
+    let mut receiver = db.monitor.subscribe();
+    if connection.write_frame("OK".to_string(), request_id, Bytes::new()).await.is_err() {
+        return Ok(("CC".to_string(), Bytes::new()));
+    }
+    loop {
+        select! {
+            event = receiver.recv() => match event {
+                Ok(line) => {
+                    if connection.write_frame("MN".to_string(), request_id, Bytes::from(line)).await.is_err() {
+                        break;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Monitor fell behind and missed {} events", missed);
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = connection.wait_closed() => break,
+            _ = kill_token.cancelled() => break,
         }
+    }
+    return Ok(("CC".to_string(), Bytes::new()));
+}
 
-        let mut reader = StreamReader::try_new(&record_batch_bytes[1..], None).expect("Read error");
-        match reader.next() {
-            Some(Ok(rb)) => {
-                record_batch = Some(rb);
+// Turns the connection into a feed of the keys of its namespace that expire or are evicted,
+// one "KE" frame of JSON per key. Payload is the key patterns to report separated by \0,
+// nothing reports every key.
+async fn handle_subscribe(
+    db: Db, payload: Vec<u8>, connection: &mut Connection, request_id: Option<u64>, kill_token: &CancellationToken
+) -> Response {
+    let patterns: Vec<String> = match read_str(&payload)? {
+        "" => Vec::new(),
+        valid_str => valid_str.split(0 as char).map(|pattern| pattern.to_string()).collect(),
+    };
+    let mut receiver = db.notifier.subscribe();
+    if connection.write_frame("OK".to_string(), request_id, Bytes::new()).await.is_err() {
+        return Ok(("CC".to_string(), Bytes::new()));
+    }
+    loop {
+        select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    let matches = event.namespace == db.namespace && (
+                        patterns.len() == 0 || patterns.iter().any(|pattern| glob_match(pattern, &event.key))
+                    );
+                    if !matches {
+                        continue;
+                    }
+                    let line = serde_json::json!({
+                        "at_ms": event.at_ms,
+                        "event": event.reason,
+                        "key": event.key,
+                    });
+                    let written = connection
+                        .write_frame("KE".to_string(), request_id, Bytes::from(line.to_string()))
+                        .await;
+                    if written.is_err() {
+                        break;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Key event subscriber fell behind and missed {} events", missed);
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
             },
-            Some(Err(_)) => {
-                let error_code: u16 = 4;
-                return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+            _ = connection.wait_closed() => break,
+            _ = kill_token.cancelled() => break,
+        }
+    }
+    return Ok(("CC".to_string(), Bytes::new()));
+}
+
+// Turns the connection into a feed of the change log, one "CH" frame of JSON per change in the
+// order they were made. Payload is the u64 sequence number to start from, the changes still
+// retained from there are sent first. When some of them were already dropped a "GP" frame
+// with the first sequence number still retained comes before them. 0 starts from the oldest
+// retained change.
+async fn handle_change_data(
+    db: Db, payload: Vec<u8>, connection: &mut Connection, request_id: Option<u64>, kill_token: &CancellationToken
+) -> Response {
+    let from_sequence = read_u64(&payload, 0)?;
+    if db.settings.change_log_size.load(Ordering::Relaxed) == 0 {
+        return Err(3);
+    }
+    // Subscribed before reading the log, so no change falls between the two
+    let mut receiver = db.changes.subscribe();
+    if connection.write_frame("OK".to_string(), request_id, Bytes::new()).await.is_err() {
+        return Ok(("CC".to_string(), Bytes::new()));
+    }
+    let mut next_sequence = match from_sequence {
+        0 => db.changes.since(u64::MAX).1,
+        _ => from_sequence,
+    };
+    next_sequence = match write_retained_changes(&db, connection, request_id, next_sequence).await {
+        Ok(next_sequence) => next_sequence,
+        Err(_) => return Ok(("CC".to_string(), Bytes::new())),
+    };
+    loop {
+        select! {
+            change = receiver.recv() => match change {
+                Ok(change) => {
+                    if change.sequence < next_sequence {
+                        continue;
+                    }
+                    let written = connection
+                        .write_frame("CH".to_string(), request_id, Bytes::from(change.to_json()))
+                        .await;
+                    if written.is_err() {
+                        break;
+                    }
+                    next_sequence = change.sequence + 1;
+                },
+                // The missed changes are read from the log instead, as long as it still has them
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    next_sequence = match write_retained_changes(&db, connection, request_id, next_sequence).await {
+                        Ok(next_sequence) => next_sequence,
+                        Err(_) => break,
+                    };
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
             },
-            None => {
-                let error_code: u16 = 4;
-                return ("ER".to_string(), error_code.to_be_bytes().to_vec());
-            }
+            _ = connection.wait_closed() => break,
+            _ = kill_token.cancelled() => break,
         }
-    } else {
-        let error_code: u16 = 2;
-        return ("ER".to_string(), error_code.to_be_bytes().to_vec());
     }
+    return Ok(("CC".to_string(), Bytes::new()));
+}
+
+// Writes the retained changes from `next_sequence` on, after a "GP" frame when the log no longer
+// has all of them. Returns the sequence number of the change to send next.
+async fn write_retained_changes(
+    db: &Database, connection: &mut Connection, request_id: Option<u64>, next_sequence: u64
+) -> std::io::Result<u64> {
+    let (changes, first_retained) = db.changes.since(next_sequence);
+    let mut next_sequence = next_sequence;
+    if first_retained > next_sequence {
+        let gap_payload = Bytes::copy_from_slice(&first_retained.to_be_bytes());
+        connection.write_frame("GP".to_string(), request_id, gap_payload).await?;
+        next_sequence = first_retained;
+    }
+    for change in changes {
+        connection.write_frame("CH".to_string(), request_id, Bytes::from(change.to_json())).await?;
+        next_sequence = change.sequence + 1;
+    }
+    return Ok(next_sequence);
+}
 
-    let filtered_record_batch = process_filter(&record_batch.unwrap(), &query.columns, &query.filterlogic, &query.filter);
+// The u32 count most read keys with their hits and last access, as a JSON array
+async fn handle_hot_keys(db: Db, payload: Vec<u8>) -> Response {
+    let count = read_u32(&payload, 0)? as usize;
+    let hot_keys: Vec<serde_json::Value> = db.stats.hot_keys(count)
+        .into_iter()
+        .map(|(key, hits, last_access_ms)| serde_json::json!({
+            "key": key,
+            "hits": hits,
+            "last_access_ms": last_access_ms,
+        }))
+        .collect();
+    return Ok(("HK".to_string(), Bytes::from(serde_json::Value::Array(hot_keys).to_string())));
+}
 
-    let alignment = 64;
-    let write_legacy_ipc_format = false;
-    let mut write_options: IpcWriteOptions = IpcWriteOptions::try_new(
-        alignment, write_legacy_ipc_format, MetadataVersion::V5
-    ).unwrap();
-    if query.compression_type == "lz4" {
-        write_options = write_options.try_with_compression(Some(CompressionType::LZ4_FRAME)).unwrap();
-    } else if query.compression_type == "zstd" {
-        write_options = write_options.try_with_compression(Some(CompressionType::ZSTD)).unwrap();
+// Sets up the connection. Payload is the id of the algorithm responses should be compressed
+// with (u8: 0 none, 1 lz4, 2 zstd) and the zstd level (i32). Every response after the answer
+// to this one then carries the algorithm id in its first payload byte. An optional last byte
+// of 1 turns on checksums: every frame after the answer, in both directions, carries the
+// CRC32C of its payload (u32) after its request id.
+async fn handle_hello(payload: Vec<u8>, requested_options: &mut Option<(u8, i32, bool)>) -> Response {
+    let algorithm = match payload.first() {
+        Some(algorithm) => *algorithm,
+        None => return Err(10),
+    };
+    let level = read_i32(&payload, 1)?;
+    if ![COMPRESSION_NONE, COMPRESSION_LZ4, COMPRESSION_ZSTD].contains(&algorithm) {
+        return Err(7);
+    }
+    if algorithm == COMPRESSION_ZSTD && !zstd::compression_level_range().contains(&level) {
+        return Err(3);
+    }
+    let checksums = match payload.get(5) {
+        None | Some(0) => false,
+        Some(1) => true,
+        Some(_) => return Err(3),
+    };
+    *requested_options = Some((algorithm, level, checksums));
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+// Moves the connection to the namespace named in the payload, which is created empty when
+// its first command runs. Watched keys belong to the namespace they were watched in, so SE
+// is refused while any are watched. A namespace that does not exist yet is refused with error
+// code 17 once there are max_namespaces of them.
+async fn handle_select(
+    namespaces: &Namespaces, payload: Vec<u8>, namespace: &mut String,
+    watched_versions: &HashMap<String, Option<u64>>
+) -> Response {
+    let name = read_str(&payload)?;
+    if name.len() == 0 || name.len() > MAX_NAMESPACE_NAME_LEN || watched_versions.len() > 0 {
+        return Err(3);
     }
+    let max_namespaces = namespaces.default_db().settings.max_namespaces.load(Ordering::Relaxed);
+    if !namespaces.contains(name) && max_namespaces > 0 && namespaces.len() as u64 >= max_namespaces {
+        return Err(17);
+    }
+    *namespace = name.to_string();
+    return Ok(("OK".to_string(), Bytes::new()));
+}
 
-    let mut writer = StreamWriter::try_new_with_options(
-        Vec::new(), &filtered_record_batch.schema(), write_options
-    ).expect("Schema error");
+// Connected clients as a JSON array
+async fn handle_client_list(db: Db) -> Response {
+    return Ok(("CL".to_string(), Bytes::from(db.clients.list().to_string())));
+}
 
-    let _ = writer.write(&filtered_record_batch);
-    let _ = writer.finish();
-    let buffer: Vec<u8> = writer.into_inner().expect("Buffer error");
+// Closes the client with the given u64 id once its current command finishes
+async fn handle_client_kill(db: Db, payload: Vec<u8>) -> Response {
+    let client_id = read_u64(&payload, 0)?;
+    if !db.clients.kill(client_id) {
+        return Err(2);
+    }
+    tracing::info!("Closing client {}", client_id);
+    return Ok(("OK".to_string(), Bytes::new()));
+}
 
-    if query.cachetime > 0 {
-        shared_db.insert(payload_query_string.clone(), buffer.clone());
-        let now = SystemTime::now();
-        let duration = Duration::from_millis(query.cachetime);
-        timeout_db.insert(payload_query_string, now + duration);
+// Server statistics as a JSON object
+async fn handle_info(db: Db) -> Response {
+    let mut key_count: u64 = 0;
+    let mut key_bytes: u64 = 0;
+    let mut value_bytes: u64 = 0;
+    for entry in db.shared_db.iter() {
+        key_count += 1;
+        key_bytes += entry.key().len() as u64;
+        value_bytes += entry.value().len() as u64;
     }
-    return ("AR".to_string(), buffer);
+
+    // Hits and misses only mean something for the commands that read keys
+    let command_outcomes: BTreeMap<String, serde_json::Value> = db.stats.command_outcomes()
+        .into_iter()
+        .map(|(message_type, (errors, hits, misses))| {
+            let outcomes = match LOOKUP_COMMANDS.contains(&message_type.as_str()) {
+                true => serde_json::json!({ "errors": errors, "hits": hits, "misses": misses }),
+                false => serde_json::json!({ "errors": errors }),
+            };
+            (message_type, outcomes)
+        })
+        .collect();
+
+    let info = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "namespace": db.namespace,
+        "uptime_seconds": db.stats.uptime().as_secs(),
+        "keys": {
+            "count": key_count,
+            "cached_results": db.result_cache.len(),
+            "with_ttl": db.timeout_db.len(),
+            "indexed": db.index_db.len(),
+            "with_dependents": db.dependency_db.len(),
+        },
+        "memory": {
+            "key_bytes": key_bytes,
+            "value_bytes": value_bytes,
+            "cached_result_bytes": db.result_cache.used_bytes(),
+            "batch_cache_bytes": db.batch_cache.used_bytes(),
+            "batch_cache_keys": db.batch_cache.len(),
+            // What the memory watermarks are compared with, for every namespace together
+            "watermark_bytes": db.memory.used_bytes(),
+        },
+        "connections": {
+            "current": db.stats.connected_clients.load(Ordering::Relaxed),
+            "max": db.settings.max_connections.load(Ordering::Relaxed),
+            "total": db.stats.total_connections.load(Ordering::Relaxed),
+        },
+        "commands": db.stats.command_counts(),
+        "command_outcomes": command_outcomes,
+        "error_codes": db.stats.error_codes(),
+        "cache_manager": {
+            "sweeps": db.stats.expiry_sweeps.load(Ordering::Relaxed),
+            "expired_keys": db.stats.expired_keys.load(Ordering::Relaxed),
+            "expired_results": db.stats.expired_results.load(Ordering::Relaxed),
+            "last_sweep_micros": db.stats.last_sweep_micros.load(Ordering::Relaxed),
+            "freed_value_bytes": db.stats.freed_value_bytes.load(Ordering::Relaxed),
+            "defrag_passes": db.stats.defrag_passes.load(Ordering::Relaxed),
+            "last_defrag_micros": db.stats.last_defrag_micros.load(Ordering::Relaxed),
+            "evicted_keys": db.stats.evicted_keys.load(Ordering::Relaxed),
+        },
+        "queries": {
+            "coalesced": db.stats.coalesced_queries.load(Ordering::Relaxed),
+        },
+        "write_behind": match &db.write_behind {
+            Some(write_behind) => serde_json::json!({
+                "pending": write_behind.pending_len(),
+                "persisted_writes": write_behind.persisted_writes.load(Ordering::Relaxed),
+                "failed_writes": write_behind.failed_writes.load(Ordering::Relaxed),
+            }),
+            None => serde_json::Value::Null,
+        },
+        "webhook": match &db.webhook {
+            Some(webhook) => serde_json::json!({
+                "pending": webhook.pending_len(),
+                "posted_events": webhook.posted_events.load(Ordering::Relaxed),
+                "failed_posts": webhook.failed_posts.load(Ordering::Relaxed),
+                "dropped_events": webhook.dropped_events.load(Ordering::Relaxed),
+            }),
+            None => serde_json::Value::Null,
+        },
+        "read_through": {
+            "loaded_keys": db.stats.loaded_keys.load(Ordering::Relaxed),
+            "failed_loads": db.stats.failed_loads.load(Ordering::Relaxed),
+        },
+    });
+    return Ok(("NF".to_string(), Bytes::from(info.to_string())));
+}
+
+// Compacts stored values and shrinks the maps right away. Answers a JSON object of what the
+// pass gave back.
+async fn handle_defragment(db: Db) -> Response {
+    let report = run_blocking(move || defragment(&db)).await?;
+    let info = serde_json::json!({
+        "compacted_values": report.compacted_values,
+        "compacted_bytes": report.compacted_bytes,
+        "released_slots": report.released_slots,
+    });
+    return Ok(("DF".to_string(), Bytes::from(info.to_string())));
 }
 
-async fn handle_get_data(payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let get_key = std::str::from_utf8(&payload).expect("Payload error");
+// Payload is a setting name, or nothing for all of them. Answers a JSON object of the values.
+async fn handle_config_get(db: Db, payload: Vec<u8>) -> Response {
+    let name = read_str(&payload)?;
 
-    if let Some(bytes_data) = shared_db.get(get_key) {
-        let data_type = bytes_data[0] as char;
-        if data_type == 'A' {
-            return ("AR".to_string(), bytes_data[1..].to_vec());
-        } else if data_type == 'B' {
-            return ("BY".to_string(), bytes_data[1..].to_vec());
-        } else if data_type == 'I' {
-            return ("IN".to_string(), bytes_data[1..].to_vec());
-        } else if data_type == 'F' {
-            return ("FL".to_string(), bytes_data[1..].to_vec());
-        } else {
-            let error_code: u16 = 5;
-            return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+    let mut values = serde_json::Map::new();
+    for setting_name in SETTING_NAMES {
+        if name.is_empty() || name == setting_name {
+            values.insert(setting_name.to_string(), db.settings.get(setting_name).unwrap().into());
         }
-    } else {
-        let error_code: u16 = 2;
-        return ("ER".to_string(), error_code.to_be_bytes().to_vec());
     }
+    if values.len() == 0 {
+        return Err(3);
+    }
+    return Ok(("CG".to_string(), Bytes::from(serde_json::Value::Object(values).to_string())));
 }
 
-async fn handle_delete(timeout_db: TimeoutDB, payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let del_key = std::str::from_utf8(&payload).expect("Payload error");
+// Payload is the setting name and its new value separated by a null byte
+async fn handle_config_set(db: Db, payload: Vec<u8>) -> Response {
+    let setting_str = read_str(&payload)?;
 
-    let _ = timeout_db.remove(del_key);
-    if let Some(_) = shared_db.remove(del_key) {
-        return ("OK".to_string(), vec![0; 0]);
-    } else {
-        let error_code: u16 = 2;
-        return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+    match setting_str.split_once(0 as char) {
+        Some((name, value)) if db.settings.set(name, value) => {
+            return Ok(("OK".to_string(), Bytes::new()));
+        },
+        _ => return Err(3),
+    }
+}
+
+// Reads the configuration file and environment again like SIGHUP does. Answers a JSON array
+// of the settings that changed.
+async fn handle_config_reload(db: Db) -> Response {
+    match run_blocking(move || db.settings.reload()).await? {
+        Ok(changed) => return Ok(("RC".to_string(), Bytes::from(serde_json::json!(changed).to_string()))),
+        Err(_) => return Err(3),
+    }
+}
+
+async fn handle_watch(
+    db: Db, payload: Vec<u8>, watched_versions: &mut HashMap<String, Option<u64>>
+) -> Response {
+    let watch_keys_str = read_str(&payload)?;
+
+    for key in watch_keys_str.split(0 as char) {
+        let version = db.version_db.get(key).map(|version| *version);
+        watched_versions.insert(key.to_string(), version);
+    }
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+async fn handle_unwatch(watched_versions: &mut HashMap<String, Option<u64>>) -> Response {
+    watched_versions.clear();
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+// Payload is a sequence of commands framed as [type][u64 length][payload]. They run only if
+// no watched key changed since WA, answering "XA" otherwise, and their responses come back
+// framed the same way in an "EX". Either way the connection stops watching.
+async fn handle_exec(
+    db: Db, payload: Vec<u8>, watched_versions: &mut HashMap<String, Option<u64>>
+) -> Response {
+    let watched = std::mem::take(watched_versions);
+
+    let mut commands: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+        let command_end = match payload.get(offset + 2..offset + 10) {
+            Some(length_bytes) => offset + 10 + u64::from_be_bytes(length_bytes.try_into().unwrap()) as usize,
+            None => payload.len() + 1,
+        };
+        let command_type = match command_end <= payload.len() {
+            true => std::str::from_utf8(&payload[offset..offset + 2]).unwrap_or(""),
+            false => "",
+        };
+        if !EXEC_COMMANDS.contains(&command_type) {
+            return Err(3);
+        }
+        commands.push((command_type.to_string(), payload[offset + 10..command_end].to_vec()));
+        offset = command_end;
+    }
+    // Waits before taking the gate, so other clients are not held up by it
+    if commands.iter().any(|(command_type, _)| VALUE_WRITE_COMMANDS.contains(&command_type.as_str())) {
+        wait_for_memory(&db, payload.len()).await?;
+    }
+
+    let _exclusive_permit = db.write_gate.write().await;
+    for (key, version) in watched.iter() {
+        if db.version_db.get(key).map(|version| *version) != *version {
+            return Ok(("XA".to_string(), Bytes::new()));
+        }
+    }
+
+    let mut responses_payload_bytes: Vec<u8> = Vec::new();
+    for (command_type, command_payload) in commands {
+        let cloned_db = Arc::clone(&db);
+        let versions_before = key_versions_before(&db, &command_type, &command_payload);
+        let response = match command_type.as_str() {
+            "SD" => handle_set_data(cloned_db, command_payload).await,
+            "SG" => handle_set_data_guarded(cloned_db, command_payload).await,
+            "SX" => handle_set_data_existing(cloned_db, command_payload).await,
+            "AP" => handle_append_data(cloned_db, command_payload).await,
+            "TS" => handle_time_series_append(cloned_db, command_payload).await,
+            "II" => handle_increment_integer(cloned_db, command_payload).await,
+            "IF" => handle_increment_float(cloned_db, command_payload).await,
+            "IB" => handle_increment_integer_bounded(cloned_db, command_payload).await,
+            "FB" => handle_increment_float_bounded(cloned_db, command_payload).await,
+            "LP" => handle_list_push(cloned_db, command_payload, true).await,
+            "RP" => handle_list_push(cloned_db, command_payload, false).await,
+            "LO" => handle_list_pop(cloned_db, command_payload, true).await,
+            "RO" => handle_list_pop(cloned_db, command_payload, false).await,
+            "HS" => handle_hash_set(cloned_db, command_payload).await,
+            "HD" => handle_hash_delete(cloned_db, command_payload).await,
+            "SA" => handle_set_add(cloned_db, command_payload).await,
+            "SR" => handle_set_remove(cloned_db, command_payload).await,
+            "ZA" => handle_sorted_set_add(cloned_db, command_payload).await,
+            "ZR" => handle_sorted_set_remove(cloned_db, command_payload).await,
+            "BS" => handle_set_bit(cloned_db, command_payload).await,
+            "BA" => handle_append_bytes(cloned_db, command_payload).await,
+            "BW" => handle_set_byte_range(cloned_db, command_payload).await,
+            "PA" => handle_hyperloglog_add(cloned_db, command_payload).await,
+            "MR" => handle_bloom_reserve(cloned_db, command_payload).await,
+            "MA" => handle_bloom_add(cloned_db, command_payload).await,
+            "KR" => handle_top_k_reserve(cloned_db, command_payload).await,
+            "KA" => handle_top_k_add(cloned_db, command_payload).await,
+            "JS" => handle_json_set(cloned_db, command_payload).await,
+            "JD" => handle_json_delete(cloned_db, command_payload).await,
+            "DL" => handle_delete(cloned_db, command_payload).await,
+            "DM" => handle_delete_many(cloned_db, command_payload).await,
+            "RN" => handle_rename(cloned_db, command_payload, true).await,
+            "RX" => handle_rename(cloned_db, command_payload, false).await,
+            "RS" => handle_restore(cloned_db, command_payload).await,
+            "TA" => handle_touch_at(cloned_db, command_payload).await,
+            "TH" => handle_touch(cloned_db, command_payload).await,
+            "PS" => handle_persist(cloned_db, command_payload).await,
+            "HM" => handle_touch_many(cloned_db, command_payload).await,
+            "DP" => handle_declare_dependency(cloned_db, command_payload).await,
+            _ => handle_unknown_type().await,
+        };
+        record_changes(&db, &command_type, versions_before, &response);
+        let (response_type, response_payload) = response.unwrap_or_else(error_response);
+        responses_payload_bytes.extend(response_type.as_bytes());
+        responses_payload_bytes.extend((response_payload.len() as u64).to_be_bytes());
+        responses_payload_bytes.extend(response_payload);
+    }
+    return Ok(("EX".to_string(), Bytes::from(responses_payload_bytes)));
+}
+
+enum SetGuard {
+    Always,
+    // FNV-1a hash of the value the writer expects to replace, 0 when the key must not exist
+    ValueHash(u64),
+    // Only replaces, so a refresh never recreates a key that was deleted on purpose
+    Exists,
+}
+
+impl SetGuard {
+    fn allows(&self, current_value: Option<&Bytes>) -> bool {
+        match (self, current_value) {
+            (SetGuard::Always, _) => true,
+            (SetGuard::ValueHash(expected_hash), Some(value)) => stored_value_hash(value) == *expected_hash,
+            (SetGuard::ValueHash(expected_hash), None) => *expected_hash == 0,
+            (SetGuard::Exists, current_value) => current_value.is_some(),
+        }
     }
 }
 
-async fn handle_touch(timeout_db: TimeoutDB, payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let cache_time_bytes: [u8; 8] = payload[0..8].try_into().expect("Incorrect length");
-    let cache_time_ms = u64::from_be_bytes(cache_time_bytes);
+async fn handle_set_data(db: Db, payload: Vec<u8>) -> Response {
+    let written_key = write_behind_key(&db, &payload, 8);
+    let response = run_set_data(Arc::clone(&db), payload, 0, SetGuard::Always).await;
+    queue_write_behind(&db, written_key, &response);
+    return response;
+}
+
+// An SD payload prefixed with the hash the current value must have. When it does not match,
+// nothing is written and the response is "CF" with the hash of the current value.
+async fn handle_set_data_guarded(db: Db, payload: Vec<u8>) -> Response {
+    let expected_hash = read_u64(&payload, 0)?;
+    let written_key = write_behind_key(&db, &payload, 16);
+    let response = run_set_data(Arc::clone(&db), payload, 8, SetGuard::ValueHash(expected_hash)).await;
+    queue_write_behind(&db, written_key, &response);
+    return response;
+}
+
+// Same payload as SD, but the key must already exist
+async fn handle_set_data_existing(db: Db, payload: Vec<u8>) -> Response {
+    let written_key = write_behind_key(&db, &payload, 8);
+    let response = run_set_data(Arc::clone(&db), payload, 0, SetGuard::Exists).await;
+    queue_write_behind(&db, written_key, &response);
+    return response;
+}
 
-    let key = match std::str::from_utf8(&payload[8..]) {
-        Ok(valid_str) => { valid_str.to_string() },
-        Err(_) => { panic!("Invalid") },
+// Stores CSV as an Arrow value, for producers without an Arrow library. Payload is the u64 cache
+// time and the key prefixed with its u16 length as in SD, then the JSON options of CsvOptions
+// prefixed with their u32 length, 0 for none, and the CSV.
+async fn handle_ingest_csv(db: Db, payload: Vec<u8>) -> Response {
+    let cache_time = read_u64(&payload, 0)?;
+    let (key, key_end) = read_prefixed_key(&payload, 8)?;
+    let key = key.to_string();
+    let options_end = match read_u32(&payload, key_end)? {
+        options_len if key_end + 4 + options_len as usize <= payload.len() => key_end + 4 + options_len as usize,
+        _ => return Err(10),
     };
+    let options = parse_csv_options(&payload[key_end + 4..options_end])?;
+    let parsed = run_blocking(move || {
+        let chunks = read_csv(&options, &payload[options_end..])?;
+        let mut value = vec!['A' as u8];
+        value.extend(write_record_batch_chunks(&chunks));
+        return Ok::<Vec<u8>, u16>(value);
+    }).await;
+    let value = parsed??;
 
-    if shared_db.contains_key(&key) {
-        let now = SystemTime::now();
-        let duration = Duration::from_millis(cache_time_ms);
-        timeout_db.insert(key, now + duration);
-        return ("OK".to_string(), vec![0; 0]);
-    } else {
-        let error_code: u16 = 2;
-        return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+    // Stored like an SD of the Arrow value
+    let mut set_payload: Vec<u8> = Vec::with_capacity(10 + key.len() + value.len());
+    set_payload.extend(cache_time.to_be_bytes());
+    set_payload.extend((key.len() as u16).to_be_bytes());
+    set_payload.extend(key.as_bytes());
+    set_payload.extend(value);
+    let written_key = write_behind_key(&db, &set_payload, 8);
+    let response = run_set_data(Arc::clone(&db), set_payload, 0, SetGuard::Always).await;
+    queue_write_behind(&db, written_key, &response);
+    return response;
+}
+
+// Key of an SD, SG, SX or AP payload to persist once it is written, None when write-behind is
+// off
+fn write_behind_key(db: &Database, payload: &[u8], key_offset: usize) -> Option<String> {
+    db.write_behind.as_ref()?;
+    return read_prefixed_key(payload, key_offset).ok().map(|(key, _)| key.to_string());
+}
+
+fn queue_write_behind(db: &Database, written_key: Option<String>, response: &Response) {
+    if let (Some(write_behind), Some(key), Ok((response_type, _))) = (&db.write_behind, written_key, response) {
+        if response_type == "OK" {
+            write_behind.queue(&db.namespace, &key);
+        }
+    }
+}
+
+// Runs set_data on the SD payload starting at `offset`. Arrow values are decoded for their
+// zone maps, indexes and dictionary encoding and large values may be compressed, so those
+// are stored from the blocking pool.
+async fn run_set_data(db: Db, payload: Vec<u8>, offset: usize, guard: SetGuard) -> Response {
+    let value_tag = read_prefixed_key(&payload, offset + 8)
+        .ok()
+        .and_then(|(_, key_end)| payload.get(key_end).copied());
+    let compressing = db.settings.value_compression.load(Ordering::Relaxed) != COMPRESSION_NONE
+        && payload.len() as u64 >= db.settings.compression_threshold.load(Ordering::Relaxed);
+    if value_tag != Some('A' as u8) && !compressing {
+        return set_data(&db, &payload[offset..], guard);
     }
+    return run_blocking(move || set_data(&db, &payload[offset..], guard)).await?;
 }
 
-async fn handle_ttl(timeout_db: TimeoutDB, payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let ttl_key = std::str::from_utf8(&payload).expect("Payload error");
+fn set_data(db: &Database, payload: &[u8], guard: SetGuard) -> Response {
+    let cache_time_ms = read_u64(&payload, 0)?;
+    let (key, key_index_until) = read_prefixed_key(&payload, 8)?;
+    let key = key.to_string();
 
-    if let Some(live_until) = timeout_db.get(ttl_key) {
-        let now = SystemTime::now();
-        match live_until.duration_since(now) {
-            Ok(ttl) => {
-                let ttl_u64 = ttl.as_millis() as u64;
-                return ("TL".to_string(), ttl_u64.to_be_bytes().to_vec());
+    let value = &payload[key_index_until..];
+    if value.len() > 0 && value[0] as char == 'C' {
+        if value.len() < 2 || lookup_codec(value[1]).is_none() {
+            return Err(7);
+        }
+    }
+    if value.len() > 0 && value[0] == JSON_TAG && serde_json::from_slice::<serde_json::Value>(&value[1..]).is_err() {
+        return Err(3);
+    }
+    if value.len() > 0 && value[0] == STRING_TAG {
+        let max_string_length = db.settings.max_string_length.load(Ordering::Relaxed);
+        validate_string(&value[1..], max_string_length)?;
+    }
+
+    let mut value = Bytes::copy_from_slice(value);
+    if value.first() == Some(&('A' as u8)) {
+        if let Some((encoded_value, _)) = decode_ipc_stream(&value[1..]).ok()
+            .and_then(|chunks| dictionary_encoded_value(db, &chunks))
+        {
+            value = encoded_value;
+        }
+    }
+    let stored_value = stored_form(db, value);
+    // The guard is checked under the entry lock so no other write can slip in between
+    let conflict_hash = match db.shared_db.entry(key.clone()) {
+        dashmap::Entry::Occupied(mut entry) => {
+            if guard.allows(Some(entry.get())) {
+                let _ = db.zone_db.remove(&key);
+                db.bump_version(&key);
+                db.memory.value_changed(&key, Some(entry.get().len()), Some(stored_value.len()));
+                entry.insert(stored_value.clone());
+                None
+            } else {
+                Some(stored_value_hash(entry.get()))
             }
-            Err(_e) => {
-                let error_code: u16 = 0;
-                return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+        },
+        dashmap::Entry::Vacant(entry) => {
+            if guard.allows(None) {
+                let _ = db.zone_db.remove(&key);
+                db.bump_version(&key);
+                db.memory.value_changed(&key, None, Some(stored_value.len()));
+                entry.insert(stored_value.clone());
+                None
+            } else {
+                Some(0)
             }
+        },
+    };
+    if let Some(current_hash) = conflict_hash {
+        if let SetGuard::Exists = guard {
+            return Err(2);
         }
-    } else {
-        if shared_db.contains_key(ttl_key) {
-            let ttl_u64: u64 = 0;
-            return ("TL".to_string(), ttl_u64.to_be_bytes().to_vec());
-        } else {
-            let error_code: u16 = 2;
-            return ("ER".to_string(), error_code.to_be_bytes().to_vec());
-        }
+        return Ok(("CF".to_string(), Bytes::copy_from_slice(&current_hash.to_be_bytes())));
     }
+    invalidate_dependents(&key, db);
+    refresh_arrow_metadata(db, &key, &stored_value);
+
+    set_expiry(db, &key, cache_time_ms);
+    return Ok(("OK".to_string(), Bytes::new()));
 }
 
-async fn handle_list_keys(shared_db: SharedDB) -> (String, Vec<u8>) {
-    let mut keys_payload_bytes: Vec<u8> = Vec::new();
+// Appends the record batches of an IPC stream to an Arrow key as new chunks. The stored
+// stream only loses its end marker, the existing chunks are neither decoded nor rewritten.
+async fn handle_append_data(db: Db, payload: Vec<u8>) -> Response {
+    let key = read_prefixed_key(&payload, 0)?.0.to_string();
+    // Appends to the same key take turns, from reading the stored chunks to rebuilding indexes
+    let _key_guard = db.key_locks.lock(&key).await;
+    let append_db = Arc::clone(&db);
+    let response = run_blocking(move || append_data(&append_db, &payload)).await?;
+    queue_write_behind(&db, Some(key), &response);
+    return response;
+}
+
+// New chunks ready to be added to a stored value: their IPC messages without the schema, in
+// the dictionary encoding of the stored chunks, and their zone maps
+struct PreparedAppend {
+    messages: Vec<u8>,
+    zone_maps: Vec<ZoneMap>,
+}
+
+fn append_data(db: &Database, payload: &[u8]) -> Response {
+    let (key, key_index_until) = read_prefixed_key(payload, 0)?;
+    let key = key.to_string();
 
-    for entry in shared_db.iter() {
-        let key_bytes = entry.key().as_bytes();
-        let _query: Query = match serde_json::from_slice(key_bytes) {
-            Ok(_q) => _q,
-            Err(_e) => {
-                keys_payload_bytes.extend(key_bytes);
-                keys_payload_bytes.push(0);
+    let stream = &payload[key_index_until..];
+    let chunks = decode_ipc_stream(stream)?;
+    let zone_maps: Vec<ZoneMap> = chunks.iter().map(compute_zone_map).collect();
+    let chunk_messages = &stream[ipc_schema_message_len(stream)..ipc_stream_end(stream)];
+
+    // The new chunks are checked and encoded against the stored ones without holding their
+    // shard, then added only if no other command replaced the value meanwhile
+    loop {
+        let stored = db.shared_db.get(&key).map(|stored_value| {
+            let version = db.version_db.get(&key).map(|version| *version);
+            (stored_value.clone(), version)
+        });
+        let (stored_value, version) = match stored {
+            Some(stored) => stored,
+            None => {
+                if insert_appended_value(db, &key, stream, &chunks, zone_maps.clone()) {
+                    break;
+                }
                 continue;
-            }
+            },
         };
+
+        let decompressed_value = decompress_stored(&stored_value)?.map(Bytes::from);
+        let value = decompressed_value.as_ref().unwrap_or(&stored_value);
+        let prepared = prepare_append(value, &chunks, chunk_messages, zone_maps.clone())?;
+        if extend_stored_value(db, &key, version, stored_value, decompressed_value, prepared) {
+            break;
+        }
     }
-    keys_payload_bytes.pop();
-    return ("KY".to_string(), keys_payload_bytes);
+
+    // Only an append that went through changes what the derived keys were computed from
+    invalidate_dependents(&key, db);
+    rebuild_appended_index(db, &key);
+    return Ok(("OK".to_string(), Bytes::new()));
 }
 
-async fn handle_delete_many(timeout_db: TimeoutDB, payload: Vec<u8>, shared_db: SharedDB) -> (String, Vec<u8>) {
-    let del_keys_str = std::str::from_utf8(&payload).expect("Payload error");
-    let del_keys: Vec<&str> = del_keys_str.split(0 as char).collect();
-    let mut count: u16 = 0;
+// Indexes cover every chunk, so they are rebuilt from the whole value after an append
+fn rebuild_appended_index(db: &Database, key: &str) {
+    let indexed_columns = db.index_db.get(key).map(|key_index| key_index.columns.clone());
+    if let Some(columns) = indexed_columns {
+        let value = db.shared_db.get(key).map(|value| value.clone());
+        let rebuilt = match value {
+            Some(value) => decode_record_batch(&value)
+                .and_then(|record_batch| build_key_index(db, key, &value, record_batch, columns)),
+            None => Err(2),
+        };
+        if rebuilt.is_err() {
+            let _ = db.index_db.remove(key);
+        }
+    }
+}
 
-    for key in del_keys {
-        let _ = timeout_db.remove(key);
-        if let Some(_) = shared_db.remove(key) {
-            count += 1;
+// Checks that the new chunks fit the stored value and encodes them like its chunks
+fn prepare_append(
+    value: &[u8],
+    chunks: &Vec<RecordBatch>,
+    chunk_messages: &[u8],
+    zone_maps: Vec<ZoneMap>,
+) -> Result<PreparedAppend, u16> {
+    if value.len() == 0 || value[0] as char != 'A' {
+        return Err(5);
+    }
+    let stored_schema = match StreamReader::try_new(&value[1..], None) {
+        Ok(reader) => reader.schema(),
+        Err(_) => return Err(4),
+    };
+    let schema = chunks[0].schema();
+    // Time-series keys only take chunks sorted into place by TS
+    if time_column(&stored_schema) != time_column(&schema) {
+        return Err(5);
+    }
+    // New chunks take the dictionary encoding of the stored ones, dictionaries included
+    if has_string_dictionaries(&stored_schema) {
+        if !matches_encoded_schema(&schema, &stored_schema) {
+            return Err(8);
         }
+        let encoded_chunks = chunks
+            .iter()
+            .map(|chunk| cast_chunk(chunk, stored_schema.clone()))
+            .collect::<Result<Vec<RecordBatch>, _>>()
+            .map_err(|_| 8u16)?;
+        let encoded_stream = write_record_batch_chunks(&encoded_chunks);
+        let messages_start = ipc_schema_message_len(&encoded_stream);
+        return Ok(PreparedAppend {
+            messages: encoded_stream[messages_start..ipc_stream_end(&encoded_stream)].to_vec(),
+            zone_maps: encoded_chunks.iter().map(compute_zone_map).collect(),
+        });
+    }
+    if stored_schema != schema {
+        return Err(8);
     }
-    return ("DM".to_string(), count.to_be_bytes().to_vec());
+    return Ok(PreparedAppend { messages: chunk_messages.to_vec(), zone_maps: zone_maps });
+}
+
+// Adds the prepared chunks to the stored value unless its version is no longer `version`.
+// `decompressed_value` is the original of a compressed stored value, None when it is stored
+// uncompressed.
+fn extend_stored_value(
+    db: &Database,
+    key: &str,
+    version: Option<u64>,
+    stored_value: Bytes,
+    decompressed_value: Option<Bytes>,
+    prepared: PreparedAppend,
+) -> bool {
+    let mut entry = match db.shared_db.get_mut(key) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    if db.version_db.get(key).map(|version| *version) != version {
+        return false;
+    }
+
+    // Extended in place unless responses still hold the stored value, then copied
+    let previous_len = entry.len();
+    let value = decompressed_value.unwrap_or(stored_value);
+    let stored_end = ipc_stream_end(&value[1..]) + 1;
+    *entry = Bytes::new();
+    let mut appended_value = match value.try_into_mut() {
+        Ok(unshared_value) => unshared_value,
+        Err(shared_value) => BytesMut::from(&shared_value[..]),
+    };
+    appended_value.truncate(stored_end);
+    appended_value.extend_from_slice(&prepared.messages);
+    appended_value.extend_from_slice(&IPC_END_OF_STREAM);
+    *entry = stored_form(db, appended_value.freeze());
+    db.bump_version(key);
+    db.memory.value_changed(key, Some(previous_len), Some(entry.len()));
+    if let Some(mut stored_zone_maps) = db.zone_db.get_mut(key) {
+        Arc::make_mut(&mut stored_zone_maps).extend(prepared.zone_maps);
+    }
+    return true;
+}
+
+// Appends rows to a time-series key, creating it when it does not exist. Payload is the key and
+// then the name of its time column, each prefixed with their u16 length, and an IPC stream of
+// the rows in any order. The time column is a Timestamp or Int64 column without nulls, and
+// may be left empty for a key that exists. Rows at or after the latest stored time are added
+// as a new chunk without touching the stored ones, earlier rows are merged into the chunks
+// they fall in.
+async fn handle_time_series_append(db: Db, payload: Vec<u8>) -> Response {
+    let key = read_prefixed_key(&payload, 0)?.0.to_string();
+    // Shares the key lock of AP, so appends to the same key take turns
+    let _key_guard = db.key_locks.lock(&key).await;
+    let append_db = Arc::clone(&db);
+    let response = run_blocking(move || append_time_series(&append_db, &payload)).await?;
+    queue_write_behind(&db, Some(key), &response);
+    return response;
+}
+
+fn append_time_series(db: &Database, payload: &[u8]) -> Response {
+    let (key, key_index_until) = read_prefixed_key(payload, 0)?;
+    let key = key.to_string();
+    let (time_column_name, column_index_until) = read_prefixed_key(payload, key_index_until)?;
+    let time_column_name = time_column_name.to_string();
+    let chunks = decode_ipc_stream(&payload[column_index_until..])?;
+
+    // Like AP, the rows are placed against the stored chunks without holding their shard, then
+    // stored only if no other command replaced the value meanwhile
+    loop {
+        let stored = db.shared_db.get(&key).map(|stored_value| {
+            let version = db.version_db.get(&key).map(|version| *version);
+            let zone_maps = db.zone_db.get(&key).map(|zone_maps| Arc::clone(&zone_maps));
+            (stored_value.clone(), version, zone_maps)
+        });
+        let (stored_value, version, zone_maps) = match stored {
+            Some(stored) => stored,
+            None => {
+                if time_column_name.len() == 0 {
+                    return Err(3);
+                }
+                let chunk = sort_by_time(&chunks, &time_column_name)?;
+                let zone_maps = vec![compute_zone_map(&chunk)];
+                let sorted_chunks = vec![chunk];
+                let stream = write_record_batch_chunks(&sorted_chunks);
+                if insert_appended_value(db, &key, &stream, &sorted_chunks, zone_maps) {
+                    break;
+                }
+                continue;
+            },
+        };
+
+        let decompressed_value = decompress_stored(&stored_value)?.map(Bytes::from);
+        let value = decompressed_value.as_ref().unwrap_or(&stored_value);
+        let placed = place_time_series_rows(value, zone_maps, &chunks, &time_column_name)?;
+        let stored = match placed {
+            PlacedRows::Append(prepared) => {
+                extend_stored_value(db, &key, version, stored_value, decompressed_value, prepared)
+            },
+            PlacedRows::Rewrite(value) => replace_arrow_value(db, &key, version, value),
+        };
+        if stored {
+            break;
+        }
+    }
+
+    // Only rows that were stored change what the derived keys were computed from
+    invalidate_dependents(&key, db);
+    rebuild_appended_index(db, &key);
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+// Where rows appended to a time-series key go: in a chunk of their own after the stored ones,
+// or merged into the stored chunks, which rewrites the value
+enum PlacedRows {
+    Append(PreparedAppend),
+    Rewrite(Bytes),
+}
+
+fn place_time_series_rows(
+    value: &[u8], zone_maps: Option<Arc<Vec<ZoneMap>>>, chunks: &Vec<RecordBatch>, time_column_name: &str
+) -> Result<PlacedRows, u16> {
+    if value.len() == 0 || value[0] as char != 'A' {
+        return Err(5);
+    }
+    let stored_schema = StreamReader::try_new(&value[1..], None).map_err(|_| 4u16)?.schema();
+    let stored_time_column = time_column(&stored_schema).ok_or(5u16)?.to_string();
+    if time_column_name.len() > 0 && time_column_name != stored_time_column {
+        return Err(8);
+    }
+    let chunk = sort_by_time(chunks, &stored_time_column)?;
+    let times = chunk_times(&chunk, &stored_time_column)?;
+
+    let latest = zone_maps.and_then(|zone_maps| latest_time(&zone_maps, &stored_time_column));
+    let stored_chunks = match latest {
+        Some(_) => None,
+        None => Some(decode_ipc_stream(&value[1..])?),
+    };
+    let latest = match &stored_chunks {
+        Some(stored_chunks) => {
+            let mut latest: Option<i64> = None;
+            for stored_chunk in stored_chunks.iter().rev() {
+                let stored_times = chunk_times(stored_chunk, &stored_time_column)?;
+                if stored_times.len() > 0 {
+                    latest = Some(stored_times.value(stored_times.len() - 1));
+                    break;
+                }
+            }
+            latest
+        },
+        None => latest,
+    };
+    if times.len() == 0 || latest.map_or(true, |latest| times.value(0) >= latest) {
+        let sorted_chunks = vec![chunk];
+        let stream = write_record_batch_chunks(&sorted_chunks);
+        let messages = &stream[ipc_schema_message_len(&stream)..ipc_stream_end(&stream)];
+        let zone_maps = vec![compute_zone_map(&sorted_chunks[0])];
+        return Ok(PlacedRows::Append(prepare_append(value, &sorted_chunks, messages, zone_maps)?));
+    }
+
+    // Stored chunks from the first one holding a later time are merged with the new rows, in
+    // the dictionary encoding of the stored ones
+    let mut stored_chunks = match stored_chunks {
+        Some(stored_chunks) => stored_chunks,
+        None => decode_ipc_stream(&value[1..])?,
+    };
+    let earliest = times.value(0);
+    let mut merge_from = stored_chunks.len();
+    for (position, stored_chunk) in stored_chunks.iter().enumerate() {
+        let stored_times = chunk_times(stored_chunk, &stored_time_column)?;
+        if stored_times.len() > 0 && stored_times.value(stored_times.len() - 1) > earliest {
+            merge_from = position;
+            break;
+        }
+    }
+    let chunk = match has_string_dictionaries(&stored_schema) {
+        true => cast_chunk(&chunk, stored_schema.clone()).map_err(|_| 8u16)?,
+        false if chunk.schema() != stored_schema => return Err(8),
+        false => chunk,
+    };
+    let mut merged_chunks = stored_chunks.split_off(merge_from);
+    merged_chunks.push(chunk);
+    stored_chunks.push(sort_by_time(&merged_chunks, &stored_time_column)?);
+    let mut rewritten_value = vec!['A' as u8];
+    rewritten_value.extend(write_record_batch_chunks(&stored_chunks));
+    return Ok(PlacedRows::Rewrite(Bytes::from(rewritten_value)));
+}
+
+// Replaces an Arrow value unless its version is no longer `version`, then recomputes its zone
+// maps
+fn replace_arrow_value(db: &Database, key: &str, version: Option<u64>, value: Bytes) -> bool {
+    let mut entry = match db.shared_db.get_mut(key) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    if db.version_db.get(key).map(|version| *version) != version {
+        return false;
+    }
+    let stored_value = stored_form(db, value);
+    let _ = db.zone_db.remove(key);
+    db.bump_version(key);
+    db.memory.value_changed(key, Some(entry.len()), Some(stored_value.len()));
+    *entry = stored_value.clone();
+    drop(entry);
+    refresh_arrow_metadata(db, key, &stored_value);
+    return true;
+}
+
+// Trims the Arrow keys of a namespace holding rows past their retention and returns how many
+// it trimmed. Keys are only decoded when their zone maps show rows to trim. Runs on the
+// blocking pool for the cache manager.
+pub fn trim_retained_keys(db: &Database) -> u64 {
+    let retained_keys: Vec<(String, Retention)> = db.retention_db
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    let mut trimmed_keys: u64 = 0;
+    for (key, retention) in retained_keys {
+        let zone_maps = db.zone_db.get(&key).map(|zone_maps| Arc::clone(&zone_maps));
+        if zone_maps.map_or(false, |zone_maps| !exceeds_retention(&zone_maps, &retention)) {
+            continue;
+        }
+        if trim_key(db, &key, &retention) {
+            trimmed_keys += 1;
+        }
+    }
+    return trimmed_keys;
+}
+
+// Rewrites a key without its rows past the retention, unless another command changed it
+// meanwhile. Returns whether it did.
+fn trim_key(db: &Database, key: &str, retention: &Retention) -> bool {
+    let (chunks, _, version) = match read_record_batch_chunks(db, key) {
+        Ok(read) => read,
+        Err(_) => return false,
+    };
+    let kept_chunks = match trim_chunks(&chunks, retention) {
+        Ok(Some(kept_chunks)) => kept_chunks,
+        _ => return false,
+    };
+    let mut value = vec!['A' as u8];
+    value.extend(write_record_batch_chunks(&kept_chunks));
+    invalidate_dependents(key, db);
+    if !replace_arrow_value(db, key, version, Bytes::from(value)) {
+        return false;
+    }
+    rebuild_appended_index(db, key);
+    let trimmed_hash = db.shared_db.get(key).map(|stored_value| stored_value_hash(&stored_value));
+    db.record_change("trimmed", Some(key), trimmed_hash);
+    return true;
+}
+
+// Stores the chunks as a new value unless another command created the key meanwhile
+fn insert_appended_value(
+    db: &Database,
+    key: &str,
+    stream: &[u8],
+    chunks: &Vec<RecordBatch>,
+    zone_maps: Vec<ZoneMap>,
+) -> bool {
+    let (value, zone_maps) = match dictionary_encoded_value(db, chunks) {
+        Some((encoded_value, encoded_chunks)) => {
+            (encoded_value, encoded_chunks.iter().map(compute_zone_map).collect())
+        },
+        None => {
+            let mut value = vec!['A' as u8];
+            value.extend_from_slice(stream);
+            (Bytes::from(value), zone_maps)
+        },
+    };
+    match db.shared_db.entry(key.to_string()) {
+        dashmap::Entry::Occupied(_) => return false,
+        dashmap::Entry::Vacant(entry) => {
+            if let Some(live_until) = default_expiry(db) {
+                db.timeout_db.insert(key.to_string(), live_until);
+            }
+            db.bump_version(key);
+            let stored_value = stored_form(db, value);
+            db.memory.value_changed(key, None, Some(stored_value.len()));
+            entry.insert(stored_value);
+            db.zone_db.insert(key.to_string(), Arc::new(zone_maps));
+            if let Some(retention) = retention(&chunks[0].schema()) {
+                db.retention_db.insert(key.to_string(), retention);
+            }
+            return true;
+        },
+    }
+}
+
+// Limits of an increment from IB or FB. The result is clamped between `min` and `max`, and a
+// key the increment creates expires after `ttl_ms` instead of the default TTL.
+struct IncrementBounds<T> {
+    min: Option<T>,
+    max: Option<T>,
+    ttl_ms: Option<u64>,
+}
+
+impl<T: PartialOrd + Copy> IncrementBounds<T> {
+    fn none() -> IncrementBounds<T> {
+        return IncrementBounds { min: None, max: None, ttl_ms: None };
+    }
+
+    fn clamp(&self, value: T) -> T {
+        let value = match self.min {
+            Some(min) if value < min => min,
+            _ => value,
+        };
+        match self.max {
+            Some(max) if value > max => return max,
+            _ => return value,
+        }
+    }
+
+    fn expiry(&self, db: &Database) -> Option<SystemTime> {
+        match self.ttl_ms {
+            Some(ttl_ms) => return Some(SystemTime::now() + Duration::from_millis(ttl_ms)),
+            None => return default_expiry(db),
+        }
+    }
+}
+
+// Bounds that follow the increment amount of IB and FB: flags (u8) telling which of the min,
+// max and TTL are set (bits 0, 1 and 2), then min and max as the amount's type and TTL (u64),
+// each always present. Returns them with the offset of the key. Fails with error code 3 when
+// the min is above the max.
+fn read_increment_bounds<T: PartialOrd + Copy>(
+    payload: &[u8], read_bound: fn(&[u8], usize) -> Result<T, u16>
+) -> Result<(IncrementBounds<T>, usize), u16> {
+    let flags = *payload.get(8).ok_or(10u16)?;
+    let min = read_bound(payload, 9)?;
+    let max = read_bound(payload, 17)?;
+    let ttl_ms = read_u64(payload, 25)?;
+    let bounds = IncrementBounds {
+        min: (flags & 1 != 0).then_some(min),
+        max: (flags & 2 != 0).then_some(max),
+        ttl_ms: (flags & 4 != 0).then_some(ttl_ms),
+    };
+    if let (Some(min), Some(max)) = (bounds.min, bounds.max) {
+        if min > max {
+            return Err(3);
+        }
+    }
+    return Ok((bounds, 33));
+}
+
+async fn handle_increment_integer(db: Db, payload: Vec<u8>) -> Response {
+    let increment_amount = read_i64(&payload, 0)?;
+    let key = read_rest(&payload, 8).and_then(read_str)?;
+    return increment_integer(&db, key, increment_amount, IncrementBounds::none());
+}
+
+// II with bounds between the amount and the key, see read_increment_bounds. Clamped to a max,
+// the counter of a rate limiter or quota stops there and the client compares against it.
+async fn handle_increment_integer_bounded(db: Db, payload: Vec<u8>) -> Response {
+    let increment_amount = read_i64(&payload, 0)?;
+    let (bounds, key_start) = read_increment_bounds(&payload, read_i64)?;
+    let key = read_rest(&payload, key_start).and_then(read_str)?;
+    return increment_integer(&db, key, increment_amount, bounds);
+}
+
+fn increment_integer(
+    db: &Database, key: &str, increment_amount: i64, bounds: IncrementBounds<i64>
+) -> Response {
+    let response = match db.shared_db.entry(key.to_string()) {
+        dashmap::Entry::Occupied(mut entry) => {
+            let int_bytes = entry.get_mut();
+            if int_bytes.len() != 9 || int_bytes[0] as char != 'I' {
+                return Err(5);
+            }
+            let int_data = i64::from_be_bytes(int_bytes[1..].try_into().unwrap());
+            let int_data = bounds.clamp(int_data.saturating_add(increment_amount));
+
+            // Replaced rather than written in place, responses may still hold the old value
+            let mut int_bytes_vec = vec!['I' as u8];
+            int_bytes_vec.extend(int_data.to_be_bytes());
+            *int_bytes = Bytes::from(int_bytes_vec);
+            db.bump_version(key);
+            Ok(("IN".to_string(), int_bytes.slice(1..)))
+        }
+        dashmap::Entry::Vacant(entry) => {
+            let mut int_bytes_vec = vec!['I' as u8];
+            int_bytes_vec.extend(bounds.clamp(increment_amount).to_be_bytes());
+            let int_bytes = Bytes::from(int_bytes_vec);
+
+            // Set along with the key, so a counter is never seen without its expiry
+            if let Some(live_until) = bounds.expiry(db) {
+                db.timeout_db.insert(key.to_string(), live_until);
+            }
+            db.bump_version(key);
+            db.memory.value_changed(key, None, Some(int_bytes.len()));
+            entry.insert(int_bytes.clone());
+            Ok(("IN".to_string(), int_bytes.slice(1..)))
+        }
+    };
+    invalidate_dependents(key, db);
+    return response;
+}
+
+async fn handle_increment_float(db: Db, payload: Vec<u8>) -> Response {
+    let increment_amount = read_f64(&payload, 0)?;
+    let key = read_rest(&payload, 8).and_then(read_str)?;
+    return increment_float(&db, key, increment_amount, IncrementBounds::none());
+}
+
+// IF with bounds between the amount and the key, see read_increment_bounds
+async fn handle_increment_float_bounded(db: Db, payload: Vec<u8>) -> Response {
+    let increment_amount = read_f64(&payload, 0)?;
+    let (bounds, key_start) = read_increment_bounds(&payload, read_f64)?;
+    let key = read_rest(&payload, key_start).and_then(read_str)?;
+    return increment_float(&db, key, increment_amount, bounds);
+}
+
+fn increment_float(
+    db: &Database, key: &str, increment_amount: f64, bounds: IncrementBounds<f64>
+) -> Response {
+    let response = match db.shared_db.entry(key.to_string()) {
+        dashmap::Entry::Occupied(mut entry) => {
+            let float_bytes = entry.get_mut();
+            if float_bytes.len() != 9 || float_bytes[0] as char != 'F' {
+                return Err(5);
+            }
+            let float_data = f64::from_be_bytes(float_bytes[1..].try_into().unwrap());
+            let float_data = bounds.clamp(float_data + increment_amount);
+
+            // Replaced rather than written in place, responses may still hold the old value
+            let mut float_bytes_vec = vec!['F' as u8];
+            float_bytes_vec.extend(float_data.to_be_bytes());
+            *float_bytes = Bytes::from(float_bytes_vec);
+            db.bump_version(key);
+            Ok(("FL".to_string(), float_bytes.slice(1..)))
+        }
+        dashmap::Entry::Vacant(entry) => {
+            let mut float_bytes_vec = vec!['F' as u8];
+            float_bytes_vec.extend(bounds.clamp(increment_amount).to_be_bytes());
+            let float_bytes = Bytes::from(float_bytes_vec);
+
+            // Set along with the key, so a counter is never seen without its expiry
+            if let Some(live_until) = bounds.expiry(db) {
+                db.timeout_db.insert(key.to_string(), live_until);
+            }
+            db.bump_version(key);
+            db.memory.value_changed(key, None, Some(float_bytes.len()));
+            entry.insert(float_bytes.clone());
+            Ok(("FL".to_string(), float_bytes.slice(1..)))
+        }
+    };
+    invalidate_dependents(key, db);
+    return response;
+}
+
+async fn handle_get_arrow_data(
+    db: Db, payload: Vec<u8>, writer: &FrameWriter, request_id: Option<u64>, deadline: Deadline
+) -> Response {
+    let payload_str = read_str(&payload)?;
+    let query: Query = match serde_json::from_str(payload_str) {
+        Ok(q) => q,
+        Err(_e) => return Err(3),
+    };
+
+    result_format(&query)?;
+
+    // A view whose source changed is brought up to date first, which drops its cached results
+    if let QueryKey::Single(key) = &query.key {
+        if db.view_db.contains_key(key) {
+            refresh_view(&db, key).await;
+        }
+    }
+
+    let query_cache_key = canonical_query(&query);
+    if let Some(cached_result) = db.result_cache.get(&query_cache_key) {
+        return Ok(("AR".to_string(), cached_result));
+    }
+
+    let keys = resolve_query_keys(&db, &query.key);
+    for key in keys.iter() {
+        if !db.shared_db.contains_key(key) {
+            if known_missing(&db, key) {
+                return Err(18);
+            }
+            load_missing_key(&db, key).await;
+            if !db.shared_db.contains_key(key) {
+                remember_miss(&db, key);
+            }
+        }
+    }
+    // Pollers that already hold the current version get a tiny "UC" instead of the data
+    if let (Some(known_version), 1) = (query.if_version_not, keys.len()) {
+        if let Some(_value) = db.shared_db.get(&keys[0]) {
+            if db.version_db.get(&keys[0]).map(|version| *version) == Some(known_version) {
+                db.stats.record_key_access(&keys[0]);
+                return Ok(("UC".to_string(), Bytes::copy_from_slice(&known_version.to_be_bytes())));
+            }
+        }
+    }
+
+    // Identical queries running at the same time share one run. Streamed results are written
+    // by the request itself, and one request's deadline must not cut the run short for the
+    // others, so those queries run on their own.
+    let streaming = query.cachetime == 0 && query.stream_chunk_size.is_some();
+    if streaming || deadline.is_set() {
+        return run_query(db, query, keys, query_cache_key, writer, request_id, deadline).await;
+    }
+    let flight_db = Arc::clone(&db);
+    let flight_key = query_cache_key.clone();
+    let (response, coalesced) = db.query_flights.run(&flight_key, || {
+        run_query(flight_db, query, keys, query_cache_key, writer, request_id, deadline)
+    }).await;
+    if coalesced {
+        db.stats.coalesced_queries.fetch_add(1, Ordering::Relaxed);
+    }
+    return response;
+}
+
+// Runs a GA query that missed the result cache, and caches its result when it asks for it.
+// Decoding, filtering and encoding run on the blocking pool. A streamed result is encoded
+// chunk by chunk as it is written, so its buffer is left empty there.
+async fn run_query(
+    db: Db,
+    query: Query,
+    keys: Vec<String>,
+    query_cache_key: String,
+    writer: &FrameWriter,
+    request_id: Option<u64>,
+    deadline: Deadline,
+) -> Response {
+    let streaming = query.cachetime == 0 && query.stream_chunk_size.is_some();
+    let blocking_db = Arc::clone(&db);
+    let blocking_keys = keys.clone();
+    let result = run_blocking(move || {
+        let record_batch = query_record_batch(&blocking_db, &query, &blocking_keys, &deadline)?;
+        // A streamed result is not encoded yet, the size of its arrays stands in for it
+        let buffer = match streaming {
+            true => {
+                check_query_limit(&blocking_db.settings.max_result_bytes, record_batch.get_array_memory_size())?;
+                Vec::new()
+            },
+            false => encode_result(&record_batch, result_format(&query)?, &query.compression_type, true)?,
+        };
+        check_query_limit(&blocking_db.settings.max_result_bytes, buffer.len())?;
+        deadline.check()?;
+        return Ok((query, record_batch, buffer));
+    }).await.and_then(|result| result);
+    let (query, filtered_record_batch, buffer) = result?;
+    if query.cachetime == 0 {
+        if let Some(chunk_size) = query.stream_chunk_size {
+            let format = result_format(&query)?;
+            return stream_record_batch(
+                writer, request_id, &filtered_record_batch, format, &query.compression_type, chunk_size, deadline
+            ).await;
+        }
+    }
+
+    let buffer = Bytes::from(buffer);
+    cache_query_result(&db, &query, &query_cache_key, buffer.clone(), &keys);
+    return Ok(("AR".to_string(), buffer));
+}
+
+// Keeps the result of a query for its cachetime, when it has one
+fn cache_query_result(db: &Database, query: &Query, query_cache_key: &str, buffer: Bytes, keys: &Vec<String>) {
+    if query.cachetime == 0 {
+        return;
+    }
+    let expires_at = SystemTime::now() + Duration::from_millis(query.cachetime);
+    let refresh_ahead = match query.refresh_ahead_ms {
+        Some(refresh_ahead_ms) if refresh_ahead_ms > 0 => Some(Duration::from_millis(refresh_ahead_ms)),
+        _ => None,
+    };
+    db.result_cache.insert(query_cache_key, buffer, expires_at, refresh_ahead, keys);
+}
+
+// Computes the cached GA results about to expire again, for the cache manager, so hot queries
+// asking for them with refresh_ahead_ms never wait for them. Results whose keys are gone are
+// left to expire.
+pub async fn refresh_cached_results(db: &Db) {
+    for query_cache_key in db.result_cache.due_for_refresh(SystemTime::now()) {
+        let query: Query = match serde_json::from_str(&query_cache_key) {
+            Ok(query) => query,
+            Err(_) => continue,
+        };
+        let keys = resolve_query_keys(db, &query.key);
+        if keys.len() == 0 || keys.iter().any(|key| !db.shared_db.contains_key(key)) {
+            continue;
+        }
+        let refresh_db = Arc::clone(db);
+        let refreshed = run_blocking(move || {
+            let record_batch = query_record_batch(&refresh_db, &query, &keys, &Deadline::none())?;
+            let buffer = encode_result(&record_batch, result_format(&query)?, &query.compression_type, true)?;
+            check_query_limit(&refresh_db.settings.max_result_bytes, buffer.len())?;
+            cache_query_result(&refresh_db, &query, &query_cache_key, Bytes::from(buffer), &keys);
+            return Ok(());
+        }).await.and_then(|result| result);
+        if let Err(error_code) = refreshed {
+            tracing::debug!("A cached result could not be computed again, error {}", error_code);
+        }
+    }
+}
+
+// The query re-serialized with fields in declaration order and absent options as null, so
+// queries that differ only in field order, whitespace or omitted options share a cached result
+fn canonical_query(query: &Query) -> String {
+    return serde_json::to_string(query).unwrap();
+}
+
+// Reads the keys of a GA query and applies its filters, ranking and sampling
+fn query_record_batch(
+    db: &Database, query: &Query, keys: &Vec<String>, deadline: &Deadline
+) -> Result<RecordBatch, u16> {
+    // Indexes point at rows of the whole key, which a time window leaves out
+    let key_index = match (keys.len(), &query.time_window) {
+        (1, None) => db.index_db.get(&keys[0]).map(|key_index| {
+            (key_index.record_batch.clone(), key_index.indexes.clone(), key_index.version)
+        }),
+        _ => None,
+    };
+    let (chunks, indexes, zone_maps, version) = match key_index {
+        Some((record_batch, indexes, version)) => (vec![record_batch], Some(indexes), None, version),
+        None if keys.len() == 1 => {
+            let (chunks, zone_maps, version) = read_record_batch_chunks(db, &keys[0])?;
+            (chunks, None, zone_maps, version)
+        },
+        None => (vec![read_union_record_batch(db, keys)?], None, None, None),
+    };
+    for key in keys {
+        db.record_read(key);
+    }
+    let (chunks, zone_maps) = match &query.time_window {
+        Some(_) if keys.len() > 1 => return Err(3),
+        Some(time_window) => window_chunks(chunks, zone_maps, time_window)?,
+        None => (chunks, zone_maps),
+    };
+    deadline.check()?;
+    let scanned_rows: usize = chunks.iter().map(|chunk| chunk.num_rows()).sum();
+    check_query_limit(&db.settings.max_scan_rows, scanned_rows)?;
+    let zone_maps = zone_maps.filter(|zone_maps| zone_maps.len() == chunks.len());
+
+    // Chunks are filtered independently unless the query ranks or samples across all rows
+    let filtered_record_batch = if chunks.len() > 1 && query.top_k.is_none() && query.sample.is_none() {
+        let filtered_chunks: Vec<RecordBatch> = chunks
+            .par_iter()
+            .enumerate()
+            .map(|(position, chunk)| {
+                let zone_map = zone_maps.as_ref().map(|zone_maps| &zone_maps[position]);
+                process_filter(chunk, query, None, zone_map, deadline)
+            })
+            .collect::<Result<Vec<RecordBatch>, u16>>()?;
+        concat_batches(&filtered_chunks[0].schema(), &filtered_chunks).unwrap()
+    } else {
+        let zone_map = match (&zone_maps, chunks.len()) {
+            (Some(zone_maps), 1) => Some(&zone_maps[0]),
+            _ => None,
+        };
+        let record_batch = match chunks.len() {
+            1 => chunks[0].clone(),
+            _ => concat_batches(&chunks[0].schema(), &chunks).unwrap(),
+        };
+        process_filter(&record_batch, query, indexes.as_ref(), zone_map, deadline)?
+    };
+    check_query_limit(&db.settings.max_result_rows, filtered_record_batch.num_rows())?;
+    let filtered_record_batch = match query.plain_strings {
+        Some(true) => match dictionary_decode(filtered_record_batch) {
+            Ok(record_batch) => record_batch,
+            Err(_) => return Err(12),
+        },
+        _ => filtered_record_batch,
+    };
+    return match version {
+        Some(version) => Ok(with_version_metadata(filtered_record_batch, version)),
+        None => Ok(filtered_record_batch),
+    };
+}
+
+// The rows of a time-series key within a time window, each chunk sliced to them. The zone
+// maps of the chunks still bound the sliced rows, so they are kept.
+fn window_chunks(
+    chunks: Vec<RecordBatch>, zone_maps: Option<Arc<Vec<ZoneMap>>>, time_window: &TimeWindow
+) -> Result<ZonedChunks, u16> {
+    let rows = window_rows(&chunks, time_window)?;
+    if rows.len() == 0 {
+        return Ok((vec![chunks[0].slice(0, 0)], None));
+    }
+    let zone_maps = zone_maps.filter(|zone_maps| zone_maps.len() == chunks.len()).map(|zone_maps| {
+        Arc::new(rows.iter().map(|(position, _, _)| zone_maps[*position].clone()).collect())
+    });
+    let chunks = rows.iter().map(|(position, first, count)| chunks[*position].slice(*first, *count)).collect();
+    return Ok((chunks, zone_maps));
+}
+
+// Fails with error code 17, result too large, when `count` is over `limit`. A limit of 0 is off.
+fn check_query_limit(limit: &AtomicU64, count: usize) -> Result<(), u16> {
+    let limit = limit.load(Ordering::Relaxed);
+    if limit > 0 && count as u64 > limit {
+        return Err(17);
+    }
+    return Ok(());
+}
+
+// Downsamples a time-series key into buckets of a fixed width, one row each, so dashboards get
+// a small batch instead of every row. Payload is a JSON ResampleQuery.
+async fn handle_resample(db: Db, payload: Vec<u8>) -> Response {
+    let query: ResampleQuery = match serde_json::from_slice(&payload) {
+        Ok(q) => q,
+        Err(_e) => return Err(3),
+    };
+    if !db.shared_db.contains_key(&query.key) {
+        load_missing_key(&db, &query.key).await;
+    }
+    let buffer = run_blocking(move || resample_key(&db, &query)).await??;
+    return Ok(("AR".to_string(), Bytes::from(buffer)));
+}
+
+fn resample_key(db: &Database, query: &ResampleQuery) -> Result<Vec<u8>, u16> {
+    let (chunks, zone_maps, _) = read_record_batch_chunks(db, &query.key)?;
+    db.record_read(&query.key);
+    let (chunks, _) = match &query.time_window {
+        Some(time_window) => window_chunks(chunks, zone_maps, time_window)?,
+        None => (chunks, zone_maps),
+    };
+    let scanned_rows: usize = chunks.iter().map(|chunk| chunk.num_rows()).sum();
+    check_query_limit(&db.settings.max_scan_rows, scanned_rows)?;
+    let record_batch = resample(&chunks, query)?;
+    check_query_limit(&db.settings.max_result_rows, record_batch.num_rows())?;
+    return Ok(write_record_batch(&record_batch, &query.compression_type));
+}
+
+// Registers a view: the result of a GA query on a single key, stored under the key named
+// "view" and computed again whenever the source key changes. The cache time of the query is
+// that of the view. Writing or deleting the view's key ends the view.
+async fn handle_create_view(db: Db, payload: Vec<u8>) -> Response {
+    let (view_key, query) = match parse_view_request(&payload) {
+        Some(view_request) => view_request,
+        None => return Err(3),
+    };
+    let source = match &query.key {
+        QueryKey::Single(key) if *key != view_key && !is_glob(key) => key.clone(),
+        _ => return Err(3),
+    };
+    result_format(&query)?;
+    if !db.shared_db.contains_key(&source) {
+        load_missing_key(&db, &source).await;
+    }
+    let _view_guard = db.key_locks.lock(&view_key).await;
+    let view = View {
+        source: source,
+        query: canonical_query(&query),
+        source_version: None,
+        view_version: None,
+    };
+    let view_db = Arc::clone(&db);
+    let compute_key = view_key.clone();
+    run_blocking(move || compute_view(&view_db, &compute_key, view, true)).await??;
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+// Registers a trigger, given as JSON, replacing the one of the same name. Its actions run after
+// every command that sets or deletes a key matching its pattern.
+async fn handle_create_trigger(db: Db, payload: Vec<u8>) -> Response {
+    let trigger: Trigger = match serde_json::from_slice(&payload) {
+        Ok(trigger) => trigger,
+        Err(_e) => return Err(3),
+    };
+    trigger.validate()?;
+    db.trigger_db.insert(trigger.name.clone(), trigger);
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+// Removes the trigger of the name in the payload
+async fn handle_drop_trigger(db: Db, payload: Vec<u8>) -> Response {
+    let name = read_str(&payload)?;
+    match db.trigger_db.remove(name) {
+        Some(_) => return Ok(("OK".to_string(), Bytes::new())),
+        None => return Err(2),
+    }
+}
+
+// The view key of a VW request and its query, which is the rest of the request
+fn parse_view_request(payload: &[u8]) -> Option<(String, Query)> {
+    let mut request: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(payload).ok()?;
+    let view_key = match request.remove("view")? {
+        serde_json::Value::String(view_key) => view_key,
+        _ => return None,
+    };
+    let query: Query = serde_json::from_value(serde_json::Value::Object(request)).ok()?;
+    return Some((view_key, query));
+}
+
+// Brings the views of a namespace up to date with their sources, for the cache manager
+pub async fn refresh_views(db: &Db) {
+    let view_keys: Vec<String> = db.view_db.iter().map(|entry| entry.key().clone()).collect();
+    for view_key in view_keys {
+        refresh_view(db, &view_key).await;
+    }
+}
+
+// Computes a view again when its source changed since it was computed. A view whose source is
+// gone is deleted, and one whose key a command wrote is no longer kept up to date.
+async fn refresh_view(db: &Db, view_key: &str) {
+    if !view_is_stale(db, view_key) {
+        return;
+    }
+    // Taken in the order writes take them
+    let _write_permit = db.write_gate.read().await;
+    let _view_guard = db.key_locks.lock(view_key).await;
+    if !view_is_stale(db, view_key) {
+        return;
+    }
+    let view = match db.view_db.get(view_key) {
+        Some(view) => view.clone(),
+        None => return,
+    };
+    if db.version_db.get(view_key).map(|version| *version) != view.view_version {
+        let _ = db.view_db.remove(view_key);
+        return;
+    }
+    if !db.shared_db.contains_key(&view.source) {
+        invalidate_dependents(view_key, db);
+        if db.remove_key(view_key) {
+            db.record_change("invalidated", Some(view_key), None);
+        }
+        return;
+    }
+    let refresh_db = Arc::clone(db);
+    let refresh_key = view_key.to_string();
+    if let Ok(Err(error_code)) = run_blocking(move || compute_view(&refresh_db, &refresh_key, view, false)).await {
+        tracing::debug!("View {} could not be computed again, error {}", view_key, error_code);
+    }
+}
+
+fn view_is_stale(db: &Database, view_key: &str) -> bool {
+    return match db.view_db.get(view_key) {
+        Some(view) => db.version_db.get(&view.source).map(|version| *version) != view.source_version,
+        None => false,
+    };
+}
+
+// Runs the query of a view and stores its result, under the view key's lock. A new view
+// replaces whatever the key holds, a refreshed one only the result it computed before. A
+// query that fails on a refresh keeps the last result until the source changes again.
+fn compute_view(db: &Database, view_key: &str, mut view: View, create: bool) -> Result<(), u16> {
+    let query: Query = serde_json::from_str(&view.query).map_err(|_| 12u16)?;
+    let source_version = db.version_db.get(&view.source).map(|version| *version);
+    let record_batch = match query_record_batch(db, &query, &vec![view.source.clone()], &Deadline::none()) {
+        Ok(record_batch) => record_batch,
+        Err(error_code) => {
+            if !create {
+                view.source_version = source_version;
+                db.view_db.insert(view_key.to_string(), view);
+            }
+            return Err(error_code);
+        },
+    };
+    let mut value = vec!['A' as u8];
+    value.extend(write_record_batch(&record_batch, ""));
+    let value = Bytes::from(value);
+
+    invalidate_dependents(view_key, db);
+    if create {
+        let stored_value = stored_form(db, value);
+        db.insert_value(view_key, stored_value.clone());
+        refresh_arrow_metadata(db, view_key, &stored_value);
+        set_expiry(db, view_key, query.cachetime);
+    } else if replace_arrow_value(db, view_key, view.view_version, value) {
+        let refreshed_hash = db.shared_db.get(view_key).map(|stored_value| stored_value_hash(&stored_value));
+        db.record_change("refreshed", Some(view_key), refreshed_hash);
+    } else {
+        let _ = db.view_db.remove(view_key);
+        return Ok(());
+    }
+    view.source_version = source_version;
+    view.view_version = db.version_db.get(view_key).map(|version| *version);
+    db.view_db.insert(view_key.to_string(), view);
+    return Ok(());
+}
+
+async fn handle_join(db: Db, payload: Vec<u8>) -> Response {
+    return run_blocking(move || join(&db, &payload)).await?;
+}
+
+fn join(db: &Database, payload: &[u8]) -> Response {
+    let join_query: JoinQuery = match serde_json::from_slice(payload) {
+        Ok(q) => q,
+        Err(_e) => return Err(3),
+    };
+
+    let left = read_record_batch(db, &join_query.left)?;
+    let right = read_record_batch(db, &join_query.right)?;
+
+    // Keys compare as plain strings, whichever side was stored dictionary encoded
+    let (left, right) = match (dictionary_decode(left), dictionary_decode(right)) {
+        (Ok(left), Ok(right)) => (left, right),
+        _ => return Err(12),
+    };
+    let joined_record_batch = match hash_join(
+        &left, &right, &join_query.left_on, &join_query.right_on, &join_query.how
+    ) {
+        Ok(rb) => rb,
+        Err(e) => {
+            tracing::debug!("Join failed: {}", e);
+            return Err(3);
+        }
+    };
+
+    if let Some(store_key) = join_query.store_key {
+        let value = match dictionary_encoded_value(db, &vec![joined_record_batch.clone()]) {
+            Some((encoded_value, _)) => encoded_value,
+            None => {
+                let mut value = vec!['A' as u8];
+                value.extend(write_record_batch(&joined_record_batch, &join_query.compression_type));
+                Bytes::from(value)
+            },
+        };
+
+        invalidate_dependents(&store_key, db);
+        let stored_value = stored_form(db, value);
+        db.insert_value(&store_key, stored_value.clone());
+        refresh_arrow_metadata(db, &store_key, &stored_value);
+        set_expiry(db, &store_key, join_query.cachetime);
+        db.dependency_db.entry(join_query.left).or_default().insert(store_key.clone());
+        db.dependency_db.entry(join_query.right).or_default().insert(store_key);
+        return Ok(("OK".to_string(), Bytes::new()));
+    }
+
+    let buffer = write_record_batch(&joined_record_batch, &join_query.compression_type);
+    return Ok(("AR".to_string(), Bytes::from(buffer)));
+}
+
+// Gives a written key its expiry. Keys written without a cache time live for the default TTL
+// when one is configured and forever otherwise.
+fn set_expiry(db: &Database, key: &str, cache_time_ms: u64) {
+    let live_until = match cache_time_ms {
+        0 => default_expiry(db),
+        _ => Some(SystemTime::now() + Duration::from_millis(cache_time_ms)),
+    };
+    match live_until {
+        Some(live_until) => { db.timeout_db.insert(key.to_string(), live_until); },
+        None => { let _ = db.timeout_db.remove(key); },
+    }
+}
+
+// Expiry of a key created without a cache time, None when no default TTL is configured
+fn default_expiry(db: &Database) -> Option<SystemTime> {
+    match db.settings.default_ttl_ms.load(Ordering::Relaxed) {
+        0 => return None,
+        default_ttl_ms => return Some(SystemTime::now() + Duration::from_millis(default_ttl_ms)),
+    }
+}
+
+// Tags a GA result with the version of the key it was read from
+fn with_version_metadata(record_batch: RecordBatch, version: u64) -> RecordBatch {
+    let mut metadata = record_batch.schema().metadata().clone();
+    metadata.insert("cupiddb.version".to_string(), version.to_string());
+    let schema = Arc::new(record_batch.schema().as_ref().clone().with_metadata(metadata));
+    // Built anew rather than with with_schema, which refuses to change the version of a value
+    // stored from an earlier result
+    return RecordBatch::try_new(schema, record_batch.columns().to_vec()).unwrap();
+}
+
+// Sends a result larger than `chunk_size` bytes as "AC" continuation frames followed by a
+// final "AR" frame, each holding a self-contained IPC stream of consecutive rows, so only one
+// chunk is ever serialized at a time. CSV results name their columns in the first chunk only,
+// so the chunks put together are one CSV.
+async fn stream_record_batch(
+    writer: &FrameWriter,
+    request_id: Option<u64>,
+    record_batch: &RecordBatch,
+    format: ResultFormat,
+    compression_type: &str,
+    chunk_size: usize,
+    deadline: Deadline,
+) -> Response {
+    let row_count = record_batch.num_rows();
+    let total_size = record_batch.get_array_memory_size();
+    if row_count == 0 || total_size <= chunk_size {
+        let buffer = encode_result(record_batch, format, compression_type, true)?;
+        return Ok(("AR".to_string(), Bytes::from(buffer)));
+    }
+
+    let row_size = (total_size / row_count).max(1);
+    let chunk_rows = (chunk_size / row_size).max(1);
+    let mut offset = 0;
+    while offset + chunk_rows < row_count {
+        // The error ends the stream in place of its last chunk
+        deadline.check()?;
+        let chunk = record_batch.slice(offset, chunk_rows);
+        let buffer = encode_result(&chunk, format, compression_type, offset == 0)?;
+        // The connection is closing, the rest of the result could not be sent
+        if writer.write_frame("AC".to_string(), request_id, Bytes::from(buffer)).await.is_err() {
+            return Ok(("CC".to_string(), Bytes::new()));
+        }
+        offset += chunk_rows;
+    }
+    let last_chunk = record_batch.slice(offset, row_count - offset);
+    let buffer = encode_result(&last_chunk, format, compression_type, false)?;
+    return Ok(("AR".to_string(), Bytes::from(buffer)));
+}
+
+// Format the query asks for its result in. Fails with error code 3 for an unknown one.
+fn result_format(query: &Query) -> Result<ResultFormat, u16> {
+    match query.output_format.as_deref() {
+        None | Some("arrow") => return Ok(ResultFormat::Arrow),
+        Some("csv") => return Ok(ResultFormat::Csv),
+        Some("jsonl") => return Ok(ResultFormat::JsonLines),
+        Some(_) => return Err(3),
+    }
+}
+
+// A GA result in the format the query asked for. `first_chunk` is false for the chunks of a
+// streamed result after the first, which leave out the CSV header. Compression only applies to
+// Arrow results. Fails with error code 5 when a column has a type the format can not hold.
+fn encode_result(
+    record_batch: &RecordBatch, format: ResultFormat, compression_type: &str, first_chunk: bool
+) -> Result<Vec<u8>, u16> {
+    match format {
+        ResultFormat::Arrow => return Ok(write_record_batch(record_batch, compression_type)),
+        ResultFormat::Csv => return write_csv(record_batch, first_chunk),
+        ResultFormat::JsonLines => return write_json_lines(record_batch),
+    }
+}
+
+// A single key is used as is when it exists, otherwise a key containing `*` or `?`
+// selects every Arrow key matching it, in key order
+fn resolve_query_keys(db: &Database, query_key: &QueryKey) -> Vec<String> {
+    match query_key {
+        QueryKey::Single(key) => {
+            if !is_glob(key) || db.shared_db.contains_key(key) {
+                return vec![key.clone()];
+            }
+            let mut keys: Vec<String> = db.shared_db
+                .iter()
+                .filter(|entry| entry.value().first() == Some(&('A' as u8)) && glob_match(key, entry.key()))
+                .map(|entry| entry.key().clone())
+                .collect();
+            keys.sort();
+            return keys;
+        },
+        QueryKey::Many(keys) => keys.clone(),
+    }
+}
+
+// Decodes and concatenates the Arrow values of all keys, which must share a schema
+fn read_union_record_batch(db: &Database, keys: &Vec<String>) -> Result<RecordBatch, u16> {
+    if keys.len() == 0 {
+        return Err(2);
+    }
+    if keys.len() == 1 {
+        return read_record_batch(db, &keys[0]);
+    }
+
+    let mut record_batches: Vec<RecordBatch> = Vec::with_capacity(keys.len());
+    for key in keys {
+        record_batches.push(read_record_batch(db, key)?);
+    }
+    // Keys that differ only in which string columns are dictionary encoded still concatenate
+    if record_batches.iter().any(|record_batch| record_batch.schema() != record_batches[0].schema()) {
+        record_batches = match record_batches.into_iter().map(dictionary_decode).collect() {
+            Ok(decoded_batches) => decoded_batches,
+            Err(_) => return Err(8),
+        };
+    }
+    let schema = record_batches[0].schema();
+    return match concat_batches(&schema, &record_batches) {
+        Ok(rb) => Ok(rb),
+        Err(_) => Err(8),
+    };
+}
+
+// Decodes the Arrow value stored under `key`, or returns the error code to respond with
+fn read_record_batch(db: &Database, key: &str) -> Result<RecordBatch, u16> {
+    let record_batch_bytes = match db.shared_db.get(key) {
+        Some(bytes) => bytes,
+        None => return Err(2),
+    };
+    let version = db.version_db.get(key).map(|version| *version);
+    let chunks = cached_record_batch_chunks(db, key, &record_batch_bytes, version)?;
+    return concat_record_batch_chunks(chunks);
+}
+
+// Decodes the chunks of the Arrow value stored under `key` together with their zone maps and
+// the value's version. Both are looked up while the value is held, and writers update them
+// before releasing the value, so they always describe these exact chunks.
+fn read_record_batch_chunks(db: &Database, key: &str) -> Result<VersionedChunks, u16> {
+    let record_batch_bytes = match db.shared_db.get(key) {
+        Some(bytes) => bytes,
+        None => return Err(2),
+    };
+    let zone_maps = db.zone_db.get(key).map(|zone_maps| Arc::clone(&zone_maps));
+    let version = db.version_db.get(key).map(|version| *version);
+    let chunks = cached_record_batch_chunks(db, key, &record_batch_bytes, version)?;
+    return Ok((chunks, zone_maps, version));
+}
+
+// Chunks of the value stored under `key`, decoded only when this version of it is not in the
+// batch cache. The version must have been read while holding the value.
+fn cached_record_batch_chunks(
+    db: &Database, key: &str, record_batch_bytes: &[u8], version: Option<u64>
+) -> Result<Vec<RecordBatch>, u16> {
+    let capacity = db.settings.batch_cache_size.load(Ordering::Relaxed);
+    let version = match (version, capacity) {
+        (Some(version), 1..) => version,
+        _ => {
+            // The size may have been lowered with CS since the last insert
+            db.batch_cache.shrink_to(capacity);
+            return decode_record_batch_chunks(record_batch_bytes);
+        },
+    };
+    if let Some(chunks) = db.batch_cache.get(key, version) {
+        return Ok(chunks);
+    }
+    let chunks = decode_record_batch_chunks(record_batch_bytes)?;
+    db.batch_cache.insert(key, version, &chunks, capacity);
+    return Ok(chunks);
+}
+
+// Decodes a stored Arrow value as a single batch, concatenating its chunks if it has several
+fn decode_record_batch(record_batch_bytes: &[u8]) -> Result<RecordBatch, u16> {
+    return concat_record_batch_chunks(decode_record_batch_chunks(record_batch_bytes)?);
+}
+
+fn concat_record_batch_chunks(mut chunks: Vec<RecordBatch>) -> Result<RecordBatch, u16> {
+    if chunks.len() == 1 {
+        return Ok(chunks.pop().unwrap());
+    }
+    return match concat_batches(&chunks[0].schema(), &chunks) {
+        Ok(rb) => Ok(rb),
+        Err(_) => Err(4),
+    };
+}
+
+// Every record batch of the stored IPC stream is one chunk
+fn decode_record_batch_chunks(record_batch_bytes: &[u8]) -> Result<Vec<RecordBatch>, u16> {
+    let decompressed = decompress_stored(record_batch_bytes)?;
+    let record_batch_bytes = decompressed.as_deref().unwrap_or(record_batch_bytes);
+    if record_batch_bytes.len() == 0 || record_batch_bytes[0] as char != 'A' {
+        return Err(4);
+    }
+    return decode_ipc_stream(&record_batch_bytes[1..]);
+}
+
+fn decode_ipc_stream(stream_bytes: &[u8]) -> Result<Vec<RecordBatch>, u16> {
+    let reader = match StreamReader::try_new(stream_bytes, None) {
+        Ok(reader) => reader,
+        Err(_) => return Err(4),
+    };
+    let chunks: Vec<RecordBatch> = match reader.collect() {
+        Ok(chunks) => chunks,
+        Err(_) => return Err(4),
+    };
+    if chunks.len() == 0 {
+        return Err(4);
+    }
+    return Ok(chunks);
+}
+
+const IPC_END_OF_STREAM: [u8; 8] = [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0];
+
+// End of the IPC message starting at `offset`, or None at the end marker or end of the
+// stream. Handles both the current and the legacy framing.
+fn ipc_message_end(stream_bytes: &[u8], offset: usize) -> Option<usize> {
+    if stream_bytes.len() < offset + 4 {
+        return None;
+    }
+    let (prefix_len, metadata_len) = if stream_bytes[offset..offset + 4] == IPC_END_OF_STREAM[0..4] {
+        let len_bytes = stream_bytes.get(offset + 4..offset + 8)?;
+        (8, i32::from_le_bytes(len_bytes.try_into().unwrap()) as usize)
+    } else {
+        (4, i32::from_le_bytes(stream_bytes[offset..offset + 4].try_into().unwrap()) as usize)
+    };
+    if metadata_len == 0 {
+        return None;
+    }
+    let metadata_start = offset + prefix_len;
+    let metadata = stream_bytes.get(metadata_start..metadata_start + metadata_len)?;
+    let body_len = root_as_message(metadata).ok()?.bodyLength() as usize;
+    return Some(metadata_start + metadata_len + body_len);
+}
+
+// Length of the schema message that opens an IPC stream
+fn ipc_schema_message_len(stream_bytes: &[u8]) -> usize {
+    return ipc_message_end(stream_bytes, 0).unwrap_or(0);
+}
+
+// Where the messages of an IPC stream end, before its end marker if it has one
+fn ipc_stream_end(stream_bytes: &[u8]) -> usize {
+    let mut offset = 0;
+    while let Some(message_end) = ipc_message_end(stream_bytes, offset) {
+        offset = message_end;
+    }
+    return offset.min(stream_bytes.len());
+}
+
+fn write_record_batch(record_batch: &RecordBatch, compression_type: &str) -> Vec<u8> {
+    let alignment = 64;
+    let write_legacy_ipc_format = false;
+    let mut write_options: IpcWriteOptions = IpcWriteOptions::try_new(
+        alignment, write_legacy_ipc_format, MetadataVersion::V5
+    ).unwrap();
+    if compression_type == "lz4" {
+        write_options = write_options.try_with_compression(Some(CompressionType::LZ4_FRAME)).unwrap();
+    } else if compression_type == "zstd" {
+        write_options = write_options.try_with_compression(Some(CompressionType::ZSTD)).unwrap();
+    }
+
+    let mut writer = StreamWriter::try_new_with_options(
+        Vec::new(), &record_batch.schema(), write_options
+    ).expect("Schema error");
+
+    let _ = writer.write(record_batch);
+    let _ = writer.finish();
+    return writer.into_inner().expect("Buffer error");
+}
+
+// Uncompressed IPC stream of the chunks, which must share a schema
+fn write_record_batch_chunks(chunks: &Vec<RecordBatch>) -> Vec<u8> {
+    let mut writer = StreamWriter::try_new(Vec::new(), &chunks[0].schema()).expect("Schema error");
+    for chunk in chunks {
+        let _ = writer.write(chunk);
+    }
+    let _ = writer.finish();
+    return writer.into_inner().expect("Buffer error");
+}
+
+async fn handle_get_data(db: Db, payload: Vec<u8>) -> Response {
+    let get_key = read_str(&payload)?;
+
+    if !db.shared_db.contains_key(get_key) {
+        if known_missing(&db, get_key) {
+            return Err(18);
+        }
+        load_missing_key(&db, get_key).await;
+    }
+    if let Some(bytes_data) = db.shared_db.get(get_key) {
+        db.stats.record_key_access(get_key);
+        return value_response(&bytes_data);
+    } else {
+        remember_miss(&db, get_key);
+        return Err(2);
+    }
+}
+
+// Same as GD with the key's version (u64) put in front of the response payload
+async fn handle_get_data_versioned(db: Db, payload: Vec<u8>) -> Response {
+    let get_key = read_str(&payload)?;
+
+    if !db.shared_db.contains_key(get_key) {
+        if known_missing(&db, get_key) {
+            return Err(18);
+        }
+        load_missing_key(&db, get_key).await;
+    }
+    if let Some(bytes_data) = db.shared_db.get(get_key) {
+        db.stats.record_key_access(get_key);
+        let version = db.version_db.get(get_key).map(|version| *version).unwrap_or(0);
+        let (response_type, response_payload) = value_response(&bytes_data)?;
+        let mut versioned_payload = version.to_be_bytes().to_vec();
+        versioned_payload.extend(response_payload);
+        return Ok((response_type, Bytes::from(versioned_payload)));
+    } else {
+        remember_miss(&db, get_key);
+        return Err(2);
+    }
+}
+
+// Reads several keys, separated by null characters, as they all were at one moment, so a
+// writer changing some of them is seen either for all or for none. Each key gets a response
+// framed like the ones of an "EX", the versioned payload of GV or error code 2 when the key
+// does not exist.
+async fn handle_get_snapshot(db: Db, payload: Vec<u8>) -> Response {
+    let snapshot_keys_str = read_str(&payload)?;
+    let snapshot_keys: Vec<&str> = snapshot_keys_str.split(0 as char).collect();
+
+    // Versions are never reused, so values whose versions did not change while the others
+    // were read were all current when checking began. Past a few writers getting in between,
+    // the write gate holds them back for the time of one read.
+    let mut snapshot = None;
+    for _ in 0..SNAPSHOT_READ_ATTEMPTS {
+        let values = read_versioned_values(&db, &snapshot_keys);
+        let unchanged = values.iter().zip(snapshot_keys.iter()).all(|(value, key)| {
+            db.version_db.get(*key).map(|version| *version) == value.as_ref().map(|(_, version)| *version)
+        });
+        if unchanged {
+            snapshot = Some(values);
+            break;
+        }
+    }
+    let snapshot = match snapshot {
+        Some(values) => values,
+        None => {
+            let _exclusive_permit = db.write_gate.write().await;
+            read_versioned_values(&db, &snapshot_keys)
+        },
+    };
+
+    let mut responses_payload_bytes: Vec<u8> = Vec::new();
+    for (key, value) in snapshot_keys.iter().zip(snapshot) {
+        let response = match value {
+            Some((stored_value, version)) => {
+                db.record_read(key);
+                value_response(&stored_value).map(|(response_type, response_payload)| {
+                    let mut versioned_payload = version.to_be_bytes().to_vec();
+                    versioned_payload.extend(response_payload);
+                    (response_type, Bytes::from(versioned_payload))
+                })
+            },
+            None => Err(2),
+        };
+        let (response_type, response_payload) = response.unwrap_or_else(error_response);
+        responses_payload_bytes.extend(response_type.as_bytes());
+        responses_payload_bytes.extend((response_payload.len() as u64).to_be_bytes());
+        responses_payload_bytes.extend(response_payload);
+    }
+    return Ok(("GS".to_string(), Bytes::from(responses_payload_bytes)));
+}
+
+// The stored value of each key with its version, None for keys that do not exist. The version
+// is read while holding the value, so it is always the one that belongs to it.
+fn read_versioned_values(db: &Database, keys: &Vec<&str>) -> Vec<Option<(Bytes, u64)>> {
+    return keys
+        .iter()
+        .map(|key| {
+            let stored_value = db.shared_db.get(*key)?;
+            let version = db.version_db.get(*key).map(|version| *version).unwrap_or(0);
+            Some((stored_value.clone(), version))
+        })
+        .collect();
+}
+
+fn value_response(stored_value: &Bytes) -> Response {
+    let bytes_data = decompress_value(stored_value)?;
+    // An empty value has no type tag and falls through to the wrong type error
+    let data_type = bytes_data.first().map(|tag| *tag as char).unwrap_or(' ');
+    if data_type == 'A' {
+        return Ok(("AR".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'B' {
+        return Ok(("BY".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'I' {
+        return Ok(("IN".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'F' {
+        return Ok(("FL".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'L' {
+        return Ok(("LI".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'H' {
+        return Ok(("HA".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'S' {
+        return Ok(("ST".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'J' {
+        return Ok(("JS".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'E' {
+        return Ok(("SM".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'O' {
+        return Ok(("ZS".to_string(), bytes_data.slice(1..)));
+    } else if data_type == 'C' {
+        // Surface the content type so clients can pick the right decoder. A codec id that is
+        // missing or unknown means the value is damaged.
+        let codec = bytes_data.get(1).and_then(|codec_id| lookup_codec(*codec_id)).ok_or(7u16)?;
+        let content_type = codec.content_type.as_bytes();
+        let mut coded_payload = (content_type.len() as u16).to_be_bytes().to_vec();
+        coded_payload.extend(content_type);
+        coded_payload.extend(&bytes_data[2..]);
+        return Ok(("BC".to_string(), Bytes::from(coded_payload)));
+    } else {
+        return Err(5);
+    }
+}
+
+// Changes a collection value, such as a list, while holding its entry so that commands
+// changing the same key one after the other never lose an update. `change` gets the value
+// after its tag, None when the key does not exist, and returns the new value after the tag,
+// None to leave it as it is, along with the response. An empty new value deletes the key.
+fn update_collection<F>(db: &Database, key: &str, tag: u8, change: F) -> Response
+where
+    F: FnOnce(Option<&[u8]>) -> Result<(Option<Vec<u8>>, (String, Bytes)), u16>,
+{
+    let tagged = |value: Vec<u8>| {
+        let mut tagged_value = Vec::with_capacity(value.len() + 1);
+        tagged_value.push(tag);
+        tagged_value.extend(value);
+        Bytes::from(tagged_value)
+    };
+    let response = match db.shared_db.entry(key.to_string()) {
+        dashmap::Entry::Occupied(mut entry) => {
+            if entry.get().first() != Some(&tag) {
+                return Err(5);
+            }
+            let (new_value, response) = change(Some(&entry.get()[1..]))?;
+            match new_value {
+                Some(new_value) if new_value.len() == 0 => {
+                    let removed_value = entry.remove();
+                    db.forget_key_state(key);
+                    db.value_removed(key, &removed_value);
+                },
+                // Replaced rather than written in place, responses may still hold the old value
+                Some(new_value) => {
+                    let new_value = tagged(new_value);
+                    db.memory.value_changed(key, Some(entry.get().len()), Some(new_value.len()));
+                    *entry.get_mut() = new_value;
+                    db.bump_version(key);
+                },
+                None => return Ok(response),
+            }
+            response
+        },
+        dashmap::Entry::Vacant(entry) => {
+            let (new_value, response) = change(None)?;
+            match new_value {
+                Some(new_value) if new_value.len() > 0 => {
+                    if let Some(live_until) = default_expiry(db) {
+                        db.timeout_db.insert(key.to_string(), live_until);
+                    }
+                    db.bump_version(key);
+                    let new_value = tagged(new_value);
+                    db.memory.value_changed(key, None, Some(new_value.len()));
+                    entry.insert(new_value);
+                },
+                _ => return Ok(response),
+            }
+            response
+        },
+    };
+    invalidate_dependents(key, db);
+    return Ok(response);
+}
+
+// Pushes the elements framed after the key (u16 length prefixed) on one end of a list, which
+// is created when the key does not exist. Answers with the new length of the list.
+async fn handle_list_push(db: Db, payload: Vec<u8>, left: bool) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let elements = match read_framed(&payload, key_end)? {
+        elements if elements.len() > 0 => elements,
+        _ => return Err(3),
+    };
+
+    return update_collection(&db, key, LIST_TAG, |list| {
+        let (pushed_list, list_len) = push_elements(list, &elements, left)?;
+        let list_len = list_len as i64;
+        return Ok((Some(pushed_list), ("IN".to_string(), Bytes::copy_from_slice(&list_len.to_be_bytes()))));
+    });
+}
+
+// Pops up to a count (u32) of elements off one end of the list named after it, answering
+// with them framed in the order they were popped. A list left empty is deleted.
+async fn handle_list_pop(db: Db, payload: Vec<u8>, left: bool) -> Response {
+    let count = read_u32(&payload, 0)? as usize;
+    let key = read_rest(&payload, 4).and_then(read_str)?;
+
+    return update_collection(&db, key, LIST_TAG, |list| {
+        let (rest_of_list, popped) = pop_elements(list.ok_or(2u16)?, count, left)?;
+        let new_list = match count {
+            0 => None,
+            _ => Some(rest_of_list),
+        };
+        return Ok((new_list, ("LI".to_string(), Bytes::from(popped))));
+    });
+}
+
+// Elements of the list named after a start (i64) and a stop (i64) position, both included.
+// Negative positions count from the right end.
+async fn handle_list_range(db: Db, payload: Vec<u8>) -> Response {
+    let start = read_i64(&payload, 0)?;
+    let stop = read_i64(&payload, 8)?;
+    let key = read_rest(&payload, 16).and_then(read_str)?;
+
+    let list = read_collection(&db, key, LIST_TAG)?;
+    let elements = list_range(&list[1..], start, stop)?;
+    return Ok(("LI".to_string(), Bytes::from(elements)));
+}
+
+// Sets the fields of a hash to the values framed after its key (u16 length prefixed),
+// alternating field and value. The hash is created when the key does not exist. Answers
+// with how many of the fields were new.
+async fn handle_hash_set(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let pairs = match read_framed(&payload, key_end)? {
+        pairs if pairs.len() > 0 => pairs,
+        _ => return Err(3),
+    };
+
+    return update_collection(&db, key, HASH_TAG, |hash| {
+        let (new_hash, added) = set_fields(hash, &pairs)?;
+        let added = added as i64;
+        return Ok((Some(new_hash), ("IN".to_string(), Bytes::copy_from_slice(&added.to_be_bytes()))));
+    });
+}
+
+// The value of the field that follows the key (u16 length prefixed) of a hash
+async fn handle_hash_get(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let hash = read_collection(&db, key, HASH_TAG)?;
+    match get_field(&hash[1..], &payload[key_end..])? {
+        Some(value) => return Ok(("BY".to_string(), hash.slice_ref(value))),
+        None => return Err(2),
+    }
+}
+
+// Deletes the fields framed after the key (u16 length prefixed) from a hash, answering with
+// how many of them it had. A hash left without fields is deleted.
+async fn handle_hash_delete(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let fields = read_framed(&payload, key_end)?;
+
+    return update_collection(&db, key, HASH_TAG, |hash| {
+        let (new_hash, deleted) = match hash {
+            Some(hash) => delete_fields(hash, &fields)?,
+            None => (Vec::new(), 0),
+        };
+        let new_hash = match deleted {
+            0 => None,
+            _ => Some(new_hash),
+        };
+        let deleted = deleted as i64;
+        return Ok((new_hash, ("IN".to_string(), Bytes::copy_from_slice(&deleted.to_be_bytes()))));
+    });
+}
+
+// Every field of a hash with its value, framed alternating field and value
+async fn handle_hash_get_all(db: Db, payload: Vec<u8>) -> Response {
+    let key = read_str(&payload)?;
+    let hash = read_collection(&db, key, HASH_TAG)?;
+    // Checked so a damaged value is an error rather than a response the client can not read
+    hash_fields(&hash[1..])?;
+    return Ok(("HA".to_string(), hash.slice(1..)));
+}
+
+// Adds the members framed after the key (u16 length prefixed) to a set, which is created
+// when the key does not exist. Answers with how many of them are new.
+async fn handle_set_add(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let members = match read_framed(&payload, key_end)? {
+        members if members.len() > 0 => members,
+        _ => return Err(3),
+    };
+
+    return update_collection(&db, key, SET_TAG, |set| {
+        let (new_set, added) = add_members(set, &members)?;
+        let new_set = match added {
+            0 => None,
+            _ => Some(new_set),
+        };
+        let added = added as i64;
+        return Ok((new_set, ("IN".to_string(), Bytes::copy_from_slice(&added.to_be_bytes()))));
+    });
+}
+
+// Removes the members framed after the key (u16 length prefixed) from a set, answering with
+// how many of them it had. A set left without members is deleted.
+async fn handle_set_remove(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let members = read_framed(&payload, key_end)?;
+
+    return update_collection(&db, key, SET_TAG, |set| {
+        let (new_set, removed) = match set {
+            Some(set) => remove_members(set, &members)?,
+            None => (Vec::new(), 0),
+        };
+        let new_set = match removed {
+            0 => None,
+            _ => Some(new_set),
+        };
+        let removed = removed as i64;
+        return Ok((new_set, ("IN".to_string(), Bytes::copy_from_slice(&removed.to_be_bytes()))));
+    });
+}
+
+// Whether the member that follows the key (u16 length prefixed) is in the set, as an integer
+// of 1 or 0. A key that does not exist is an empty set.
+async fn handle_set_has(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let contained = match read_collection(&db, key, SET_TAG) {
+        Ok(set) => contains_member(&set[1..], &payload[key_end..])?,
+        Err(2) => false,
+        Err(error_code) => return Err(error_code),
+    };
+    return Ok(("IN".to_string(), Bytes::copy_from_slice(&(contained as i64).to_be_bytes())));
+}
+
+// Every member of a set, framed and sorted by their bytes
+async fn handle_set_members(db: Db, payload: Vec<u8>) -> Response {
+    let key = read_str(&payload)?;
+    let set = read_collection(&db, key, SET_TAG)?;
+    return Ok(("SM".to_string(), set.slice(1..)));
+}
+
+// Members of any, or with `intersect` every, one of the sets whose keys are separated by null
+// characters, framed and sorted. Keys that do not exist are empty sets.
+async fn handle_set_combine(db: Db, payload: Vec<u8>, intersect: bool) -> Response {
+    let set_keys_str = read_str(&payload)?;
+
+    let mut sets: Vec<Bytes> = Vec::new();
+    for key in set_keys_str.split(0 as char) {
+        match read_collection(&db, key, SET_TAG) {
+            Ok(set) => sets.push(set.slice(1..)),
+            Err(2) => sets.push(Bytes::new()),
+            Err(error_code) => return Err(error_code),
+        }
+    }
+    let sets: Vec<&[u8]> = sets.iter().map(|set| &set[..]).collect();
+    let members = match intersect {
+        true => intersect_members(&sets)?,
+        false => union_members(&sets)?,
+    };
+    return Ok(("SM".to_string(), Bytes::from(members)));
+}
+
+// Adds the members framed after the key (u16 length prefixed), alternating member and score
+// (f64), to a sorted set, or gives them their new score. The sorted set is created when the
+// key does not exist. Answers with how many of the members are new.
+async fn handle_sorted_set_add(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let entries = match read_framed(&payload, key_end).and_then(|elements| scored_members(&elements))? {
+        entries if entries.len() > 0 => entries,
+        _ => return Err(3),
+    };
+
+    return update_collection(&db, key, SORTED_SET_TAG, |sorted_set| {
+        let (new_sorted_set, added) = add_entries(sorted_set, &entries)?;
+        let added = added as i64;
+        return Ok((Some(new_sorted_set), ("IN".to_string(), Bytes::copy_from_slice(&added.to_be_bytes()))));
+    });
+}
+
+// Removes the members framed after the key (u16 length prefixed) from a sorted set,
+// answering with how many of them it had. A sorted set left without members is deleted.
+async fn handle_sorted_set_remove(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let members = read_framed(&payload, key_end)?;
+
+    return update_collection(&db, key, SORTED_SET_TAG, |sorted_set| {
+        let (new_sorted_set, removed) = match sorted_set {
+            Some(sorted_set) => remove_entries(sorted_set, &members)?,
+            None => (Vec::new(), 0),
+        };
+        let new_sorted_set = match removed {
+            0 => None,
+            _ => Some(new_sorted_set),
+        };
+        let removed = removed as i64;
+        return Ok((new_sorted_set, ("IN".to_string(), Bytes::copy_from_slice(&removed.to_be_bytes()))));
+    });
+}
+
+// Score of the member that follows the key (u16 length prefixed) of a sorted set
+async fn handle_sorted_set_score(db: Db, payload: Vec<u8>) -> Response {
+    let (score, _) = read_member_position(&db, &payload)?;
+    return Ok(("FL".to_string(), Bytes::copy_from_slice(&score.to_be_bytes())));
+}
+
+// Rank of the member that follows the key (u16 length prefixed) of a sorted set, 0 for the
+// lowest score
+async fn handle_sorted_set_rank(db: Db, payload: Vec<u8>) -> Response {
+    let (_, rank) = read_member_position(&db, &payload)?;
+    return Ok(("IN".to_string(), Bytes::copy_from_slice(&(rank as i64).to_be_bytes())));
+}
+
+fn read_member_position(db: &Database, payload: &[u8]) -> Result<(f64, usize), u16> {
+    let (key, key_end) = read_prefixed_key(payload, 0)?;
+    let sorted_set = read_collection(db, key, SORTED_SET_TAG)?;
+    return member_position(&sorted_set[1..], &payload[key_end..])?.ok_or(2);
+}
+
+// Members of the sorted set named after a minimum (f64) and a maximum (f64) score, both
+// included, framed alternating member and score in order
+async fn handle_sorted_set_range_by_score(db: Db, payload: Vec<u8>) -> Response {
+    let min = read_f64(&payload, 0)?;
+    let max = read_f64(&payload, 8)?;
+    let key = read_rest(&payload, 16).and_then(read_str)?;
+
+    let entries = read_collection(&db, key, SORTED_SET_TAG)
+        .and_then(|sorted_set| range_by_score(&sorted_set[1..], min, max))?;
+    return Ok(("ZS".to_string(), Bytes::from(entries)));
+}
+
+// Members of the sorted set named after a start (i64) and a stop (i64) rank, both included,
+// framed alternating member and score in order. Negative ranks count from the highest score.
+async fn handle_sorted_set_range_by_rank(db: Db, payload: Vec<u8>) -> Response {
+    let start = read_i64(&payload, 0)?;
+    let stop = read_i64(&payload, 8)?;
+    let key = read_rest(&payload, 16).and_then(read_str)?;
+
+    let entries = read_collection(&db, key, SORTED_SET_TAG)
+        .and_then(|sorted_set| range_by_rank(&sorted_set[1..], start, stop))?;
+    return Ok(("ZS".to_string(), Bytes::from(entries)));
+}
+
+// The stored value of a collection key, tag included, counted as a read of the key. Fails
+// with error code 2 when the key does not exist and 5 when it holds another type.
+fn read_collection(db: &Database, key: &str, tag: u8) -> Result<Bytes, u16> {
+    let stored_value = match db.shared_db.get(key) {
+        Some(stored_value) => {
+            db.stats.record_key_access(key);
+            stored_value.clone()
+        },
+        None => return Err(2),
+    };
+    if stored_value.first() != Some(&tag) {
+        return Err(5);
+    }
+    return Ok(stored_value);
+}
+
+// Changes a value of type `tag`, such as a byte value, in place while holding its entry, so
+// commands changing the same key one after the other never lose an update. `change` gets the
+// bytes after the tag, empty when the key does not exist, and returns whether it changed them
+// along with the response. The bytes are copied only when responses still hold them, and are
+// kept uncompressed so the next change does not have to decompress them again.
+fn update_in_place<F>(db: &Database, key: &str, tag: u8, change: F) -> Response
+where
+    F: FnOnce(&mut BytesMut) -> Result<(bool, (String, Bytes)), u16>,
+{
+    let response = match db.shared_db.entry(key.to_string()) {
+        dashmap::Entry::Occupied(mut entry) => {
+            let value = decompress_value(entry.get())?;
+            if value.first() != Some(&tag) {
+                return Err(5);
+            }
+            // The entry lets go of the value, so it is the only owner unless responses hold it
+            let previous_len = entry.get().len();
+            drop(std::mem::take(entry.get_mut()));
+            let mut value = match value.try_into_mut() {
+                Ok(unshared_value) => unshared_value,
+                Err(shared_value) => BytesMut::from(&shared_value[..]),
+            };
+            let mut bytes_data = value.split_off(1);
+            let changed = change(&mut bytes_data);
+            value.unsplit(bytes_data);
+            db.memory.value_changed(key, Some(previous_len), Some(value.len()));
+            *entry.get_mut() = value.freeze();
+            match changed? {
+                (true, response) => {
+                    db.bump_version(key);
+                    response
+                },
+                (false, response) => return Ok(response),
+            }
+        },
+        dashmap::Entry::Vacant(entry) => {
+            let mut bytes_data = BytesMut::new();
+            match change(&mut bytes_data)? {
+                (true, response) => {
+                    let mut value = BytesMut::with_capacity(bytes_data.len() + 1);
+                    value.extend_from_slice(&[tag]);
+                    value.extend_from_slice(&bytes_data);
+                    if let Some(live_until) = default_expiry(db) {
+                        db.timeout_db.insert(key.to_string(), live_until);
+                    }
+                    db.bump_version(key);
+                    db.memory.value_changed(key, None, Some(value.len()));
+                    entry.insert(value.freeze());
+                    response
+                },
+                (false, response) => return Ok(response),
+            }
+        },
+    };
+    invalidate_dependents(key, db);
+    return Ok(response);
+}
+
+// The bytes after the tag of a byte value, counted as a read of the key. Fails with error
+// code 2 when the key does not exist and 5 when it holds another type.
+fn read_byte_value(db: &Database, key: &str) -> Result<Bytes, u16> {
+    let stored_value = match db.shared_db.get(key) {
+        Some(stored_value) => {
+            db.stats.record_key_access(key);
+            stored_value.clone()
+        },
+        None => return Err(2),
+    };
+    let value = decompress_value(&stored_value)?;
+    if value.first() != Some(&b'B') {
+        return Err(5);
+    }
+    return Ok(value.slice(1..));
+}
+
+// Sets the bit at an offset (u64) to a value (u8 of 0 or 1) in the byte value named after
+// them, which grows with zero bytes to reach it or is created when the key does not exist.
+// Answers with the previous value of the bit.
+async fn handle_set_bit(db: Db, payload: Vec<u8>) -> Response {
+    let offset = match read_u64(&payload, 0)? {
+        value if value <= MAX_BIT_OFFSET => value,
+        _ => return Err(3),
+    };
+    let bit = match payload.get(8) {
+        Some(0) => false,
+        Some(1) => true,
+        Some(_) => return Err(3),
+        None => return Err(10),
+    };
+    let key = read_rest(&payload, 9).and_then(read_str)?;
+
+    return update_in_place(&db, key, b'B', |bits| {
+        let stored_len = bits.len();
+        let previous = set_bit(bits, offset, bit) as i64;
+        let changed = previous != bit as i64 || bits.len() != stored_len;
+        return Ok((changed, ("IN".to_string(), Bytes::copy_from_slice(&previous.to_be_bytes()))));
+    });
+}
+
+// The bit at an offset (u64) of the byte value named after it, 0 past its end or when the key
+// does not exist
+async fn handle_get_bit(db: Db, payload: Vec<u8>) -> Response {
+    let offset = read_u64(&payload, 0)?;
+    let key = read_rest(&payload, 8).and_then(read_str)?;
+
+    let bit = match read_byte_value(&db, key) {
+        Ok(bits) => get_bit(&bits, offset),
+        Err(2) => false,
+        Err(error_code) => return Err(error_code),
+    };
+    return Ok(("IN".to_string(), Bytes::copy_from_slice(&(bit as i64).to_be_bytes())));
+}
+
+// Bits set in the byte value named after a start (i64) and an end (i64) byte, both included.
+// Negative positions count from the last byte, 0 and -1 count the whole value.
+async fn handle_count_bits(db: Db, payload: Vec<u8>) -> Response {
+    let start = read_i64(&payload, 0)?;
+    let end = read_i64(&payload, 8)?;
+    let key = read_rest(&payload, 16).and_then(read_str)?;
+
+    let count = match read_byte_value(&db, key) {
+        Ok(bits) => count_bits(&bits, start, end) as i64,
+        Err(2) => 0,
+        Err(error_code) => return Err(error_code),
+    };
+    return Ok(("IN".to_string(), Bytes::copy_from_slice(&count.to_be_bytes())));
+}
+
+// Appends the bytes that follow the key (u16 length prefixed) to a byte value, which is
+// created when the key does not exist. Answers with the new length of the value.
+async fn handle_append_bytes(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let appended = &payload[key_end..];
+
+    return update_in_place(&db, key, b'B', |bytes_data| {
+        bytes_data.extend_from_slice(appended);
+        let new_len = bytes_data.len() as i64;
+        return Ok((appended.len() > 0, ("IN".to_string(), Bytes::copy_from_slice(&new_len.to_be_bytes()))));
+    });
+}
+
+// Bytes of the byte value named after a start (i64) and an end (i64) position, both included.
+// Negative positions count from the last byte.
+async fn handle_get_byte_range(db: Db, payload: Vec<u8>) -> Response {
+    let start = read_i64(&payload, 0)?;
+    let end = read_i64(&payload, 8)?;
+    let key = read_rest(&payload, 16).and_then(read_str)?;
+
+    let bytes_data = read_byte_value(&db, key)?;
+    match range_bounds(bytes_data.len(), start, end) {
+        Some((start, end)) => return Ok(("BY".to_string(), bytes_data.slice(start..=end))),
+        None => return Ok(("BY".to_string(), Bytes::new())),
+    }
+}
+
+// Overwrites a byte value from an offset (u64) with the bytes that follow the key (u16 length
+// prefixed), padding it with zero bytes to reach the offset. The value is created when the
+// key does not exist. Answers with the new length of the value.
+async fn handle_set_byte_range(db: Db, payload: Vec<u8>) -> Response {
+    let offset = read_u64(&payload, 0)?;
+    let (key, key_end) = read_prefixed_key(&payload, 8)?;
+    let written = &payload[key_end..];
+    if offset.saturating_add(written.len() as u64) > MAX_BYTE_RANGE_END {
+        return Err(3);
+    }
+
+    return update_in_place(&db, key, b'B', |bytes_data| {
+        let offset = offset as usize;
+        let range_end = offset + written.len();
+        if written.len() > 0 {
+            if bytes_data.len() < range_end {
+                bytes_data.resize(range_end, 0);
+            }
+            bytes_data[offset..range_end].copy_from_slice(written);
+        }
+        let new_len = bytes_data.len() as i64;
+        return Ok((written.len() > 0, ("IN".to_string(), Bytes::copy_from_slice(&new_len.to_be_bytes()))));
+    });
+}
+
+// Adds the elements framed after the key (u16 length prefixed) to a HyperLogLog, which is
+// created when the key does not exist. Answers with 1 when the estimated count may have
+// changed and 0 otherwise.
+async fn handle_hyperloglog_add(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let elements = read_framed(&payload, key_end)?;
+
+    return update_in_place(&db, key, HYPERLOGLOG_TAG, |registers| {
+        let created = registers.len() == 0;
+        if created {
+            registers.resize(REGISTERS, 0);
+        }
+        hyperloglog_registers(registers)?;
+        let mut changed = created;
+        for element in elements {
+            changed |= add_element(registers, element);
+        }
+        return Ok((changed, ("IN".to_string(), Bytes::copy_from_slice(&(changed as i64).to_be_bytes()))));
+    });
+}
+
+// Estimated distinct elements added to any of the HyperLogLogs whose keys are separated by
+// null characters. Keys that do not exist count as empty.
+async fn handle_hyperloglog_count(db: Db, payload: Vec<u8>) -> Response {
+    let hyperloglog_keys_str = read_str(&payload)?;
+
+    let mut hyperloglogs: Vec<Bytes> = Vec::new();
+    for key in hyperloglog_keys_str.split(0 as char) {
+        match read_collection(&db, key, HYPERLOGLOG_TAG) {
+            Ok(hyperloglog) => hyperloglogs.push(hyperloglog.slice(1..)),
+            Err(2) => {},
+            Err(error_code) => return Err(error_code),
+        }
+    }
+    let mut registers: Vec<&[u8]> = Vec::with_capacity(hyperloglogs.len());
+    for hyperloglog in hyperloglogs.iter() {
+        registers.push(hyperloglog_registers(hyperloglog)?);
+    }
+    let count = estimate_count(&registers) as i64;
+    return Ok(("IN".to_string(), Bytes::copy_from_slice(&count.to_be_bytes())));
+}
+
+// Creates a Bloom filter sized for the capacity (u64) and false positive rate (f64) after the
+// key (u16 length prefixed). Answers with 1 when it created the filter and 0 when the key
+// already holds one, which is left as it is.
+async fn handle_bloom_reserve(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let capacity = read_u64(&payload, key_end)?;
+    let false_positive_rate = read_f64(&payload, key_end + 8)?;
+    let filter = new_filter(capacity, false_positive_rate)?;
+
+    return update_in_place(&db, key, BLOOM_TAG, |bits| {
+        let created = bits.len() == 0;
+        if created {
+            bits.extend_from_slice(&filter);
+        }
+        return Ok((created, ("IN".to_string(), Bytes::copy_from_slice(&(created as i64).to_be_bytes()))));
+    });
+}
+
+// Adds the elements framed after the key (u16 length prefixed) to a Bloom filter, which is
+// created for 10000 elements at a 1% false positive rate when the key does not exist. Answers
+// with how many of the elements had certainly not been added before.
+async fn handle_bloom_add(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let elements = read_framed(&payload, key_end)?;
+
+    return update_in_place(&db, key, BLOOM_TAG, |filter| {
+        let created = filter.len() == 0;
+        if created {
+            filter.extend_from_slice(&new_filter(DEFAULT_CAPACITY, DEFAULT_FALSE_POSITIVE_RATE)?);
+        }
+        check_filter(filter)?;
+        let added = elements.iter().filter(|element| add_to_filter(filter, element)).count() as i64;
+        return Ok((created || added > 0, ("IN".to_string(), Bytes::copy_from_slice(&added.to_be_bytes()))));
+    });
+}
+
+// Whether each element framed after the key (u16 length prefixed) may have been added to the
+// Bloom filter, one byte per element: 1 when it may have been and 0 when it certainly was not.
+// A key that does not exist has none of them.
+async fn handle_bloom_check(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let elements = read_framed(&payload, key_end)?;
+
+    let filter = match read_collection(&db, key, BLOOM_TAG) {
+        Ok(filter) => filter,
+        Err(2) => return Ok(("BY".to_string(), Bytes::from(vec![0u8; elements.len()]))),
+        Err(error_code) => return Err(error_code),
+    };
+    check_filter(&filter[1..])?;
+    let found: Vec<u8> = elements.iter().map(|element| might_contain(&filter[1..], element) as u8).collect();
+    return Ok(("BY".to_string(), Bytes::from(found)));
+}
+
+// Creates a top-k sketch keeping the number of heavy hitters (u32) after the key (u16 length
+// prefixed), with a count-min sketch of the width and depth (u32 each) that follow. Wider
+// sketches count more precisely and deeper ones are more often right. Answers with 1 when it
+// created the sketch and 0 when the key already holds one, which is left as it is.
+async fn handle_top_k_reserve(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let sizes = read_u32(&payload, key_end)
+        .and_then(|top| Ok((top, read_u32(&payload, key_end + 4)?, read_u32(&payload, key_end + 8)?)));
+    let sketch = sizes.and_then(|(top, width, depth)| new_sketch(top, width, depth))?;
+
+    return update_in_place(&db, key, TOP_K_TAG, |counters| {
+        let created = counters.len() == 0;
+        if created {
+            counters.extend_from_slice(&sketch);
+        }
+        return Ok((created, ("IN".to_string(), Bytes::copy_from_slice(&(created as i64).to_be_bytes()))));
+    });
+}
+
+// Adds the increment (u64) after the key (u16 length prefixed) to the counts of the elements
+// framed after it, in a top-k sketch created for 10 heavy hitters when the key does not exist.
+// Answers with the estimated count of each element after it (u64 each), so an increment of 0
+// reads the counts.
+async fn handle_top_k_add(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let by = read_u64(&payload, key_end)?;
+    let elements = read_framed(&payload, key_end + 8)?;
+
+    return update_in_place(&db, key, TOP_K_TAG, |sketch| {
+        let created = sketch.len() == 0;
+        if created {
+            sketch.extend_from_slice(&new_sketch(DEFAULT_TOP, DEFAULT_WIDTH, DEFAULT_DEPTH)?);
+        }
+        let counts = increment(sketch, &elements, by)?;
+        let counts: Vec<u8> = counts.iter().flat_map(|count| count.to_be_bytes()).collect();
+        let changed = created || (by > 0 && elements.len() > 0);
+        return Ok((changed, ("BY".to_string(), Bytes::from(counts))));
+    });
+}
+
+// The heavy hitters of a top-k sketch, framed as element and estimated count (u64) in turn,
+// highest count first. A key that does not exist has none.
+async fn handle_top_k_list(db: Db, payload: Vec<u8>) -> Response {
+    let (key, _) = read_prefixed_key(&payload, 0)?;
+
+    let sketch = match read_collection(&db, key, TOP_K_TAG) {
+        Ok(sketch) => sketch,
+        Err(2) => return Ok(("TK".to_string(), Bytes::new())),
+        Err(error_code) => return Err(error_code),
+    };
+    let heavy_hitters = top_elements(&sketch[1..])?;
+    return Ok(("TK".to_string(), sketch.slice_ref(heavy_hitters)));
+}
+
+// The part of a JSON document at the path that follows its key (u16 length prefixed), as
+// JSON text
+async fn handle_json_get(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let steps = read_str(&payload[key_end..]).and_then(parse_path)?;
+    let json = read_collection(&db, key, JSON_TAG)?;
+    let document: serde_json::Value = match serde_json::from_slice(&json[1..]) {
+        Ok(document) => document,
+        Err(_) => return Err(12),
+    };
+    match get_path(&document, &steps) {
+        Some(part) => return Ok(("JS".to_string(), Bytes::from(part.to_string()))),
+        None => return Err(2),
+    }
+}
+
+// Sets the part of a JSON document at a path to the JSON text that follows them, both key
+// and path being u16 length prefixed. A key that does not exist is created by setting the
+// whole document with the path "$".
+async fn handle_json_set(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let (path, path_end) = read_prefixed_key(&payload, key_end)?;
+    let steps = parse_path(path)?;
+    let value: serde_json::Value = match serde_json::from_slice(&payload[path_end..]) {
+        Ok(value) => value,
+        Err(_) => return Err(3),
+    };
+
+    return update_collection(&db, key, JSON_TAG, |json| {
+        let mut document: serde_json::Value = match json {
+            Some(json) => serde_json::from_slice(json).map_err(|_| 12u16)?,
+            None if steps.len() == 0 => serde_json::Value::Null,
+            None => return Err(2),
+        };
+        set_path(&mut document, &steps, value)?;
+        return Ok((Some(document.to_string().into_bytes()), ("OK".to_string(), Bytes::new())));
+    });
+}
+
+// Removes the part of a JSON document at the path that follows its key (u16 length prefixed),
+// answering with 1 when it was there and 0 otherwise
+async fn handle_json_delete(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_end) = read_prefixed_key(&payload, 0)?;
+    let steps = read_str(&payload[key_end..]).and_then(parse_path)?;
+
+    return update_collection(&db, key, JSON_TAG, |json| {
+        let mut document: serde_json::Value = match json {
+            Some(json) => serde_json::from_slice(json).map_err(|_| 12u16)?,
+            None => return Ok((None, ("IN".to_string(), Bytes::copy_from_slice(&0i64.to_be_bytes())))),
+        };
+        let deleted = delete_path(&mut document, &steps)?;
+        let new_json = match deleted {
+            true => Some(document.to_string().into_bytes()),
+            false => None,
+        };
+        let deleted = deleted as i64;
+        return Ok((new_json, ("IN".to_string(), Bytes::copy_from_slice(&deleted.to_be_bytes()))));
+    });
+}
+
+async fn handle_delete(db: Db, payload: Vec<u8>) -> Response {
+    let del_key = read_str(&payload)?;
+
+    invalidate_dependents(del_key, &db);
+    if db.remove_key(del_key) {
+        return Ok(("OK".to_string(), Bytes::new()));
+    } else {
+        return Err(2);
+    }
+}
+
+async fn handle_touch(db: Db, payload: Vec<u8>) -> Response {
+    let cache_time_ms = read_u64(&payload, 0)?;
+
+    let key = read_rest(&payload, 8).and_then(read_str)?.to_string();
+
+    if db.shared_db.contains_key(&key) {
+        let now = SystemTime::now();
+        let duration = Duration::from_millis(cache_time_ms);
+        db.timeout_db.insert(key, now + duration);
+        return Ok(("OK".to_string(), Bytes::new()));
+    } else {
+        return Err(2);
+    }
+}
+
+// Like TH, but the expiry is an absolute Unix timestamp in milliseconds
+async fn handle_touch_at(db: Db, payload: Vec<u8>) -> Response {
+    let expire_at_ms = read_u64(&payload, 0)?;
+
+    let key = read_rest(&payload, 8).and_then(read_str)?.to_string();
+
+    if !db.shared_db.contains_key(&key) {
+        return Err(2);
+    }
+
+    let live_until = UNIX_EPOCH + Duration::from_millis(expire_at_ms);
+    if live_until <= SystemTime::now() {
+        // Already past, the key goes right away instead of at the next expiry sweep
+        invalidate_dependents(&key, &db);
+        db.remove_key(&key);
+    } else {
+        db.timeout_db.insert(key, live_until);
+    }
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+async fn handle_ttl(db: Db, payload: Vec<u8>) -> Response {
+    let ttl_key = read_str(&payload)?;
+
+    if let Some(live_until) = db.timeout_db.get(ttl_key) {
+        let now = SystemTime::now();
+        match live_until.duration_since(now) {
+            Ok(ttl) => {
+                let ttl_u64 = ttl.as_millis() as u64;
+                return Ok(("TL".to_string(), Bytes::copy_from_slice(&ttl_u64.to_be_bytes())));
+            }
+            Err(_e) => return Err(0),
+        }
+    } else {
+        // A TL of 0 means expiring now, keys that never expire get their own response
+        if db.shared_db.contains_key(ttl_key) {
+            return Ok(("NT".to_string(), Bytes::new()));
+        } else {
+            return Err(2);
+        }
+    }
+}
+
+// One i64 per requested key, in order: the remaining milliseconds, -1 for a key without
+// expiry and -2 for a missing or already expired key
+async fn handle_ttl_many(db: Db, payload: Vec<u8>) -> Response {
+    let ttl_keys_str = read_str(&payload)?;
+    let ttl_keys: Vec<&str> = ttl_keys_str.split(0 as char).collect();
+    let mut ttls_payload_bytes: Vec<u8> = Vec::with_capacity(ttl_keys.len() * 8);
+
+    let now = SystemTime::now();
+    for key in ttl_keys {
+        let ttl: i64 = match db.timeout_db.get(key) {
+            Some(live_until) => match live_until.duration_since(now) {
+                Ok(ttl) => ttl.as_millis() as i64,
+                Err(_e) => -2,
+            },
+            None => {
+                if db.shared_db.contains_key(key) { -1 } else { -2 }
+            },
+        };
+        ttls_payload_bytes.extend(ttl.to_be_bytes());
+    }
+    return Ok(("TM".to_string(), Bytes::from(ttls_payload_bytes)));
+}
+
+// Same as TH for every key, answers with the number of keys that exist as a u16, which stays
+// at 65535 when more of them were touched
+async fn handle_touch_many(db: Db, payload: Vec<u8>) -> Response {
+    let cache_time_ms = read_u64(&payload, 0)?;
+    let touch_keys_str = read_str(&payload[8..])?;
+    let touch_keys: Vec<&str> = touch_keys_str.split(0 as char).collect();
+    let mut count: u16 = 0;
+
+    let live_until = SystemTime::now() + Duration::from_millis(cache_time_ms);
+    for key in touch_keys {
+        if db.shared_db.contains_key(key) {
+            db.timeout_db.insert(key.to_string(), live_until);
+            count = count.saturating_add(1);
+        }
+    }
+    return Ok(("HM".to_string(), Bytes::copy_from_slice(&count.to_be_bytes())));
+}
+
+// Removes the key's expiry, the response payload is 1 if it had one and 0 otherwise
+async fn handle_persist(db: Db, payload: Vec<u8>) -> Response {
+    let persist_key = read_str(&payload)?;
+
+    if !db.shared_db.contains_key(persist_key) {
+        return Err(2);
+    }
+    let had_ttl = db.timeout_db.remove(persist_key).is_some();
+    return Ok(("PS".to_string(), Bytes::from(vec![had_ttl as u8])));
+}
+
+async fn handle_list_keys(db: Db) -> Response {
+    let mut keys_payload_bytes: Vec<u8> = Vec::new();
+
+    for entry in db.shared_db.iter() {
+        keys_payload_bytes.extend(entry.key().as_bytes());
+        keys_payload_bytes.push(0);
+    }
+    keys_payload_bytes.pop();
+    return Ok(("KY".to_string(), Bytes::from(keys_payload_bytes)));
+}
+
+// Pages through the keys with a cursor so large keyspaces are never listed in one call.
+// Payload is the cursor (u64), 0 to start, the page size (u32) and an optional glob pattern
+// the keys of the page are matched against. Answers the next cursor, 0 after the last page,
+// and the matching keys separated by null characters.
+async fn handle_scan_keys(db: Db, payload: Vec<u8>) -> Response {
+    let cursor = read_u64(&payload, 0)?;
+    let count = read_u32(&payload, 8)?;
+    let pattern = read_rest(&payload, 12).and_then(read_str)?.to_string();
+
+    let (next_cursor, keys) = run_blocking(move || db.scan_keys(cursor, count as usize)).await?;
+    let mut scan_payload_bytes: Vec<u8> = next_cursor.to_be_bytes().to_vec();
+    for key in keys {
+        if pattern.is_empty() || glob_match(&pattern, &key) {
+            scan_payload_bytes.extend(key.as_bytes());
+            scan_payload_bytes.push(0);
+        }
+    }
+    if scan_payload_bytes.len() > 8 {
+        scan_payload_bytes.pop();
+    }
+    return Ok(("SN".to_string(), Bytes::from(scan_payload_bytes)));
+}
+
+async fn handle_delete_many(db: Db, payload: Vec<u8>) -> Response {
+    let del_keys_str = read_str(&payload)?;
+    let del_keys: Vec<&str> = del_keys_str.split(0 as char).collect();
+    let mut count: u16 = 0;
+
+    for key in del_keys {
+        invalidate_dependents(key, &db);
+        if db.remove_key(key) {
+            count += 1;
+        }
+    }
+    return Ok(("DM".to_string(), Bytes::copy_from_slice(&count.to_be_bytes())));
+}
+
+// Deletes every key of the connection's namespace. Answers how many keys there were.
+async fn handle_flush(db: Db) -> Response {
+    let count = run_blocking(move || {
+        let keys: Vec<String> = db.shared_db.iter().map(|entry| entry.key().clone()).collect();
+        let mut count: u64 = 0;
+        for key in keys {
+            invalidate_dependents(&key, &db);
+            if db.remove_key(&key) {
+                count += 1;
+            }
+        }
+        return count;
+    }).await?;
+    return Ok(("FL".to_string(), Bytes::copy_from_slice(&count.to_be_bytes())));
+}
+
+// Empties the namespace named in the payload, or the connection's own for an empty payload,
+// by swapping in fresh maps. The old ones are freed on the blocking pool, so the flush takes
+// no shard locks and answers right away whatever the number of keys.
+async fn handle_flush_async(namespaces: &Namespaces, namespace: &str, payload: Vec<u8>) -> Response {
+    let target = match read_str(&payload)? {
+        "" => namespace,
+        valid_str => valid_str,
+    };
+    if let Some(flushed_db) = namespaces.empty(target) {
+        flushed_db.record_change("FA", None, None);
+        tokio::task::spawn_blocking(move || {
+            let mut freed_bytes: u64 = 0;
+            for entry in flushed_db.shared_db.iter() {
+                freed_bytes += entry.value().len() as u64;
+                flushed_db.memory.value_changed(entry.key(), Some(entry.value().len()), None);
+                flushed_db.stats.forget_key(entry.key());
+            }
+            flushed_db.stats.freed_value_bytes.fetch_add(freed_bytes, Ordering::Relaxed);
+            drop(flushed_db);
+        });
+    }
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+// The key in the envelope RS restores it from, with its type tag, value and time to live
+async fn handle_dump(db: Db, payload: Vec<u8>) -> Response {
+    let dump_key = read_str(&payload)?;
+    let envelope = dump_key_envelope(&db, dump_key)?;
+    return Ok(("DU".to_string(), Bytes::from(envelope)));
+}
+
+fn dump_key_envelope(db: &Database, key: &str) -> Result<Vec<u8>, u16> {
+    let stored_value = db.shared_db.get(key).map(|stored_value| stored_value.clone()).ok_or(2u16)?;
+    // A key past its expiry that was not removed yet is restored expiring at once
+    let ttl_ms = match db.timeout_db.get(key) {
+        Some(live_until) => live_until
+            .duration_since(SystemTime::now())
+            .map(|ttl| ttl.as_millis() as u64)
+            .unwrap_or(0)
+            .max(1),
+        None => 0,
+    };
+    let value = decompress_value(&stored_value)?;
+    return Ok(dump_envelope(&value, ttl_ms));
+}
+
+// Moves a key with its time to live to another instance, into the namespace of the same name,
+// and deletes it here once that instance stored it. Payload is the u64 milliseconds to wait for
+// the other instance, 0 for MIGRATE_TIMEOUT and at most MAX_MIGRATE_TIMEOUT, a u8 1 to replace
+// the key there when it exists, else 0, the key prefixed with its u16 length and the host:port
+// of the instance. Only the key is locked while it moves, and it is deleted here only if it was
+// not written meanwhile. Answers with a u8 1 when the key was moved and 0 when the other
+// instance had it and kept it, or when it was written here while on its way, in which case it
+// is kept here as well. Fails with error code 3 when the address is this server's own, and 16
+// when the other instance could not be reached in time or did not store the key.
+async fn handle_migrate(db: Db, payload: Vec<u8>) -> Response {
+    let timeout = match read_u64(&payload, 0)? {
+        0 => MIGRATE_TIMEOUT,
+        value => Duration::from_millis(value).min(MAX_MIGRATE_TIMEOUT),
+    };
+    let replace = match payload.get(8) {
+        Some(replace_byte) => *replace_byte == 1,
+        None => return Err(10),
+    };
+    let (migrate_key, key_end) = read_prefixed_key(&payload, 9)?;
+    let migrate_key = migrate_key.to_string();
+    let address = match read_str(&payload[key_end..])? {
+        valid_str if valid_str.len() > 0 => valid_str.to_string(),
+        _ => return Err(3),
+    };
+
+    let _key_guard = db.key_locks.lock(&migrate_key).await;
+    let version = db.version_db.get(&migrate_key).map(|version| *version);
+    let envelope = dump_key_envelope(&db, &migrate_key)?;
+    let mut restore_payload: Vec<u8> = Vec::with_capacity(3 + migrate_key.len() + envelope.len());
+    restore_payload.push(replace as u8);
+    restore_payload.extend((migrate_key.len() as u16).to_be_bytes());
+    restore_payload.extend(migrate_key.as_bytes());
+    restore_payload.extend(envelope);
+    let mut requests: Vec<(&str, Vec<u8>)> = Vec::new();
+    if db.namespace != DEFAULT_NAMESPACE {
+        requests.push(("SE", db.namespace.as_bytes().to_vec()));
+    }
+    requests.push(("RS", restore_payload));
+
+    let migration = async {
+        return match connect_instance(&address, &db.bind_addresses).await? {
+            Some(mut stream) => request_instance(&mut stream, requests).await.map(Some),
+            None => Ok(None),
+        };
+    };
+    let responses = match tokio::time::timeout(timeout, migration).await {
+        Ok(Ok(Some(responses))) => responses,
+        Ok(Ok(None)) => return Err(3),
+        Ok(Err(e)) => {
+            tracing::warn!("Migrating {} to {} failed: {}", migrate_key, address, e);
+            return Err(16);
+        },
+        Err(_) => {
+            tracing::warn!("Migrating {} to {} timed out", migrate_key, address);
+            return Err(16);
+        },
+    };
+    // Every request before the RS must have succeeded, or it went to the wrong namespace
+    let mut restored = false;
+    for (response_type, response_payload) in responses {
+        match response_type.as_str() {
+            "OK" => {},
+            "RS" => restored = response_payload[..] == [1],
+            _ => {
+                tracing::warn!("{} refused {}: {} {:?}", address, migrate_key, response_type, response_payload);
+                return Err(16);
+            },
+        }
+    }
+    if !restored {
+        return Ok(("MG".to_string(), Bytes::from(vec![0u8])));
+    }
+    // Writes that do not take the key lock, such as SD, give the key a new version
+    let unchanged = |_: &String, _: &Bytes| db.version_db.get(&migrate_key).map(|version| *version) == version;
+    match db.shared_db.remove_if(&migrate_key, unchanged) {
+        Some((_, value)) => {
+            db.forget_key_state(&migrate_key);
+            db.value_removed(&migrate_key, &value);
+            invalidate_dependents(&migrate_key, &db);
+            return Ok(("MG".to_string(), Bytes::from(vec![1u8])));
+        },
+        None => {
+            tracing::warn!("{} was written while moving to {}, keeping the newer value here", migrate_key, address);
+            return Ok(("MG".to_string(), Bytes::from(vec![0u8])));
+        },
+    }
+}
+
+// Stores a key from a DU envelope with the time to live it had when dumped. Payload is a u8 1
+// to replace the key when it exists, else 0, then the key prefixed with its u16 length and the
+// envelope. Answers with a u8 1 when the key was restored and 0 when it existed.
+async fn handle_restore(db: Db, payload: Vec<u8>) -> Response {
+    let replace = match payload.first() {
+        Some(replace_byte) => *replace_byte == 1,
+        None => return Err(10),
+    };
+    let (restore_key, key_end) = read_prefixed_key(&payload, 1)?;
+    let restore_key = restore_key.to_string();
+    let (value, ttl_ms) = read_envelope(&payload[key_end..])?;
+
+    // Stored like an SD, guarded so that without replacing it only creates the key
+    let mut set_payload: Vec<u8> = Vec::with_capacity(10 + restore_key.len() + value.len());
+    set_payload.extend(ttl_ms.to_be_bytes());
+    set_payload.extend((restore_key.len() as u16).to_be_bytes());
+    set_payload.extend(restore_key.as_bytes());
+    set_payload.extend(value);
+    let guard = match replace {
+        true => SetGuard::Always,
+        false => SetGuard::ValueHash(0),
+    };
+    let written_key = write_behind_key(&db, &set_payload, 8);
+    let response = run_set_data(Arc::clone(&db), set_payload, 0, guard).await;
+    queue_write_behind(&db, written_key, &response);
+    let (response_type, response_payload) = response?;
+    match response_type.as_str() {
+        "OK" => {
+            // A time to live of 0 in the envelope keeps the key without one, not the default
+            if ttl_ms == 0 {
+                let _ = db.timeout_db.remove(&restore_key);
+            }
+            return Ok(("RS".to_string(), Bytes::from(vec![1u8])));
+        },
+        "CF" => return Ok(("RS".to_string(), Bytes::from(vec![0u8]))),
+        _ => return Ok((response_type, response_payload)),
+    }
+}
+
+// Payload is the current key and the new key separated by a null byte. RN replaces whatever
+// the new key held, RX leaves an existing new key alone and answers whether it renamed.
+async fn handle_rename(db: Db, payload: Vec<u8>, overwrite: bool) -> Response {
+    let keys_str = read_str(&payload)?;
+    let (from_key, to_key) = match keys_str.split_once(0 as char) {
+        Some((from_key, to_key)) if !to_key.is_empty() => (from_key, to_key),
+        _ => return Err(3),
+    };
+
+    let renamed = rename_key(&db, from_key, to_key, overwrite)?;
+    if overwrite {
+        return Ok(("OK".to_string(), Bytes::new()));
+    }
+    return Ok(("RX".to_string(), Bytes::from(vec![renamed as u8])));
+}
+
+// Moves the value with its expiry, indexes, zone maps and retention. Runs with the write gate
+// held exclusively, so no other write comes between checking the new key and the move. The new
+// key is published with its expiry already in place before the current one is dropped, so a
+// reader always finds the value under one of them.
+fn rename_key(db: &Database, from_key: &str, to_key: &str, overwrite: bool) -> Result<bool, u16> {
+    if from_key == to_key {
+        return match db.shared_db.contains_key(from_key) {
+            true => Ok(true),
+            false => Err(2),
+        };
+    }
+    let value = match db.shared_db.get(from_key) {
+        Some(value) => value.clone(),
+        None => return Err(2),
+    };
+    if !overwrite && db.shared_db.contains_key(to_key) {
+        return Ok(false);
+    }
+    let live_until = db.timeout_db.get(from_key).map(|live_until| *live_until);
+    let key_index = db.index_db.remove(from_key).map(|(_, key_index)| key_index);
+    let zone_maps = db.zone_db.remove(from_key).map(|(_, zone_maps)| zone_maps);
+    let retention = db.retention_db.remove(from_key).map(|(_, retention)| retention);
+
+    invalidate_dependents(to_key, db);
+    let _ = db.index_db.remove(to_key);
+    match live_until {
+        Some(live_until) => { db.timeout_db.insert(to_key.to_string(), live_until); },
+        None => { let _ = db.timeout_db.remove(to_key); },
+    }
+    db.insert_value(to_key, value);
+    if let Some(zone_maps) = zone_maps {
+        db.zone_db.insert(to_key.to_string(), zone_maps);
+    }
+    if let Some(retention) = retention {
+        db.retention_db.insert(to_key.to_string(), retention);
+    }
+    if let Some(key_index) = key_index {
+        db.index_db.insert(to_key.to_string(), key_index);
+    }
+
+    if let Some((_, moved_value)) = db.shared_db.remove(from_key) {
+        db.memory.value_changed(from_key, Some(moved_value.len()), None);
+    }
+    let _ = db.timeout_db.remove(from_key);
+    let _ = db.version_db.remove(from_key);
+    db.stats.forget_key(from_key);
+    invalidate_dependents(from_key, db);
+    return Ok(true);
+}
+
+// Stored form of a value, compressed when the settings ask for it
+fn stored_form(db: &Database, value: Bytes) -> Bytes {
+    let algorithm = db.settings.value_compression.load(Ordering::Relaxed);
+    let threshold = db.settings.compression_threshold.load(Ordering::Relaxed);
+    return compress_value(value, algorithm, threshold);
+}
+
+// Arrow value of the chunks with their repetitive string columns dictionary encoded, along
+// with the encoded chunks. None when the settings turn encoding off or no column qualifies.
+fn dictionary_encoded_value(db: &Database, chunks: &Vec<RecordBatch>) -> Option<(Bytes, Vec<RecordBatch>)> {
+    let max_distinct_percent = db.settings.dictionary_max_distinct.load(Ordering::Relaxed);
+    let encoded_chunks = dictionary_encode_chunks(chunks, max_distinct_percent).ok()??;
+    let mut value = vec!['A' as u8];
+    value.extend(write_record_batch_chunks(&encoded_chunks));
+    return Some((Bytes::from(value), encoded_chunks));
+}
+
+// Hash of the original value, so a compressed value hashes the same as it did when written
+fn stored_value_hash(stored_value: &Bytes) -> u64 {
+    match decompress_value(stored_value) {
+        Ok(value) => return value_hash(&value),
+        Err(_) => return value_hash(stored_value),
+    }
+}
+
+// 64-bit FNV-1a over the stored value, type tag included, so clients can compute it too
+fn value_hash(value: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in value {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+// Takes an expiring lock stored as a bytes key holding the owner's token. Payload is the TTL
+// (u64, required), the key length (u16), the key and the token. The response payload is 1
+// when the lock was taken and 0 when another owner holds it.
+async fn handle_lock(db: Db, payload: Vec<u8>) -> Response {
+    let lock_time_ms = read_u64(&payload, 0)?;
+    let (key, key_index_until) = read_prefixed_key(&payload, 8)?;
+    let key = key.to_string();
+    if lock_time_ms == 0 {
+        return Err(3);
+    }
+
+    let mut value = vec!['B' as u8];
+    value.extend(&payload[key_index_until..]);
+    let value = Bytes::from(value);
+    let now = SystemTime::now();
+    let live_until = now + Duration::from_millis(lock_time_ms);
+
+    // The expiry is set under the entry lock, so a lock is never visible without one
+    let acquired = match db.shared_db.entry(key.clone()) {
+        dashmap::Entry::Occupied(mut entry) => {
+            // A holder whose TTL ran out loses the lock even before the expiry sweep
+            let expired = db.timeout_db.get(&key).map(|until| *until <= now).unwrap_or(false);
+            if expired {
+                db.timeout_db.insert(key.clone(), live_until);
+                let _ = db.zone_db.remove(&key);
+                db.bump_version(&key);
+                db.memory.value_changed(&key, Some(entry.get().len()), Some(value.len()));
+                entry.insert(value);
+            }
+            expired
+        },
+        dashmap::Entry::Vacant(entry) => {
+            db.timeout_db.insert(key.clone(), live_until);
+            db.bump_version(&key);
+            db.memory.value_changed(&key, None, Some(value.len()));
+            entry.insert(value);
+            true
+        },
+    };
+    if acquired {
+        invalidate_dependents(&key, &db);
+    }
+    return Ok(("LK".to_string(), Bytes::from(vec![acquired as u8])));
+}
+
+// Releases a lock only for the owner whose token it holds. Payload is the key length (u16),
+// the key and the token, the response payload is 1 when the lock was released.
+async fn handle_unlock(db: Db, payload: Vec<u8>) -> Response {
+    let (key, key_index_until) = read_prefixed_key(&payload, 0)?;
+    let key = key.to_string();
+    let token = &payload[key_index_until..];
+
+    let released = match db.shared_db.entry(key.clone()) {
+        dashmap::Entry::Occupied(entry) => {
+            let value = entry.get();
+            if value.len() > 0 && value[0] as char == 'B' && &value[1..] == token {
+                let _ = db.timeout_db.remove(&key);
+                let _ = db.version_db.remove(&key);
+                let (_, removed_value) = entry.remove_entry();
+                db.value_removed(&key, &removed_value);
+                true
+            } else {
+                false
+            }
+        },
+        dashmap::Entry::Vacant(_) => false,
+    };
+    if released {
+        invalidate_dependents(&key, &db);
+    }
+    return Ok(("UL".to_string(), Bytes::from(vec![released as u8])));
+}
+
+async fn handle_declare_dependency(db: Db, payload: Vec<u8>) -> Response {
+    let keys_str = read_str(&payload)?;
+    let mut keys = keys_str.split(0 as char);
+    // First key is the derived key, the rest are the keys it was built from
+    let derived_key = keys.next().unwrap_or("");
+    if derived_key.is_empty() {
+        return Err(3);
+    }
+
+    for source_key in keys {
+        db.dependency_db.entry(source_key.to_string()).or_default().insert(derived_key.to_string());
+    }
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+async fn handle_create_index(db: Db, payload: Vec<u8>) -> Response {
+    return run_blocking(move || create_index(&db, &payload)).await?;
+}
+
+fn create_index(db: &Database, payload: &[u8]) -> Response {
+    let payload_str = read_str(payload)?;
+    let mut parts = payload_str.split(0 as char);
+    // First part is the key, the rest are the columns to index
+    let key = parts.next().unwrap_or("");
+    let value = match db.shared_db.get(key) {
+        Some(value) => value.clone(),
+        None => return Err(2),
+    };
+
+    let mut columns = match db.index_db.get(key) {
+        Some(key_index) => key_index.columns.clone(),
+        None => Vec::new(),
+    };
+    for column in parts {
+        if !columns.iter().any(|indexed_column| indexed_column == column) {
+            columns.push(column.to_string());
+        }
+    }
+
+    let record_batch = decode_record_batch(&value)?;
+    build_key_index(db, key, &value, record_batch, columns)?;
+    return Ok(("OK".to_string(), Bytes::new()));
+}
+
+async fn handle_drop_index(db: Db, payload: Vec<u8>) -> Response {
+    let key = read_str(&payload)?;
+
+    if let Some(_) = db.index_db.remove(key) {
+        return Ok(("OK".to_string(), Bytes::new()));
+    } else {
+        return Err(2);
+    }
+}
+
+// Recomputes the zone maps and declared indexes of a freshly stored value, the caller must
+// have dropped the old zone maps before storing it
+fn refresh_arrow_metadata(db: &Database, key: &str, value: &[u8]) {
+    let indexed_columns = db.index_db.get(key).map(|key_index| key_index.columns.clone());
+    let mut chunks = match decode_record_batch_chunks(value) {
+        Ok(chunks) => chunks,
+        Err(_) => {
+            let _ = db.index_db.remove(key);
+            let _ = db.retention_db.remove(key);
+            return;
+        }
+    };
+
+    let zone_maps = Arc::new(chunks.iter().map(compute_zone_map).collect::<Vec<ZoneMap>>());
+    if let Some(current_value) = db.shared_db.get(key) {
+        if current_value[..] == *value {
+            db.zone_db.insert(key.to_string(), zone_maps);
+            match retention(&chunks[0].schema()) {
+                Some(retention) => { db.retention_db.insert(key.to_string(), retention); },
+                None => { let _ = db.retention_db.remove(key); },
+            }
+        }
+    }
+
+    if let Some(columns) = indexed_columns {
+        let record_batch = match chunks.len() {
+            1 => chunks.pop().unwrap(),
+            _ => match concat_batches(&chunks[0].schema(), &chunks) {
+                Ok(rb) => rb,
+                Err(_) => {
+                    let _ = db.index_db.remove(key);
+                    return;
+                }
+            },
+        };
+        if build_key_index(db, key, value, record_batch, columns).is_err() {
+            let _ = db.index_db.remove(key);
+        }
+    }
+}
+
+fn build_key_index(
+    db: &Database, key: &str, value: &[u8], record_batch: RecordBatch, columns: Vec<String>
+) -> Result<(), u16> {
+    let key_index = match KeyIndex::build(record_batch, columns) {
+        Ok(key_index) => key_index,
+        Err(e) => {
+            tracing::debug!("Index build failed for {}: {}", key, e);
+            return Err(3);
+        }
+    };
+
+    // Only publish the index if no other write replaced the value while it was being built
+    let mut key_index = key_index;
+    if let Some(current_value) = db.shared_db.get(key) {
+        if current_value[..] == *value {
+            key_index.version = db.version_db.get(key).map(|version| *version);
+            db.index_db.insert(key.to_string(), key_index);
+        }
+    }
+    return Ok(());
+}
+
+async fn handle_wrong_protocol() -> Response {
+    return Err(6);
+}
+
+async fn handle_payload_too_large() -> Response {
+    return Err(9);
+}
+
+async fn handle_bad_message_type() -> Response {
+    return Err(11);
+}
+
+// The payload was read whole, so the connection can go on with the next frame
+async fn handle_checksum_mismatch() -> Response {
+    return Err(15);
 }
 
-async fn handle_wrong_protocol() -> (String, Vec<u8>) {
-    let error_code: u16 = 6;
-    return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+// Answers at once with the payload it was sent, for clients checking the connection
+async fn handle_ping(payload: Vec<u8>) -> Response {
+    return Ok(("PI".to_string(), Bytes::from(payload)));
 }
 
-async fn handle_connection_close() -> (String, Vec<u8>) {
-    return ("CC".to_string(), vec![0; 0]);
+async fn handle_connection_close() -> Response {
+    return Ok(("CC".to_string(), Bytes::new()));
 }
 
-async fn handle_unknown_type() -> (String, Vec<u8>) {
-    let error_code: u16 = 1;
-    return ("ER".to_string(), error_code.to_be_bytes().to_vec());
+async fn handle_unknown_type() -> Response {
+    return Err(1);
 }
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::handler::payload::{read_framed, write_framed};
+
+// Stored layout of a hash value: 'H' followed by its fields and their values, framed as in
+// requests and alternating field, value, in the order the fields were first set.
+pub const HASH_TAG: u8 = b'H';
+
+type FieldValue<'a> = (&'a [u8], &'a [u8]);
+
+// (field, value) pairs of a stored hash after its tag. Fails with error code 12 when the
+// value is damaged.
+pub fn hash_fields(hash: &[u8]) -> Result<Vec<FieldValue<'_>>, u16> {
+    let elements = read_framed(hash, 0).map_err(|_| 12u16)?;
+    let pairs = elements.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(12);
+    }
+    return Ok(pairs.map(|pair| (pair[0], pair[1])).collect());
+}
+
+// The hash with the fields of `pairs`, alternating field and value, set to their values.
+// Returns it with how many of the fields were new. Fails with error code 3 when a field
+// has no value.
+pub fn set_fields(hash: Option<&[u8]>, pairs: &Vec<&[u8]>) -> Result<(Vec<u8>, usize), u16> {
+    if !pairs.chunks_exact(2).remainder().is_empty() {
+        return Err(3);
+    }
+    let mut fields = hash_fields(hash.unwrap_or(&[]))?;
+    let mut positions: HashMap<&[u8], usize> = fields
+        .iter()
+        .enumerate()
+        .map(|(position, (field, _))| (*field, position))
+        .collect();
+    let mut added: usize = 0;
+    for pair in pairs.chunks(2) {
+        match positions.get(pair[0]) {
+            Some(position) => fields[*position].1 = pair[1],
+            None => {
+                positions.insert(pair[0], fields.len());
+                fields.push((pair[0], pair[1]));
+                added += 1;
+            },
+        }
+    }
+    return Ok((write_pairs(&fields), added));
+}
+
+pub fn get_field<'a>(hash: &'a [u8], field: &[u8]) -> Result<Option<&'a [u8]>, u16> {
+    let value = hash_fields(hash)?
+        .into_iter()
+        .find(|(stored_field, _)| *stored_field == field)
+        .map(|(_, value)| value);
+    return Ok(value);
+}
+
+// The hash without `fields`, with how many of them it had
+pub fn delete_fields(hash: &[u8], fields: &Vec<&[u8]>) -> Result<(Vec<u8>, usize), u16> {
+    let fields: HashSet<&[u8]> = fields.iter().copied().collect();
+    let stored_fields = hash_fields(hash)?;
+    let stored_len = stored_fields.len();
+    let kept_fields: Vec<(&[u8], &[u8])> = stored_fields
+        .into_iter()
+        .filter(|(field, _)| !fields.contains(field))
+        .collect();
+    let deleted = stored_len - kept_fields.len();
+    return Ok((write_pairs(&kept_fields), deleted));
+}
+
+fn write_pairs(fields: &Vec<(&[u8], &[u8])>) -> Vec<u8> {
+    return write_framed(fields.iter().flat_map(|(field, value)| [*field, *value]));
+}
@@ -0,0 +1,116 @@
+// Stored layout of a HyperLogLog value: 'P' followed by one byte per register. It estimates
+// how many distinct elements were added, within about 0.8%, in a fixed 16 KiB.
+pub const HYPERLOGLOG_TAG: u8 = b'P';
+
+// Bits of an element's hash that choose its register
+const REGISTER_BITS: u32 = 14;
+pub const REGISTERS: usize = 1 << REGISTER_BITS;
+
+// Registers of a stored HyperLogLog after its tag. Fails with error code 12 when the value
+// is damaged.
+pub fn hyperloglog_registers(hyperloglog: &[u8]) -> Result<&[u8], u16> {
+    match hyperloglog.len() {
+        REGISTERS => return Ok(hyperloglog),
+        _ => return Err(12),
+    }
+}
+
+// Counts the element in the registers, returns whether one of them changed
+pub fn add_element(registers: &mut [u8], element: &[u8]) -> bool {
+    let hash = element_hash(element);
+    let register_index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // Position of the first set bit among the remaining hash bits, 1 based
+    let rank = ((hash >> REGISTER_BITS).leading_zeros() - REGISTER_BITS + 1) as u8;
+    if rank > registers[register_index] {
+        registers[register_index] = rank;
+        return true;
+    }
+    return false;
+}
+
+// Estimated distinct elements added to any of the HyperLogLogs
+pub fn estimate_count(hyperloglogs: &Vec<&[u8]>) -> u64 {
+    let mut registers = vec![0u8; REGISTERS];
+    for hyperloglog in hyperloglogs {
+        for (register, other_register) in registers.iter_mut().zip(hyperloglog.iter()) {
+            *register = (*register).max(*other_register);
+        }
+    }
+
+    let m = REGISTERS as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+    let inverse_sum: f64 = registers.iter().map(|register| 2f64.powi(-(*register as i32))).sum();
+    let estimate = alpha * m * m / inverse_sum;
+    // Small counts leave registers empty, linear counting estimates them better
+    let empty_registers = registers.iter().filter(|register| **register == 0).count();
+    if estimate <= 2.5 * m && empty_registers > 0 {
+        return (m * (m / empty_registers as f64).ln()).round() as u64;
+    }
+    return estimate.round() as u64;
+}
+
+// FNV-1a followed by the MurmurHash3 finalizer, which spreads every input bit over the
+// register index and the rank
+pub fn element_hash(element: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in element {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    return hash;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate_of(elements: impl Iterator<Item = String>) -> u64 {
+        let mut registers = vec![0u8; REGISTERS];
+        for element in elements {
+            add_element(&mut registers, element.as_bytes());
+        }
+        return estimate_count(&vec![&registers[..]]);
+    }
+
+    fn assert_close(estimate: u64, count: u64, tolerance: f64) {
+        let error = (estimate as f64 - count as f64).abs() / count as f64;
+        assert!(error < tolerance, "estimated {} for {} distinct elements", estimate, count);
+    }
+
+    #[test]
+    fn estimate_is_close_to_the_distinct_count() {
+        assert_close(estimate_of((0..100_000).map(|element| format!("element-{}", element))), 100_000, 0.03);
+        assert_close(estimate_of((0..1_000).map(|element| format!("element-{}", element))), 1_000, 0.03);
+        assert_eq!(estimate_of(std::iter::empty()), 0);
+    }
+
+    #[test]
+    fn repeated_elements_are_counted_once() {
+        let elements = (0..50_000).map(|element| format!("element-{}", element % 10_000));
+        assert_close(estimate_of(elements), 10_000, 0.03);
+    }
+
+    #[test]
+    fn merged_estimate_counts_the_union() {
+        let mut first = vec![0u8; REGISTERS];
+        let mut second = vec![0u8; REGISTERS];
+        for element in 0..60_000 {
+            add_element(&mut first, format!("element-{}", element).as_bytes());
+            add_element(&mut second, format!("element-{}", element + 40_000).as_bytes());
+        }
+        assert_close(estimate_count(&vec![&first[..], &second[..]]), 100_000, 0.03);
+    }
+
+    #[test]
+    fn damaged_registers_are_errors() {
+        assert!(hyperloglog_registers(&vec![0u8; REGISTERS]).is_ok());
+        assert_eq!(hyperloglog_registers(&[]), Err(12));
+        assert_eq!(hyperloglog_registers(&vec![0u8; REGISTERS - 1]), Err(12));
+        assert_eq!(hyperloglog_registers(&vec![0u8; REGISTERS + 1]), Err(12));
+    }
+}
@@ -0,0 +1,95 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use arrow::array::{make_comparator, Array, ArrayRef, BooleanArray, UInt32Array};
+use arrow::compute::{sort_to_indices, take, SortOptions};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+
+// Indexes declared on one Arrow key, together with the batch they were built from so a
+// query never pairs an index with a different version of the value
+pub struct KeyIndex {
+    pub columns: Vec<String>,
+    pub record_batch: RecordBatch,
+    pub indexes: HashMap<String, ColumnIndex>,
+    // Version of the value the batch was decoded from, set when the index is published
+    pub version: Option<u64>,
+}
+
+impl KeyIndex {
+    pub fn build(record_batch: RecordBatch, columns: Vec<String>) -> Result<KeyIndex, ArrowError> {
+        let mut indexes = HashMap::new();
+        for column in columns.iter() {
+            let array = record_batch.column_by_name(column).ok_or_else(|| {
+                ArrowError::InvalidArgumentError(format!("Column {} does not exist", column))
+            })?;
+            indexes.insert(column.clone(), ColumnIndex::build(array)?);
+        }
+        return Ok(KeyIndex { columns: columns, record_batch: record_batch, indexes: indexes, version: None });
+    }
+}
+
+// Sorted index over a single column, null rows are left out since they never match a filter
+#[derive(Clone)]
+pub struct ColumnIndex {
+    sorted_rows: UInt32Array,
+    sorted_values: ArrayRef,
+    row_count: usize,
+}
+
+impl ColumnIndex {
+    pub fn build(column: &ArrayRef) -> Result<ColumnIndex, ArrowError> {
+        let sort_options = SortOptions { descending: false, nulls_first: false };
+        let sorted_rows = sort_to_indices(column, Some(sort_options), None)?;
+        let sorted_rows = sorted_rows.slice(0, column.len() - column.null_count());
+        let sorted_values = take(column.as_ref(), &sorted_rows, None)?;
+
+        return Ok(ColumnIndex {
+            sorted_rows: sorted_rows,
+            sorted_values: sorted_values,
+            row_count: column.len(),
+        });
+    }
+
+    // Builds the filter mask by binary searching the sorted values instead of comparing every row
+    pub fn mask(&self, filter_value: &dyn Array, filter_type: &str) -> BooleanArray {
+        let compare = make_comparator(self.sorted_values.as_ref(), filter_value, SortOptions::default())
+            .expect("Index and filter value types differ");
+        let valid_count = self.sorted_rows.len();
+        let lower = partition_point(valid_count, |position| compare(position, 0) == Ordering::Less);
+        let upper = partition_point(valid_count, |position| compare(position, 0) != Ordering::Greater);
+
+        let ranges: Vec<Range<usize>> = match filter_type {
+            "eq" => vec![lower..upper],
+            "gt" => vec![upper..valid_count],
+            "lt" => vec![0..lower],
+            "gte" => vec![lower..valid_count],
+            "lte" => vec![0..upper],
+            _ => vec![0..lower, upper..valid_count],
+        };
+
+        let mut selected = vec![false; self.row_count];
+        for range in ranges {
+            for position in range {
+                selected[self.sorted_rows.value(position) as usize] = true;
+            }
+        }
+        return BooleanArray::from(selected);
+    }
+}
+
+// First position in 0..len for which `is_before` is false, `is_before` must be monotonic
+fn partition_point<F: Fn(usize) -> bool>(len: usize, is_before: F) -> usize {
+    let mut low = 0;
+    let mut high = len;
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if is_before(middle) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, UInt32Array};
+use arrow::compute::take;
+use arrow::datatypes::{Field, Schema};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use arrow::row::{Row, RowConverter, SortField};
+
+pub fn hash_join(
+    left: &RecordBatch,
+    right: &RecordBatch,
+    left_on: &Vec<String>,
+    right_on: &Vec<String>,
+    how: &str,
+) -> Result<RecordBatch, ArrowError> {
+    if left_on.len() == 0 || left_on.len() != right_on.len() {
+        return Err(ArrowError::InvalidArgumentError("Join columns do not pair up".to_string()));
+    }
+    let is_left_join = how == "left";
+
+    let left_keys = key_columns(left, left_on)?;
+    let right_keys = key_columns(right, right_on)?;
+    let sort_fields: Vec<SortField> = left_keys
+        .iter()
+        .map(|column| SortField::new(column.data_type().clone()))
+        .collect();
+    let converter = RowConverter::new(sort_fields)?;
+    let left_rows = converter.convert_columns(&left_keys)?;
+    let right_rows = converter.convert_columns(&right_keys)?;
+
+    // Build on the right side, rows with a null key never match
+    let mut right_table: HashMap<Row, Vec<u32>> = HashMap::with_capacity(right.num_rows());
+    for index in 0..right.num_rows() {
+        if right_keys.iter().any(|column| column.is_null(index)) {
+            continue;
+        }
+        right_table.entry(right_rows.row(index)).or_default().push(index as u32);
+    }
+
+    let mut left_indices: Vec<u32> = Vec::new();
+    let mut right_indices: Vec<Option<u32>> = Vec::new();
+    for index in 0..left.num_rows() {
+        let matches = if left_keys.iter().any(|column| column.is_null(index)) {
+            None
+        } else {
+            right_table.get(&left_rows.row(index))
+        };
+        match matches {
+            Some(matched_rows) => {
+                for right_index in matched_rows {
+                    left_indices.push(index as u32);
+                    right_indices.push(Some(*right_index));
+                }
+            },
+            None => {
+                if is_left_join {
+                    left_indices.push(index as u32);
+                    right_indices.push(None);
+                }
+            },
+        }
+    }
+    let left_indices = UInt32Array::from(left_indices);
+    let right_indices = UInt32Array::from(right_indices);
+
+    let mut fields: Vec<Field> = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for (field, column) in left.schema().fields().iter().zip(left.columns()) {
+        fields.push(field.as_ref().clone());
+        columns.push(take(column.as_ref(), &left_indices, None)?);
+    }
+    // Right join columns duplicate the left ones, clashing names get a suffix
+    for (field, column) in right.schema().fields().iter().zip(right.columns()) {
+        if right_on.contains(field.name()) {
+            continue;
+        }
+        let mut right_field = field.as_ref().clone().with_nullable(field.is_nullable() || is_left_join);
+        if left.schema().field_with_name(field.name()).is_ok() {
+            right_field = right_field.with_name(format!("{}_right", field.name()));
+        }
+        fields.push(right_field);
+        columns.push(take(column.as_ref(), &right_indices, None)?);
+    }
+
+    return RecordBatch::try_new(Arc::new(Schema::new(fields)), columns);
+}
+
+fn key_columns(record_batch: &RecordBatch, names: &Vec<String>) -> Result<Vec<ArrayRef>, ArrowError> {
+    return names
+        .iter()
+        .map(|name| {
+            record_batch.column_by_name(name).cloned().ok_or_else(|| {
+                ArrowError::InvalidArgumentError(format!("Join column {} does not exist", name))
+            })
+        })
+        .collect();
+}
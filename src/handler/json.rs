@@ -0,0 +1,143 @@
+use arrow::json::LineDelimitedWriter;
+use arrow::record_batch::RecordBatch;
+use serde_json::Value;
+
+// Stored layout of a JSON value: 'J' followed by the document as JSON text. Parts of it are
+// read and changed through paths, so clients do not have to send the whole document back.
+pub const JSON_TAG: u8 = b'J';
+
+// One step of a path into a JSON document
+pub enum PathStep {
+    Field(String),
+    // Negative indexes count from the end of the array, -1 being the last element
+    Index(i64),
+}
+
+// Steps of a path such as $.jobs[0].name or $["dotted.name"], a subset of JSONPath without
+// wildcards, slices or filters. "$" alone is the whole document. Fails with error code 3
+// when the path is malformed.
+pub fn parse_path(path: &str) -> Result<Vec<PathStep>, u16> {
+    let mut rest = path.strip_prefix('$').ok_or(3u16)?;
+    let mut steps: Vec<PathStep> = Vec::new();
+    while let Some(step_char) = rest.chars().next() {
+        if step_char == '.' {
+            let name_end = rest[1..].find(['.', '[']).map(|end| end + 1).unwrap_or(rest.len());
+            if name_end == 1 {
+                return Err(3);
+            }
+            steps.push(PathStep::Field(rest[1..name_end].to_string()));
+            rest = &rest[name_end..];
+        } else if step_char == '[' {
+            let step_end = rest.find(']').ok_or(3u16)?;
+            let inner = &rest[1..step_end];
+            let quoted = inner.len() >= 2
+                && (inner.starts_with('"') && inner.ends_with('"') || inner.starts_with('\'') && inner.ends_with('\''));
+            match quoted {
+                true => steps.push(PathStep::Field(inner[1..inner.len() - 1].to_string())),
+                false => steps.push(PathStep::Index(inner.parse::<i64>().map_err(|_| 3u16)?)),
+            }
+            rest = &rest[step_end + 1..];
+        } else {
+            return Err(3);
+        }
+    }
+    return Ok(steps);
+}
+
+// The part of the document at the path, None when it has no such part
+pub fn get_path<'a>(document: &'a Value, steps: &[PathStep]) -> Option<&'a Value> {
+    let mut current = document;
+    for step in steps {
+        current = match (step, current) {
+            (PathStep::Field(name), Value::Object(object)) => object.get(name)?,
+            (PathStep::Index(index), Value::Array(array)) => array.get(array_position(array.len(), *index)?)?,
+            _ => return None,
+        };
+    }
+    return Some(current);
+}
+
+// Sets the part of the document at the path to `value`. The last step may name a new field of
+// an object or the position just past the end of an array, which appends to it, while the
+// steps before it must exist. Fails with error code 2 when they do not and 5 when a step does
+// not fit the type it is applied to.
+pub fn set_path(document: &mut Value, steps: &[PathStep], value: Value) -> Result<(), u16> {
+    let (last_step, parent_steps) = match steps.split_last() {
+        Some(split_steps) => split_steps,
+        None => {
+            *document = value;
+            return Ok(());
+        },
+    };
+    match (last_step, parent_mut(document, parent_steps)?) {
+        (PathStep::Field(name), Value::Object(object)) => {
+            object.insert(name.clone(), value);
+        },
+        (PathStep::Index(index), Value::Array(array)) => {
+            if *index == array.len() as i64 {
+                array.push(value);
+            } else {
+                let position = array_position(array.len(), *index).ok_or(2u16)?;
+                array[position] = value;
+            }
+        },
+        _ => return Err(5),
+    }
+    return Ok(());
+}
+
+// Removes the part of the document at the path, returns whether it was there. The whole
+// document can not be removed this way, which fails with error code 3.
+pub fn delete_path(document: &mut Value, steps: &[PathStep]) -> Result<bool, u16> {
+    let (last_step, parent_steps) = steps.split_last().ok_or(3u16)?;
+    let parent = match parent_mut(document, parent_steps) {
+        Ok(parent) => parent,
+        Err(_) => return Ok(false),
+    };
+    match (last_step, parent) {
+        (PathStep::Field(name), Value::Object(object)) => return Ok(object.remove(name).is_some()),
+        (PathStep::Index(index), Value::Array(array)) => match array_position(array.len(), *index) {
+            Some(position) => {
+                array.remove(position);
+                return Ok(true);
+            },
+            None => return Ok(false),
+        },
+        _ => return Ok(false),
+    }
+}
+
+fn parent_mut<'a>(document: &'a mut Value, steps: &[PathStep]) -> Result<&'a mut Value, u16> {
+    let mut current = document;
+    for step in steps {
+        current = match (step, current) {
+            (PathStep::Field(name), Value::Object(object)) => object.get_mut(name).ok_or(2u16)?,
+            (PathStep::Index(index), Value::Array(array)) => {
+                let position = array_position(array.len(), *index).ok_or(2u16)?;
+                &mut array[position]
+            },
+            _ => return Err(5),
+        };
+    }
+    return Ok(current);
+}
+
+fn array_position(len: usize, index: i64) -> Option<usize> {
+    let position = match index < 0 {
+        true => len as i64 + index,
+        false => index,
+    };
+    match position >= 0 && position < len as i64 {
+        true => return Some(position as usize),
+        false => return None,
+    }
+}
+
+// The rows of a GA result as JSON lines, one object per row with a field per non-null column.
+// Fails with error code 5 when a column has a type JSON can not hold.
+pub fn write_json_lines(record_batch: &RecordBatch) -> Result<Vec<u8>, u16> {
+    let mut writer = LineDelimitedWriter::new(Vec::new());
+    writer.write(record_batch).map_err(|_| 5u16)?;
+    writer.finish().map_err(|_| 5u16)?;
+    return Ok(writer.into_inner());
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use dashmap::DashMap;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+// Locks of single keys, held by commands that read a value, change it and write it back, so
+// that two of them changing the same key one after the other never lose an update. Only the
+// keys being changed right now have a lock.
+pub struct KeyLocks {
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+// Holds the lock of a key and drops it from the map once nobody else wants it
+pub struct KeyGuard<'a> {
+    locks: &'a KeyLocks,
+    key: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> KeyLocks {
+        KeyLocks {
+            locks: DashMap::new(),
+        }
+    }
+
+    // Waits for the command holding the key's lock, if any, to be done
+    pub async fn lock(&self, key: &str) -> KeyGuard<'_> {
+        let guard = self.lock_of(key).lock_owned().await;
+        return KeyGuard { locks: self, key: key.to_string(), guard: Some(guard) };
+    }
+
+    // Cloned while holding the map entry, so a lock being released can not be removed between
+    // being looked up and being waited on
+    fn lock_of(&self, key: &str) -> Arc<Mutex<()>> {
+        return Arc::clone(self.locks.entry(key.to_string()).or_default().value());
+    }
+}
+
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        // The map holds the only reference left unless another command is waiting for the lock
+        self.locks.locks.remove_if(&self.key, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
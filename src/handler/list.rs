@@ -0,0 +1,63 @@
+use crate::handler::payload::{read_framed, write_framed};
+
+// Stored layout of a list value: 'L' followed by its elements from left to right, framed as
+// in requests. Lists are small queues and buffers, so every change rewrites the whole value.
+pub const LIST_TAG: u8 = b'L';
+
+// Elements of a stored list after its tag. Fails with error code 12 when the value is damaged.
+pub fn list_elements(list: &[u8]) -> Result<Vec<&[u8]>, u16> {
+    return read_framed(list, 0).map_err(|_| 12u16);
+}
+
+// The list with `elements` pushed one after the other on its left or right end, along with
+// its new length. Pushed on the left they end up in reverse order, as with Redis LPUSH.
+pub fn push_elements(list: Option<&[u8]>, elements: &Vec<&[u8]>, left: bool) -> Result<(Vec<u8>, usize), u16> {
+    let stored = list.unwrap_or(&[]);
+    let stored_len = list_elements(stored)?.len();
+    let mut pushed_list: Vec<u8> = Vec::with_capacity(stored.len() + elements.len() * 4);
+    match left {
+        true => {
+            pushed_list.extend(write_framed(elements.iter().rev().copied()));
+            pushed_list.extend_from_slice(stored);
+        },
+        false => {
+            pushed_list.extend_from_slice(stored);
+            pushed_list.extend(write_framed(elements.iter().copied()));
+        },
+    }
+    return Ok((pushed_list, stored_len + elements.len()));
+}
+
+// Takes up to `count` elements off the left or right end of the list. Returns what is left
+// of the list and the popped elements framed in the order they were popped.
+pub fn pop_elements(list: &[u8], count: usize, left: bool) -> Result<(Vec<u8>, Vec<u8>), u16> {
+    let mut elements = list_elements(list)?;
+    let count = count.min(elements.len());
+    let popped: Vec<&[u8]> = match left {
+        true => elements.drain(..count).collect(),
+        false => elements.drain(elements.len() - count..).rev().collect(),
+    };
+    return Ok((write_framed(elements), write_framed(popped)));
+}
+
+// Elements from `start` to `stop` included, framed
+pub fn list_range(list: &[u8], start: i64, stop: i64) -> Result<Vec<u8>, u16> {
+    let elements = list_elements(list)?;
+    match range_bounds(elements.len(), start, stop) {
+        Some((start, stop)) => return Ok(write_framed(elements[start..=stop].iter().copied())),
+        None => return Ok(Vec::new()),
+    }
+}
+
+// Positions from `start` to `stop` included of `len` ordered elements, None when no element
+// is in between. Negative positions count from the end, -1 being the last element, and
+// positions past either end are clamped.
+pub fn range_bounds(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = match start < 0 { true => (len + start).max(0), false => start };
+    let stop = match stop < 0 { true => len + stop, false => stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    return Some((start as usize, stop as usize));
+}
@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use arrow::datatypes::SchemaRef;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+// Fetches values the cache does not have from where they are kept, so a GD or GA miss is
+// filled by the server instead of every client loading and writing the value itself
+pub trait Loader: Send + Sync {
+    // The value of the key, with its type tag as in SD, or None when the backend does not
+    // have it either. Runs on the blocking pool.
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+}
+
+// Loader from a read_through setting: "dir:<path>" reads <path>/<key>.parquet, .arrow or
+// .value, "exec:<program>" runs a program for backends such as S3 or a database, killed when
+// it has not finished after timeout
+pub fn parse_loader(spec: &str, timeout: Duration) -> Result<Box<dyn Loader>, String> {
+    if let Some(path) = spec.strip_prefix("dir:") {
+        let path = PathBuf::from(path);
+        if !path.is_dir() {
+            return Err(format!("{} is not a directory", path.display()));
+        }
+        return Ok(Box::new(DirectoryLoader { path: path }));
+    }
+    if let Some(program) = spec.strip_prefix("exec:") {
+        if program.len() == 0 {
+            return Err("exec: needs a program".to_string());
+        }
+        return Ok(Box::new(CommandLoader { program: program.to_string(), timeout: timeout }));
+    }
+    return Err("must start with dir: or exec:".to_string());
+}
+
+// Values from a directory of Parquet and Arrow IPC files, and of values written behind, named
+// after their keys
+pub struct DirectoryLoader {
+    path: PathBuf,
+}
+
+impl Loader for DirectoryLoader {
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let file_name = match key_file_name(key) {
+            Some(file_name) => file_name,
+            None => return Ok(None),
+        };
+        if let Some(file) = open_file(self.path.join(format!("{}.parquet", file_name)))? {
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file).map_err(|e| e.to_string())?;
+            let schema = reader.schema().clone();
+            let batches = reader
+                .build()
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<RecordBatch>, _>>()
+                .map_err(|e| e.to_string())?;
+            return Ok(Some(arrow_value(schema, &batches)?));
+        }
+        if let Some(file) = open_file(self.path.join(format!("{}.arrow", file_name)))? {
+            let reader = FileReader::try_new(file, None).map_err(|e| e.to_string())?;
+            let schema = reader.schema();
+            let batches = reader.collect::<Result<Vec<RecordBatch>, _>>().map_err(|e| e.to_string())?;
+            return Ok(Some(arrow_value(schema, &batches)?));
+        }
+        // Values of any type as write-behind persists them, with their type tag
+        if let Some(mut file) = open_file(self.path.join(format!("{}.value", file_name)))? {
+            let mut value: Vec<u8> = Vec::new();
+            file.read_to_end(&mut value).map_err(|e| e.to_string())?;
+            return Ok(Some(value));
+        }
+        return Ok(None);
+    }
+}
+
+// Values printed by a program run with the key as its only argument, after "--" so a key
+// starting with a dash is not taken for an option. It prints the value with its type tag, as
+// in SD, and nothing when it does not have the key. Exiting with a failure status, or not
+// exiting before the timeout, is a failed load.
+pub struct CommandLoader {
+    program: String,
+    timeout: Duration,
+}
+
+impl Loader for CommandLoader {
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let mut child = Command::new(&self.program)
+            .arg("--")
+            .arg(key)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        // Read both pipes while the program runs, so it does not block on a full one
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+        let status = match wait_child(&mut child, self.timeout)? {
+            Some(status) => status,
+            None => {
+                // Killed, the readers end with the pipes and are not waited for, as a program the
+                // loader started could still hold them open
+                return Err(format!("{} did not finish in {} ms", self.program, self.timeout.as_millis()));
+            },
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr.join().unwrap_or_default()).into_owned();
+            return Err(format!("{} exited with {}: {}", self.program, status, stderr.trim()));
+        }
+        match stdout.len() {
+            0 => return Ok(None),
+            _ => return Ok(Some(stdout)),
+        }
+    }
+}
+
+// The exit status of the child, or None when it was still running after timeout and was killed
+fn wait_child(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>, String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// Reads a pipe of a child to its end on a thread of its own
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    return thread::spawn(move || {
+        let mut output: Vec<u8> = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        return output;
+    });
+}
+
+// Name of the files of a key in a directory, with the characters that would lead outside of it
+// percent encoded. None for the empty key.
+pub fn key_file_name(key: &str) -> Option<String> {
+    if key.len() == 0 {
+        return None;
+    }
+    let mut file_name = String::with_capacity(key.len());
+    for (position, key_char) in key.char_indices() {
+        match key_char {
+            '%' => file_name.push_str("%25"),
+            '/' => file_name.push_str("%2F"),
+            '\\' => file_name.push_str("%5C"),
+            '\0' => file_name.push_str("%00"),
+            '.' if position == 0 => file_name.push_str("%2E"),
+            _ => file_name.push(key_char),
+        }
+    }
+    return Some(file_name);
+}
+
+fn open_file(path: PathBuf) -> Result<Option<File>, String> {
+    match File::open(&path) {
+        Ok(file) => return Ok(Some(file)),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Can not open {}: {}", path.display(), e)),
+    }
+}
+
+// An Arrow value as SD stores it, 'A' followed by an IPC stream of the batches
+fn arrow_value(schema: SchemaRef, batches: &Vec<RecordBatch>) -> Result<Vec<u8>, String> {
+    let mut writer = StreamWriter::try_new(vec![b'A'], &schema).map_err(|e| e.to_string())?;
+    for batch in batches {
+        writer.write(batch).map_err(|e| e.to_string())?;
+    }
+    writer.finish().map_err(|e| e.to_string())?;
+    return writer.into_inner().map_err(|e| e.to_string());
+}
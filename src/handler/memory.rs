@@ -0,0 +1,156 @@
+// Memory watermarks. Past the soft watermark the cache manager evicts the least recently read
+// keys and value writes are slowed down the closer memory gets to the hard watermark, past
+// which they are refused so the server is not killed for running out of memory.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::handler::database::Db;
+use crate::handler::dependency::invalidate_dependents;
+use crate::handler::filterer::SplitMix64;
+use crate::handler::settings::Settings;
+
+// Delay of a value write just under the hard watermark, writes closer to the soft one wait less
+const MAX_WRITE_DELAY: Duration = Duration::from_millis(50);
+
+// Keys of each namespace one eviction pass picks from, and most passes of one sweep. A sweep
+// that did not evict enough leaves the rest to the next one.
+const EVICTION_SAMPLE_SIZE: usize = 64;
+const EVICTION_PASSES: usize = 8;
+
+// Bytes held by the keys, values and caches of every namespace, which is what the watermarks are
+// compared with. The allocator keeps freed memory for reuse, so the resident size of the process
+// would not go down as keys are evicted.
+pub struct MemoryUsage {
+    // Keys and values, kept up to date by the commands writing and removing them
+    stored_bytes: AtomicU64,
+    // Cached results and batches, counted again by every sweep
+    cache_bytes: AtomicU64,
+}
+
+impl MemoryUsage {
+    pub fn new() -> MemoryUsage {
+        MemoryUsage {
+            stored_bytes: AtomicU64::new(0),
+            cache_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        return self.stored_bytes.load(Ordering::Relaxed) + self.cache_bytes.load(Ordering::Relaxed);
+    }
+
+    // Counts a change to the value of a key, from `previous_len` bytes to `current_len`, None
+    // meaning the key does not exist. Called while holding the key's entry.
+    pub fn value_changed(&self, key: &str, previous_len: Option<usize>, current_len: Option<usize>) {
+        let previous_bytes = previous_len.map_or(0, |len| (key.len() + len) as u64);
+        let current_bytes = current_len.map_or(0, |len| (key.len() + len) as u64);
+        if current_bytes >= previous_bytes {
+            self.stored_bytes.fetch_add(current_bytes - previous_bytes, Ordering::Relaxed);
+        } else {
+            self.stored_bytes.fetch_sub(previous_bytes - current_bytes, Ordering::Relaxed);
+        }
+    }
+
+    // Counts what the caches of the namespaces hold again, and returns the bytes in use
+    pub fn measure(&self, namespaces: &Vec<Db>) -> u64 {
+        let cache_bytes: u64 = namespaces
+            .iter()
+            .map(|db| db.result_cache.used_bytes() + db.batch_cache.used_bytes())
+            .sum();
+        self.cache_bytes.store(cache_bytes, Ordering::Relaxed);
+        return self.used_bytes();
+    }
+
+    // How long a value write of `payload_size` bytes waits before it runs. Fails with error code
+    // 14 past the hard watermark.
+    pub fn admit_write(&self, settings: &Settings, payload_size: usize) -> Result<Duration, u16> {
+        let soft_limit = settings.memory_soft_limit.load(Ordering::Relaxed);
+        let hard_limit = settings.memory_hard_limit.load(Ordering::Relaxed);
+        if soft_limit == 0 && hard_limit == 0 {
+            return Ok(Duration::ZERO);
+        }
+        // The payload is not stored yet, writes admitted at once must not all pass the watermark
+        let used_bytes = self.used_bytes() + payload_size as u64;
+        if hard_limit > 0 && used_bytes >= hard_limit {
+            return Err(14);
+        }
+        if soft_limit == 0 || used_bytes <= soft_limit {
+            return Ok(Duration::ZERO);
+        }
+        // Without a hard watermark above it, writes get the longest delay at twice the soft one
+        let span = match hard_limit > soft_limit {
+            true => hard_limit - soft_limit,
+            false => soft_limit,
+        };
+        let over = (used_bytes - soft_limit).min(span);
+        return Ok(MAX_WRITE_DELAY.mul_f64(over as f64 / span as f64));
+    }
+
+    // Bytes in use past the soft watermark, 0 when it is off
+    pub fn over_soft_limit(&self, settings: &Settings) -> u64 {
+        let soft_limit = settings.memory_soft_limit.load(Ordering::Relaxed);
+        if soft_limit == 0 {
+            return 0;
+        }
+        return self.used_bytes().saturating_sub(soft_limit);
+    }
+}
+
+// Removes keys read least recently, keys never read first, until their keys and values add up to
+// `target_bytes` or EVICTION_PASSES samples were used up. Each pass picks EVICTION_SAMPLE_SIZE
+// keys of each namespace at random and evicts the least recently read of them, like an LRU
+// cache would but without ordering every key. Returns the bytes removed.
+pub async fn evict_least_recent(namespaces: &Vec<Db>, target_bytes: u64) -> u64 {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let mut rng = SplitMix64::new(seed);
+    let mut evicted_bytes: u64 = 0;
+    for _ in 0..EVICTION_PASSES {
+        // (namespace position, last read in Unix ms, key)
+        let mut candidates: Vec<(usize, u64, String)> = Vec::new();
+        for (position, db) in namespaces.iter().enumerate() {
+            for key in sample_keys(db, &mut rng) {
+                candidates.push((position, db.stats.last_access_ms(&key), key));
+            }
+        }
+        if candidates.len() == 0 {
+            break;
+        }
+        candidates.sort_unstable_by_key(|candidate| candidate.1);
+        // The more recently read half of the sample is kept, it is unlikely to be the coldest
+        candidates.truncate(candidates.len().div_ceil(2));
+
+        for (position, _, key) in candidates {
+            if evicted_bytes >= target_bytes {
+                return evicted_bytes;
+            }
+            let db = &namespaces[position];
+            let _write_permit = db.write_gate.read().await;
+            let size = db.shared_db.get(&key).map(|value| (key.len() + value.len()) as u64);
+            if db.remove_key(&key) {
+                invalidate_dependents(&key, db);
+                db.notifier.publish(&db.namespace, &key, "evicted");
+                db.record_change("evicted", Some(&key), None);
+                db.stats.evicted_keys.fetch_add(1, Ordering::Relaxed);
+                evicted_bytes += size.unwrap_or(0);
+            }
+        }
+    }
+    return evicted_bytes;
+}
+
+// Up to EVICTION_SAMPLE_SIZE keys of the namespace picked uniformly by reservoir sampling, so
+// only the picked keys are copied
+fn sample_keys(db: &Db, rng: &mut SplitMix64) -> Vec<String> {
+    let mut sample: Vec<String> = Vec::with_capacity(EVICTION_SAMPLE_SIZE);
+    for (seen, entry) in db.shared_db.iter().enumerate() {
+        if sample.len() < EVICTION_SAMPLE_SIZE {
+            sample.push(entry.key().clone());
+            continue;
+        }
+        let position = (rng.next() % (seen as u64 + 1)) as usize;
+        if position < EVICTION_SAMPLE_SIZE {
+            sample[position] = entry.key().clone();
+        }
+    }
+    return sample;
+}
@@ -2,3 +2,46 @@ pub mod handler;
 pub mod filterer;
 pub mod connection;
 pub mod cache_manager;
+pub mod codec;
+pub mod dependency;
+pub mod joiner;
+pub mod pattern;
+pub mod database;
+pub mod indexer;
+pub mod zonemap;
+pub mod stats;
+pub mod settings;
+pub mod clients;
+pub mod monitor;
+pub mod payload;
+pub mod batch_cache;
+pub mod result_cache;
+pub mod compression;
+pub mod dictionary;
+pub mod defrag;
+pub mod notifier;
+pub mod memory;
+pub mod deadline;
+pub mod singleflight;
+pub mod key_locks;
+pub mod list;
+pub mod hash;
+pub mod set;
+pub mod sorted_set;
+pub mod bitmap;
+pub mod hyperloglog;
+pub mod bloom;
+pub mod topk;
+pub mod string;
+pub mod json;
+pub mod loader;
+pub mod write_behind;
+pub mod webhook;
+pub mod changes;
+pub mod dump;
+pub mod csv;
+pub mod timeseries;
+pub mod retention;
+pub mod resample;
+pub mod views;
+pub mod triggers;
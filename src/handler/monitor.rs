@@ -0,0 +1,56 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Events a lagging monitor can fall behind by before it starts missing some
+const MONITOR_CAPACITY: usize = 1024;
+
+// Feed of processed commands for connections in MN mode
+pub struct Monitor {
+    sender: broadcast::Sender<String>,
+}
+
+pub struct CommandEvent<'a> {
+    pub client_id: u64,
+    pub message_type: &'a str,
+    pub key: Option<String>,
+    pub request_bytes: usize,
+    pub response_type: &'a str,
+    pub response_bytes: usize,
+    pub latency: Duration,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        let (sender, _) = broadcast::channel(MONITOR_CAPACITY);
+        Monitor {
+            sender: sender,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        return self.sender.receiver_count() > 0;
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        return self.sender.subscribe();
+    }
+
+    pub fn publish(&self, event: CommandEvent) {
+        if !self.is_active() {
+            return;
+        }
+        let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let line = serde_json::json!({
+            "at_ms": at_ms,
+            "client": event.client_id,
+            "type": event.message_type,
+            "key": event.key,
+            "request_bytes": event.request_bytes,
+            "response_type": event.response_type,
+            "response_bytes": event.response_bytes,
+            "latency_micros": event.latency.as_micros() as u64,
+        });
+        // Fails only when the last monitor left since the check above
+        let _ = self.sender.send(line.to_string());
+    }
+}
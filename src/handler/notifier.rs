@@ -0,0 +1,54 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Events a lagging subscriber can fall behind by before it starts missing some
+const NOTIFIER_CAPACITY: usize = 4096;
+
+// A key the server removed on its own rather than because a client asked for it, or a key a
+// trigger that notifies watches
+pub struct KeyEvent {
+    pub namespace: String,
+    pub key: String,
+    // "expired" or "evicted", or "set" or "deleted" from a trigger
+    pub reason: &'static str,
+    pub at_ms: u64,
+}
+
+// Feed of expired and evicted keys for connections in SB mode, so jobs that build the values
+// can rebuild them before they are asked for again, along with the keys triggers notify about
+pub struct Notifier {
+    sender: broadcast::Sender<Arc<KeyEvent>>,
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        let (sender, _) = broadcast::channel(NOTIFIER_CAPACITY);
+        Notifier {
+            sender: sender,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        return self.sender.receiver_count() > 0;
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<KeyEvent>> {
+        return self.sender.subscribe();
+    }
+
+    pub fn publish(&self, namespace: &str, key: &str, reason: &'static str) {
+        if !self.is_active() {
+            return;
+        }
+        let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let event = KeyEvent {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            reason: reason,
+            at_ms: at_ms,
+        };
+        // Fails only when the last subscriber left since the check above
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
@@ -0,0 +1,141 @@
+// Readers for the fields of request payloads. They fail with error code 10 when the payload
+// is too short for the field and 11 when text is not valid UTF-8, so handlers can answer
+// with an error frame instead of bringing the connection down.
+// Several elements are framed as their length (u32) followed by their bytes, one after the
+// other, in requests, responses and stored collection values alike.
+
+pub fn read_u64(payload: &[u8], offset: usize) -> Result<u64, u16> {
+    return Ok(u64::from_be_bytes(read_array(payload, offset)?));
+}
+
+pub fn read_u32(payload: &[u8], offset: usize) -> Result<u32, u16> {
+    return Ok(u32::from_be_bytes(read_array(payload, offset)?));
+}
+
+pub fn read_i32(payload: &[u8], offset: usize) -> Result<i32, u16> {
+    return Ok(i32::from_be_bytes(read_array(payload, offset)?));
+}
+
+pub fn read_i64(payload: &[u8], offset: usize) -> Result<i64, u16> {
+    return Ok(i64::from_be_bytes(read_array(payload, offset)?));
+}
+
+pub fn read_f64(payload: &[u8], offset: usize) -> Result<f64, u16> {
+    return Ok(f64::from_be_bytes(read_array(payload, offset)?));
+}
+
+// Everything from `offset` on
+pub fn read_rest(payload: &[u8], offset: usize) -> Result<&[u8], u16> {
+    match payload.get(offset..) {
+        Some(rest) => return Ok(rest),
+        None => return Err(10),
+    }
+}
+
+pub fn read_str(bytes: &[u8]) -> Result<&str, u16> {
+    match std::str::from_utf8(bytes) {
+        Ok(valid_str) => return Ok(valid_str),
+        Err(_) => return Err(11),
+    }
+}
+
+// A key preceded by its u16 length, returned with the offset right after it
+pub fn read_prefixed_key(payload: &[u8], offset: usize) -> Result<(&str, usize), u16> {
+    let length_bytes: [u8; 2] = read_array(payload, offset)?;
+    let key_end = offset + 2 + u16::from_be_bytes(length_bytes) as usize;
+    match payload.get(offset + 2..key_end) {
+        Some(key_bytes) => return Ok((read_str(key_bytes)?, key_end)),
+        None => return Err(10),
+    }
+}
+
+// Every framed element from `offset` to the end of the payload
+pub fn read_framed(payload: &[u8], offset: usize) -> Result<Vec<&[u8]>, u16> {
+    let mut elements: Vec<&[u8]> = Vec::new();
+    let mut element_start = offset;
+    while element_start < payload.len() {
+        let element_end = element_start + 4 + read_u32(payload, element_start)? as usize;
+        match payload.get(element_start + 4..element_end) {
+            Some(element) => elements.push(element),
+            None => return Err(10),
+        }
+        element_start = element_end;
+    }
+    return Ok(elements);
+}
+
+pub fn write_framed<'a>(elements: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut framed: Vec<u8> = Vec::new();
+    for element in elements {
+        framed.extend((element.len() as u32).to_be_bytes());
+        framed.extend_from_slice(element);
+    }
+    return framed;
+}
+
+// An offset so large that the field would end past usize::MAX is past the end as well
+fn read_array<const N: usize>(payload: &[u8], offset: usize) -> Result<[u8; N], u16> {
+    let end = offset.checked_add(N).ok_or(10u16)?;
+    match payload.get(offset..end) {
+        Some(bytes) => return Ok(bytes.try_into().unwrap()),
+        None => return Err(10),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixed_key_is_read_with_its_end() {
+        assert_eq!(read_prefixed_key(&[0, 2, b'a', b'b', 7], 0), Ok(("ab", 4)));
+        assert_eq!(read_prefixed_key(&[9, 0, 0], 1), Ok(("", 3)));
+    }
+
+    #[test]
+    fn truncated_length_prefix_is_an_error() {
+        assert_eq!(read_prefixed_key(&[], 0), Err(10));
+        assert_eq!(read_prefixed_key(&[0], 0), Err(10));
+        assert_eq!(read_prefixed_key(&[1, 2, 0], 2), Err(10));
+    }
+
+    #[test]
+    fn key_longer_than_the_payload_is_an_error() {
+        assert_eq!(read_prefixed_key(&[0, 3, b'a', b'b'], 0), Err(10));
+        assert_eq!(read_prefixed_key(&[0xFF, 0xFF], 0), Err(10));
+    }
+
+    #[test]
+    fn key_that_is_not_utf8_is_an_error() {
+        assert_eq!(read_prefixed_key(&[0, 1, 0xFF], 0), Err(11));
+    }
+
+    #[test]
+    fn offset_past_the_end_is_an_error() {
+        let payload = [0u8; 8];
+        assert_eq!(read_u64(&payload, 0), Ok(0));
+        assert_eq!(read_u64(&payload, 1), Err(10));
+        assert_eq!(read_u32(&payload, 8), Err(10));
+        assert_eq!(read_u32(&payload, 100), Err(10));
+        assert_eq!(read_prefixed_key(&payload, 9), Err(10));
+        assert_eq!(read_rest(&payload, 9), Err(10));
+        assert_eq!(read_rest(&payload, 8), Ok(&[][..]));
+    }
+
+    #[test]
+    fn offset_near_usize_max_does_not_overflow() {
+        let payload = [0u8; 8];
+        assert_eq!(read_array::<8>(&payload, usize::MAX), Err(10));
+        assert_eq!(read_array::<2>(&payload, usize::MAX - 1), Err(10));
+        assert_eq!(read_prefixed_key(&payload, usize::MAX - 1), Err(10));
+        assert_eq!(read_framed(&payload, usize::MAX), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn framed_elements_round_trip_and_truncation_is_an_error() {
+        let framed = write_framed([&b"ab"[..], &b""[..], &b"c"[..]]);
+        assert_eq!(read_framed(&framed, 0), Ok(vec![&b"ab"[..], &b""[..], &b"c"[..]]));
+        assert_eq!(read_framed(&framed[..framed.len() - 1], 0), Err(10));
+        assert_eq!(read_framed(&framed[..2], 0), Err(10));
+    }
+}
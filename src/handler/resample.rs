@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use arrow::array::{Array, ArrayRef, AsArray, Float64Builder, Int64Array, Int64Builder};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Float64Type, Schema};
+use arrow::record_batch::RecordBatch;
+use serde::Deserialize;
+
+use crate::handler::timeseries::{chunk_times, millis_in_unit, time_column, TimeWindow, TIME_COLUMN_METADATA};
+
+// Aggregates RE computes over the rows of a bucket
+const AGGREGATES: [&str; 7] = ["open", "high", "low", "close", "mean", "sum", "count"];
+
+// An RE request, downsampling a time-series key into buckets of a fixed width
+#[derive(Deserialize)]
+pub struct ResampleQuery {
+    pub key: String,
+    // Width of the buckets in milliseconds, aligned on the epoch
+    pub bucket_ms: u64,
+    // Numeric column the aggregates are computed over
+    pub column: String,
+    // Any of AGGREGATES, each giving a column named after the value column and the aggregate
+    pub aggregates: Vec<String>,
+    pub time_window: Option<TimeWindow>,
+    pub compression_type: String,
+}
+
+// Aggregates of the rows of one bucket so far. Rows without a value count for no aggregate.
+struct Bucket {
+    start: i64,
+    open: Option<f64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    close: Option<f64>,
+    sum: f64,
+    count: i64,
+}
+
+impl Bucket {
+    fn new(start: i64) -> Bucket {
+        Bucket { start: start, open: None, high: None, low: None, close: None, sum: 0.0, count: 0 }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.open = self.open.or(Some(value));
+        self.high = Some(self.high.map_or(value, |high| high.max(value)));
+        self.low = Some(self.low.map_or(value, |low| low.min(value)));
+        self.close = Some(value);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// One row per bucket holding rows of the chunks, which must be from a time-series key, with
+// the start of the bucket in the time column followed by the aggregates. Fails with error code
+// 3 for an unknown aggregate, a bucket shorter than the unit of the time column or a value
+// column the chunks do not have, and 5 when the chunks are not from a time-series key or the
+// value column is not numeric.
+pub fn resample(chunks: &Vec<RecordBatch>, query: &ResampleQuery) -> Result<RecordBatch, u16> {
+    let unknown_aggregate = query.aggregates.iter().any(|aggregate| !AGGREGATES.contains(&aggregate.as_str()));
+    if query.aggregates.len() == 0 || unknown_aggregate {
+        return Err(3);
+    }
+    let schema = chunks[0].schema();
+    let time_column = time_column(&schema).ok_or(5u16)?;
+    let time_field = schema.field_with_name(time_column).map_err(|_| 5u16)?.clone();
+    let value_type = schema.field_with_name(&query.column).map_err(|_| 3u16)?.data_type().clone();
+    if !value_type.is_numeric() {
+        return Err(5);
+    }
+    let bucket_width = millis_in_unit(query.bucket_ms.min(i64::MAX as u64) as i64, time_field.data_type());
+    if bucket_width <= 0 {
+        return Err(3);
+    }
+
+    let mut buckets: Vec<Bucket> = Vec::new();
+    for chunk in chunks.iter() {
+        let times = chunk_times(chunk, time_column)?;
+        let column = chunk.column_by_name(&query.column).ok_or(3u16)?;
+        let values = cast(column, &DataType::Float64).map_err(|_| 5u16)?;
+        let values = values.as_primitive::<Float64Type>();
+        for row in 0..chunk.num_rows() {
+            // Rows are in time order, so a bucket ends where the next one starts
+            let start = times.value(row).div_euclid(bucket_width) * bucket_width;
+            if buckets.last().map_or(true, |bucket| bucket.start != start) {
+                buckets.push(Bucket::new(start));
+            }
+            if values.is_valid(row) {
+                buckets.last_mut().unwrap().add(values.value(row));
+            }
+        }
+    }
+
+    let starts: ArrayRef = Arc::new(Int64Array::from_iter_values(buckets.iter().map(|bucket| bucket.start)));
+    let mut fields = vec![Field::new(time_column, time_field.data_type().clone(), false)];
+    let mut columns = vec![cast(&starts, time_field.data_type()).map_err(|_| 12u16)?];
+    for aggregate in query.aggregates.iter() {
+        let name = format!("{}_{}", query.column, aggregate);
+        if aggregate == "count" {
+            let mut builder = Int64Builder::with_capacity(buckets.len());
+            buckets.iter().for_each(|bucket| builder.append_value(bucket.count));
+            fields.push(Field::new(name, DataType::Int64, false));
+            columns.push(Arc::new(builder.finish()));
+            continue;
+        }
+        let mut builder = Float64Builder::with_capacity(buckets.len());
+        for bucket in buckets.iter() {
+            let value = match aggregate.as_str() {
+                "open" => bucket.open,
+                "high" => bucket.high,
+                "low" => bucket.low,
+                "close" => bucket.close,
+                "sum" => Some(bucket.sum),
+                _ => match bucket.count {
+                    0 => None,
+                    count => Some(bucket.sum / count as f64),
+                },
+            };
+            builder.append_option(value);
+        }
+        fields.push(Field::new(name, DataType::Float64, true));
+        columns.push(Arc::new(builder.finish()));
+    }
+    // The buckets are themselves in time order
+    let metadata = HashMap::from([(TIME_COLUMN_METADATA.to_string(), time_column.to_string())]);
+    let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+    return RecordBatch::try_new(schema, columns).map_err(|_| 12u16);
+}
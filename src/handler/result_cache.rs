@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use bytes::Bytes;
+use dashmap::DashMap;
+
+struct CachedResult {
+    payload: Bytes,
+    expires_at: SystemTime,
+    // How long before it expires the result is computed again, if it was read since it was cached
+    refresh_ahead: Option<Duration>,
+    read: AtomicBool,
+}
+
+// Encoded GA results kept for their query's cachetime, apart from the user keys. Results
+// are keyed by the canonical query and dropped as soon as one of their source keys changes.
+pub struct ResultCache {
+    results: DashMap<String, CachedResult>,
+    // Source key to the queries whose cached result was read from it
+    dependents: DashMap<String, HashSet<String>>,
+    used_bytes: AtomicU64,
+}
+
+impl ResultCache {
+    pub fn new() -> ResultCache {
+        ResultCache {
+            results: DashMap::new(),
+            dependents: DashMap::new(),
+            used_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, query_key: &str) -> Option<Bytes> {
+        let result = self.results.get(query_key)?;
+        if result.expires_at <= SystemTime::now() {
+            return None;
+        }
+        result.read.store(true, Ordering::Relaxed);
+        return Some(result.payload.clone());
+    }
+
+    pub fn insert(
+        &self,
+        query_key: &str,
+        payload: Bytes,
+        expires_at: SystemTime,
+        refresh_ahead: Option<Duration>,
+        source_keys: &Vec<String>,
+    ) {
+        for source_key in source_keys {
+            self.dependents.entry(source_key.clone()).or_default().insert(query_key.to_string());
+        }
+        self.used_bytes.fetch_add(payload.len() as u64, Ordering::Relaxed);
+        let result = CachedResult {
+            payload: payload,
+            expires_at: expires_at,
+            refresh_ahead: refresh_ahead,
+            read: AtomicBool::new(false),
+        };
+        if let Some(replaced) = self.results.insert(query_key.to_string(), result) {
+            self.used_bytes.fetch_sub(replaced.payload.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    // Drops the results read from `source_key`
+    pub fn invalidate(&self, source_key: &str) {
+        if let Some((_, query_keys)) = self.dependents.remove(source_key) {
+            for query_key in query_keys {
+                self.remove(&query_key);
+            }
+        }
+    }
+
+    // Drops the results whose cachetime ran out, returns how many there were
+    pub fn remove_expired(&self, now: SystemTime) -> u64 {
+        let expired_keys: Vec<String> = self.results
+            .iter()
+            .filter(|result| result.expires_at <= now)
+            .map(|result| result.key().clone())
+            .collect();
+        for query_key in expired_keys.iter() {
+            self.remove(query_key);
+        }
+        // Queries whose results are all gone no longer need their source keys tracked
+        if expired_keys.len() > 0 {
+            self.dependents.retain(|_, query_keys| {
+                query_keys.retain(|query_key| self.results.contains_key(query_key));
+                query_keys.len() > 0
+            });
+        }
+        return expired_keys.len() as u64;
+    }
+
+    // Queries whose results were read since they were cached and expire within their refresh
+    // ahead time, so they are computed again before anyone has to wait for them
+    pub fn due_for_refresh(&self, now: SystemTime) -> Vec<String> {
+        return self.results
+            .iter()
+            .filter(|result| result.expires_at > now && result.read.load(Ordering::Relaxed))
+            .filter(|result| match result.refresh_ahead {
+                Some(refresh_ahead) => result.expires_at <= now + refresh_ahead,
+                None => false,
+            })
+            .map(|result| result.key().clone())
+            .collect();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.results.len();
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        return self.used_bytes.load(Ordering::Relaxed);
+    }
+
+    fn remove(&self, query_key: &str) {
+        if let Some((_, removed)) = self.results.remove(query_key) {
+            self.used_bytes.fetch_sub(removed.payload.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
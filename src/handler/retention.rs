@@ -0,0 +1,103 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use arrow::datatypes::{DataType, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::handler::timeseries::{chunk_times, earliest_time, millis_in_unit, time_column};
+use crate::handler::zonemap::ZoneMap;
+
+// Schema metadata declaring how many rows an Arrow key keeps, set in the stream sent with SD,
+// AP or TS that creates it
+pub const MAX_ROWS_METADATA: &str = "cupiddb.max_rows";
+
+// Schema metadata declaring how old, in milliseconds, the rows of a time-series key may get
+pub const MAX_AGE_METADATA: &str = "cupiddb.max_age_ms";
+
+// Rows an Arrow key keeps. The cache manager trims the oldest ones past either limit.
+#[derive(Clone)]
+pub struct Retention {
+    pub max_rows: Option<usize>,
+    pub max_age_ms: Option<u64>,
+    // Column the age of rows is measured on, None for Arrow keys that are not time-series keys,
+    // which keep only max_rows
+    pub time_column: Option<String>,
+}
+
+// Retention declared in the schema of an Arrow key, None when it declares no limit. Limits that
+// are not numbers are ignored.
+pub fn retention(schema: &Schema) -> Option<Retention> {
+    let metadata = schema.metadata();
+    let max_rows = metadata.get(MAX_ROWS_METADATA).and_then(|max_rows| max_rows.parse::<usize>().ok());
+    let time_column = time_column(schema).map(|name| name.to_string());
+    let max_age_ms = match time_column {
+        Some(_) => metadata.get(MAX_AGE_METADATA).and_then(|max_age_ms| max_age_ms.parse::<u64>().ok()),
+        None => None,
+    };
+    if max_rows.is_none() && max_age_ms.is_none() {
+        return None;
+    }
+    return Some(Retention { max_rows: max_rows, max_age_ms: max_age_ms, time_column: time_column });
+}
+
+// Whether the chunks described by the zone maps hold rows past the retention, so the key only
+// has to be decoded when it does
+pub fn exceeds_retention(zone_maps: &Vec<ZoneMap>, retention: &Retention) -> bool {
+    if let Some(max_rows) = retention.max_rows {
+        let rows: usize = zone_maps
+            .iter()
+            .map(|zone_map| zone_map.values().next().map_or(0, |stats| stats.row_count))
+            .sum();
+        if rows > max_rows {
+            return true;
+        }
+    }
+    if let (Some(max_age_ms), Some(time_column)) = (retention.max_age_ms, &retention.time_column) {
+        if let Some((earliest, time_type)) = earliest_time(zone_maps, time_column) {
+            return earliest < oldest_kept_time(max_age_ms, &time_type);
+        }
+    }
+    return false;
+}
+
+// The chunks without their rows past the retention, the oldest rows being the first ones. None
+// when every row is kept. A key trimmed of all its rows keeps an empty chunk.
+pub fn trim_chunks(chunks: &Vec<RecordBatch>, retention: &Retention) -> Result<Option<Vec<RecordBatch>>, u16> {
+    let rows: usize = chunks.iter().map(|chunk| chunk.num_rows()).sum();
+    let mut trimmed_rows = rows.saturating_sub(retention.max_rows.unwrap_or(usize::MAX));
+    if let (Some(max_age_ms), Some(time_column)) = (retention.max_age_ms, &retention.time_column) {
+        let time_type = chunks[0].schema().field_with_name(time_column).map_err(|_| 5u16)?.data_type().clone();
+        let oldest_kept = oldest_kept_time(max_age_ms, &time_type);
+        let mut aged_rows: usize = 0;
+        for chunk in chunks.iter() {
+            let times = chunk_times(chunk, time_column)?;
+            let chunk_aged_rows = times.values().partition_point(|time| *time < oldest_kept);
+            aged_rows += chunk_aged_rows;
+            if chunk_aged_rows < chunk.num_rows() {
+                break;
+            }
+        }
+        trimmed_rows = trimmed_rows.max(aged_rows);
+    }
+    if trimmed_rows == 0 {
+        return Ok(None);
+    }
+
+    let mut kept_chunks: Vec<RecordBatch> = Vec::with_capacity(chunks.len());
+    for chunk in chunks.iter() {
+        if trimmed_rows >= chunk.num_rows() {
+            trimmed_rows -= chunk.num_rows();
+            continue;
+        }
+        kept_chunks.push(chunk.slice(trimmed_rows, chunk.num_rows() - trimmed_rows));
+        trimmed_rows = 0;
+    }
+    if kept_chunks.len() == 0 {
+        kept_chunks.push(chunks[0].slice(0, 0));
+    }
+    return Ok(Some(kept_chunks));
+}
+
+// Time of the oldest row a key keeping `max_age_ms` of rows has, in the unit of its time column
+fn oldest_kept_time(max_age_ms: u64, time_type: &DataType) -> i64 {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    return millis_in_unit(now_ms.saturating_sub(max_age_ms as i64), time_type);
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeSet;
+
+use crate::handler::payload::{read_framed, write_framed};
+
+// Stored layout of a set value: 'E' followed by its members framed as in requests, sorted
+// by their bytes so membership is a binary search and every member appears once. 'S'
+// already marks strings.
+pub const SET_TAG: u8 = b'E';
+
+// Members of a stored set after its tag. Fails with error code 12 when the value is damaged.
+pub fn set_members(set: &[u8]) -> Result<Vec<&[u8]>, u16> {
+    return read_framed(set, 0).map_err(|_| 12u16);
+}
+
+// The set with `members` added, along with how many of them it did not have yet
+pub fn add_members(set: Option<&[u8]>, members: &Vec<&[u8]>) -> Result<(Vec<u8>, usize), u16> {
+    let mut stored_members: BTreeSet<&[u8]> = set_members(set.unwrap_or(&[]))?.into_iter().collect();
+    let stored_len = stored_members.len();
+    stored_members.extend(members.iter().copied());
+    let added = stored_members.len() - stored_len;
+    return Ok((write_framed(stored_members), added));
+}
+
+// The set without `members`, along with how many of them it had
+pub fn remove_members(set: &[u8], members: &Vec<&[u8]>) -> Result<(Vec<u8>, usize), u16> {
+    let mut stored_members: BTreeSet<&[u8]> = set_members(set)?.into_iter().collect();
+    let stored_len = stored_members.len();
+    for member in members {
+        stored_members.remove(member);
+    }
+    let removed = stored_len - stored_members.len();
+    return Ok((write_framed(stored_members), removed));
+}
+
+pub fn contains_member(set: &[u8], member: &[u8]) -> Result<bool, u16> {
+    return Ok(set_members(set)?.binary_search(&member).is_ok());
+}
+
+// Members of any of the stored sets, framed and sorted
+pub fn union_members(sets: &Vec<&[u8]>) -> Result<Vec<u8>, u16> {
+    let mut members: BTreeSet<&[u8]> = BTreeSet::new();
+    for set in sets {
+        members.extend(set_members(set)?);
+    }
+    return Ok(write_framed(members));
+}
+
+// Members of every one of the stored sets, framed and sorted
+pub fn intersect_members(sets: &Vec<&[u8]>) -> Result<Vec<u8>, u16> {
+    let mut members: Vec<&[u8]> = match sets.first() {
+        Some(set) => set_members(set)?,
+        None => Vec::new(),
+    };
+    for set in &sets[1..] {
+        let other_members = set_members(set)?;
+        members.retain(|member| other_members.binary_search(member).is_ok());
+    }
+    return Ok(write_framed(members));
+}
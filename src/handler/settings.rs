@@ -0,0 +1,308 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use tracing::Level;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+use crate::config::{AppConfig, ConfigReload};
+use crate::handler::compression::{compression_id, compression_name};
+
+// Configuration values that CG/CS can read and change while the server runs
+pub struct Settings {
+    pub cleanup_interval_ms: AtomicU64,
+    // Most expired keys one sweep removes per namespace, 0 removes all of them
+    pub cleanup_batch_size: AtomicU64,
+    // Sweeps that fill their batch are followed at once by larger ones instead of waiting
+    pub adaptive_cleanup: AtomicBool,
+    // 0 accepts payloads of any size
+    pub max_payload_size: AtomicU64,
+    // 0 accepts any number of connections
+    pub max_connections: AtomicU64,
+    // Bytes of decoded Arrow chunks kept for repeated queries, 0 disables the cache
+    pub batch_cache_size: AtomicU64,
+    // Algorithm new values of at least `compression_threshold` bytes are stored with
+    pub value_compression: AtomicU8,
+    pub compression_threshold: AtomicU64,
+    // Utf8 columns whose distinct values are at most this percentage of their rows are stored
+    // dictionary encoded, 0 stores them as they are
+    pub dictionary_max_distinct: AtomicU64,
+    // Least time between background defragmentation passes, 0 disables them
+    pub defrag_interval_ms: AtomicU64,
+    // Time to live of keys written without a cache time, 0 keeps them until deleted
+    pub default_ttl_ms: AtomicU64,
+    // Memory use in bytes past which keys are evicted and writes slowed down, and past which
+    // values are no longer accepted. 0 disables either watermark.
+    pub memory_soft_limit: AtomicU64,
+    pub memory_hard_limit: AtomicU64,
+    // Limits of a single GA query on the rows it reads and the rows and bytes it answers with,
+    // 0 disables a limit
+    pub max_scan_rows: AtomicU64,
+    pub max_result_rows: AtomicU64,
+    pub max_result_bytes: AtomicU64,
+    // Longest string value in bytes SD accepts, 0 for no limit
+    pub max_string_length: AtomicU64,
+    // Latest changes to keys kept for CD consumers to catch up from, 0 turns the change log off
+    pub change_log_size: AtomicU64,
+    // How long GD, GV and GA remember that a key was missing, so lookups of it in the meantime
+    // answer without trying to load it. 0 turns negative caching off.
+    pub negative_cache_ttl_ms: AtomicU64,
+    // Most namespaces that can exist at once, the default one included. 0 for no limit.
+    pub max_namespaces: AtomicU64,
+    log_level: Mutex<Level>,
+    log_reload: reload::Handle<LevelFilter, Registry>,
+    config_reload: ConfigReload,
+}
+
+pub const SETTING_NAMES: [&str; 21] = [
+    "cleanup_interval_ms", "cleanup_batch_size", "adaptive_cleanup", "max_payload_size", "max_connections", "batch_cache_size", "value_compression",
+    "compression_threshold", "dictionary_max_distinct", "defrag_interval_ms", "default_ttl_ms", "memory_soft_limit", "memory_hard_limit",
+    "max_scan_rows", "max_result_rows", "max_result_bytes", "max_string_length", "change_log_size", "negative_cache_ttl_ms",
+    "max_namespaces", "log_level"
+];
+
+impl Settings {
+    // Starting values of the settings, from the configuration the server was started with
+    pub fn new(config: &AppConfig) -> Settings {
+        Settings {
+            cleanup_interval_ms: AtomicU64::new(config.cleanup_interval_ms),
+            cleanup_batch_size: AtomicU64::new(config.cleanup_batch_size),
+            adaptive_cleanup: AtomicBool::new(config.adaptive_cleanup),
+            max_payload_size: AtomicU64::new(config.max_payload_size),
+            max_connections: AtomicU64::new(config.max_connections),
+            batch_cache_size: AtomicU64::new(config.batch_cache_size),
+            value_compression: AtomicU8::new(config.value_compression),
+            compression_threshold: AtomicU64::new(config.compression_threshold),
+            dictionary_max_distinct: AtomicU64::new(config.dictionary_max_distinct),
+            defrag_interval_ms: AtomicU64::new(config.defrag_interval_ms),
+            default_ttl_ms: AtomicU64::new(config.default_ttl_ms),
+            memory_soft_limit: AtomicU64::new(config.memory_soft_limit),
+            memory_hard_limit: AtomicU64::new(config.memory_hard_limit),
+            max_scan_rows: AtomicU64::new(config.max_scan_rows),
+            max_result_rows: AtomicU64::new(config.max_result_rows),
+            max_result_bytes: AtomicU64::new(config.max_result_bytes),
+            max_string_length: AtomicU64::new(config.max_string_length),
+            change_log_size: AtomicU64::new(config.change_log_size),
+            negative_cache_ttl_ms: AtomicU64::new(config.negative_cache_ttl_ms),
+            max_namespaces: AtomicU64::new(config.max_namespaces),
+            log_level: Mutex::new(config.log_level),
+            log_reload: config.log_reload.clone(),
+            config_reload: config.config_reload.clone(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "cleanup_interval_ms" => Some(self.cleanup_interval_ms.load(Ordering::Relaxed).to_string()),
+            "cleanup_batch_size" => Some(self.cleanup_batch_size.load(Ordering::Relaxed).to_string()),
+            "adaptive_cleanup" => Some(self.adaptive_cleanup.load(Ordering::Relaxed).to_string()),
+            "max_payload_size" => Some(self.max_payload_size.load(Ordering::Relaxed).to_string()),
+            "max_connections" => Some(self.max_connections.load(Ordering::Relaxed).to_string()),
+            "batch_cache_size" => Some(self.batch_cache_size.load(Ordering::Relaxed).to_string()),
+            "value_compression" => Some(compression_name(self.value_compression.load(Ordering::Relaxed)).to_string()),
+            "compression_threshold" => Some(self.compression_threshold.load(Ordering::Relaxed).to_string()),
+            "dictionary_max_distinct" => Some(self.dictionary_max_distinct.load(Ordering::Relaxed).to_string()),
+            "defrag_interval_ms" => Some(self.defrag_interval_ms.load(Ordering::Relaxed).to_string()),
+            "default_ttl_ms" => Some(self.default_ttl_ms.load(Ordering::Relaxed).to_string()),
+            "memory_soft_limit" => Some(self.memory_soft_limit.load(Ordering::Relaxed).to_string()),
+            "memory_hard_limit" => Some(self.memory_hard_limit.load(Ordering::Relaxed).to_string()),
+            "max_scan_rows" => Some(self.max_scan_rows.load(Ordering::Relaxed).to_string()),
+            "max_result_rows" => Some(self.max_result_rows.load(Ordering::Relaxed).to_string()),
+            "max_result_bytes" => Some(self.max_result_bytes.load(Ordering::Relaxed).to_string()),
+            "max_string_length" => Some(self.max_string_length.load(Ordering::Relaxed).to_string()),
+            "change_log_size" => Some(self.change_log_size.load(Ordering::Relaxed).to_string()),
+            "negative_cache_ttl_ms" => Some(self.negative_cache_ttl_ms.load(Ordering::Relaxed).to_string()),
+            "max_namespaces" => Some(self.max_namespaces.load(Ordering::Relaxed).to_string()),
+            "log_level" => Some(self.log_level.lock().unwrap().to_string()),
+            _ => None,
+        }
+    }
+
+    // Reads the configuration again and applies the settings it sets that can change at runtime.
+    // Returns the names of those that changed. If any value is not accepted none of them change.
+    pub fn reload(&self) -> Result<Vec<String>, String> {
+        let result = self.apply_configuration();
+        match &result {
+            Ok(changed) => tracing::info!("Reloaded configuration, changed settings: [{}]", changed.join(", ")),
+            Err(e) => tracing::error!("Configuration not reloaded: {}", e),
+        }
+        return result;
+    }
+
+    fn apply_configuration(&self) -> Result<Vec<String>, String> {
+        let settings = self.config_reload.runtime_settings()?;
+        let mut previous_values: Vec<(&str, String)> = Vec::new();
+        let mut changed: Vec<String> = Vec::new();
+        for (key, name, value) in settings {
+            let previous_value = self.get(name).unwrap_or_default();
+            if !self.set(name, &value) {
+                for (name, previous_value) in previous_values.iter().rev() {
+                    self.set(name, previous_value);
+                }
+                return Err(format!("Invalid {} {:?}", key, value));
+            }
+            if self.get(name).unwrap_or_default() != previous_value {
+                changed.push(name.to_string());
+            }
+            previous_values.push((name, previous_value));
+        }
+        return Ok(changed);
+    }
+
+    // Returns false for an unknown setting or a value it does not accept
+    pub fn set(&self, name: &str, value: &str) -> bool {
+        match name {
+            "cleanup_interval_ms" => match value.parse::<u64>() {
+                Ok(interval) if interval > 0 => {
+                    self.cleanup_interval_ms.store(interval, Ordering::Relaxed);
+                    return true;
+                },
+                _ => return false,
+            },
+            "cleanup_batch_size" => match value.parse::<u64>() {
+                Ok(size) => {
+                    self.cleanup_batch_size.store(size, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "adaptive_cleanup" => match value.parse::<bool>() {
+                Ok(adaptive) => {
+                    self.adaptive_cleanup.store(adaptive, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_payload_size" => match value.parse::<u64>() {
+                Ok(size) => {
+                    self.max_payload_size.store(size, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_connections" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.max_connections.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "batch_cache_size" => match value.parse::<u64>() {
+                Ok(size) => {
+                    self.batch_cache_size.store(size, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "value_compression" => match compression_id(value) {
+                Some(algorithm) => {
+                    self.value_compression.store(algorithm, Ordering::Relaxed);
+                    return true;
+                },
+                None => return false,
+            },
+            "compression_threshold" => match value.parse::<u64>() {
+                Ok(threshold) => {
+                    self.compression_threshold.store(threshold, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "dictionary_max_distinct" => match value.parse::<u64>() {
+                Ok(percent) if percent <= 100 => {
+                    self.dictionary_max_distinct.store(percent, Ordering::Relaxed);
+                    return true;
+                },
+                _ => return false,
+            },
+            "defrag_interval_ms" => match value.parse::<u64>() {
+                Ok(interval) => {
+                    self.defrag_interval_ms.store(interval, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "default_ttl_ms" => match value.parse::<u64>() {
+                Ok(ttl) => {
+                    self.default_ttl_ms.store(ttl, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "memory_soft_limit" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.memory_soft_limit.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "memory_hard_limit" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.memory_hard_limit.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_scan_rows" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.max_scan_rows.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_result_rows" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.max_result_rows.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_result_bytes" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.max_result_bytes.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_string_length" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.max_string_length.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "change_log_size" => match value.parse::<u64>() {
+                Ok(size) => {
+                    self.change_log_size.store(size, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "negative_cache_ttl_ms" => match value.parse::<u64>() {
+                Ok(ttl_ms) => {
+                    self.negative_cache_ttl_ms.store(ttl_ms, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "max_namespaces" => match value.parse::<u64>() {
+                Ok(limit) => {
+                    self.max_namespaces.store(limit, Ordering::Relaxed);
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            "log_level" => match value.parse::<Level>() {
+                Ok(level) => {
+                    if self.log_reload.reload(LevelFilter::from_level(level)).is_err() {
+                        return false;
+                    }
+                    *self.log_level.lock().unwrap() = level;
+                    tracing::info!("Log level set to {level}");
+                    return true;
+                },
+                Err(_) => return false,
+            },
+            _ => return false,
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::future::Future;
+use std::sync::Arc;
+use bytes::Bytes;
+use dashmap::DashMap;
+use tokio::sync::OnceCell;
+
+// Response of a query, or the code of its error, shared by every request that waited for it
+type Flight = Arc<OnceCell<Result<(String, Bytes), u16>>>;
+
+// Identical queries running at the same time, such as many clients asking again for a result
+// that just expired. The first one runs the query and the others wait for its response
+// instead of each doing the same work.
+pub struct Singleflight {
+    flights: DashMap<String, Flight>,
+}
+
+impl Singleflight {
+    pub fn new() -> Singleflight {
+        Singleflight {
+            flights: DashMap::new(),
+        }
+    }
+
+    // Runs `work` unless a query with the same key is already running, in which case its
+    // response is returned. The second value is whether the response came from another request.
+    pub async fn run<F, Fut>(&self, query_key: &str, work: F) -> (Result<(String, Bytes), u16>, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, Bytes), u16>>,
+    {
+        let flight: Flight = Arc::clone(self.flights.entry(query_key.to_string()).or_default().value());
+        let mut ran = false;
+        let response = flight.get_or_init(|| {
+            ran = true;
+            work()
+        }).await.clone();
+        // Later requests start a new flight, which sees changes made since this one started
+        self.flights.remove_if(query_key, |_, current| Arc::ptr_eq(current, &flight));
+        return (response, !ran);
+    }
+}
@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use crate::handler::list::range_bounds;
+use crate::handler::payload::{read_framed, write_framed};
+
+// Stored layout of a sorted set value: 'O' followed by its members and their scores, framed
+// as in requests and alternating member, score (f64), ordered by score and then by member.
+// 'Z' already marks compressed values.
+pub const SORTED_SET_TAG: u8 = b'O';
+
+// (member, score) pairs of a stored sorted set after its tag, in order. Fails with error
+// code 12 when the value is damaged.
+pub fn sorted_entries(sorted_set: &[u8]) -> Result<Vec<(&[u8], f64)>, u16> {
+    let elements = read_framed(sorted_set, 0).map_err(|_| 12u16)?;
+    return scored_members(&elements).map_err(|_| 12u16);
+}
+
+// (member, score) pairs of framed request elements alternating member and score. Fails with
+// error code 3 when a member has no score or a score is not 8 bytes or not a number.
+pub fn scored_members<'a>(elements: &Vec<&'a [u8]>) -> Result<Vec<(&'a [u8], f64)>, u16> {
+    let pairs = elements.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(3);
+    }
+    let mut entries: Vec<(&[u8], f64)> = Vec::with_capacity(elements.len() / 2);
+    for pair in pairs {
+        let score = match <[u8; 8]>::try_from(pair[1]) {
+            Ok(score_bytes) => f64::from_be_bytes(score_bytes),
+            Err(_) => return Err(3),
+        };
+        if score.is_nan() {
+            return Err(3);
+        }
+        // -0.0 would be ordered before 0.0 although they compare equal
+        entries.push((pair[0], score + 0.0));
+    }
+    return Ok(entries);
+}
+
+// The sorted set with the members of `entries` added or given their new score, along with
+// how many of them it did not have yet
+pub fn add_entries(sorted_set: Option<&[u8]>, entries: &Vec<(&[u8], f64)>) -> Result<(Vec<u8>, usize), u16> {
+    let mut scores: HashMap<&[u8], f64> = sorted_entries(sorted_set.unwrap_or(&[]))?.into_iter().collect();
+    let stored_len = scores.len();
+    scores.extend(entries.iter().copied());
+    let added = scores.len() - stored_len;
+    return Ok((write_entries(scores.into_iter().collect()), added));
+}
+
+// The sorted set without `members`, along with how many of them it had
+pub fn remove_entries(sorted_set: &[u8], members: &Vec<&[u8]>) -> Result<(Vec<u8>, usize), u16> {
+    let members: HashSet<&[u8]> = members.iter().copied().collect();
+    let entries = sorted_entries(sorted_set)?;
+    let stored_len = entries.len();
+    let kept_entries: Vec<(&[u8], f64)> = entries
+        .into_iter()
+        .filter(|(member, _)| !members.contains(member))
+        .collect();
+    let removed = stored_len - kept_entries.len();
+    return Ok((write_entries(kept_entries), removed));
+}
+
+// Score of the member and its rank, the number of members ordered before it
+pub fn member_position(sorted_set: &[u8], member: &[u8]) -> Result<Option<(f64, usize)>, u16> {
+    let position = sorted_entries(sorted_set)?
+        .into_iter()
+        .enumerate()
+        .find(|(_, (stored_member, _))| *stored_member == member)
+        .map(|(rank, (_, score))| (score, rank));
+    return Ok(position);
+}
+
+// Entries scored from `min` to `max` included, framed like the stored ones
+pub fn range_by_score(sorted_set: &[u8], min: f64, max: f64) -> Result<Vec<u8>, u16> {
+    let entries = sorted_entries(sorted_set)?;
+    let start = entries.partition_point(|(_, score)| *score < min);
+    let end = entries.partition_point(|(_, score)| *score <= max);
+    return Ok(write_entries(entries[start..end.max(start)].to_vec()));
+}
+
+// Entries ranked from `start` to `stop` included, negative ranks counting from the highest
+// score, framed like the stored ones
+pub fn range_by_rank(sorted_set: &[u8], start: i64, stop: i64) -> Result<Vec<u8>, u16> {
+    let entries = sorted_entries(sorted_set)?;
+    match range_bounds(entries.len(), start, stop) {
+        Some((start, stop)) => return Ok(write_entries(entries[start..=stop].to_vec())),
+        None => return Ok(Vec::new()),
+    }
+}
+
+fn write_entries(mut entries: Vec<(&[u8], f64)>) -> Vec<u8> {
+    entries.sort_by(|(member, score), (other_member, other_score)| {
+        match score.total_cmp(other_score) {
+            Ordering::Equal => member.cmp(other_member),
+            ordering => ordering,
+        }
+    });
+    let score_bytes: Vec<[u8; 8]> = entries.iter().map(|(_, score)| score.to_be_bytes()).collect();
+    return write_framed(
+        entries.iter().zip(score_bytes.iter()).flat_map(|((member, _), score)| [*member, &score[..]])
+    );
+}
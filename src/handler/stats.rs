@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+
+// Reads of one key
+pub struct KeyAccess {
+    pub hits: AtomicU64,
+    pub last_access_ms: AtomicU64,
+}
+
+// Responses to one message type that were not plain successes
+#[derive(Default)]
+pub struct CommandOutcomes {
+    pub errors: AtomicU64,
+    // Only counted for the commands that read keys
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+// Commands whose responses count as hits or misses
+pub const LOOKUP_COMMANDS: [&str; 3] = ["GD", "GV", "GA"];
+
+// Server-wide counters reported by NF
+pub struct Stats {
+    started_at: SystemTime,
+    pub connected_clients: AtomicUsize,
+    pub total_connections: AtomicU64,
+    command_counts: DashMap<String, AtomicU64>,
+    command_outcomes: DashMap<String, CommandOutcomes>,
+    error_codes: DashMap<u16, AtomicU64>,
+    pub expiry_sweeps: AtomicU64,
+    pub expired_keys: AtomicU64,
+    pub expired_results: AtomicU64,
+    pub last_sweep_micros: AtomicU64,
+    // Bytes of values dropped by deletes and expiry, what defragmentation can give back
+    pub freed_value_bytes: AtomicU64,
+    pub defrag_passes: AtomicU64,
+    pub last_defrag_micros: AtomicU64,
+    // Keys removed past the soft memory watermark
+    pub evicted_keys: AtomicU64,
+    // GA queries answered with the response of an identical one running at the same time
+    pub coalesced_queries: AtomicU64,
+    // Keys stored from the read-through loader after a miss, and loads that failed
+    pub loaded_keys: AtomicU64,
+    pub failed_loads: AtomicU64,
+    key_access: DashMap<String, KeyAccess>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started_at: SystemTime::now(),
+            connected_clients: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            command_counts: DashMap::new(),
+            command_outcomes: DashMap::new(),
+            error_codes: DashMap::new(),
+            expiry_sweeps: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            expired_results: AtomicU64::new(0),
+            last_sweep_micros: AtomicU64::new(0),
+            freed_value_bytes: AtomicU64::new(0),
+            defrag_passes: AtomicU64::new(0),
+            last_defrag_micros: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            coalesced_queries: AtomicU64::new(0),
+            loaded_keys: AtomicU64::new(0),
+            failed_loads: AtomicU64::new(0),
+            key_access: DashMap::new(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        return self.started_at.elapsed().unwrap_or_default();
+    }
+
+    pub fn record_command(&self, message_type: &str) {
+        // The common case only takes a shard read lock
+        if let Some(count) = self.command_counts.get(message_type) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.command_counts
+            .entry(message_type.to_string())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Counts the error code of an ER response and, for reads, whether the key was there.
+    // A key that is missing, expired or known to be missing is a miss, any other error is neither.
+    pub fn record_response(&self, message_type: &str, response_type: &str, response_payload: &[u8]) {
+        let error_code = match (response_type, response_payload) {
+            ("ER", [high, low]) => Some(u16::from_be_bytes([*high, *low])),
+            _ => None,
+        };
+        let is_lookup = LOOKUP_COMMANDS.contains(&message_type);
+        if error_code.is_none() && !is_lookup {
+            return;
+        }
+        if let Some(code) = error_code {
+            self.error_codes.entry(code).or_default().fetch_add(1, Ordering::Relaxed);
+        }
+
+        let outcomes = match self.command_outcomes.get(message_type) {
+            Some(outcomes) => outcomes,
+            None => self.command_outcomes.entry(message_type.to_string()).or_default().downgrade(),
+        };
+        match error_code {
+            Some(0) | Some(2) | Some(18) if is_lookup => {
+                outcomes.errors.fetch_add(1, Ordering::Relaxed);
+                outcomes.misses.fetch_add(1, Ordering::Relaxed);
+            },
+            Some(_) => {
+                outcomes.errors.fetch_add(1, Ordering::Relaxed);
+            },
+            None => {
+                outcomes.hits.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+    }
+
+    // Errors, hits and misses per message type that had any
+    pub fn command_outcomes(&self) -> BTreeMap<String, (u64, u64, u64)> {
+        return self.command_outcomes
+            .iter()
+            .map(|entry| (entry.key().clone(), (
+                entry.value().errors.load(Ordering::Relaxed),
+                entry.value().hits.load(Ordering::Relaxed),
+                entry.value().misses.load(Ordering::Relaxed),
+            )))
+            .collect();
+    }
+
+    pub fn error_codes(&self) -> BTreeMap<u16, u64> {
+        return self.error_codes
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+    }
+
+    pub fn command_counts(&self) -> BTreeMap<String, u64> {
+        return self.command_counts
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+    }
+
+    // Callers hold the key's entry, so a key deleted in the meantime, whose access was forgotten
+    // with it, is not counted again
+    pub fn record_key_access(&self, key: &str) {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        if let Some(access) = self.key_access.get(key) {
+            access.hits.fetch_add(1, Ordering::Relaxed);
+            access.last_access_ms.store(now_ms, Ordering::Relaxed);
+            return;
+        }
+        let access = self.key_access
+            .entry(key.to_string())
+            .or_insert_with(|| KeyAccess { hits: AtomicU64::new(0), last_access_ms: AtomicU64::new(0) });
+        access.hits.fetch_add(1, Ordering::Relaxed);
+        access.last_access_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    // Unix ms of the last read of the key, 0 if it was never read
+    pub fn last_access_ms(&self, key: &str) -> u64 {
+        return self.key_access
+            .get(key)
+            .map(|access| access.last_access_ms.load(Ordering::Relaxed))
+            .unwrap_or(0);
+    }
+
+    pub fn forget_key(&self, key: &str) {
+        let _ = self.key_access.remove(key);
+    }
+
+    // The `count` most read keys as (key, hits, last access in Unix ms), most hits first
+    pub fn hot_keys(&self, count: usize) -> Vec<(String, u64, u64)> {
+        let mut keys: Vec<(String, u64, u64)> = self.key_access
+            .iter()
+            .map(|entry| (
+                entry.key().clone(),
+                entry.value().hits.load(Ordering::Relaxed),
+                entry.value().last_access_ms.load(Ordering::Relaxed),
+            ))
+            .collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(count);
+        return keys;
+    }
+}
@@ -0,0 +1,13 @@
+// Stored layout of a string value: 'S' followed by its UTF-8 text. Unlike bytes, a string is
+// checked when it is written, so commands reading it can rely on valid text.
+pub const STRING_TAG: u8 = b'S';
+
+// The text of a string value being written. Fails with error code 11 when it is not UTF-8
+// and with error code 9 when it is longer than `max_length` bytes, 0 meaning no limit.
+pub fn validate_string(string: &[u8], max_length: u64) -> Result<&str, u16> {
+    let text = std::str::from_utf8(string).map_err(|_| 11u16)?;
+    if max_length > 0 && text.len() as u64 > max_length {
+        return Err(9);
+    }
+    return Ok(text);
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use arrow::array::{Array, ArrayRef, AsArray, Int64Array};
+use arrow::compute::{cast, concat_batches, sort_to_indices, take};
+use arrow::datatypes::{DataType, Int64Type, Schema, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+
+use crate::handler::zonemap::ZoneMap;
+
+// Schema metadata naming the time column of a time-series key. Time-series keys are Arrow keys
+// appended to with TS, whose chunks are each sorted by that column and follow one another in
+// time, so GA reads them like any Arrow key while time ranges are found by binary search.
+pub const TIME_COLUMN_METADATA: &str = "cupiddb.time_column";
+
+// The time column of a time-series key, None for other Arrow keys
+pub fn time_column(schema: &Schema) -> Option<&str> {
+    return schema.metadata().get(TIME_COLUMN_METADATA).map(|name| name.as_str());
+}
+
+// Times of the rows of a chunk as integers in the unit of its time column. Fails with error
+// code 3 when the chunk has no such column or a row has no time, and 5 when the column is not
+// a Timestamp or Int64 column.
+pub fn chunk_times(chunk: &RecordBatch, time_column: &str) -> Result<Int64Array, u16> {
+    let column = chunk.column_by_name(time_column).ok_or(3u16)?;
+    return column_times(column);
+}
+
+fn column_times(column: &ArrayRef) -> Result<Int64Array, u16> {
+    match column.data_type() {
+        DataType::Timestamp(_, _) | DataType::Int64 => {},
+        _ => return Err(5),
+    }
+    if column.null_count() > 0 {
+        return Err(3);
+    }
+    let times = cast(column, &DataType::Int64).map_err(|_| 5u16)?;
+    return Ok(times.as_primitive::<Int64Type>().clone());
+}
+
+// The rows of the chunks as one chunk sorted by time, with the time column named in its schema
+pub fn sort_by_time(chunks: &Vec<RecordBatch>, time_column: &str) -> Result<RecordBatch, u16> {
+    let mut metadata = chunks[0].schema().metadata().clone();
+    metadata.insert(TIME_COLUMN_METADATA.to_string(), time_column.to_string());
+    let schema = Arc::new(chunks[0].schema().as_ref().clone().with_metadata(metadata));
+    let chunk = concat_batches(&chunks[0].schema(), chunks).map_err(|_| 4u16)?;
+    let times = chunk_times(&chunk, time_column)?;
+    let sorted_rows = sort_to_indices(&times, None, None).map_err(|_| 12u16)?;
+    let columns = chunk
+        .columns()
+        .iter()
+        .map(|column| take(column.as_ref(), &sorted_rows, None))
+        .collect::<Result<Vec<ArrayRef>, _>>()
+        .map_err(|_| 12u16)?;
+    return RecordBatch::try_new(schema, columns).map_err(|_| 12u16);
+}
+
+// Latest time of a time-series key from the zone map of its last chunk. None when the chunk
+// has no rows or no bounds.
+pub fn latest_time(zone_maps: &Vec<ZoneMap>, time_column: &str) -> Option<i64> {
+    let max = zone_maps.last()?.get(time_column)?.max.as_ref()?;
+    return column_times(max).ok().map(|times| times.value(0));
+}
+
+// Earliest time of a time-series key from the zone map of its first chunk with rows, along with
+// the type of its time column
+pub fn earliest_time(zone_maps: &Vec<ZoneMap>, time_column: &str) -> Option<(i64, DataType)> {
+    let stats = zone_maps.iter().filter_map(|zone_map| zone_map.get(time_column)).find(|stats| stats.row_count > 0)?;
+    let min = stats.min.as_ref()?;
+    return column_times(min).ok().map(|times| (times.value(0), min.data_type().clone()));
+}
+
+// Rows of a time-series key a GA query reads, found by binary search on its time column. Times
+// are in the unit of the column, and every bound may be left out.
+#[derive(Deserialize, Serialize)]
+pub struct TimeWindow {
+    // Earliest and latest time, both included
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    // Only rows of the last milliseconds before now. Int64 time columns are taken to hold
+    // milliseconds since the epoch.
+    pub last_ms: Option<u64>,
+    // Only the latest row at or before this time
+    pub as_of: Option<i64>,
+}
+
+// Rows of the chunks within the window as (chunk position, first row, row count), skipping
+// chunks with none. Fails with error code 5 when the chunks are not from a time-series key.
+pub fn window_rows(chunks: &Vec<RecordBatch>, window: &TimeWindow) -> Result<Vec<(usize, usize, usize)>, u16> {
+    let schema = chunks[0].schema();
+    let time_column = time_column(&schema).ok_or(5u16)?;
+    let time_type = schema.field_with_name(time_column).map_err(|_| 5u16)?.data_type().clone();
+    let mut from = window.from.unwrap_or(i64::MIN);
+    if let Some(last_ms) = window.last_ms {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        from = from.max(millis_in_unit(now_ms.saturating_sub(last_ms as i64), &time_type));
+    }
+    let to = window.to.unwrap_or(i64::MAX).min(window.as_of.unwrap_or(i64::MAX));
+
+    let mut rows: Vec<(usize, usize, usize)> = Vec::new();
+    for (position, chunk) in chunks.iter().enumerate() {
+        let times = chunk_times(chunk, time_column)?;
+        let times = times.values();
+        if times.len() == 0 || times[0] > to || times[times.len() - 1] < from {
+            continue;
+        }
+        let first = times.partition_point(|time| *time < from);
+        let end = times.partition_point(|time| *time <= to);
+        if first < end {
+            rows.push((position, first, end - first));
+        }
+    }
+    if window.as_of.is_some() {
+        let latest = rows.last().map(|(position, first, count)| (*position, first + count - 1, 1));
+        return Ok(latest.into_iter().collect());
+    }
+    return Ok(rows);
+}
+
+// Milliseconds since the epoch in the unit of a time column
+pub fn millis_in_unit(millis: i64, time_type: &DataType) -> i64 {
+    match time_type {
+        DataType::Timestamp(TimeUnit::Second, _) => return millis.div_euclid(1000),
+        DataType::Timestamp(TimeUnit::Microsecond, _) => return millis.saturating_mul(1000),
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => return millis.saturating_mul(1_000_000),
+        _ => return millis,
+    }
+}
@@ -0,0 +1,198 @@
+use bytes::BytesMut;
+
+use crate::handler::hyperloglog::element_hash;
+use crate::handler::payload::{read_framed, read_u32, write_framed};
+
+// Stored layout of a top-k sketch value: 'K' followed by how many heavy hitters it keeps, the
+// width and the depth of its count-min sketch (u32 each), depth rows of width counters (u64)
+// and the heavy hitters framed as element and count (u64) in turn, highest count first. Counts
+// are estimates that may be too high, by more the narrower the sketch, but never too low.
+pub const TOP_K_TAG: u8 = b'K';
+
+// Size of the sketches KA creates
+pub const DEFAULT_TOP: u32 = 10;
+pub const DEFAULT_WIDTH: u32 = 2048;
+pub const DEFAULT_DEPTH: u32 = 5;
+
+// Most heavy hitters a sketch keeps, and most counters it has, which keeps it under 512 MiB
+const MAX_TOP: u32 = 1000;
+const MAX_COUNTERS: u64 = 64 * 1024 * 1024;
+
+// Bytes before the counters
+const HEADER_LEN: usize = 12;
+
+// An empty sketch, after its tag. Fails with error code 3 when a size is 0 or it keeps more than
+// 1000 heavy hitters, and 9 when it would have too many counters.
+pub fn new_sketch(top: u32, width: u32, depth: u32) -> Result<Vec<u8>, u16> {
+    if top == 0 || top > MAX_TOP || width == 0 || depth == 0 {
+        return Err(3);
+    }
+    if width as u64 * depth as u64 > MAX_COUNTERS {
+        return Err(9);
+    }
+    let counters_end = HEADER_LEN + width as usize * depth as usize * 8;
+    let mut sketch = Vec::with_capacity(counters_end);
+    sketch.extend(top.to_be_bytes());
+    sketch.extend(width.to_be_bytes());
+    sketch.extend(depth.to_be_bytes());
+    sketch.resize(counters_end, 0);
+    return Ok(sketch);
+}
+
+// Adds `by` to the counts of the elements and answers with their estimated counts after it,
+// keeping the elements among the heavy hitters when they count more than the lightest one.
+// Fails with error code 12 when the sketch is damaged.
+pub fn increment(sketch: &mut BytesMut, elements: &Vec<&[u8]>, by: u64) -> Result<Vec<u64>, u16> {
+    let (top, width, depth) = sketch_size(sketch)?;
+    let counters_end = HEADER_LEN + width * depth * 8;
+    let mut heavy_hitters: Vec<(Vec<u8>, u64)> = heavy_hitters(sketch, counters_end)?
+        .into_iter()
+        .map(|(element, count)| (element.to_vec(), count))
+        .collect();
+
+    let mut counts: Vec<u64> = Vec::with_capacity(elements.len());
+    for element in elements.iter() {
+        let mut count = u64::MAX;
+        for (row, column) in counter_columns(element, width, depth).enumerate() {
+            let offset = HEADER_LEN + (row * width + column) * 8;
+            let counter = u64::from_be_bytes(sketch[offset..offset + 8].try_into().unwrap()).saturating_add(by);
+            sketch[offset..offset + 8].copy_from_slice(&counter.to_be_bytes());
+            count = count.min(counter);
+        }
+        counts.push(count);
+
+        match heavy_hitters.iter().position(|(heavy_hitter, _)| heavy_hitter == element) {
+            Some(position) => heavy_hitters[position].1 = count,
+            None if heavy_hitters.len() < top => heavy_hitters.push((element.to_vec(), count)),
+            None => {
+                // The lightest heavy hitter is last
+                if heavy_hitters.last().map_or(false, |(_, lightest_count)| count > *lightest_count) {
+                    *heavy_hitters.last_mut().unwrap() = (element.to_vec(), count);
+                }
+            },
+        }
+        heavy_hitters.sort_by(|(element, count), (other_element, other_count)| {
+            return other_count.cmp(count).then_with(|| element.cmp(other_element));
+        });
+    }
+
+    let count_bytes: Vec<[u8; 8]> = heavy_hitters.iter().map(|(_, count)| count.to_be_bytes()).collect();
+    sketch.truncate(counters_end);
+    sketch.extend(write_framed(
+        heavy_hitters.iter().zip(count_bytes.iter()).flat_map(|((element, _), count)| [&element[..], &count[..]])
+    ));
+    return Ok(counts);
+}
+
+// The heavy hitters framed as element and count in turn, highest count first. Fails with error
+// code 12 when the sketch is damaged.
+pub fn top_elements(sketch: &[u8]) -> Result<&[u8], u16> {
+    let (_, width, depth) = sketch_size(sketch)?;
+    let counters_end = HEADER_LEN + width * depth * 8;
+    heavy_hitters(sketch, counters_end)?;
+    return Ok(&sketch[counters_end..]);
+}
+
+// How many heavy hitters the sketch keeps, its width and its depth
+fn sketch_size(sketch: &[u8]) -> Result<(usize, usize, usize), u16> {
+    let top = read_u32(sketch, 0).map_err(|_| 12u16)? as usize;
+    let width = read_u32(sketch, 4).map_err(|_| 12u16)? as usize;
+    let depth = read_u32(sketch, 8).map_err(|_| 12u16)? as usize;
+    // Sizes no sketch is created with, read from a damaged header, must not overflow
+    if top == 0 || width == 0 || depth == 0 || width as u64 * depth as u64 > MAX_COUNTERS {
+        return Err(12);
+    }
+    if sketch.len() < HEADER_LEN + width * depth * 8 {
+        return Err(12);
+    }
+    return Ok((top, width, depth));
+}
+
+fn heavy_hitters(sketch: &[u8], counters_end: usize) -> Result<Vec<(&[u8], u64)>, u16> {
+    let elements = read_framed(sketch, counters_end).map_err(|_| 12u16)?;
+    let pairs = elements.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(12);
+    }
+    return pairs
+        .map(|pair| match <[u8; 8]>::try_from(pair[1]) {
+            Ok(count_bytes) => Ok((pair[0], u64::from_be_bytes(count_bytes))),
+            Err(_) => Err(12),
+        })
+        .collect();
+}
+
+// Counter of the element in each row, derived from two hashes of it by double hashing
+fn counter_columns(element: &[u8], width: usize, depth: usize) -> impl Iterator<Item = usize> {
+    let hash = element_hash(element);
+    let step = element_hash(&hash.to_be_bytes()) | 1;
+    return (0..depth as u64).map(move |row| (hash.wrapping_add(row.wrapping_mul(step)) % width as u64) as usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(sketch: &mut BytesMut, element: &[u8]) {
+        increment(sketch, &vec![element], 1).unwrap();
+    }
+
+    #[test]
+    fn heavy_hitters_are_kept() {
+        let mut sketch = BytesMut::from(&new_sketch(5, DEFAULT_WIDTH, DEFAULT_DEPTH).unwrap()[..]);
+        // Five elements seen 1000, 900, ... 600 times among 20000 seen once, spread over the stream
+        for round in 0..1000 {
+            for heavy in 0..5 {
+                if round < 1000 - heavy * 100 {
+                    count(&mut sketch, format!("heavy-{}", heavy).as_bytes());
+                }
+            }
+            for light in 0..20 {
+                count(&mut sketch, format!("light-{}-{}", round, light).as_bytes());
+            }
+        }
+
+        let elements = read_framed(top_elements(&sketch).unwrap(), 0).unwrap();
+        let top: Vec<(&[u8], u64)> = elements
+            .chunks_exact(2)
+            .map(|pair| (pair[0], u64::from_be_bytes(pair[1].try_into().unwrap())))
+            .collect();
+        assert_eq!(top.len(), 5);
+        for (heavy, (element, count)) in top.iter().enumerate() {
+            assert_eq!(*element, format!("heavy-{}", heavy).as_bytes());
+            let true_count = 1000 - heavy as u64 * 100;
+            assert!(*count >= true_count && *count < true_count + 50, "{} counted {}", heavy, count);
+        }
+    }
+
+    #[test]
+    fn damaged_sketches_are_errors() {
+        let sketch = new_sketch(3, 16, 2).unwrap();
+        assert_eq!(sketch_size(&sketch), Ok((3, 16, 2)));
+        assert_eq!(top_elements(&sketch), Ok(&[][..]));
+
+        assert_eq!(sketch_size(&[]), Err(12));
+        assert_eq!(sketch_size(&sketch[..11]), Err(12));
+        assert_eq!(sketch_size(&sketch[..sketch.len() - 1]), Err(12));
+        // Sizes of a damaged header that would overflow or leave no counters
+        let mut header = sketch.clone();
+        header[4..12].copy_from_slice(&[0xFF; 8]);
+        assert_eq!(sketch_size(&header), Err(12));
+        header[4..12].copy_from_slice(&[0, 0, 0, 16, 0, 0, 0, 0]);
+        assert_eq!(sketch_size(&header), Err(12));
+
+        // Heavy hitters with an element but no count, and with a count that is not 8 bytes
+        let mut odd = sketch.clone();
+        odd.extend(write_framed([&b"a"[..]]));
+        assert_eq!(top_elements(&odd), Err(12));
+        let mut short_count = sketch.clone();
+        short_count.extend(write_framed([&b"a"[..], &[0u8; 4][..]]));
+        assert_eq!(top_elements(&short_count), Err(12));
+        let mut truncated = sketch.clone();
+        truncated.extend(&[0, 0, 0, 9, b'a']);
+        assert_eq!(top_elements(&truncated), Err(12));
+
+        let mut damaged = BytesMut::from(&header[..]);
+        assert_eq!(increment(&mut damaged, &vec![&b"a"[..]], 1), Err(12));
+    }
+}
@@ -0,0 +1,48 @@
+use serde::Deserialize;
+
+use crate::handler::pattern::glob_match;
+
+// Events a trigger fires on: a command writing a key it watches, or deleting it
+const TRIGGER_EVENTS: [&str; 2] = ["set", "deleted"];
+
+// A trigger registered with TR. Right after a command sets or deletes a key matching its
+// pattern, the server runs its actions, so clients do not have to orchestrate them.
+#[derive(Deserialize, Clone)]
+pub struct Trigger {
+    pub name: String,
+    // Glob pattern of the keys it watches
+    pub pattern: String,
+    // Any of TRIGGER_EVENTS, every one when left out
+    pub events: Option<Vec<String>>,
+    // Keys to delete, along with the keys derived from them
+    pub invalidate: Option<Vec<String>>,
+    // View to compute again right away rather than when it is next read or swept
+    pub refresh_view: Option<String>,
+    // Whether SB subscribers get the event
+    pub notify: Option<bool>,
+}
+
+impl Trigger {
+    // Fails with error code 3 for an unknown event or a trigger without an action
+    pub fn validate(&self) -> Result<(), u16> {
+        let events = self.events.as_deref().unwrap_or(&[]);
+        if events.iter().any(|event| !TRIGGER_EVENTS.contains(&event.as_str())) {
+            return Err(3);
+        }
+        let has_action = self.invalidate.as_ref().map_or(false, |keys| keys.len() > 0)
+            || self.refresh_view.is_some()
+            || self.notify == Some(true);
+        if !has_action {
+            return Err(3);
+        }
+        return Ok(());
+    }
+
+    pub fn fires_on(&self, key: &str, event: &str) -> bool {
+        let watches_event = match &self.events {
+            Some(events) => events.iter().any(|watched_event| watched_event == event),
+            None => true,
+        };
+        return watches_event && glob_match(&self.pattern, key);
+    }
+}
@@ -0,0 +1,14 @@
+// A key holding the result of a GA query on another key, registered with VW. The server
+// computes it again whenever the source key has changed, so reads of the view get a small
+// precomputed batch.
+#[derive(Clone)]
+pub struct View {
+    pub source: String,
+    // The GA query, as JSON
+    pub query: String,
+    // Versions of the source the view was computed from and of the view it was stored as. A
+    // view whose version is no longer its own was written or deleted by a command, and is
+    // not kept up to date anymore.
+    pub source_version: Option<u64>,
+    pub view_version: Option<u64>,
+}
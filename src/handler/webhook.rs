@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::handler::pattern::glob_match;
+
+// Events a webhook can be sent: keys set or deleted by a command or by the server, expired or
+// evicted
+pub const WEBHOOK_EVENTS: [&str; 4] = ["set", "deleted", "expired", "evicted"];
+
+// Longest wait between posts after one failed, doubling from the interval
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Events kept while the endpoint does not take them, the oldest ones are dropped past it
+const MAX_PENDING_EVENTS: usize = 100_000;
+
+// Longest a post may take, from connecting to reading the status line of the answer
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest answer head read, only its status line matters
+const MAX_RESPONSE_HEAD: usize = 4096;
+
+// An http:// endpoint, split into what the connection and the request need
+struct WebhookUrl {
+    // host:port to connect to
+    address: String,
+    // Host header
+    host: String,
+    path: String,
+}
+
+// Key events posted to an HTTP endpoint in batches, for schedulers and other services that can
+// not keep an SB connection open. Each post is a JSON array of events, and events the endpoint
+// did not take with a 2xx status are posted again.
+pub struct Webhook {
+    url: WebhookUrl,
+    // Glob patterns of the keys whose events are posted, every key when empty
+    patterns: Vec<String>,
+    events: Vec<String>,
+    pending: Mutex<VecDeque<serde_json::Value>>,
+    // Pending events that make a post start before its interval is over, and most events per post
+    batch_size: usize,
+    post_now: Notify,
+    pub posted_events: AtomicU64,
+    pub failed_posts: AtomicU64,
+    pub dropped_events: AtomicU64,
+}
+
+impl Webhook {
+    // A webhook posting the events to the URL, which must start with http://. Patterns are comma
+    // separated.
+    pub fn new(url: &str, patterns: &str, events: Vec<String>, batch_size: usize) -> Result<Webhook, String> {
+        let url = parse_url(url)?;
+        let patterns = patterns
+            .split(',')
+            .map(|pattern| pattern.trim().to_string())
+            .filter(|pattern| pattern.len() > 0)
+            .collect();
+        return Ok(Webhook {
+            url: url,
+            patterns: patterns,
+            events: events,
+            pending: Mutex::new(VecDeque::new()),
+            batch_size: batch_size,
+            post_now: Notify::new(),
+            posted_events: AtomicU64::new(0),
+            failed_posts: AtomicU64::new(0),
+            dropped_events: AtomicU64::new(0),
+        });
+    }
+
+    // Queues the change a command or the server made to a key, as in the change log, when the
+    // webhook watches the key and the event it is
+    pub fn queue(&self, namespace: &str, key: &str, command: &str, value_hash: Option<u64>) {
+        let event = match (command, value_hash) {
+            ("expired", _) => "expired",
+            ("evicted", _) => "evicted",
+            (_, Some(_)) => "set",
+            (_, None) => "deleted",
+        };
+        if !self.events.iter().any(|watched_event| watched_event == event) {
+            return;
+        }
+        if self.patterns.len() > 0 && !self.patterns.iter().any(|pattern| glob_match(pattern, key)) {
+            return;
+        }
+        let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let line = serde_json::json!({
+            "at_ms": at_ms,
+            "namespace": namespace,
+            "key": key,
+            "event": event,
+            "command": command,
+            "value_hash": value_hash,
+        });
+        let mut pending = self.pending.lock().unwrap();
+        pending.push_back(line);
+        if pending.len() > MAX_PENDING_EVENTS {
+            pending.pop_front();
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+        }
+        if pending.len() >= self.batch_size {
+            self.post_now.notify_one();
+        }
+    }
+
+    pub fn pending_len(&self) -> usize {
+        return self.pending.lock().unwrap().len();
+    }
+
+    // Posts the pending events, a batch at a time. Returns whether every post succeeded, the
+    // events of a failed one being pending again ahead of those queued since.
+    async fn post_pending(&self) -> bool {
+        loop {
+            let batch: Vec<serde_json::Value> = {
+                let mut pending = self.pending.lock().unwrap();
+                let batch_len = pending.len().min(self.batch_size);
+                pending.drain(..batch_len).collect()
+            };
+            if batch.len() == 0 {
+                return true;
+            }
+            let body = serde_json::to_string(&batch).unwrap_or_default();
+            match timeout(POST_TIMEOUT, self.post(&body)).await.unwrap_or_else(|_| Err("timed out".to_string())) {
+                Ok(()) => {
+                    self.posted_events.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                },
+                Err(reason) => {
+                    tracing::warn!("Posting key events to {}{} failed: {}", self.url.host, self.url.path, reason);
+                    self.failed_posts.fetch_add(1, Ordering::Relaxed);
+                    let mut pending = self.pending.lock().unwrap();
+                    for line in batch.into_iter().rev() {
+                        pending.push_front(line);
+                    }
+                    while pending.len() > MAX_PENDING_EVENTS {
+                        pending.pop_front();
+                        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return false;
+                },
+            }
+        }
+    }
+
+    // One POST of the body on a connection of its own. Fails unless the answer has a 2xx status.
+    async fn post(&self, body: &str) -> Result<(), String> {
+        let mut socket = TcpStream::connect(&self.url.address).await.map_err(|e| e.to_string())?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            self.url.path, self.url.host, body.len(), body
+        );
+        socket.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        let mut response = Vec::with_capacity(512);
+        let mut buffer = [0; 512];
+        while !response.windows(2).any(|window| window == b"\r\n") && response.len() < MAX_RESPONSE_HEAD {
+            match socket.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => response.extend_from_slice(&buffer[..read]),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let status_line = String::from_utf8_lossy(&response);
+        let status = status_line.split_whitespace().nth(1).unwrap_or("");
+        if !status.starts_with('2') || status.len() != 3 {
+            return Err(format!("answered {}", status_line.lines().next().unwrap_or("nothing")));
+        }
+        return Ok(());
+    }
+}
+
+// Events of a webhook_events setting, comma separated, every one of WEBHOOK_EVENTS when empty
+pub fn parse_events(events: &str) -> Result<Vec<String>, String> {
+    let events: Vec<String> = match events.trim() {
+        "" => WEBHOOK_EVENTS.iter().map(|event| event.to_string()).collect(),
+        events => events.split(',').map(|event| event.trim().to_string()).collect(),
+    };
+    if let Some(event) = events.iter().find(|event| !WEBHOOK_EVENTS.contains(&event.as_str())) {
+        return Err(format!("unknown event {}", event));
+    }
+    return Ok(events);
+}
+
+fn parse_url(url: &str) -> Result<WebhookUrl, String> {
+    let rest = match url.trim().strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return Err("must start with http://".to_string()),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(path_start) => (&rest[..path_start], &rest[path_start..]),
+        None => (rest, "/"),
+    };
+    if host.len() == 0 {
+        return Err("has no host".to_string());
+    }
+    let address = match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+        _ => format!("{}:80", host),
+    };
+    return Ok(WebhookUrl { address: address, host: host.to_string(), path: path.to_string() });
+}
+
+// Posts the pending events every `interval`, or sooner once a batch is full, until shutdown,
+// when the ones left are posted one last time. After a failed post it waits twice as long, up
+// to MAX_RETRY_DELAY, before trying again.
+pub async fn webhook_poster(shutdown_token: CancellationToken, webhook: Arc<Webhook>, interval: Duration) {
+    let mut delay = interval;
+    loop {
+        select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = webhook.post_now.notified(), if delay == interval => {},
+            _ = shutdown_token.cancelled() => break,
+        }
+        match webhook.post_pending().await {
+            true => delay = interval,
+            false => delay = (delay * 2).min(MAX_RETRY_DELAY),
+        }
+    }
+    if !webhook.post_pending().await {
+        tracing::error!("Exiting with {} key events that could not be posted", webhook.pending_len());
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::handler::compression::decompress_value;
+use crate::handler::database::{Namespaces, DEFAULT_NAMESPACE};
+use crate::handler::loader::key_file_name;
+
+// Longest wait between flushes after persisting failed, doubling from the flush interval
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Where written values are persisted, away from the write path
+pub trait Sink: Send + Sync {
+    // Persists the value, with its type tag as in SD, of a key of a namespace. Runs on the
+    // blocking pool.
+    fn persist(&self, namespace: &str, key: &str, value: &[u8]) -> Result<(), String>;
+}
+
+// Sink from a write_behind setting: "dir:<path>" writes <path>/<key>.value, which read-through
+// from the same directory loads back, "exec:<program>" runs a program for backends such as S3
+pub fn parse_sink(spec: &str) -> Result<Box<dyn Sink>, String> {
+    if let Some(path) = spec.strip_prefix("dir:") {
+        let path = PathBuf::from(path);
+        if !path.is_dir() {
+            return Err(format!("{} is not a directory", path.display()));
+        }
+        return Ok(Box::new(DirectorySink { path: path }));
+    }
+    if let Some(program) = spec.strip_prefix("exec:") {
+        if program.len() == 0 {
+            return Err("exec: needs a program".to_string());
+        }
+        return Ok(Box::new(CommandSink { program: program.to_string() }));
+    }
+    return Err("must start with dir: or exec:".to_string());
+}
+
+// One file per key, in a subdirectory named after the namespace outside the default one
+pub struct DirectorySink {
+    path: PathBuf,
+}
+
+impl Sink for DirectorySink {
+    fn persist(&self, namespace: &str, key: &str, value: &[u8]) -> Result<(), String> {
+        let file_name = match key_file_name(key) {
+            Some(file_name) => file_name,
+            None => {
+                tracing::warn!("The empty key is not persisted, it has no file name");
+                return Ok(());
+            },
+        };
+        let mut path = self.path.clone();
+        if namespace != DEFAULT_NAMESPACE {
+            path.push(namespace);
+            fs::create_dir_all(&path).map_err(|e| format!("Can not create {}: {}", path.display(), e))?;
+        }
+        // Written next to the file and renamed over it, so a crash never leaves half a value
+        let temporary_path = path.join(format!(".{}.value.tmp", file_name));
+        let value_path = path.join(format!("{}.value", file_name));
+        fs::write(&temporary_path, value).map_err(|e| format!("Can not write {}: {}", temporary_path.display(), e))?;
+        return fs::rename(&temporary_path, &value_path)
+            .map_err(|e| format!("Can not write {}: {}", value_path.display(), e));
+    }
+}
+
+// Values written to the standard input of a program run with the key and the namespace as its
+// arguments. Exiting with a failure status is a failed write, which is tried again.
+pub struct CommandSink {
+    program: String,
+}
+
+impl Sink for CommandSink {
+    fn persist(&self, namespace: &str, key: &str, value: &[u8]) -> Result<(), String> {
+        let mut child = Command::new(&self.program)
+            .arg(key)
+            .arg(namespace)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        // Dropped once written so the program sees the end of its input
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(value).map_err(|e| e.to_string())?;
+        }
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("{} exited with {}: {}", self.program, output.status, stderr.trim()));
+        }
+        return Ok(());
+    }
+}
+
+// Keys written with SD or AP waiting to be persisted. A key written again before the next
+// flush is persisted once, with the value it has then.
+pub struct WriteBehind {
+    sink: Arc<dyn Sink>,
+    // (namespace, key)
+    pending: Mutex<HashSet<(String, String)>>,
+    // Pending keys that make a flush start before its interval is over
+    batch_size: usize,
+    flush_now: Notify,
+    pub persisted_writes: AtomicU64,
+    pub failed_writes: AtomicU64,
+}
+
+impl WriteBehind {
+    pub fn new(sink: Box<dyn Sink>, batch_size: usize) -> WriteBehind {
+        WriteBehind {
+            sink: Arc::from(sink),
+            pending: Mutex::new(HashSet::new()),
+            batch_size: batch_size,
+            flush_now: Notify::new(),
+            persisted_writes: AtomicU64::new(0),
+            failed_writes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn queue(&self, namespace: &str, key: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert((namespace.to_string(), key.to_string()));
+        if pending.len() >= self.batch_size {
+            self.flush_now.notify_one();
+        }
+    }
+
+    pub fn pending_len(&self) -> usize {
+        return self.pending.lock().unwrap().len();
+    }
+
+    // Persists the pending keys with their current values. Keys deleted or expired since they
+    // were written are left out. Returns whether every write succeeded, the failed ones being
+    // pending again.
+    async fn flush(&self, namespaces: &Namespaces) -> bool {
+        let keys: Vec<(String, String)> = self.pending.lock().unwrap().drain().collect();
+        if keys.len() == 0 {
+            return true;
+        }
+        let mut writes: Vec<(String, String, Bytes)> = Vec::with_capacity(keys.len());
+        for (namespace, key) in keys {
+            let db = namespaces.select(&namespace);
+            let stored_value = match db.shared_db.get(&key) {
+                Some(stored_value) => stored_value.clone(),
+                None => continue,
+            };
+            match decompress_value(&stored_value) {
+                Ok(value) => writes.push((namespace, key, value)),
+                Err(_) => tracing::warn!("Can not persist {}, its value is damaged", key),
+            }
+        }
+
+        let sink = Arc::clone(&self.sink);
+        let (persisted, failed) = tokio::task::spawn_blocking(move || {
+            let mut persisted: u64 = 0;
+            let mut failed: Vec<(String, String)> = Vec::new();
+            for (namespace, key, value) in writes {
+                match sink.persist(&namespace, &key, &value) {
+                    Ok(()) => persisted += 1,
+                    Err(reason) => {
+                        tracing::warn!("Persisting {} failed: {}", key, reason);
+                        failed.push((namespace, key));
+                    },
+                }
+            }
+            return (persisted, failed);
+        }).await.unwrap_or_default();
+        self.persisted_writes.fetch_add(persisted, Ordering::Relaxed);
+        self.failed_writes.fetch_add(failed.len() as u64, Ordering::Relaxed);
+        let all_persisted = failed.len() == 0;
+        self.pending.lock().unwrap().extend(failed);
+        return all_persisted;
+    }
+}
+
+// Flushes the pending writes every `interval`, or sooner once a batch is full, until
+// shutdown, when the ones left are flushed one last time. After a failed flush it waits twice
+// as long, up to MAX_RETRY_DELAY, before trying again.
+pub async fn write_behind_flusher(
+    shutdown_token: CancellationToken, namespaces: Arc<Namespaces>, write_behind: Arc<WriteBehind>, interval: Duration
+) {
+    let mut delay = interval;
+    loop {
+        select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = write_behind.flush_now.notified(), if delay == interval => {},
+            _ = shutdown_token.cancelled() => break,
+        }
+        match write_behind.flush(&namespaces).await {
+            true => delay = interval,
+            false => delay = (delay * 2).min(MAX_RETRY_DELAY),
+        }
+    }
+    if !write_behind.flush(&namespaces).await {
+        tracing::error!("Exiting with {} writes that could not be persisted", write_behind.pending_len());
+    }
+}
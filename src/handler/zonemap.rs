@@ -0,0 +1,91 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use arrow::array::{make_comparator, Array, ArrayRef};
+use arrow::compute::SortOptions;
+use arrow::record_batch::RecordBatch;
+
+pub type ZoneMap = HashMap<String, ColumnStats>;
+
+// Min/max are single-element arrays so they compare against filter values of any type
+#[derive(Clone)]
+pub struct ColumnStats {
+    pub min: Option<ArrayRef>,
+    pub max: Option<ArrayRef>,
+    pub null_count: usize,
+    pub row_count: usize,
+}
+
+pub fn compute_zone_map(record_batch: &RecordBatch) -> ZoneMap {
+    let mut zone_map = HashMap::new();
+    for (field, column) in record_batch.schema().fields().iter().zip(record_batch.columns()) {
+        let (min, max) = match make_comparator(column.as_ref(), column.as_ref(), SortOptions::default()) {
+            Ok(compare) => {
+                let mut valid_rows = (0..column.len()).filter(|row| column.is_valid(*row));
+                match valid_rows.next() {
+                    Some(first) => {
+                        let mut min_row = first;
+                        let mut max_row = first;
+                        for row in valid_rows {
+                            if compare(row, min_row) == Ordering::Less {
+                                min_row = row;
+                            }
+                            if compare(row, max_row) == Ordering::Greater {
+                                max_row = row;
+                            }
+                        }
+                        (Some(column.slice(min_row, 1)), Some(column.slice(max_row, 1)))
+                    },
+                    None => (None, None),
+                }
+            },
+            // Types without an ordering get no bounds and are always evaluated
+            Err(_) => (None, None),
+        };
+        zone_map.insert(field.name().clone(), ColumnStats {
+            min: min,
+            max: max,
+            null_count: column.null_count(),
+            row_count: column.len(),
+        });
+    }
+    return zone_map;
+}
+
+// Decides a filter from the column bounds alone: Some(false) when no row can match,
+// Some(true) when every row matches, None when the rows have to be compared
+pub fn zone_map_outcome(stats: &ColumnStats, filter_value: &dyn Array, filter_type: &str) -> Option<bool> {
+    if stats.null_count == stats.row_count {
+        return Some(false);
+    }
+    let (min, max) = match (&stats.min, &stats.max) {
+        (Some(min), Some(max)) => (min, max),
+        _ => return None,
+    };
+    let min_order = make_comparator(min.as_ref(), filter_value, SortOptions::default()).ok()?(0, 0);
+    let max_order = make_comparator(max.as_ref(), filter_value, SortOptions::default()).ok()?(0, 0);
+
+    let (none_match, all_match) = match filter_type {
+        "eq" => (
+            min_order == Ordering::Greater || max_order == Ordering::Less,
+            min_order == Ordering::Equal && max_order == Ordering::Equal,
+        ),
+        "gt" => (max_order != Ordering::Greater, min_order == Ordering::Greater),
+        "lt" => (min_order != Ordering::Less, max_order == Ordering::Less),
+        "gte" => (max_order == Ordering::Less, min_order != Ordering::Less),
+        "lte" => (min_order == Ordering::Greater, max_order != Ordering::Greater),
+        _ => (
+            min_order == Ordering::Equal && max_order == Ordering::Equal,
+            min_order == Ordering::Greater || max_order == Ordering::Less,
+        ),
+    };
+
+    if none_match {
+        return Some(false);
+    }
+    // Null rows never match, so a column with nulls can not match everywhere
+    if all_match && stats.null_count == 0 {
+        return Some(true);
+    }
+    return None;
+}
@@ -0,0 +1,102 @@
+// HTTP endpoints for the liveness and readiness probes of orchestrators. /livez answers as
+// long as the server runs, /readyz only while it accepts clients and is under its hard memory
+// watermark. Both stop being answered once shutdown begins.
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::handler::database::Namespaces;
+
+// Longest request head read, probes send a few short lines
+const MAX_REQUEST_SIZE: usize = 4096;
+
+// How long a probe may take to send its request head before it is dropped unanswered
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Pause after a failed accept, such as when out of file descriptors, before trying again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+pub async fn serve_health_checks(listener: TcpListener, shutdown_token: CancellationToken, namespaces: Arc<Namespaces>) {
+    loop {
+        let socket = select! {
+            res = listener.accept() => match res {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a health check: {}", e);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                },
+            },
+            _ = shutdown_token.cancelled() => break,
+        };
+        let cloned_token = shutdown_token.clone();
+        let cloned_namespaces = Arc::clone(&namespaces);
+        tokio::spawn(async move {
+            answer_health_check(socket, cloned_token, cloned_namespaces).await;
+        });
+    }
+    tracing::debug!("Stopped serving health checks");
+}
+
+async fn answer_health_check(mut socket: TcpStream, shutdown_token: CancellationToken, namespaces: Arc<Namespaces>) {
+    let request = match timeout(REQUEST_READ_TIMEOUT, read_request_head(&mut socket)).await {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let request_line = String::from_utf8_lossy(&request);
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = match path {
+        "/livez" | "/healthz" => ("200 OK", serde_json::json!({ "live": true })),
+        "/readyz" => {
+            let db = namespaces.default_db();
+            let connections = db.stats.connected_clients.load(Ordering::Relaxed) as u64;
+            let max_connections = db.settings.max_connections.load(Ordering::Relaxed);
+            let memory_used = db.memory.used_bytes();
+            let memory_hard_limit = db.settings.memory_hard_limit.load(Ordering::Relaxed);
+            let accepting_connections = !shutdown_token.is_cancelled()
+                && (max_connections == 0 || connections < max_connections);
+            let under_memory_limit = memory_hard_limit == 0 || memory_used < memory_hard_limit;
+            let ready = accepting_connections && under_memory_limit;
+            let body = serde_json::json!({
+                "ready": ready,
+                "accepting_connections": accepting_connections,
+                "connections": connections,
+                "max_connections": max_connections,
+                "under_memory_limit": under_memory_limit,
+                "memory_used_bytes": memory_used,
+                "memory_hard_limit": memory_hard_limit,
+            });
+            match ready {
+                true => ("200 OK", body),
+                false => ("503 Service Unavailable", body),
+            }
+        },
+        _ => ("404 Not Found", serde_json::json!({ "error": "not found" })),
+    };
+
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+    let _ = socket.shutdown().await;
+}
+
+// The request up to the end of its head, or as much as came before the client stopped sending
+async fn read_request_head(socket: &mut TcpStream) -> Vec<u8> {
+    let mut request = Vec::with_capacity(512);
+    let mut buffer = [0; 512];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        match socket.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => request.extend_from_slice(&buffer[..read]),
+        }
+    }
+    return request;
+}
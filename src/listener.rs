@@ -0,0 +1,150 @@
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::time::Duration;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+
+use crate::handler::connection::Connection;
+
+// Pending connections each listener queues before accepting them
+const LISTEN_BACKLOG: i32 = 1024;
+
+// A TCP address or a Unix socket path the server accepts clients on
+pub enum Listener {
+    Tcp(TcpListener, SocketOptions),
+    Unix(UnixListener),
+}
+
+// Options of accepted TCP sockets, 0 leaves an option at the system default
+#[derive(Clone)]
+pub struct SocketOptions {
+    // Seconds a connection is idle before keepalive probes are sent, 0 disables keepalive
+    pub keepalive_idle: u64,
+    // Seconds between unanswered probes
+    pub keepalive_interval: u64,
+    // Unanswered probes after which the connection is dropped
+    pub keepalive_count: u32,
+    pub receive_buffer_size: usize,
+    pub send_buffer_size: usize,
+    // Type of service of IPv4 packets, traffic class of IPv6 ones
+    pub ip_tos: u32,
+}
+
+impl SocketOptions {
+    // Buffer sizes are set on the listening socket, which accepted sockets inherit them from,
+    // since the receive buffer has to be known before the handshake to pick the window scale
+    fn apply_to_listener(&self, socket: &Socket) -> io::Result<()> {
+        if self.receive_buffer_size > 0 {
+            socket.set_recv_buffer_size(self.receive_buffer_size)?;
+        }
+        if self.send_buffer_size > 0 {
+            socket.set_send_buffer_size(self.send_buffer_size)?;
+        }
+        return Ok(());
+    }
+
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if self.keepalive_idle > 0 {
+            let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(self.keepalive_idle));
+            if self.keepalive_interval > 0 {
+                keepalive = keepalive.with_interval(Duration::from_secs(self.keepalive_interval));
+            }
+            if self.keepalive_count > 0 {
+                keepalive = keepalive.with_retries(self.keepalive_count);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if self.ip_tos > 0 {
+            match stream.local_addr()?.is_ipv6() {
+                true => socket.set_tclass_v6(self.ip_tos)?,
+                false => socket.set_tos(self.ip_tos)?,
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl Listener {
+    // Binds every address. An IPv6 address on a port an IPv4 address also uses is bound to
+    // IPv6 only, so that both can be listened on.
+    pub fn bind_all(addresses: &Vec<String>, options: &SocketOptions) -> Result<Vec<(String, Listener)>, String> {
+        // None for Unix socket paths
+        let mut socket_addresses: Vec<Option<SocketAddr>> = Vec::new();
+        for address in addresses.iter() {
+            if address.starts_with('/') {
+                socket_addresses.push(None);
+                continue;
+            }
+            match address.to_socket_addrs().map(|mut resolved| resolved.next()) {
+                Ok(Some(socket_address)) => socket_addresses.push(Some(socket_address)),
+                Ok(None) => return Err(format!("Invalid bind address {}: it resolves to no address", address)),
+                Err(e) => return Err(format!("Invalid bind address {}: {}", address, e)),
+            }
+        }
+        let ipv4_ports: Vec<u16> = socket_addresses
+            .iter()
+            .flatten()
+            .filter(|socket_address| socket_address.is_ipv4())
+            .map(|socket_address| socket_address.port())
+            .collect();
+
+        let mut listeners = Vec::new();
+        for (address, socket_address) in addresses.iter().zip(socket_addresses) {
+            let listener = match socket_address {
+                Some(socket_address) => {
+                    let ipv6_only = socket_address.is_ipv6() && ipv4_ports.contains(&socket_address.port());
+                    Listener::bind_tcp(socket_address, ipv6_only, options)
+                },
+                None => Listener::bind_unix(address),
+            };
+            match listener {
+                Ok(listener) => listeners.push((address.clone(), listener)),
+                Err(e) => return Err(format!("Can not listen on {}: {}", address, e)),
+            }
+        }
+        return Ok(listeners);
+    }
+
+    fn bind_tcp(address: SocketAddr, ipv6_only: bool, options: &SocketOptions) -> io::Result<Listener> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if address.is_ipv6() {
+            socket.set_only_v6(ipv6_only)?;
+        }
+        options.apply_to_listener(&socket)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(socket.into())?, options.clone()));
+    }
+
+    // A socket file left behind by an earlier run is replaced
+    fn bind_unix(path: &str) -> io::Result<Listener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        return Ok(Listener::Unix(UnixListener::bind(path)?));
+    }
+
+    pub async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener, options) => {
+                let (socket, address) = listener.accept().await?;
+                let _ = socket.set_nodelay(true);
+                if let Err(e) = options.apply(&socket) {
+                    tracing::warn!("Failed to set socket options of client {}: {}", address, e);
+                }
+                return Ok(Connection::new(socket));
+            },
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                return Ok(Connection::from_unix(socket));
+            },
+        }
+    }
+}
@@ -1,8 +1,16 @@
+use std::process::exit;
+use clap::Parser;
 use tokio::runtime::Builder;
 
+mod bench;
+mod cli;
 mod config;
 mod server;
 mod handler;
+mod health;
+mod listener;
+mod migrate;
+use crate::cli::{Cli, Command};
 use crate::config::AppConfig;
 use crate::server::Server;
 
@@ -12,7 +20,26 @@ use mimalloc::MiMalloc;
 static GLOBAL: MiMalloc = MiMalloc;
 
 fn main() {
-    let config = AppConfig::new();
+    let cli = Cli::parse();
+    match &cli.command {
+        Some(Command::ImportRedis { redis_address, cupid_address, integers }) => {
+            migrate::import_redis(redis_address, cupid_address, *integers);
+            return;
+        },
+        Some(Command::Bench(args)) => {
+            bench::run_bench(args);
+            return;
+        },
+        None => {},
+    }
+
+    let config = match AppConfig::new(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
 
     let runtime = Builder::new_multi_thread()
         .enable_io()
@@ -27,7 +54,13 @@ fn main() {
 }
 
 async fn start_server(config: AppConfig) {
-    let server = Server::new(config).await;
+    let server = match Server::new(config).await {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("{}", e);
+            exit(1);
+        }
+    };
 
     server.run().await;
 }
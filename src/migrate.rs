@@ -0,0 +1,233 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::exit;
+
+// Copies string keys, with their TTLs, from a Redis instance into a running CupidDB. Keys of
+// any other Redis type are reported and skipped. With `integers`, strings holding a decimal
+// integer are stored as integers, else every value is stored as bytes as it is.
+pub fn import_redis(redis_address: &str, cupid_address: &str, integers: bool) {
+    let redis_address = redis_address.trim_start_matches("redis://").trim_end_matches('/');
+
+    let mut redis = match RedisClient::connect(redis_address) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Can not connect to Redis at {}: {}", redis_address, e);
+            exit(1);
+        }
+    };
+    let mut cupid = match TcpStream::connect(cupid_address) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Can not connect to CupidDB at {}: {}", cupid_address, e);
+            exit(1);
+        }
+    };
+
+    let mut migrated: usize = 0;
+    let mut incompatible: Vec<(Vec<u8>, String)> = Vec::new();
+    let mut cursor = b"0".to_vec();
+    loop {
+        let reply = match redis.command(&[b"SCAN", &cursor, b"COUNT", b"1000"]) {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!("Can not scan the keys of Redis at {}: {}", redis_address, e);
+                exit(1);
+            }
+        };
+        let (next_cursor, keys) = match reply {
+            Reply::Array(mut items) if items.len() == 2 => {
+                let keys = items.pop().unwrap();
+                (items.pop().unwrap().into_bytes(), keys)
+            },
+            other => {
+                eprintln!("Unexpected SCAN reply: {:?}", other);
+                exit(1);
+            }
+        };
+
+        if let Reply::Array(keys) = keys {
+            for key in keys {
+                let key = key.into_bytes();
+                match migrate_key(&mut redis, &mut cupid, &key, integers) {
+                    Ok(None) => migrated += 1,
+                    Ok(Some(reason)) => incompatible.push((key, reason)),
+                    Err(e) => {
+                        eprintln!("Migration aborted at key {}: {}", String::from_utf8_lossy(&key), e);
+                        exit(1);
+                    }
+                }
+            }
+        }
+
+        if next_cursor == b"0" {
+            break;
+        }
+        cursor = next_cursor;
+    }
+
+    println!("Migrated {} keys from {} to {}", migrated, redis_address, cupid_address);
+    if incompatible.len() > 0 {
+        println!("Skipped {} incompatible keys:", incompatible.len());
+        for (key, reason) in incompatible {
+            println!("  {}: {}", String::from_utf8_lossy(&key), reason);
+        }
+    }
+}
+
+// Returns the reason the key was skipped, if it was
+fn migrate_key(
+    redis: &mut RedisClient, cupid: &mut TcpStream, key: &[u8], integers: bool
+) -> io::Result<Option<String>> {
+    let key_type = String::from_utf8_lossy(&redis.command(&[b"TYPE", key])?.into_bytes()).to_string();
+    if key_type == "none" {
+        // Expired or deleted between SCAN and TYPE
+        return Ok(Some("key disappeared during migration".to_string()));
+    }
+    if key_type != "string" {
+        return Ok(Some(format!("unsupported Redis type {}", key_type)));
+    }
+    if key.len() > u16::MAX as usize {
+        return Ok(Some("key is too long".to_string()));
+    }
+    if std::str::from_utf8(key).is_err() {
+        return Ok(Some("key is not valid UTF-8".to_string()));
+    }
+
+    let value = match redis.command(&[b"GET", key])? {
+        Reply::Bulk(Some(value)) => value,
+        _ => return Ok(Some("key disappeared during migration".to_string())),
+    };
+    let ttl_ms = match redis.command(&[b"PTTL", key])? {
+        Reply::Integer(ttl) if ttl > 0 => ttl as u64,
+        Reply::Integer(-2) => return Ok(Some("key expired during migration".to_string())),
+        _ => 0,
+    };
+
+    // Integers become CupidDB integers so II keeps working on them, when asked for. Only those
+    // written the way the integer prints, so "007" or "+7" come back as they were.
+    let int_value = match integers {
+        true => std::str::from_utf8(&value)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .filter(|int_value| int_value.to_string().as_bytes() == value.as_slice()),
+        false => None,
+    };
+    let mut stored_value: Vec<u8>;
+    match int_value {
+        Some(int_value) => {
+            stored_value = vec!['I' as u8];
+            stored_value.extend(int_value.to_be_bytes());
+        },
+        None => {
+            stored_value = vec!['B' as u8];
+            stored_value.extend(value);
+        },
+    }
+
+    let mut payload = ttl_ms.to_be_bytes().to_vec();
+    payload.extend((key.len() as u16).to_be_bytes());
+    payload.extend(key);
+    payload.extend(stored_value);
+
+    let mut frame = "ASD".to_string().into_bytes();
+    frame.extend((payload.len() as u64).to_be_bytes());
+    frame.extend(payload);
+    cupid.write_all(&frame)?;
+
+    let mut header_buffer = [0; 11];
+    cupid.read_exact(&mut header_buffer)?;
+    let payload_length = u64::from_be_bytes(header_buffer[3..11].try_into().unwrap());
+    let mut response_payload = vec![0; payload_length as usize];
+    cupid.read_exact(&mut response_payload)?;
+    if &header_buffer[1..3] != b"OK" {
+        return Ok(Some(format!("CupidDB rejected the value: {:?}", response_payload)));
+    }
+    return Ok(None);
+}
+
+#[derive(Debug)]
+enum Reply {
+    Simple(Vec<u8>),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Reply::Simple(value) => value,
+            Reply::Bulk(Some(value)) => value,
+            Reply::Integer(value) => value.to_string().into_bytes(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Just enough of RESP2 to drive SCAN/TYPE/GET/PTTL
+struct RedisClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RedisClient {
+    fn connect(address: &str) -> io::Result<RedisClient> {
+        let stream = TcpStream::connect(address)?;
+        return Ok(RedisClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        });
+    }
+
+    fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend(format!("${}\r\n", arg.len()).into_bytes());
+            request.extend(*arg);
+            request.extend(b"\r\n");
+        }
+        self.writer.write_all(&request)?;
+
+        return match self.read_reply()? {
+            Reply::Error(message) => Err(io::Error::other(message)),
+            reply => Ok(reply),
+        };
+    }
+
+    fn read_reply(&mut self) -> io::Result<Reply> {
+        let mut line = Vec::new();
+        self.reader.read_until(b'\n', &mut line)?;
+        if line.len() < 3 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated Redis reply"));
+        }
+        let content = &line[1..line.len() - 2];
+        let content_str = String::from_utf8_lossy(content).to_string();
+        let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Redis reply");
+
+        match line[0] {
+            b'+' => Ok(Reply::Simple(content.to_vec())),
+            b'-' => Ok(Reply::Error(content_str)),
+            b':' => Ok(Reply::Integer(content_str.parse().map_err(invalid)?)),
+            b'$' => {
+                let length: i64 = content_str.parse().map_err(invalid)?;
+                if length < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut value = vec![0; length as usize + 2];
+                self.reader.read_exact(&mut value)?;
+                value.truncate(length as usize);
+                Ok(Reply::Bulk(Some(value)))
+            },
+            b'*' => {
+                let length: i64 = content_str.parse().map_err(invalid)?;
+                let mut items = Vec::new();
+                for _ in 0..length.max(0) {
+                    items.push(self.read_reply()?);
+                }
+                Ok(Reply::Array(items))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown Redis reply type")),
+        }
+    }
+}
@@ -1,43 +1,76 @@
-use std::sync::{Arc, Mutex};
-use dashmap::DashMap;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use tokio::net::TcpListener;
 use tokio::time::{sleep, Duration};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::select;
 use tokio_util::sync::CancellationToken;
+use bytes::Bytes;
 
 use crate::config::AppConfig;
+use crate::handler::connection::Connection;
 use crate::handler::handler::handle_stream;
 use crate::handler::cache_manager::cache_manager;
+use crate::handler::database::{Database, Namespaces};
+use crate::handler::webhook::webhook_poster;
+use crate::handler::write_behind::write_behind_flusher;
+use crate::health::serve_health_checks;
+use crate::listener::Listener;
 
 pub struct Server {
-    listener: TcpListener,
+    // With the address each one was bound to
+    listeners: Vec<(String, Listener)>,
+    health_listener: Option<TcpListener>,
     config: AppConfig,
 }
 
 impl Server {
-    pub async fn new(config: AppConfig) -> Server {
-        Server {
-            listener: TcpListener::bind(config.bind_address.as_str()).await.unwrap(),
+    pub async fn new(config: AppConfig) -> Result<Server, String> {
+        let listeners = Listener::bind_all(&config.bind_addresses, &config.socket_options)?;
+        let health_listener = match &config.health_address {
+            Some(address) => match TcpListener::bind(address.as_str()).await {
+                Ok(listener) => Some(listener),
+                Err(e) => return Err(format!("Can not serve health checks on {}: {}", address, e)),
+            },
+            None => None,
+        };
+        return Ok(Server {
+            listeners: listeners,
+            health_listener: health_listener,
             config: config,
-        }
+        });
     }
 
-    pub async fn run(self) {
+    pub async fn run(mut self) {
         let shutdown_token = CancellationToken::new();
 
-        let timeout_db = Arc::new(DashMap::with_capacity_and_shard_amount(
-            self.config.cache_initial_capacity, self.config.cache_shards
-        ));
-        let shared_db = Arc::new(DashMap::with_capacity_and_shard_amount(
-            self.config.cache_initial_capacity, self.config.cache_shards
-        ));
-        let cloned_timeout_db = Arc::clone(&timeout_db);
-        let cloned_db = Arc::clone(&shared_db);
+        let db = Arc::new(Database::new(&self.config));
+        let namespaces = Arc::new(Namespaces::new(Arc::clone(&db)));
+        let cloned_namespaces = Arc::clone(&namespaces);
         let cloned_token = shutdown_token.clone();
 
         tokio::spawn(async move {
-            cache_manager(cloned_token, cloned_timeout_db, cloned_db).await;
+            cache_manager(cloned_token, cloned_namespaces).await;
+        });
+
+        // Writes are persisted and key events posted until the last connection is done, then flushed
+        // one last time
+        let flusher_token = CancellationToken::new();
+        let flusher = self.config.write_behind.clone().map(|write_behind| {
+            let cloned_token = flusher_token.clone();
+            let cloned_namespaces = Arc::clone(&namespaces);
+            let interval = Duration::from_millis(self.config.write_behind_interval_ms);
+            tokio::spawn(async move {
+                write_behind_flusher(cloned_token, cloned_namespaces, write_behind, interval).await;
+            })
+        });
+        let poster = self.config.webhook.clone().map(|webhook| {
+            let cloned_token = flusher_token.clone();
+            let interval = Duration::from_millis(self.config.webhook_interval_ms);
+            tokio::spawn(async move {
+                webhook_poster(cloned_token, webhook, interval).await;
+            })
         });
 
         let cloned_cancel_token = shutdown_token.clone();
@@ -51,43 +84,107 @@ impl Server {
             cloned_cancel_token.cancel();
         });
 
-        let connection_counter = Arc::new(Mutex::new(0 as usize));
-        loop {
-            let (socket, addr) = select! {
-                res = self.listener.accept() => {
-                    let mut counter = connection_counter.lock().unwrap();
-                    *counter += 1;
-                    res.unwrap()
-                }
-                _ = shutdown_token.cancelled() => {
-                    break; // Stop accepting new connections
-                }
-            };
-            let _ = socket.set_nodelay(true);
-            tracing::debug!("Accepted client with address {}", addr);
+        // Settings that can change at runtime are read again from the configuration on SIGHUP
+        let reload_settings = Arc::clone(&db.settings);
+        tokio::spawn(async move {
+            let mut signal_hangup = signal(SignalKind::hangup()).unwrap();
+            while signal_hangup.recv().await.is_some() {
+                tracing::info!("Received SIGHUP");
+                let _ = reload_settings.reload();
+            }
+        });
 
-            let counter_clone = Arc::clone(&connection_counter);
+        if let (Some(health_listener), Some(address)) = (self.health_listener.take(), &self.config.health_address) {
+            tracing::info!("Serving health checks on {address}");
             let cloned_token = shutdown_token.clone();
-            let cloned_timeout_db = Arc::clone(&timeout_db);
-            let cloned_db = Arc::clone(&shared_db);
-
+            let cloned_namespaces = Arc::clone(&namespaces);
             tokio::spawn(async move {
-                handle_stream(socket, cloned_token, cloned_timeout_db, cloned_db).await;
-                let mut counter = counter_clone.lock().unwrap();
-                *counter -= 1;
+                serve_health_checks(health_listener, cloned_token, cloned_namespaces).await;
             });
         }
 
+        let mut accept_loops = Vec::new();
+        for (address, listener) in std::mem::take(&mut self.listeners) {
+            tracing::info!("Listening on {address}");
+            let cloned_token = shutdown_token.clone();
+            let cloned_namespaces = Arc::clone(&namespaces);
+            accept_loops.push(tokio::spawn(async move {
+                accept_clients(listener, cloned_token, cloned_namespaces).await;
+            }));
+        }
+        for accept_loop in accept_loops {
+            let _ = accept_loop.await;
+        }
+
         tracing::info!("Gracefully shutting down with a {} second timeout.", self.config.graceful_timeout);
         for _ in 0..self.config.graceful_timeout {
-            {
-                let counter = connection_counter.lock().unwrap();
-                if *counter == 0 {
-                    break;
-                }
+            if db.stats.connected_clients.load(Ordering::Relaxed) == 0 {
+                break;
             }
             sleep(Duration::from_millis(1000)).await;
         }
+        flusher_token.cancel();
+        if let Some(flusher) = flusher {
+            let _ = flusher.await;
+        }
+        if let Some(poster) = poster {
+            let _ = poster.await;
+        }
+        for address in self.config.bind_addresses.iter().filter(|address| address.starts_with('/')) {
+            let _ = fs::remove_file(address);
+        }
         tracing::info!("Exiting");
     }
 }
+
+// Accepts clients on one listener until shutdown
+async fn accept_clients(listener: Listener, shutdown_token: CancellationToken, namespaces: Arc<Namespaces>) {
+    let db = namespaces.default_db();
+    loop {
+        let connection = select! {
+            res = listener.accept() => match res {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a client: {}", e);
+                    continue;
+                },
+            },
+            _ = shutdown_token.cancelled() => {
+                break; // Stop accepting new connections
+            }
+        };
+
+        // Connections are counted from here until their task ends, so the count can not go
+        // over the limit however many clients the listeners accept at once
+        let max_connections = db.settings.max_connections.load(Ordering::Relaxed) as usize;
+        let reserved = db.stats.connected_clients.fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+            match max_connections > 0 && open >= max_connections {
+                true => None,
+                false => Some(open + 1),
+            }
+        });
+        if let Err(open) = reserved {
+            tracing::warn!("Rejected client with address {}, {} connections are open", connection.peer_address(), open);
+            tokio::spawn(async move {
+                reject_connection(connection).await;
+            });
+            continue;
+        }
+        tracing::debug!("Accepted client with address {}", connection.peer_address());
+
+        let cloned_db = Arc::clone(&db);
+        let cloned_token = shutdown_token.clone();
+        let cloned_namespaces = Arc::clone(&namespaces);
+
+        tokio::spawn(async move {
+            handle_stream(connection, cloned_token, cloned_namespaces).await;
+            cloned_db.stats.connected_clients.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+}
+
+// Tells a client over the connection limit why it is turned away before closing
+async fn reject_connection(connection: Connection) {
+    let error_code: u16 = 13;
+    let _ = connection.write_frame("ER".to_string(), None, Bytes::copy_from_slice(&error_code.to_be_bytes())).await;
+}